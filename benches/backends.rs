@@ -0,0 +1,69 @@
+//! Compares the tree-walking interpreter with the closure backend on the
+//! sample programs, using the first run in each sample's manifest. The
+//! samples are tiny, so they mostly measure set-up; `fib_20` and
+//! `sum_loop` run long enough to show the cost per instruction. With
+//! `--features jit`, the JIT is benchmarked too.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use falsy::interpreter::{Backend, Interpreter};
+use falsy::parser::parse;
+
+fn samples() -> Vec<(String, String, String)> {
+    let mut samples = Vec::new();
+    for entry in std::fs::read_dir("tests/samples").unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension().unwrap() != "false" {
+            continue;
+        }
+        let name = path.file_stem().unwrap().to_string_lossy().to_string();
+        let source = std::fs::read_to_string(&path).unwrap();
+        let manifest: toml::Table =
+            toml::from_str(&std::fs::read_to_string(path.with_extension("toml")).unwrap()).unwrap();
+        let input = manifest["runs"][0]["input"].as_str().unwrap().to_string();
+        samples.push((name, source, input));
+    }
+    samples.sort();
+    samples.push((
+        "fib_20".to_string(),
+        "[$ 1 > [1- $ f;! \\ 1- f;! +]?]f: 20 f;! .".to_string(),
+        String::new(),
+    ));
+    samples.push((
+        "sum_loop".to_string(),
+        "0 0 [$ 100000 >~][$ @ + 65535 & \\ 1+]# % .".to_string(),
+        String::new(),
+    ));
+    samples
+}
+
+fn backends(c: &mut Criterion) {
+    for (name, source, input) in samples() {
+        let ast = parse(&source).into_result().expect("Failed to parse");
+        let mut group = c.benchmark_group(name);
+        let mut backends = vec![Backend::Tree, Backend::Closures];
+        if cfg!(feature = "jit") {
+            backends.push("jit".parse().unwrap());
+        }
+        for backend in backends {
+            group.bench_with_input(
+                BenchmarkId::from_parameter(format!("{backend:?}")),
+                &backend,
+                |b, backend| {
+                    b.iter(|| {
+                        let mut input = input.bytes();
+                        Interpreter::new()
+                            .on_input(|| input.next())
+                            .on_output(|_| {})
+                            .backend(*backend)
+                            .run_program(ast.clone())
+                            .unwrap()
+                    })
+                },
+            );
+        }
+        group.finish();
+    }
+}
+
+criterion_group!(benches, backends);
+criterion_main!(benches);
@@ -0,0 +1,149 @@
+//! Macros for embedding FALSE programs in Rust.
+//!
+//! Both macros parse the program at compile time with
+//! [`falsy::parser::parse`], and expand to the AST as an expression of type
+//! `Vec<falsy::ast::Spanned<falsy::ast::FalseInstruction>>`, ready for the
+//! interpreter with no parsing left to do at runtime. Spans are byte offsets
+//! into the embedded source, as if it had been parsed at runtime. Parse
+//! errors are compile errors.
+//!
+//! The expansion refers to the `falsy` crate, which must be a dependency
+//! under that name.
+//!
+//! ```
+//! // `false` is a keyword, so the macro is invoked by its raw name
+//! let countdown = falsy_macros::r#false!{ "3[$0>][$.1-]#%" };
+//! let hello = falsy_macros::include_false!("../tests/samples/hello_world.false");
+//! # assert_eq!(countdown.len(), 3);
+//! # assert_eq!(hello.len(), 1);
+//! ```
+
+use falsy::ast::{FalseInstruction, Spanned};
+use proc_macro2::{Span, TokenStream};
+use quote::{format_ident, quote};
+use syn::{parse_macro_input, LitStr};
+
+/// Parses a FALSE program, given as a string literal, at compile time.
+///
+/// Raw strings (`r#"..."#`) avoid escaping the program's quotes and
+/// backslashes.
+#[proc_macro]
+pub fn r#false(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let literal = parse_macro_input!(input as LitStr);
+    let source = literal.value();
+    match parse(&source, |offset, line, column, reason| {
+        let span = subspan(&literal, &source, offset).unwrap_or_else(|| literal.span());
+        syn::Error::new(span, format!("{reason} at {line}:{column}"))
+    }) {
+        Ok(ast) => ast.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+/// Parses a FALSE program from a file at compile time. The path is
+/// relative to the directory of the invoking crate's `Cargo.toml`.
+#[proc_macro]
+pub fn include_false(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let literal = parse_macro_input!(input as LitStr);
+    let path = std::path::Path::new(&std::env::var("CARGO_MANIFEST_DIR").unwrap_or_default())
+        .join(literal.value());
+    let source = match std::fs::read_to_string(&path) {
+        Ok(source) => source,
+        Err(e) => {
+            let message = format!("Failed to read {}: {e}", path.display());
+            return syn::Error::new(literal.span(), message)
+                .to_compile_error()
+                .into();
+        }
+    };
+    let ast = match parse(&source, |_, line, column, reason| {
+        let message = format!("{}:{line}:{column}: {reason}", literal.value());
+        syn::Error::new(literal.span(), message)
+    }) {
+        Ok(ast) => ast,
+        Err(e) => return e.to_compile_error().into(),
+    };
+
+    // Rebuild when the file changes
+    let path = path.to_string_lossy();
+    quote! {
+        {
+            const _: &str = ::core::include_str!(#path);
+            #ast
+        }
+    }
+    .into()
+}
+
+/// Parses `source` into the expression for its AST, or all its errors,
+/// each made by `error` from a byte offset, line, column and reason.
+fn parse(
+    source: &str,
+    error: impl Fn(usize, usize, usize, String) -> syn::Error,
+) -> Result<TokenStream, syn::Error> {
+    match falsy::parser::parse(source).into_result() {
+        Ok(ast) => Ok(instructions(&ast)),
+        Err(errors) => {
+            let mut errors = errors.into_iter().map(|e| {
+                let offset = e.span().start;
+                let (line, column) = falsy::lines::location(source, offset);
+                error(offset, line, column, e.to_string())
+            });
+            let mut combined = errors.next().expect("Parse failed without errors");
+            combined.extend(errors);
+            Err(combined)
+        }
+    }
+}
+
+/// The span of the character at `offset` in the literal's value, where
+/// the compiler supports it and the value is written out as is.
+fn subspan(literal: &LitStr, value: &str, offset: usize) -> Option<Span> {
+    let token = literal.token().to_string();
+    let start = token.find('"')? + 1;
+    if token.get(start..start + value.len())? != value {
+        return None;
+    }
+    let end = offset + value[offset..].chars().next().map_or(0, char::len_utf8);
+    literal.token().subspan(start + offset..start + end)
+}
+
+fn instructions(instructions: &[Spanned<FalseInstruction>]) -> TokenStream {
+    let items = instructions.iter().map(|spanned| {
+        let instruction = instruction(spanned.instruction());
+        let (start, end) = (spanned.span().start, spanned.span().end);
+        quote! {
+            ::falsy::ast::Spanned::new(#instruction, (#start..#end).into())
+        }
+    });
+    quote! { ::std::vec![#(#items),*] }
+}
+
+fn instruction(instruction: &FalseInstruction) -> TokenStream {
+    use FalseInstruction::*;
+
+    let path = quote! { ::falsy::ast::FalseInstruction };
+    match instruction {
+        Name(c) => quote! { #path::Name(#c) },
+        PushInt(v) => quote! { #path::PushInt(#v) },
+        PushChar(c) => quote! { #path::PushChar(#c) },
+        Lambda(body) => {
+            let body = instructions(body);
+            quote! { #path::Lambda(#body) }
+        }
+        ConditionalExecute(body) => {
+            let body = instructions(body);
+            quote! { #path::ConditionalExecute(#body) }
+        }
+        WhileLoop(condition, body) => {
+            let (condition, body) = (instructions(condition), instructions(body));
+            quote! { #path::WhileLoop(#condition, #body) }
+        }
+        WriteStr(s) => quote! { #path::WriteStr(::std::string::String::from(#s)) },
+        // The rest are unit variants, named as they are debug-printed
+        unit => {
+            let variant = format_ident!("{}", format!("{unit:?}"));
+            quote! { #path::#variant }
+        }
+    }
+}
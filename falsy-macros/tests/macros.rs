@@ -0,0 +1,42 @@
+use falsy::ast::FalseInstruction;
+use falsy::interpreter::Interpreter;
+use falsy::parser::parse;
+use falsy_macros::{include_false, r#false};
+
+#[test]
+fn expands_to_the_parsed_ast() {
+    const SOURCE: &str =
+        r#"{ factorial } [$1>[$1-f;!*]?]f: 5f;!. "!" 10, 'x 1_ ^ß 0[$3>~][1+]# \@ø"#;
+    let program =
+        r#false! { r#"{ factorial } [$1>[$1-f;!*]?]f: 5f;!. "!" 10, 'x 1_ ^ß 0[$3>~][1+]# \@ø"# };
+    assert_eq!(program, parse(SOURCE).into_result().unwrap());
+}
+
+#[test]
+fn keeps_spans_into_the_source() {
+    let program = r#false!("  [1]!");
+    assert_eq!(program[0].span(), (2..5).into());
+    let FalseInstruction::Lambda(body) = program[0].instruction() else {
+        panic!("Expected a lambda, got {program:?}");
+    };
+    assert_eq!(body[0].span(), (3..4).into());
+}
+
+#[test]
+fn includes_files() {
+    let source = include_str!("../../tests/samples/hello_world.false");
+    let program = include_false!("../tests/samples/hello_world.false");
+    assert_eq!(program, parse(source).into_result().unwrap());
+
+    let mut output = String::new();
+    Interpreter::new()
+        .on_output(|s| output.push_str(s))
+        .run_program(program)
+        .unwrap();
+    assert_eq!(output, "Hello, World!");
+}
+
+#[test]
+fn reports_parse_errors_at_compile_time() {
+    trybuild::TestCases::new().compile_fail("tests/ui/*.rs");
+}
@@ -0,0 +1,4 @@
+fn main() {
+    let _ = falsy_macros::r#false!("1 2 [+ .");
+    let _ = falsy_macros::include_false!("tests/ui/missing.false");
+}
@@ -0,0 +1,383 @@
+//! Effect analysis.
+//!
+//! Classifies every lambda (and every variable, by the lambdas it may hold)
+//! by the side effects running it can have: reading input, writing output,
+//! and reading or writing globals. Effects are transitive, so a lambda that
+//! executes another lambda through `f;!` has the effects of that lambda too.
+//!
+//! Calls that cannot be resolved statically are assumed to run any lambda in
+//! the program, since those are the only values `!` can execute.
+
+use std::collections::HashMap;
+
+use chumsky::span::SimpleSpan;
+
+use crate::ast::{FalseInstruction, Spanned};
+
+/// A set of variable names, `a` to `z`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct VariableSet(u32);
+
+impl VariableSet {
+    pub const EMPTY: VariableSet = VariableSet(0);
+    pub const ALL: VariableSet = VariableSet((1 << 26) - 1);
+
+    pub fn insert(&mut self, name: char) {
+        self.0 |= Self::bit(name);
+    }
+
+    pub fn contains(&self, name: char) -> bool {
+        self.0 & Self::bit(name) != 0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = char> + '_ {
+        ('a'..='z').filter(|c| self.contains(*c))
+    }
+
+    fn bit(name: char) -> u32 {
+        1 << (name as u8 - b'a')
+    }
+}
+
+impl FromIterator<char> for VariableSet {
+    fn from_iter<T: IntoIterator<Item = char>>(iter: T) -> Self {
+        let mut set = Self::EMPTY;
+        for name in iter {
+            set.insert(name);
+        }
+        set
+    }
+}
+
+impl std::fmt::Display for VariableSet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if *self == Self::ALL {
+            write!(f, "*")
+        } else {
+            self.iter().try_for_each(|c| write!(f, "{c}"))
+        }
+    }
+}
+
+/// What running a piece of code may do besides working on the stack.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Effects {
+    /// Uses `^`.
+    pub reads_input: bool,
+    /// Uses `,`, `.`, `"..."` or `ß`.
+    pub writes_output: bool,
+    /// Variables that may be written with `:`.
+    pub stores: VariableSet,
+    /// Variables that may be read with `;`.
+    pub fetches: VariableSet,
+}
+
+impl Effects {
+    /// No I/O and no writes to globals. Pure code may still read globals.
+    pub fn is_pure(&self) -> bool {
+        !self.reads_input && !self.writes_output && self.stores.is_empty()
+    }
+
+    pub fn touches_io(&self) -> bool {
+        self.reads_input || self.writes_output
+    }
+
+    pub fn union(self, other: Self) -> Self {
+        Self {
+            reads_input: self.reads_input || other.reads_input,
+            writes_output: self.writes_output || other.writes_output,
+            stores: self.stores.union(other.stores),
+            fetches: self.fetches.union(other.fetches),
+        }
+    }
+}
+
+/// Effects of a single lambda in the program.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LambdaEffects {
+    span: SimpleSpan<usize>,
+    effects: Effects,
+    stored_in: VariableSet,
+}
+
+impl LambdaEffects {
+    /// Span of the `[...]` that defines the lambda.
+    pub fn span(&self) -> SimpleSpan<usize> {
+        self.span
+    }
+    pub fn effects(&self) -> Effects {
+        self.effects
+    }
+    /// Variables this lambda may be stored in.
+    pub fn stored_in(&self) -> VariableSet {
+        self.stored_in
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct EffectAnalysis {
+    program: Effects,
+    lambdas: Vec<LambdaEffects>,
+    by_span: HashMap<SimpleSpan<usize>, usize>,
+    variables: [Effects; 26],
+}
+
+impl EffectAnalysis {
+    pub fn analyze(program: &[Spanned<FalseInstruction>]) -> Self {
+        let mut collector = Collector::default();
+        let root = collector.add_body(None, program);
+        collector.resolve(root)
+    }
+
+    /// Effects of running the whole program.
+    pub fn program(&self) -> Effects {
+        self.program
+    }
+
+    /// Every lambda in the program, in source order.
+    pub fn lambdas(&self) -> &[LambdaEffects] {
+        &self.lambdas
+    }
+
+    /// Effects of the lambda defined at `span`, the span of its `[...]`.
+    pub fn lambda_at(&self, span: SimpleSpan<usize>) -> Option<&LambdaEffects> {
+        self.by_span.get(&span).map(|i| &self.lambdas[*i])
+    }
+
+    /// Effects of executing whatever lambda the variable holds.
+    pub fn variable(&self, name: char) -> Effects {
+        self.variables[(name as u8 - b'a') as usize]
+    }
+}
+
+/// Index of a lambda body in `Collector::bodies`. The program itself is a
+/// body too, the one without a span.
+type BodyId = usize;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum CallTarget {
+    Body(BodyId),
+    Variable(char),
+    Unknown,
+}
+
+#[derive(Default)]
+struct Body {
+    span: Option<SimpleSpan<usize>>,
+    direct: Effects,
+    calls: Vec<CallTarget>,
+}
+
+#[derive(Default)]
+struct Collector {
+    bodies: Vec<Body>,
+    /// Lambdas stored straight into each variable.
+    variable_lambdas: HashMap<char, Vec<BodyId>>,
+    /// `b` may hold anything `a` holds, after `a; b:`.
+    aliases: Vec<(char, char)>,
+    /// Variables that may hold a lambda we can't name.
+    variable_unknown: VariableSet,
+}
+
+impl Collector {
+    fn add_body(
+        &mut self,
+        span: Option<SimpleSpan<usize>>,
+        instructions: &[Spanned<FalseInstruction>],
+    ) -> BodyId {
+        let id = self.bodies.len();
+        self.bodies.push(Body {
+            span,
+            ..Body::default()
+        });
+        self.collect(id, instructions);
+        id
+    }
+
+    fn collect(&mut self, id: BodyId, instructions: &[Spanned<FalseInstruction>]) {
+        use FalseInstruction::*;
+
+        // Body ids of lambdas defined directly in this block, by index
+        let mut defined: HashMap<usize, BodyId> = HashMap::new();
+
+        for (i, spanned) in instructions.iter().enumerate() {
+            let previous = |n: usize| i.checked_sub(n).map(|j| instructions[j].instruction());
+            match spanned.instruction() {
+                Lambda(body) => {
+                    let lambda = self.add_body(Some(spanned.span()), body);
+                    defined.insert(i, lambda);
+                }
+                ConditionalExecute(body) => self.collect(id, body),
+                WhileLoop(condition, body) => {
+                    self.collect(id, condition);
+                    self.collect(id, body);
+                }
+                Execute => {
+                    let target = match (previous(2), previous(1)) {
+                        (_, Some(Lambda(_))) => CallTarget::Body(defined[&(i - 1)]),
+                        (Some(Name(c)), Some(Fetch)) => CallTarget::Variable(*c),
+                        _ => CallTarget::Unknown,
+                    };
+                    self.bodies[id].calls.push(target);
+                }
+                Store => match previous(1) {
+                    Some(Name(c)) => {
+                        self.bodies[id].direct.stores.insert(*c);
+                        match (previous(3), previous(2)) {
+                            (_, Some(Lambda(_))) => self
+                                .variable_lambdas
+                                .entry(*c)
+                                .or_default()
+                                .push(defined[&(i - 2)]),
+                            (Some(Name(from)), Some(Fetch)) => self.aliases.push((*from, *c)),
+                            (_, Some(instruction)) if !is_integer_result(instruction) => {
+                                self.variable_unknown.insert(*c)
+                            }
+                            // The value comes from before the start of this block
+                            (_, None) => self.variable_unknown.insert(*c),
+                            _ => {}
+                        }
+                    }
+                    _ => {
+                        self.bodies[id].direct.stores = VariableSet::ALL;
+                        self.variable_unknown = VariableSet::ALL;
+                    }
+                },
+                Fetch => match previous(1) {
+                    Some(Name(c)) => self.bodies[id].direct.fetches.insert(*c),
+                    _ => self.bodies[id].direct.fetches = VariableSet::ALL,
+                },
+                ReadChar => self.bodies[id].direct.reads_input = true,
+                WriteChar | WriteStr(_) | WriteInt | Flush => {
+                    self.bodies[id].direct.writes_output = true
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Which bodies each variable may hold, following aliases.
+    fn variable_contents(&self) -> HashMap<char, (Vec<BodyId>, bool)> {
+        let mut contents: HashMap<char, (Vec<BodyId>, bool)> = ('a'..='z')
+            .map(|c| {
+                let lambdas = self.variable_lambdas.get(&c).cloned().unwrap_or_default();
+                (c, (lambdas, self.variable_unknown.contains(c)))
+            })
+            .collect();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (from, to) in &self.aliases {
+                let (from_lambdas, from_unknown) = contents[from].clone();
+                let (to_lambdas, to_unknown) = contents.get_mut(to).unwrap();
+                for lambda in from_lambdas {
+                    if !to_lambdas.contains(&lambda) {
+                        to_lambdas.push(lambda);
+                        changed = true;
+                    }
+                }
+                if from_unknown && !*to_unknown {
+                    *to_unknown = true;
+                    changed = true;
+                }
+            }
+        }
+        contents
+    }
+
+    fn resolve(self, root: BodyId) -> EffectAnalysis {
+        let contents = self.variable_contents();
+        let lambda_ids: Vec<BodyId> = (0..self.bodies.len()).filter(|id| *id != root).collect();
+
+        let targets = |target: &CallTarget| -> Vec<BodyId> {
+            match target {
+                CallTarget::Body(id) => vec![*id],
+                CallTarget::Variable(c) => match &contents[c] {
+                    (_, true) => lambda_ids.clone(),
+                    (lambdas, false) => lambdas.clone(),
+                },
+                CallTarget::Unknown => lambda_ids.clone(),
+            }
+        };
+
+        // Propagate effects along calls until nothing changes
+        let mut effects: Vec<Effects> = self.bodies.iter().map(|b| b.direct).collect();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (id, body) in self.bodies.iter().enumerate() {
+                let mut combined = effects[id];
+                for target in &body.calls {
+                    for callee in targets(target) {
+                        combined = combined.union(effects[callee]);
+                    }
+                }
+                if combined != effects[id] {
+                    effects[id] = combined;
+                    changed = true;
+                }
+            }
+        }
+
+        let variables = std::array::from_fn(|i| {
+            let name = (b'a' + i as u8) as char;
+            targets(&CallTarget::Variable(name))
+                .into_iter()
+                .fold(Effects::default(), |acc, id| acc.union(effects[id]))
+        });
+
+        let mut lambdas = Vec::new();
+        let mut by_span = HashMap::new();
+        for id in lambda_ids.iter().copied() {
+            let span = self.bodies[id].span.expect("Lambda bodies have spans");
+            let stored_in = contents
+                .iter()
+                .filter(|(_, (lambdas, _))| lambdas.contains(&id))
+                .map(|(c, _)| *c)
+                .collect();
+            by_span.insert(span, lambdas.len());
+            lambdas.push(LambdaEffects {
+                span,
+                effects: effects[id],
+                stored_in,
+            });
+        }
+
+        EffectAnalysis {
+            program: effects[root],
+            lambdas,
+            by_span,
+            variables,
+        }
+    }
+}
+
+/// Instructions whose result is always an integer, so storing it can't put a
+/// lambda in a variable.
+fn is_integer_result(instruction: &FalseInstruction) -> bool {
+    use FalseInstruction::*;
+    matches!(
+        instruction,
+        PushInt(_)
+            | PushChar(_)
+            | Add
+            | Sub
+            | Mul
+            | Div
+            | Neg
+            | BitAnd
+            | BitOr
+            | BitNot
+            | Gt
+            | Eq
+            | ReadChar
+    )
+}
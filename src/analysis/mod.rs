@@ -0,0 +1,5 @@
+//! Static analyses over the parsed AST.
+
+pub mod effects;
+pub mod ranges;
+pub mod stack_effect;
@@ -0,0 +1,672 @@
+//! Value-range analysis.
+//!
+//! Abstract interpretation of a program where every integer is tracked as an
+//! interval. It flags the places where the interpreter could fail at runtime
+//! because of a bad value: division by zero, writing a value that is not a
+//! valid `char`, and `ø` (PICK) reaching outside the stack.
+
+use std::collections::{BTreeMap, HashSet};
+
+use chumsky::span::SimpleSpan;
+
+use crate::ast::{FalseInstruction, Spanned};
+
+/// Maximum depth of lambda calls that are analyzed inline before giving up
+/// and treating the call as unknown.
+const MAX_CALL_DEPTH: usize = 32;
+
+/// Loop iterations joined precisely before widening kicks in.
+const WIDEN_AFTER: usize = 3;
+
+/// Inclusive range of `i32` values.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Interval {
+    min: i32,
+    max: i32,
+}
+
+impl Interval {
+    pub const FULL: Interval = Interval {
+        min: i32::MIN,
+        max: i32::MAX,
+    };
+
+    pub fn new(min: i32, max: i32) -> Self {
+        assert!(min <= max, "Interval bounds out of order: {min} > {max}");
+        Self { min, max }
+    }
+
+    pub fn constant(value: i32) -> Self {
+        Self::new(value, value)
+    }
+
+    pub fn min(&self) -> i32 {
+        self.min
+    }
+
+    pub fn max(&self) -> i32 {
+        self.max
+    }
+
+    pub fn contains(&self, value: i32) -> bool {
+        self.min <= value && value <= self.max
+    }
+
+    pub fn as_constant(&self) -> Option<i32> {
+        (self.min == self.max).then_some(self.min)
+    }
+
+    fn from_i64(min: i64, max: i64) -> Self {
+        if min < i32::MIN as i64 || max > i32::MAX as i64 {
+            // The operation may overflow, so anything is possible
+            Self::FULL
+        } else {
+            Self::new(min as i32, max as i32)
+        }
+    }
+
+    fn from_corners(corners: impl IntoIterator<Item = i64>) -> Self {
+        let (min, max) = corners
+            .into_iter()
+            .fold((i64::MAX, i64::MIN), |(min, max), c| {
+                (min.min(c), max.max(c))
+            });
+        Self::from_i64(min, max)
+    }
+
+    fn intersects(&self, min: i32, max: i32) -> bool {
+        self.min <= max && min <= self.max
+    }
+
+    fn join(self, other: Self) -> Self {
+        Self::new(self.min.min(other.min), self.max.max(other.max))
+    }
+
+    fn widen(self, next: Self) -> Self {
+        Self::new(
+            if next.min < self.min {
+                i32::MIN
+            } else {
+                self.min
+            },
+            if next.max > self.max {
+                i32::MAX
+            } else {
+                self.max
+            },
+        )
+    }
+
+    fn add(self, other: Self) -> Self {
+        Self::from_i64(
+            self.min as i64 + other.min as i64,
+            self.max as i64 + other.max as i64,
+        )
+    }
+
+    fn sub(self, other: Self) -> Self {
+        Self::from_i64(
+            self.min as i64 - other.max as i64,
+            self.max as i64 - other.min as i64,
+        )
+    }
+
+    fn mul(self, other: Self) -> Self {
+        let (a, b) = (self, other);
+        Self::from_corners([
+            a.min as i64 * b.min as i64,
+            a.min as i64 * b.max as i64,
+            a.max as i64 * b.min as i64,
+            a.max as i64 * b.max as i64,
+        ])
+    }
+
+    /// Division, ignoring a zero divisor since that case is a runtime error.
+    fn div(self, divisor: Self) -> Option<Self> {
+        let mut corners = Vec::new();
+        // Split the divisor into its negative and positive parts
+        let parts = [
+            (divisor.min, divisor.max.min(-1)),
+            (divisor.min.max(1), divisor.max),
+        ];
+        for (min, max) in parts.into_iter().filter(|(min, max)| min <= max) {
+            for d in [min, max] {
+                corners.push(self.min as i64 / d as i64);
+                corners.push(self.max as i64 / d as i64);
+            }
+        }
+        (!corners.is_empty()).then(|| Self::from_corners(corners))
+    }
+
+    fn neg(self) -> Self {
+        Self::from_i64(-(self.max as i64), -(self.min as i64))
+    }
+
+    fn bit_and(self, other: Self) -> Self {
+        if self.min >= 0 && other.min >= 0 {
+            Self::new(0, self.max.min(other.max))
+        } else if self.min >= 0 || other.min >= 0 {
+            // Masking with a non-negative value gives a non-negative result
+            Self::new(0, if self.min >= 0 { self.max } else { other.max })
+        } else {
+            Self::FULL
+        }
+    }
+
+    fn bit_or(self, other: Self) -> Self {
+        if self.min >= 0 && other.min >= 0 {
+            let max = self.max.max(other.max) as u32;
+            let all_bits = u32::MAX.checked_shr(max.leading_zeros()).unwrap_or(0);
+            Self::new(self.min.max(other.min), all_bits as i32)
+        } else {
+            Self::FULL
+        }
+    }
+
+    fn bit_not(self) -> Self {
+        Self::new(!self.max, !self.min)
+    }
+
+    fn gt(self, other: Self) -> Self {
+        if self.min > other.max {
+            Self::constant(-1)
+        } else if self.max <= other.min {
+            Self::constant(0)
+        } else {
+            Self::new(-1, 0)
+        }
+    }
+
+    fn eq(self, other: Self) -> Self {
+        match (self.as_constant(), other.as_constant()) {
+            (Some(a), Some(b)) if a == b => Self::constant(-1),
+            _ if !self.intersects(other.min, other.max) => Self::constant(0),
+            _ => Self::new(-1, 0),
+        }
+    }
+}
+
+impl std::fmt::Display for Interval {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.as_constant() {
+            Some(v) => write!(f, "{v}"),
+            None => write!(f, "[{}, {}]", self.min, self.max),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RangeWarningKind {
+    /// The divisor of `/` may be zero.
+    DivisionByZero { divisor: Interval },
+    /// The value written by `,` may not be a valid `char`.
+    InvalidChar { value: Interval },
+    /// The index given to `ø` may reach outside the stack. `depth` is the
+    /// number of values below the index, when it is known.
+    PickOutOfRange {
+        index: Interval,
+        depth: Option<usize>,
+    },
+}
+
+impl RangeWarningKind {
+    fn tag(&self) -> u8 {
+        match self {
+            Self::DivisionByZero { .. } => 0,
+            Self::InvalidChar { .. } => 1,
+            Self::PickOutOfRange { .. } => 2,
+        }
+    }
+
+    fn join(&mut self, other: &Self) {
+        match (self, other) {
+            (Self::DivisionByZero { divisor: a }, Self::DivisionByZero { divisor: b }) => {
+                *a = a.join(*b)
+            }
+            (Self::InvalidChar { value: a }, Self::InvalidChar { value: b }) => *a = a.join(*b),
+            (
+                Self::PickOutOfRange { index, depth },
+                Self::PickOutOfRange {
+                    index: other_index,
+                    depth: other_depth,
+                },
+            ) => {
+                *index = index.join(*other_index);
+                *depth = depth.zip(*other_depth).map(|(a, b)| a.min(b));
+            }
+            _ => unreachable!("Joined range warnings of different kinds"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RangeWarning {
+    span: SimpleSpan<usize>,
+    kind: RangeWarningKind,
+}
+
+impl RangeWarning {
+    pub fn span(&self) -> SimpleSpan<usize> {
+        self.span
+    }
+
+    pub fn kind(&self) -> &RangeWarningKind {
+        &self.kind
+    }
+
+    /// Whether the failure happens on every execution that reaches this span,
+    /// rather than only for some values.
+    pub fn is_certain(&self) -> bool {
+        match &self.kind {
+            RangeWarningKind::DivisionByZero { divisor } => divisor.as_constant() == Some(0),
+            RangeWarningKind::InvalidChar { value } => {
+                !value.intersects(0, 0xD7FF) && !value.intersects(0xE000, 0x10FFFF)
+            }
+            RangeWarningKind::PickOutOfRange { index, depth } => {
+                index.max < 0 || depth.is_some_and(|depth| index.min >= depth as i32)
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for RangeWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let certainty = if self.is_certain() { "will" } else { "may" };
+        match &self.kind {
+            RangeWarningKind::DivisionByZero { divisor } => {
+                write!(f, "Division {certainty} be by zero (divisor is {divisor})")
+            }
+            RangeWarningKind::InvalidChar { value } => write!(
+                f,
+                "Value {value} {certainty} not be a valid char for , (WRITECHAR)"
+            ),
+            RangeWarningKind::PickOutOfRange {
+                index,
+                depth: Some(depth),
+            } => write!(
+                f,
+                "Index {index} {certainty} be out of range for ø (PICK) on a stack of {depth} values"
+            ),
+            RangeWarningKind::PickOutOfRange { index, depth: None } => {
+                write!(f, "Index {index} {certainty} be out of range for ø (PICK)")
+            }
+        }
+    }
+}
+
+/// Runs the value-range analysis over a program and returns the warnings
+/// ordered by position.
+pub fn analyze(program: &[Spanned<FalseInstruction>]) -> Vec<RangeWarning> {
+    let mut analyzer = Analyzer::default();
+    analyzer.run_block(program, State::initial());
+
+    // Lambdas that were never called with a known stack (stored in a variable
+    // that is called through an unknown value, say) still deserve a look
+    let mut lambdas = Vec::new();
+    collect_lambdas(program, &mut lambdas);
+    for body in lambdas {
+        if !analyzer.visited.contains(&body.as_ptr()) {
+            analyzer.visited.insert(body.as_ptr());
+            analyzer.run_block(body, State::unknown());
+        }
+    }
+
+    analyzer.warnings.into_values().collect()
+}
+
+fn collect_lambdas<'a>(
+    instructions: &'a [Spanned<FalseInstruction>],
+    lambdas: &mut Vec<&'a [Spanned<FalseInstruction>]>,
+) {
+    for instruction in instructions {
+        match instruction.instruction() {
+            FalseInstruction::Lambda(body) => {
+                lambdas.push(body);
+                collect_lambdas(body, lambdas);
+            }
+            FalseInstruction::ConditionalExecute(body) => collect_lambdas(body, lambdas),
+            FalseInstruction::WhileLoop(condition, body) => {
+                collect_lambdas(condition, lambdas);
+                collect_lambdas(body, lambdas);
+            }
+            _ => {}
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Value<'a> {
+    Int(Interval),
+    Lambda(&'a [Spanned<FalseInstruction>]),
+    Name(char),
+    Unknown,
+}
+
+impl PartialEq for Value<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Int(a), Self::Int(b)) => a == b,
+            (Self::Lambda(a), Self::Lambda(b)) => std::ptr::eq(*a, *b),
+            (Self::Name(a), Self::Name(b)) => a == b,
+            (Self::Unknown, Self::Unknown) => true,
+            _ => false,
+        }
+    }
+}
+
+impl<'a> Value<'a> {
+    fn combine(&self, other: &Self, widen: bool) -> Self {
+        match (self, other) {
+            (Self::Int(a), Self::Int(b)) if widen => Self::Int(a.widen(*b)),
+            (Self::Int(a), Self::Int(b)) => Self::Int(a.join(*b)),
+            (a, b) if a == b => a.clone(),
+            _ => Self::Unknown,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct State<'a> {
+    stack: Vec<Value<'a>>,
+    /// Whether there may be more (unknown) values below `stack`.
+    open: bool,
+    /// `None` for variables that have not been stored yet.
+    globals: [Option<Value<'a>>; 26],
+}
+
+impl<'a> State<'a> {
+    fn initial() -> Self {
+        Self {
+            stack: Vec::new(),
+            open: false,
+            globals: std::array::from_fn(|_| None),
+        }
+    }
+
+    fn unknown() -> Self {
+        Self {
+            stack: Vec::new(),
+            open: true,
+            globals: std::array::from_fn(|_| Some(Value::Unknown)),
+        }
+    }
+
+    /// Pops a value, or returns `None` if the interpreter would fail on an
+    /// empty stack.
+    fn pop(&mut self) -> Option<Value<'a>> {
+        match self.stack.pop() {
+            Some(value) => Some(value),
+            None if self.open => Some(Value::Unknown),
+            None => None,
+        }
+    }
+
+    fn pop_int(&mut self) -> Option<Interval> {
+        match self.pop()? {
+            Value::Int(interval) => Some(interval),
+            Value::Unknown => Some(Interval::FULL),
+            Value::Lambda(_) | Value::Name(_) => None,
+        }
+    }
+
+    fn push_int(&mut self, interval: Interval) {
+        self.stack.push(Value::Int(interval));
+    }
+
+    /// Forgets everything a call to an unknown lambda could have changed.
+    fn havoc(&mut self) {
+        self.stack.clear();
+        self.open = true;
+        self.globals = std::array::from_fn(|_| Some(Value::Unknown));
+    }
+
+    fn combine(&self, other: &Self, widen: bool) -> Self {
+        let depth = self.stack.len().min(other.stack.len());
+        let stack = self.stack[self.stack.len() - depth..]
+            .iter()
+            .zip(&other.stack[other.stack.len() - depth..])
+            .map(|(a, b)| a.combine(b, widen))
+            .collect();
+        let globals = std::array::from_fn(|i| match (&self.globals[i], &other.globals[i]) {
+            (Some(a), Some(b)) => Some(a.combine(b, widen)),
+            (a, b) => a.clone().or_else(|| b.clone()),
+        });
+        Self {
+            stack,
+            open: self.open || other.open || self.stack.len() != other.stack.len(),
+            globals,
+        }
+    }
+}
+
+fn join<'a>(a: Option<State<'a>>, b: Option<State<'a>>) -> Option<State<'a>> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.combine(&b, false)),
+        (a, b) => a.or(b),
+    }
+}
+
+#[derive(Default)]
+struct Analyzer {
+    warnings: BTreeMap<(usize, usize, u8), RangeWarning>,
+    call_stack: Vec<*const Spanned<FalseInstruction>>,
+    visited: HashSet<*const Spanned<FalseInstruction>>,
+}
+
+impl Analyzer {
+    fn warn(&mut self, span: SimpleSpan<usize>, kind: RangeWarningKind) {
+        self.warnings
+            .entry((span.start, span.end, kind.tag()))
+            .and_modify(|warning| warning.kind.join(&kind))
+            .or_insert(RangeWarning { span, kind });
+    }
+
+    /// Returns the state after running `instructions`, or `None` if no
+    /// execution gets past them.
+    fn run_block<'a>(
+        &mut self,
+        instructions: &'a [Spanned<FalseInstruction>],
+        mut state: State<'a>,
+    ) -> Option<State<'a>> {
+        for instruction in instructions {
+            state = self.step(instruction, state)?;
+        }
+        Some(state)
+    }
+
+    fn step<'a>(
+        &mut self,
+        spanned: &'a Spanned<FalseInstruction>,
+        mut state: State<'a>,
+    ) -> Option<State<'a>> {
+        use FalseInstruction::*;
+
+        let span = spanned.span();
+
+        match spanned.instruction() {
+            Name(c) => state.stack.push(Value::Name(*c)),
+            PushInt(v) => state.push_int(Interval::constant(*v)),
+            PushChar(c) => state.push_int(Interval::constant((*c).into())),
+            Dup => {
+                let value = state.pop()?;
+                state.stack.extend([value.clone(), value]);
+            }
+            Drop => {
+                state.stack.pop();
+            }
+            Swap => {
+                let head = state.pop()?;
+                let next = state.pop()?;
+                state.stack.extend([head, next]);
+            }
+            Rot => {
+                let first = state.pop()?;
+                let second = state.pop()?;
+                let third = state.pop()?;
+                state.stack.extend([first, second, third]);
+            }
+            Pick => {
+                let index = state.pop_int()?;
+                let depth = (!state.open).then_some(state.stack.len());
+                let in_range =
+                    index.min >= 0 && depth.is_none_or(|depth| (index.max as i64) < depth as i64);
+                if !in_range {
+                    self.warn(span, RangeWarningKind::PickOutOfRange { index, depth });
+                }
+                let picked = index
+                    .as_constant()
+                    .filter(|i| *i >= 0 && (*i as usize) < state.stack.len())
+                    .map(|i| state.stack[state.stack.len() - 1 - i as usize].clone());
+                state.stack.push(picked.unwrap_or(Value::Unknown));
+            }
+            Add => binary_op(&mut state, Interval::add)?,
+            Sub => binary_op(&mut state, Interval::sub)?,
+            Mul => binary_op(&mut state, Interval::mul)?,
+            Div => {
+                let divisor = state.pop_int()?;
+                let dividend = state.pop_int()?;
+                if divisor.contains(0) {
+                    self.warn(span, RangeWarningKind::DivisionByZero { divisor });
+                }
+                state.push_int(dividend.div(divisor)?);
+            }
+            Neg => unary_op(&mut state, Interval::neg)?,
+            BitAnd => binary_op(&mut state, Interval::bit_and)?,
+            BitOr => binary_op(&mut state, Interval::bit_or)?,
+            BitNot => unary_op(&mut state, Interval::bit_not)?,
+            Gt => binary_op(&mut state, Interval::gt)?,
+            Eq => binary_op(&mut state, Interval::eq)?,
+            Lambda(body) => state.stack.push(Value::Lambda(body)),
+            Execute => match state.pop()? {
+                Value::Lambda(body) => return self.call(body, state),
+                Value::Unknown => state.havoc(),
+                Value::Int(_) | Value::Name(_) => return None,
+            },
+            ConditionalExecute(body) => {
+                let condition = state.pop_int()?;
+                if condition.as_constant() == Some(0) {
+                    return Some(state);
+                }
+                let taken = self.run_block(body, state.clone());
+                if condition.contains(0) {
+                    return join(taken, Some(state));
+                }
+                return taken;
+            }
+            WhileLoop(condition, body) => return self.run_loop(condition, body, state),
+            Store => match state.pop()? {
+                Value::Name(name) => {
+                    let value = match state.pop()? {
+                        Value::Name(_) => return None,
+                        value => value,
+                    };
+                    state.globals[variable_index(name)] = Some(value);
+                }
+                Value::Unknown => {
+                    let value = state.pop()?;
+                    for global in state.globals.iter_mut() {
+                        *global = Some(match global {
+                            Some(old) => old.combine(&value, false),
+                            None => value.clone(),
+                        });
+                    }
+                }
+                Value::Int(_) | Value::Lambda(_) => return None,
+            },
+            Fetch => match state.pop()? {
+                Value::Name(name) => {
+                    let value = state.globals[variable_index(name)].clone()?;
+                    state.stack.push(value);
+                }
+                Value::Unknown => state.stack.push(Value::Unknown),
+                Value::Int(_) | Value::Lambda(_) => return None,
+            },
+            ReadChar => state.push_int(Interval::new(-1, u8::MAX.into())),
+            WriteChar => {
+                let value = state.pop_int()?;
+                // Anything `char::from_u32` rejects once cast to u32
+                let invalid = value.intersects(i32::MIN, -1)
+                    || value.intersects(0xD800, 0xDFFF)
+                    || value.intersects(0x110000, i32::MAX);
+                if invalid {
+                    self.warn(span, RangeWarningKind::InvalidChar { value });
+                }
+            }
+            WriteStr(_) => {}
+            WriteInt => {
+                state.pop_int()?;
+            }
+            Flush => {}
+        }
+
+        Some(state)
+    }
+
+    fn call<'a>(
+        &mut self,
+        body: &'a [Spanned<FalseInstruction>],
+        mut state: State<'a>,
+    ) -> Option<State<'a>> {
+        let key = body.as_ptr();
+        if self.call_stack.contains(&key) || self.call_stack.len() >= MAX_CALL_DEPTH {
+            // Recursion: we don't know what the inner call does
+            state.havoc();
+            return Some(state);
+        }
+        self.visited.insert(key);
+        self.call_stack.push(key);
+        let result = self.run_block(body, state);
+        self.call_stack.pop();
+        result
+    }
+
+    fn run_loop<'a>(
+        &mut self,
+        condition: &'a [Spanned<FalseInstruction>],
+        body: &'a [Spanned<FalseInstruction>],
+        state: State<'a>,
+    ) -> Option<State<'a>> {
+        let mut head = state;
+        let mut exit = None;
+        for iteration in 0.. {
+            let Some(mut after_condition) = self.run_block(condition, head.clone()) else {
+                break;
+            };
+            let Some(result) = after_condition.pop_int() else {
+                break;
+            };
+            if result.contains(0) {
+                exit = join(exit, Some(after_condition.clone()));
+            }
+            if result.as_constant() == Some(0) {
+                break;
+            }
+            let Some(after_body) = self.run_block(body, after_condition) else {
+                break;
+            };
+            let next = head.combine(&after_body, iteration >= WIDEN_AFTER);
+            if next == head {
+                break;
+            }
+            head = next;
+        }
+        exit
+    }
+}
+
+fn binary_op(state: &mut State, op: fn(Interval, Interval) -> Interval) -> Option<()> {
+    let a = state.pop_int()?;
+    let b = state.pop_int()?;
+    state.push_int(op(b, a));
+    Some(())
+}
+
+fn unary_op(state: &mut State, op: fn(Interval) -> Interval) -> Option<()> {
+    let a = state.pop_int()?;
+    state.push_int(op(a));
+    Some(())
+}
+
+fn variable_index(name: char) -> usize {
+    (name as u8 - b'a') as usize
+}
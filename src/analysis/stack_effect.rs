@@ -0,0 +1,249 @@
+//! Stack-effect inference.
+//!
+//! Works out how many values a piece of code takes from the stack and how
+//! many it leaves in their place, when that is the same on every path.
+//! Calls are followed through `[...]!` and `x;!`, and recursive lambdas are
+//! handled by guessing the effect of the recursive call and checking that
+//! the body agrees with the guess.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::ast::{FalseInstruction, Spanned};
+
+/// Largest number of inputs or outputs guessed for a recursive call.
+const MAX_GUESS: usize = 4;
+
+/// Consumes `inputs` values from the top of the stack and pushes `outputs`
+/// values in their place.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct StackEffect {
+    pub inputs: usize,
+    pub outputs: usize,
+}
+
+impl StackEffect {
+    pub const NONE: StackEffect = StackEffect::new(0, 0);
+
+    pub const fn new(inputs: usize, outputs: usize) -> Self {
+        Self { inputs, outputs }
+    }
+
+    /// The effect of running `self` and then `next`.
+    pub fn then(self, next: Self) -> Self {
+        Self {
+            inputs: self.inputs + next.inputs.saturating_sub(self.outputs),
+            outputs: next.outputs + self.outputs.saturating_sub(next.inputs),
+        }
+    }
+
+    /// Change in stack height.
+    pub fn net(&self) -> isize {
+        self.outputs as isize - self.inputs as isize
+    }
+
+    /// The effect of running either `self` or `other`, if they agree on the
+    /// change in stack height.
+    fn either(self, other: Self) -> Option<Self> {
+        (self.net() == other.net()).then(|| {
+            let inputs = self.inputs.max(other.inputs);
+            Self::new(inputs, (inputs as isize + self.net()) as usize)
+        })
+    }
+}
+
+impl std::fmt::Display for StackEffect {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "({} -- {})", self.inputs, self.outputs)
+    }
+}
+
+/// Stack effects of the code in one program.
+pub struct StackEffects<'a> {
+    /// Lambdas stored straight into each variable.
+    stored: HashMap<char, Vec<&'a [Spanned<FalseInstruction>]>>,
+    /// Variables that may also hold something we can't see statically.
+    unknown: HashSet<char>,
+}
+
+#[derive(Default)]
+struct Env {
+    /// Assumed effects of calling variables while checking a guess.
+    guesses: HashMap<char, StackEffect>,
+    /// Variables whose effect is being worked out further up.
+    in_progress: HashSet<char>,
+}
+
+impl<'a> StackEffects<'a> {
+    pub fn new(program: &'a [Spanned<FalseInstruction>]) -> Self {
+        let mut effects = Self {
+            stored: HashMap::new(),
+            unknown: HashSet::new(),
+        };
+        effects.collect(program);
+        effects
+    }
+
+    fn collect(&mut self, instructions: &'a [Spanned<FalseInstruction>]) {
+        use FalseInstruction::*;
+
+        for (i, spanned) in instructions.iter().enumerate() {
+            let previous = |n: usize| i.checked_sub(n).map(|j| instructions[j].instruction());
+            match spanned.instruction() {
+                Lambda(body) | ConditionalExecute(body) => self.collect(body),
+                WhileLoop(condition, body) => {
+                    self.collect(condition);
+                    self.collect(body);
+                }
+                Store => match (previous(2), previous(1)) {
+                    (Some(Lambda(body)), Some(Name(c))) => {
+                        self.stored.entry(*c).or_default().push(body)
+                    }
+                    (_, Some(Name(c))) => {
+                        self.unknown.insert(*c);
+                    }
+                    _ => self.unknown.extend('a'..='z'),
+                },
+                _ => {}
+            }
+        }
+    }
+
+    /// Effect of running a block of code in place.
+    pub fn of_block(&self, instructions: &[Spanned<FalseInstruction>]) -> Option<StackEffect> {
+        self.block(instructions, &mut Env::default())
+    }
+
+    /// Effect of executing a lambda with `!`. Works for recursive lambdas
+    /// that call themselves through the variable they are stored in.
+    pub fn of_lambda(&self, body: &[Spanned<FalseInstruction>]) -> Option<StackEffect> {
+        if let Some(effect) = self.of_block(body) {
+            return Some(effect);
+        }
+        let holders = self.stored.iter().filter(|(c, lambdas)| {
+            !self.unknown.contains(c) && lambdas.iter().all(|l| std::ptr::eq(*l, body))
+        });
+        for (name, _) in holders {
+            let mut env = Env::default();
+            if let Some(effect) = self.guess(*name, &[body], &mut env) {
+                return Some(effect);
+            }
+        }
+        None
+    }
+
+    /// Effect of executing the lambda stored in a variable with `x;!`.
+    pub fn of_variable(&self, name: char) -> Option<StackEffect> {
+        self.variable(name, &mut Env::default())
+    }
+
+    fn variable(&self, name: char, env: &mut Env) -> Option<StackEffect> {
+        if let Some(guess) = env.guesses.get(&name) {
+            return Some(*guess);
+        }
+        if env.in_progress.contains(&name) || self.unknown.contains(&name) {
+            return None;
+        }
+        let lambdas = self.stored.get(&name)?;
+
+        env.in_progress.insert(name);
+        let direct = self.all_agree(lambdas, env);
+        let effect = direct.or_else(|| self.guess(name, lambdas, env));
+        env.in_progress.remove(&name);
+        effect
+    }
+
+    /// Tries every small effect for calls to `name`, and keeps the first one
+    /// that the lambdas it holds agree with.
+    fn guess(
+        &self,
+        name: char,
+        lambdas: &[&[Spanned<FalseInstruction>]],
+        env: &mut Env,
+    ) -> Option<StackEffect> {
+        let found = (0..=MAX_GUESS)
+            .flat_map(|inputs| {
+                (0..=MAX_GUESS).map(move |outputs| StackEffect::new(inputs, outputs))
+            })
+            .find(|guess| {
+                env.guesses.insert(name, *guess);
+                self.all_agree(lambdas, env) == Some(*guess)
+            });
+        env.guesses.remove(&name);
+        found
+    }
+
+    fn all_agree(
+        &self,
+        lambdas: &[&[Spanned<FalseInstruction>]],
+        env: &mut Env,
+    ) -> Option<StackEffect> {
+        let mut effects = lambdas.iter().map(|body| self.block(body, env));
+        let first = effects.next()??;
+        effects.all(|e| e == Some(first)).then_some(first)
+    }
+
+    fn block(
+        &self,
+        instructions: &[Spanned<FalseInstruction>],
+        env: &mut Env,
+    ) -> Option<StackEffect> {
+        let mut effect = StackEffect::NONE;
+        for (i, spanned) in instructions.iter().enumerate() {
+            let previous = |n: usize| i.checked_sub(n).map(|j| instructions[j].instruction());
+            effect = effect.then(self.instruction(spanned.instruction(), previous, env)?);
+        }
+        Some(effect)
+    }
+
+    fn instruction<'i>(
+        &self,
+        instruction: &FalseInstruction,
+        previous: impl Fn(usize) -> Option<&'i FalseInstruction>,
+        env: &mut Env,
+    ) -> Option<StackEffect> {
+        use FalseInstruction::*;
+
+        let effect = StackEffect::new;
+        Some(match instruction {
+            Name(_) | PushInt(_) | PushChar(_) | Lambda(_) | ReadChar => effect(0, 1),
+            Dup => effect(1, 2),
+            Drop | WriteChar | WriteInt => effect(1, 0),
+            Swap => effect(2, 2),
+            Rot => effect(3, 3),
+            Pick => match previous(1)? {
+                // The index, then the values down to the one picked
+                PushInt(k) if *k >= 0 => effect(*k as usize + 2, *k as usize + 2),
+                PushChar(k) => effect(*k as usize + 2, *k as usize + 2),
+                _ => return None,
+            },
+            Add | Sub | Mul | Div | BitAnd | BitOr | Gt | Eq => effect(2, 1),
+            Neg | BitNot => effect(1, 1),
+            Execute => {
+                let callee = match (previous(2), previous(1)?) {
+                    (_, Lambda(body)) => self.block(body, env)?,
+                    (Some(Name(c)), Fetch) => self.variable(*c, env)?,
+                    _ => return None,
+                };
+                effect(1, 0).then(callee)
+            }
+            ConditionalExecute(body) => {
+                let taken = self.block(body, env)?;
+                if taken.net() != 0 {
+                    return None;
+                }
+                effect(1, 0).then(taken).either(effect(1, 0))?
+            }
+            WhileLoop(condition, body) => {
+                let condition = self.block(condition, env)?.then(effect(1, 0));
+                let body = self.block(body, env)?;
+                if condition.net() != 0 || body.net() != 0 {
+                    return None;
+                }
+                condition.then(body).either(condition)?
+            }
+            Store => effect(2, 0),
+            Fetch => effect(1, 1),
+            WriteStr(_) | Flush => StackEffect::NONE,
+        })
+    }
+}
@@ -1,5 +1,12 @@
 use chumsky::{input::MapExtra, span::SimpleSpan};
 
+mod visit;
+
+pub use visit::{
+    fold_block, fold_instruction, variable_names, walk_block, walk_block_mut, walk_instruction,
+    walk_instruction_mut, Fold, Visitor, VisitorMut,
+};
+
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct Spanned<T>(T, SimpleSpan<usize>);
 impl Spanned<FalseInstruction> {
@@ -20,9 +27,17 @@ impl Spanned<FalseInstruction> {
         &self.0
     }
 
+    pub fn instruction_mut(&mut self) -> &mut FalseInstruction {
+        &mut self.0
+    }
+
     pub fn span(&self) -> SimpleSpan<usize> {
         self.1
     }
+
+    pub fn into_parts(self) -> (FalseInstruction, SimpleSpan<usize>) {
+        (self.0, self.1)
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
@@ -60,3 +75,108 @@ pub enum FalseInstruction {
     WriteInt,
     Flush,
 }
+
+/// FALSE source for a program, with a space between instructions, that
+/// [`parse`](crate::parser::parse) reads back as the same instructions.
+pub fn to_source(program: &[Spanned<FalseInstruction>]) -> String {
+    Block(program).to_string()
+}
+
+/// Instructions written with a space between them.
+struct Block<'a>(&'a [Spanned<FalseInstruction>]);
+
+impl std::fmt::Display for Block<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, spanned) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(" ")?;
+            }
+            write!(f, "{spanned}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for Spanned<FalseInstruction> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// Writes an instruction as FALSE source. Anything the parser can give back
+/// is written so that it parses to the same instruction. The rest is written
+/// as code that does the same: negative integers are negated with `_`, a
+/// `"` in a string is written with `'",` between two strings, and a
+/// character that no UTF-8 character starts with is pushed as a number.
+impl std::fmt::Display for FalseInstruction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use FalseInstruction::*;
+
+        match self {
+            Name(c) => write!(f, "{c}"),
+            PushInt(i32::MIN) => f.write_str("2147483647_ 1-"),
+            PushInt(v) if *v < 0 => write!(f, "{}_", -v),
+            PushInt(v) => write!(f, "{v}"),
+            PushChar(c) => match char_starting_with(*c) {
+                Some(character) => write!(f, "'{character}"),
+                None => write!(f, "{c}"),
+            },
+            Dup => f.write_str("$"),
+            Drop => f.write_str("%"),
+            Swap => f.write_str("\\"),
+            Rot => f.write_str("@"),
+            Pick => f.write_str("ø"),
+            Add => f.write_str("+"),
+            Sub => f.write_str("-"),
+            Mul => f.write_str("*"),
+            Div => f.write_str("/"),
+            Neg => f.write_str("_"),
+            BitAnd => f.write_str("&"),
+            BitOr => f.write_str("|"),
+            BitNot => f.write_str("~"),
+            Gt => f.write_str(">"),
+            Eq => f.write_str("="),
+            Lambda(body) => write!(f, "[{}]", Block(body)),
+            Execute => f.write_str("!"),
+            ConditionalExecute(body) => write!(f, "[{}]?", Block(body)),
+            WhileLoop(condition, body) => write!(f, "[{}][{}]#", Block(condition), Block(body)),
+            Store => f.write_str(":"),
+            Fetch => f.write_str(";"),
+            ReadChar => f.write_str("^"),
+            WriteChar => f.write_str(","),
+            WriteStr(s) if !s.contains('"') => write!(f, "\"{s}\""),
+            WriteStr(s) => {
+                let mut words = Vec::new();
+                for (i, part) in s.split('"').enumerate() {
+                    if i > 0 {
+                        words.push("'\" ,".to_string());
+                    }
+                    if !part.is_empty() {
+                        words.push(format!("\"{part}\""));
+                    }
+                }
+                f.write_str(&words.join(" "))
+            }
+            WriteInt => f.write_str("."),
+            Flush => f.write_str("ß"),
+        }
+    }
+}
+
+/// A character whose UTF-8 encoding starts with `byte`, so that `'` and
+/// it push `byte`.
+fn char_starting_with(byte: u8) -> Option<char> {
+    if byte.is_ascii() {
+        return Some(char::from(byte));
+    }
+    // Lead bytes, with the lowest continuation bytes that make them valid
+    [
+        [byte, 0x80].as_slice(),
+        &[byte, 0x80, 0x80],
+        &[byte, 0xa0, 0x80],
+        &[byte, 0x80, 0x80, 0x80],
+        &[byte, 0x90, 0x80, 0x80],
+    ]
+    .into_iter()
+    .find_map(|bytes| std::str::from_utf8(bytes).ok()?.chars().next())
+}
@@ -0,0 +1,171 @@
+//! Traversals over the AST, so tools don't each write out the recursion
+//! into lambdas, conditionals and loops.
+//!
+//! [`Visitor`] walks a program by reference, [`VisitorMut`] changes it in
+//! place and [`Fold`] rebuilds it by value. Every method has a default that
+//! carries on into what is inside, through the function of the same kind
+//! below: `visit_block` calls [`walk_block`], `visit_instruction_mut` calls
+//! [`walk_instruction_mut`], `fold_block` calls [`fold_block`], and so on.
+//! An implementation overrides the methods it cares about, and calls those
+//! functions from them where it still wants to go further in.
+
+use std::collections::BTreeSet;
+
+use chumsky::span::SimpleSpan;
+
+use super::{FalseInstruction, Spanned};
+
+/// Walks a program by reference.
+pub trait Visitor<'ast> {
+    /// Visits a program, or the body of a lambda, conditional or loop.
+    fn visit_block(&mut self, block: &'ast [Spanned<FalseInstruction>]) {
+        walk_block(self, block)
+    }
+
+    /// Visits an instruction, and then the blocks in it.
+    fn visit_instruction(&mut self, instruction: &'ast Spanned<FalseInstruction>) {
+        walk_instruction(self, instruction)
+    }
+
+    /// Visits a variable name.
+    fn visit_name(&mut self, _name: char, _span: SimpleSpan<usize>) {}
+}
+
+pub fn walk_block<'ast, V: Visitor<'ast> + ?Sized>(
+    visitor: &mut V,
+    block: &'ast [Spanned<FalseInstruction>],
+) {
+    for instruction in block {
+        visitor.visit_instruction(instruction);
+    }
+}
+
+pub fn walk_instruction<'ast, V: Visitor<'ast> + ?Sized>(
+    visitor: &mut V,
+    instruction: &'ast Spanned<FalseInstruction>,
+) {
+    use FalseInstruction::*;
+
+    match instruction.instruction() {
+        Name(name) => visitor.visit_name(*name, instruction.span()),
+        Lambda(body) | ConditionalExecute(body) => visitor.visit_block(body),
+        WhileLoop(condition, body) => {
+            visitor.visit_block(condition);
+            visitor.visit_block(body);
+        }
+        _ => {}
+    }
+}
+
+/// Walks a program, changing it in place.
+pub trait VisitorMut {
+    /// Visits a program, or the body of a lambda, conditional or loop.
+    /// Instructions can be added to or removed from it here.
+    fn visit_block_mut(&mut self, block: &mut Vec<Spanned<FalseInstruction>>) {
+        walk_block_mut(self, block)
+    }
+
+    /// Visits an instruction, and then the blocks in it.
+    fn visit_instruction_mut(&mut self, instruction: &mut Spanned<FalseInstruction>) {
+        walk_instruction_mut(self, instruction)
+    }
+
+    /// Visits a variable name.
+    fn visit_name_mut(&mut self, _name: &mut char, _span: SimpleSpan<usize>) {}
+}
+
+pub fn walk_block_mut<V: VisitorMut + ?Sized>(
+    visitor: &mut V,
+    block: &mut Vec<Spanned<FalseInstruction>>,
+) {
+    for instruction in block {
+        visitor.visit_instruction_mut(instruction);
+    }
+}
+
+pub fn walk_instruction_mut<V: VisitorMut + ?Sized>(
+    visitor: &mut V,
+    instruction: &mut Spanned<FalseInstruction>,
+) {
+    use FalseInstruction::*;
+
+    let span = instruction.span();
+    match instruction.instruction_mut() {
+        Name(name) => visitor.visit_name_mut(name, span),
+        Lambda(body) | ConditionalExecute(body) => visitor.visit_block_mut(body),
+        WhileLoop(condition, body) => {
+            visitor.visit_block_mut(condition);
+            visitor.visit_block_mut(body);
+        }
+        _ => {}
+    }
+}
+
+/// Rebuilds a program by value.
+pub trait Fold {
+    /// Folds a program, or the body of a lambda, conditional or loop. The
+    /// result can have more or fewer instructions.
+    fn fold_block(
+        &mut self,
+        block: Vec<Spanned<FalseInstruction>>,
+    ) -> Vec<Spanned<FalseInstruction>> {
+        fold_block(self, block)
+    }
+
+    /// Folds an instruction, after the blocks in it.
+    fn fold_instruction(
+        &mut self,
+        instruction: Spanned<FalseInstruction>,
+    ) -> Spanned<FalseInstruction> {
+        fold_instruction(self, instruction)
+    }
+
+    /// Folds a variable name.
+    fn fold_name(&mut self, name: char, _span: SimpleSpan<usize>) -> char {
+        name
+    }
+}
+
+pub fn fold_block<F: Fold + ?Sized>(
+    folder: &mut F,
+    block: Vec<Spanned<FalseInstruction>>,
+) -> Vec<Spanned<FalseInstruction>> {
+    block
+        .into_iter()
+        .map(|instruction| folder.fold_instruction(instruction))
+        .collect()
+}
+
+pub fn fold_instruction<F: Fold + ?Sized>(
+    folder: &mut F,
+    instruction: Spanned<FalseInstruction>,
+) -> Spanned<FalseInstruction> {
+    use FalseInstruction::*;
+
+    let (instruction, span) = instruction.into_parts();
+    let instruction = match instruction {
+        Name(name) => Name(folder.fold_name(name, span)),
+        Lambda(body) => Lambda(folder.fold_block(body)),
+        ConditionalExecute(body) => ConditionalExecute(folder.fold_block(body)),
+        WhileLoop(condition, body) => {
+            WhileLoop(folder.fold_block(condition), folder.fold_block(body))
+        }
+        other => other,
+    };
+    Spanned::new(instruction, span)
+}
+
+/// Every variable name in a program, including those in lambdas.
+pub fn variable_names(program: &[Spanned<FalseInstruction>]) -> BTreeSet<char> {
+    struct Names(BTreeSet<char>);
+
+    impl Visitor<'_> for Names {
+        fn visit_name(&mut self, name: char, _span: SimpleSpan<usize>) {
+            self.0.insert(name);
+        }
+    }
+
+    let mut names = Names(BTreeSet::new());
+    names.visit_block(program);
+    names.0
+}
@@ -0,0 +1,120 @@
+//! Programs bundled into a copy of the falsy executable.
+//!
+//! `falsy build` appends the program's source to the executable, followed
+//! by a fixed-size trailer. On startup, falsy looks for the trailer at the
+//! end of its own executable, and if there is one, runs the bundled program
+//! instead of reading its arguments. Executable formats ignore data past
+//! their last segment, so the copy still runs as normal.
+//!
+//! The payload is the source rather than the AST, so runtime errors can be
+//! reported against it just as when running from a file:
+//!
+//! ```text
+//! filename length (u32 LE) | filename | optimization level (u8) | source
+//! payload length (u64 LE) | "FALSYPKG"
+//! ```
+
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use crate::optimizer::OptimizationLevel;
+
+const MAGIC: &[u8; 8] = b"FALSYPKG";
+const TRAILER: usize = 8 + MAGIC.len();
+
+/// A program bundled into an executable.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Bundle {
+    /// The name of the source file, for error messages.
+    pub filename: String,
+    pub source: String,
+    pub level: OptimizationLevel,
+}
+
+impl Bundle {
+    /// Appends the bundle to `executable`, replacing any bundle it already
+    /// has.
+    pub fn append_to(&self, executable: &[u8]) -> Vec<u8> {
+        let mut out = strip(executable).to_vec();
+        let start = out.len();
+        out.extend((self.filename.len() as u32).to_le_bytes());
+        out.extend(self.filename.as_bytes());
+        out.push(match self.level {
+            OptimizationLevel::None => 0,
+            OptimizationLevel::Basic => 1,
+            OptimizationLevel::Full => 2,
+        });
+        out.extend(self.source.as_bytes());
+        let length = (out.len() - start) as u64;
+        out.extend(length.to_le_bytes());
+        out.extend(MAGIC);
+        out
+    }
+
+    /// The bundle at the end of `executable`, if it has one.
+    pub fn from_bytes(executable: &[u8]) -> Option<Self> {
+        let length = payload_length(executable)?;
+        let end = executable.len() - TRAILER;
+        Self::decode(&executable[end - length..end])
+    }
+
+    /// The bundle in the executable at `path`, if it has one. Only the end
+    /// of the file is read.
+    pub fn read(path: &Path) -> io::Result<Option<Self>> {
+        let mut file = std::fs::File::open(path)?;
+        let size = file.metadata()?.len();
+        if size < TRAILER as u64 {
+            return Ok(None);
+        }
+        let mut trailer = [0; TRAILER];
+        file.seek(SeekFrom::End(-(TRAILER as i64)))?;
+        file.read_exact(&mut trailer)?;
+        let Some(length) = trailer_length(&trailer).filter(|&l| l <= size - TRAILER as u64) else {
+            return Ok(None);
+        };
+        let length = length as usize;
+        let mut payload = vec![0; length];
+        file.seek(SeekFrom::End(-((TRAILER + length) as i64)))?;
+        file.read_exact(&mut payload)?;
+        Ok(Self::decode(&payload))
+    }
+
+    fn decode(payload: &[u8]) -> Option<Self> {
+        let (length, rest) = payload.split_first_chunk::<4>()?;
+        let length = u32::from_le_bytes(*length) as usize;
+        let filename = rest.get(..length)?;
+        let (&level, source) = rest.get(length..)?.split_first()?;
+        Some(Self {
+            filename: String::from_utf8(filename.to_vec()).ok()?,
+            source: String::from_utf8(source.to_vec()).ok()?,
+            level: match level {
+                0 => OptimizationLevel::None,
+                1 => OptimizationLevel::Basic,
+                2 => OptimizationLevel::Full,
+                _ => return None,
+            },
+        })
+    }
+}
+
+/// `executable` without its bundle, if it has one.
+pub fn strip(executable: &[u8]) -> &[u8] {
+    match payload_length(executable) {
+        Some(length) => &executable[..executable.len() - TRAILER - length],
+        None => executable,
+    }
+}
+
+/// The length of the payload before the trailer at the end of `bytes`, if
+/// there is a trailer and a payload that long before it.
+fn payload_length(bytes: &[u8]) -> Option<usize> {
+    let start = bytes.len().checked_sub(TRAILER)?;
+    let length = trailer_length(bytes[start..].try_into().unwrap())?;
+    (length <= start as u64).then_some(length as usize)
+}
+
+/// The payload length recorded in a trailer, if it is one.
+fn trailer_length(trailer: &[u8; TRAILER]) -> Option<u64> {
+    let (length, magic) = trailer.split_first_chunk::<8>()?;
+    (magic == MAGIC).then(|| u64::from_le_bytes(*length))
+}
@@ -0,0 +1,415 @@
+//! A binary format for parsed programs, so big programs don't have to be
+//! parsed again every time they run.
+//!
+//! A `.fbc` file has a fixed header, then a body covered by its checksum:
+//!
+//! ```text
+//! header: "FBC\0" | version (u16) | checksum of the body (u64)
+//! body:   source hash (u64) | filename | block | span count (u32) | spans
+//! ```
+//!
+//! Integers are little-endian, and strings are a `u32` length followed by
+//! UTF-8. A block is a `u32` instruction count followed by the
+//! instructions, each an opcode byte followed by its operands, with nested
+//! blocks written inline. The span table has an entry for every
+//! instruction, in the order they appear in the file: its start and end
+//! byte offsets and the line and column it starts at, all `u32`. The
+//! locations let runtime errors be reported without the source, and the
+//! source hash tells whether a source file found next to the bytecode is
+//! the one it was compiled from.
+//!
+//! Loading checks everything, and fails with a [`LoadError`] rather than
+//! panicking on a corrupt file.
+
+use std::collections::BTreeMap;
+
+use crate::ast::{FalseInstruction, Spanned};
+use crate::lines::LineIndex;
+
+const MAGIC: &[u8; 4] = b"FBC\0";
+/// The version of the format written, and the only one that can be loaded.
+pub const VERSION: u16 = 1;
+const HEADER: usize = MAGIC.len() + 2 + 8;
+/// Deepest nesting of lambdas and loops that will be loaded, so a corrupt
+/// file can't overflow the stack.
+const MAX_DEPTH: usize = 10_000;
+
+/// Why a file couldn't be loaded as bytecode.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LoadError {
+    /// The file doesn't start with the bytecode header.
+    NotBytecode,
+    /// The file was written by an incompatible version of falsy.
+    UnsupportedVersion(u16),
+    /// The body doesn't match its checksum.
+    ChecksumMismatch,
+    /// The file is internally inconsistent, despite its checksum.
+    Malformed(&'static str),
+}
+
+impl std::fmt::Display for LoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotBytecode => write!(f, "Not a falsy bytecode file"),
+            Self::UnsupportedVersion(version) => write!(
+                f,
+                "Unsupported bytecode version {version}, expected {VERSION}"
+            ),
+            Self::ChecksumMismatch => write!(f, "Bytecode file is corrupt (checksum mismatch)"),
+            Self::Malformed(reason) => write!(f, "Bytecode file is corrupt ({reason})"),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+/// A program loaded from bytecode.
+#[derive(Clone, Debug)]
+pub struct Bytecode {
+    /// The name of the source file it was compiled from.
+    pub filename: String,
+    pub program: Vec<Spanned<FalseInstruction>>,
+    source_hash: u64,
+    /// 1-based line and column of each instruction, by its start offset.
+    locations: BTreeMap<usize, (usize, usize)>,
+}
+
+impl Bytecode {
+    /// Whether `source` is the source this was compiled from.
+    pub fn matches_source(&self, source: &str) -> bool {
+        source_hash(source) == self.source_hash
+    }
+
+    /// 1-based line and column of the instruction starting at `offset`, or
+    /// failing that, the closest one before it.
+    pub fn location(&self, offset: usize) -> Option<(usize, usize)> {
+        self.locations
+            .range(..=offset)
+            .next_back()
+            .map(|(_, location)| *location)
+    }
+}
+
+/// Whether `bytes` look like bytecode, rather than source.
+pub fn is_bytecode(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+/// Compiles a parsed program to bytecode. `source` is the source it was
+/// parsed from, and `filename` the name to report errors against.
+pub fn compile(program: &[Spanned<FalseInstruction>], source: &str, filename: &str) -> Vec<u8> {
+    let mut writer = Writer {
+        lines: LineIndex::new(source),
+        body: Vec::new(),
+        spans: Vec::new(),
+    };
+    writer.body.extend(source_hash(source).to_le_bytes());
+    writer.string(filename);
+    writer.block(program);
+    let Writer {
+        mut body, spans, ..
+    } = writer;
+    body.extend((spans.len() as u32).to_le_bytes());
+    for span in spans {
+        for field in span {
+            body.extend(field.to_le_bytes());
+        }
+    }
+
+    let mut bytes = Vec::with_capacity(HEADER + body.len());
+    bytes.extend(MAGIC);
+    bytes.extend(VERSION.to_le_bytes());
+    bytes.extend(fnv1a(&body).to_le_bytes());
+    bytes.extend(body);
+    bytes
+}
+
+/// Loads a program from bytecode, checking its version and integrity.
+pub fn load(bytes: &[u8]) -> Result<Bytecode, LoadError> {
+    if !is_bytecode(bytes) {
+        return Err(LoadError::NotBytecode);
+    }
+    let mut header = Reader {
+        bytes: &bytes[MAGIC.len()..],
+    };
+    let version = header
+        .u16()
+        .ok_or(LoadError::Malformed("truncated header"))?;
+    if version != VERSION {
+        return Err(LoadError::UnsupportedVersion(version));
+    }
+    let checksum = header
+        .u64()
+        .ok_or(LoadError::Malformed("truncated header"))?;
+    let body = header.bytes;
+    if fnv1a(body) != checksum {
+        return Err(LoadError::ChecksumMismatch);
+    }
+
+    let mut reader = Reader { bytes: body };
+    let truncated = || LoadError::Malformed("unexpected end of file");
+    let source_hash = reader.u64().ok_or_else(truncated)?;
+    let filename = reader.string()?;
+    let instructions = reader.block(0)?;
+    let count = reader.u32().ok_or_else(truncated)? as usize;
+    let mut spans = Vec::with_capacity(count.min(reader.bytes.len() / 16));
+    for _ in 0..count {
+        let mut span = [0; 4];
+        for field in &mut span {
+            *field = reader.u32().ok_or_else(truncated)? as usize;
+        }
+        spans.push(span);
+    }
+    if !reader.bytes.is_empty() {
+        return Err(LoadError::Malformed("trailing data"));
+    }
+
+    let mut spans = spans.into_iter();
+    let mut locations = BTreeMap::new();
+    let program = attach_spans(instructions, &mut spans, &mut locations)?;
+    if spans.next().is_some() {
+        return Err(LoadError::Malformed("more spans than instructions"));
+    }
+    Ok(Bytecode {
+        filename,
+        program,
+        source_hash,
+        locations,
+    })
+}
+
+/// A hash of the source a program was compiled from.
+pub fn source_hash(source: &str) -> u64 {
+    fnv1a(source.as_bytes())
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// An instruction read from the file, before its span is known.
+enum Unspanned {
+    Leaf(FalseInstruction),
+    Lambda(Vec<Unspanned>),
+    ConditionalExecute(Vec<Unspanned>),
+    WhileLoop(Vec<Unspanned>, Vec<Unspanned>),
+}
+
+/// Gives each instruction the next span, in the order they were written.
+fn attach_spans(
+    instructions: Vec<Unspanned>,
+    spans: &mut impl Iterator<Item = [usize; 4]>,
+    locations: &mut BTreeMap<usize, (usize, usize)>,
+) -> Result<Vec<Spanned<FalseInstruction>>, LoadError> {
+    let mut out = Vec::with_capacity(instructions.len());
+    for instruction in instructions {
+        let [start, end, line, column] = spans
+            .next()
+            .ok_or(LoadError::Malformed("fewer spans than instructions"))?;
+        if start > end {
+            return Err(LoadError::Malformed("span ends before it starts"));
+        }
+        locations.insert(start, (line, column));
+        let instruction = match instruction {
+            Unspanned::Leaf(instruction) => instruction,
+            Unspanned::Lambda(body) => {
+                FalseInstruction::Lambda(attach_spans(body, spans, locations)?)
+            }
+            Unspanned::ConditionalExecute(body) => {
+                FalseInstruction::ConditionalExecute(attach_spans(body, spans, locations)?)
+            }
+            Unspanned::WhileLoop(condition, body) => {
+                let condition = attach_spans(condition, spans, locations)?;
+                FalseInstruction::WhileLoop(condition, attach_spans(body, spans, locations)?)
+            }
+        };
+        out.push(Spanned::new(instruction, (start..end).into()));
+    }
+    Ok(out)
+}
+
+struct Writer<'s> {
+    lines: LineIndex<'s>,
+    body: Vec<u8>,
+    spans: Vec<[u32; 4]>,
+}
+
+impl Writer<'_> {
+    fn string(&mut self, s: &str) {
+        self.body.extend((s.len() as u32).to_le_bytes());
+        self.body.extend(s.as_bytes());
+    }
+
+    fn block(&mut self, instructions: &[Spanned<FalseInstruction>]) {
+        self.body.extend((instructions.len() as u32).to_le_bytes());
+        for spanned in instructions {
+            self.instruction(spanned);
+        }
+    }
+
+    fn instruction(&mut self, spanned: &Spanned<FalseInstruction>) {
+        use FalseInstruction::*;
+
+        let span = spanned.span();
+        let (line, column) = self.lines.location(span.start);
+        self.spans.push([
+            span.start as u32,
+            span.end as u32,
+            line as u32,
+            column as u32,
+        ]);
+
+        let instruction = spanned.instruction();
+        self.body.push(opcode(instruction));
+        match instruction {
+            Name(c) => self.body.push(*c as u8),
+            PushInt(v) => self.body.extend(v.to_le_bytes()),
+            PushChar(c) => self.body.push(*c),
+            Lambda(body) | ConditionalExecute(body) => self.block(body),
+            WhileLoop(condition, body) => {
+                self.block(condition);
+                self.block(body);
+            }
+            WriteStr(s) => self.string(s),
+            _ => {}
+        }
+    }
+}
+
+fn opcode(instruction: &FalseInstruction) -> u8 {
+    use FalseInstruction::*;
+
+    match instruction {
+        Name(_) => 0,
+        PushInt(_) => 1,
+        PushChar(_) => 2,
+        Dup => 3,
+        Drop => 4,
+        Swap => 5,
+        Rot => 6,
+        Pick => 7,
+        Add => 8,
+        Sub => 9,
+        Mul => 10,
+        Div => 11,
+        Neg => 12,
+        BitAnd => 13,
+        BitOr => 14,
+        BitNot => 15,
+        Gt => 16,
+        Eq => 17,
+        Lambda(_) => 18,
+        Execute => 19,
+        ConditionalExecute(_) => 20,
+        WhileLoop(_, _) => 21,
+        Store => 22,
+        Fetch => 23,
+        ReadChar => 24,
+        WriteChar => 25,
+        WriteStr(_) => 26,
+        WriteInt => 27,
+        Flush => 28,
+    }
+}
+
+struct Reader<'b> {
+    bytes: &'b [u8],
+}
+
+impl Reader<'_> {
+    fn take<const N: usize>(&mut self) -> Option<[u8; N]> {
+        let (taken, rest) = self.bytes.split_first_chunk::<N>()?;
+        self.bytes = rest;
+        Some(*taken)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take::<1>().map(|[byte]| byte)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take().map(u16::from_le_bytes)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.take().map(u32::from_le_bytes)
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        self.take().map(u64::from_le_bytes)
+    }
+
+    fn string(&mut self) -> Result<String, LoadError> {
+        let length = self
+            .u32()
+            .ok_or(LoadError::Malformed("unexpected end of file"))? as usize;
+        if length > self.bytes.len() {
+            return Err(LoadError::Malformed("unexpected end of file"));
+        }
+        let (s, rest) = self.bytes.split_at(length);
+        self.bytes = rest;
+        String::from_utf8(s.to_vec()).map_err(|_| LoadError::Malformed("invalid UTF-8"))
+    }
+
+    fn block(&mut self, depth: usize) -> Result<Vec<Unspanned>, LoadError> {
+        if depth > MAX_DEPTH {
+            return Err(LoadError::Malformed("nested too deeply"));
+        }
+        let count = self
+            .u32()
+            .ok_or(LoadError::Malformed("unexpected end of file"))? as usize;
+        // Every instruction takes at least a byte
+        let mut instructions = Vec::with_capacity(count.min(self.bytes.len()));
+        for _ in 0..count {
+            instructions.push(self.instruction(depth)?);
+        }
+        Ok(instructions)
+    }
+
+    fn instruction(&mut self, depth: usize) -> Result<Unspanned, LoadError> {
+        use FalseInstruction::*;
+
+        let truncated = || LoadError::Malformed("unexpected end of file");
+        let instruction = match self.u8().ok_or_else(truncated)? {
+            0 => match self.u8().ok_or_else(truncated)? {
+                c @ b'a'..=b'z' => Name(c as char),
+                _ => return Err(LoadError::Malformed("invalid name")),
+            },
+            1 => PushInt(self.u32().ok_or_else(truncated)? as i32),
+            2 => PushChar(self.u8().ok_or_else(truncated)?),
+            3 => Dup,
+            4 => Drop,
+            5 => Swap,
+            6 => Rot,
+            7 => Pick,
+            8 => Add,
+            9 => Sub,
+            10 => Mul,
+            11 => Div,
+            12 => Neg,
+            13 => BitAnd,
+            14 => BitOr,
+            15 => BitNot,
+            16 => Gt,
+            17 => Eq,
+            18 => return Ok(Unspanned::Lambda(self.block(depth + 1)?)),
+            19 => Execute,
+            20 => return Ok(Unspanned::ConditionalExecute(self.block(depth + 1)?)),
+            21 => {
+                let condition = self.block(depth + 1)?;
+                return Ok(Unspanned::WhileLoop(condition, self.block(depth + 1)?));
+            }
+            22 => Store,
+            23 => Fetch,
+            24 => ReadChar,
+            25 => WriteChar,
+            26 => WriteStr(self.string()?),
+            27 => WriteInt,
+            28 => Flush,
+            _ => return Err(LoadError::Malformed("unknown opcode")),
+        };
+        Ok(Unspanned::Leaf(instruction))
+    }
+}
@@ -0,0 +1,351 @@
+//! Compiler to standalone C.
+//!
+//! The generated file carries its own small runtime: a growable stack of
+//! tagged values, the 26 globals, and one C function per lambda. It only
+//! needs a C99 compiler and the standard library.
+
+use std::fmt::Write;
+
+use crate::ast::{FalseInstruction, Spanned};
+use crate::lines::LineIndex;
+
+const RUNTIME: &str = r#"#include <inttypes.h>
+#include <stdarg.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+
+enum { INTEGER, LAMBDA, NAME, UNSET };
+
+typedef struct {
+    int tag;
+    int32_t value;
+} Value;
+
+static Value *stack;
+static size_t depth, capacity;
+static Value globals[26];
+
+static void call_lambda(int32_t index);
+
+static const char *type_name(Value v) {
+    switch (v.tag) {
+    case INTEGER: return "Integer";
+    case LAMBDA: return "Lambda";
+    default: return "VariableReference";
+    }
+}
+
+static void fail(int line, int column, const char *format, ...) {
+    va_list args;
+    fflush(stdout);
+    fprintf(stderr, "%s:%d:%d: ", FILENAME, line, column);
+    va_start(args, format);
+    vfprintf(stderr, format, args);
+    va_end(args);
+    fputc('\n', stderr);
+    exit(1);
+}
+
+static void push(int tag, int32_t value) {
+    if (depth == capacity) {
+        capacity = capacity ? capacity * 2 : 256;
+        stack = realloc(stack, capacity * sizeof(Value));
+        if (!stack) {
+            fputs("Out of memory\n", stderr);
+            exit(1);
+        }
+    }
+    stack[depth].tag = tag;
+    stack[depth].value = value;
+    depth++;
+}
+
+static Value pop(int line, int column, const char *empty) {
+    if (depth == 0) fail(line, column, "%s", empty);
+    return stack[--depth];
+}
+
+static int32_t pop_int(int line, int column) {
+    Value v = pop(line, column, "Stack is empty");
+    if (v.tag != INTEGER) fail(line, column, "Expected Integer on stack, got %s", type_name(v));
+    return v.value;
+}
+
+static inline void op_dup(int line, int column) {
+    if (depth == 0) fail(line, column, "Stack is empty");
+    push(stack[depth - 1].tag, stack[depth - 1].value);
+}
+
+static inline void op_drop(void) {
+    if (depth > 0) depth--;
+}
+
+static inline void op_swap(int line, int column) {
+    Value head = pop(line, column, "Stack is empty");
+    Value next = pop(line, column, "Stack only has 1 value");
+    push(head.tag, head.value);
+    push(next.tag, next.value);
+}
+
+/* Reverses the top three values, like the interpreter */
+static inline void op_rot(int line, int column) {
+    Value first = pop(line, column, "Stack is empty");
+    Value second = pop(line, column, "Stack only has 1 value");
+    Value third = pop(line, column, "Stack only has 2 values");
+    push(first.tag, first.value);
+    push(second.tag, second.value);
+    push(third.tag, third.value);
+}
+
+static inline void op_pick(int line, int column) {
+    Value head = pop(line, column, "Stack is empty");
+    Value picked;
+    if (head.tag != INTEGER) fail(line, column, "Unexpected index for \303\270 (PICK): %s", type_name(head));
+    if (head.value < 0 || (size_t)head.value >= depth)
+        fail(line, column, "Index out of range for \303\270 (PICK): %" PRId32, head.value);
+    picked = stack[depth - 1 - (size_t)head.value];
+    push(picked.tag, picked.value);
+}
+
+static inline void op_add(int line, int column) {
+    uint32_t a = (uint32_t)pop_int(line, column), b = (uint32_t)pop_int(line, column);
+    push(INTEGER, (int32_t)(b + a));
+}
+
+static inline void op_sub(int line, int column) {
+    uint32_t a = (uint32_t)pop_int(line, column), b = (uint32_t)pop_int(line, column);
+    push(INTEGER, (int32_t)(b - a));
+}
+
+static inline void op_mul(int line, int column) {
+    uint32_t a = (uint32_t)pop_int(line, column), b = (uint32_t)pop_int(line, column);
+    push(INTEGER, (int32_t)(b * a));
+}
+
+static inline void op_div(int line, int column) {
+    int32_t a = pop_int(line, column), b = pop_int(line, column);
+    if (a == 0) fail(line, column, "Division by zero");
+    push(INTEGER, b == INT32_MIN && a == -1 ? INT32_MIN : b / a);
+}
+
+static inline void op_neg(int line, int column) {
+    uint32_t a = (uint32_t)pop_int(line, column);
+    push(INTEGER, (int32_t)(0u - a));
+}
+
+static inline void op_and(int line, int column) {
+    int32_t a = pop_int(line, column), b = pop_int(line, column);
+    push(INTEGER, b & a);
+}
+
+static inline void op_or(int line, int column) {
+    int32_t a = pop_int(line, column), b = pop_int(line, column);
+    push(INTEGER, b | a);
+}
+
+static inline void op_not(int line, int column) {
+    push(INTEGER, ~pop_int(line, column));
+}
+
+static inline void op_gt(int line, int column) {
+    int32_t a = pop_int(line, column), b = pop_int(line, column);
+    push(INTEGER, b > a ? -1 : 0);
+}
+
+static inline void op_eq(int line, int column) {
+    int32_t a = pop_int(line, column), b = pop_int(line, column);
+    push(INTEGER, b == a ? -1 : 0);
+}
+
+static inline void op_execute(int line, int column) {
+    Value v = pop(line, column, "Stack is empty");
+    if (v.tag != LAMBDA) fail(line, column, "Expected lambda for Execute, got %s", type_name(v));
+    call_lambda(v.value);
+}
+
+static inline void op_store(int line, int column) {
+    Value name = pop(line, column, "Stack is empty");
+    Value value;
+    if (name.tag != NAME) fail(line, column, "Store (:) must be preceded by a name");
+    value = pop(line, column, "Stack only has 1 value");
+    if (value.tag == NAME) fail(line, column, "Names cannot be stored in names");
+    globals[name.value] = value;
+}
+
+static inline void op_fetch(int line, int column) {
+    Value name = pop(line, column, "Stack is empty");
+    if (name.tag != NAME) fail(line, column, "Fetch (;) must be preceded by a name");
+    if (globals[name.value].tag == UNSET)
+        fail(line, column, "Name %c not found in global scope", 'a' + name.value);
+    push(globals[name.value].tag, globals[name.value].value);
+}
+
+static inline void op_read_char(void) {
+    int c = getchar();
+    push(INTEGER, c == EOF ? -1 : c);
+}
+
+static inline void op_write_char(int line, int column) {
+    int32_t v = pop_int(line, column);
+    if (v < 0 || v > 0x10FFFF || (v >= 0xD800 && v <= 0xDFFF))
+        fail(line, column, "Can't output value %" PRId32 " as char", v);
+    if (v < 0x80) {
+        putchar(v);
+    } else if (v < 0x800) {
+        putchar(0xC0 | (v >> 6));
+        putchar(0x80 | (v & 0x3F));
+    } else if (v < 0x10000) {
+        putchar(0xE0 | (v >> 12));
+        putchar(0x80 | ((v >> 6) & 0x3F));
+        putchar(0x80 | (v & 0x3F));
+    } else {
+        putchar(0xF0 | (v >> 18));
+        putchar(0x80 | ((v >> 12) & 0x3F));
+        putchar(0x80 | ((v >> 6) & 0x3F));
+        putchar(0x80 | (v & 0x3F));
+    }
+}
+
+static inline void op_write_int(int line, int column) {
+    printf("%" PRId32, pop_int(line, column));
+}
+"#;
+
+/// Compiles a program to C. `source` and `filename` are used to locate
+/// runtime errors.
+pub fn compile(program: &[Spanned<FalseInstruction>], source: &str, filename: &str) -> String {
+    let mut compiler = Compiler {
+        lines: LineIndex::new(source),
+        lambdas: Vec::new(),
+    };
+    let main = compiler.block(program, 1);
+
+    let mut out = String::new();
+    writeln!(out, "/* Generated by falsy from {} */", comment(filename)).unwrap();
+    writeln!(out, "#define FILENAME {}", string_literal(filename)).unwrap();
+    out.push_str(RUNTIME);
+    out.push('\n');
+    for i in 0..compiler.lambdas.len() {
+        writeln!(out, "static void lambda_{i}(void);").unwrap();
+    }
+    for (i, body) in compiler.lambdas.iter().enumerate() {
+        writeln!(out, "\nstatic void lambda_{i}(void) {{\n{body}}}").unwrap();
+    }
+    out.push_str("\nstatic void call_lambda(int32_t index) {\n    switch (index) {\n");
+    for i in 0..compiler.lambdas.len() {
+        writeln!(out, "    case {i}: lambda_{i}(); break;").unwrap();
+    }
+    out.push_str("    }\n}\n");
+    out.push_str("\nint main(void) {\n");
+    out.push_str("    int i;\n    for (i = 0; i < 26; i++) globals[i].tag = UNSET;\n");
+    out.push_str(&main);
+    out.push_str("    fflush(stdout);\n    return 0;\n}\n");
+    out
+}
+
+struct Compiler<'s> {
+    lines: LineIndex<'s>,
+    /// Bodies of the C functions for each lambda, by index.
+    lambdas: Vec<String>,
+}
+
+impl Compiler<'_> {
+    fn block(&mut self, instructions: &[Spanned<FalseInstruction>], indent: usize) -> String {
+        let mut out = String::new();
+        for spanned in instructions {
+            self.instruction(spanned, indent, &mut out);
+        }
+        out
+    }
+
+    fn instruction(
+        &mut self,
+        spanned: &Spanned<FalseInstruction>,
+        indent: usize,
+        out: &mut String,
+    ) {
+        use FalseInstruction::*;
+
+        let pad = "    ".repeat(indent);
+        let (line, column) = self.lines.location(spanned.span().start);
+        let at = format!("{line}, {column}");
+        let statement = match spanned.instruction() {
+            Name(c) => format!("push(NAME, {});", *c as u8 - b'a'),
+            PushInt(v) => format!("push(INTEGER, {});", int_literal(*v)),
+            PushChar(c) => format!("push(INTEGER, {c});"),
+            Dup => format!("op_dup({at});"),
+            Drop => "op_drop();".to_string(),
+            Swap => format!("op_swap({at});"),
+            Rot => format!("op_rot({at});"),
+            Pick => format!("op_pick({at});"),
+            Add => format!("op_add({at});"),
+            Sub => format!("op_sub({at});"),
+            Mul => format!("op_mul({at});"),
+            Div => format!("op_div({at});"),
+            Neg => format!("op_neg({at});"),
+            BitAnd => format!("op_and({at});"),
+            BitOr => format!("op_or({at});"),
+            BitNot => format!("op_not({at});"),
+            Gt => format!("op_gt({at});"),
+            Eq => format!("op_eq({at});"),
+            Lambda(body) => {
+                // Reserve the index first, so nested lambdas come after it
+                let index = self.lambdas.len();
+                self.lambdas.push(String::new());
+                self.lambdas[index] = self.block(body, 1);
+                format!("push(LAMBDA, {index});")
+            }
+            Execute => format!("op_execute({at});"),
+            ConditionalExecute(body) => format!(
+                "if (pop_int({at})) {{\n{}{pad}}}",
+                self.block(body, indent + 1)
+            ),
+            WhileLoop(condition, body) => {
+                let inner = "    ".repeat(indent + 1);
+                format!(
+                    "for (;;) {{\n{}{inner}if (!pop_int({at})) break;\n{}{pad}}}",
+                    self.block(condition, indent + 1),
+                    self.block(body, indent + 1)
+                )
+            }
+            Store => format!("op_store({at});"),
+            Fetch => format!("op_fetch({at});"),
+            ReadChar => "op_read_char();".to_string(),
+            WriteChar => format!("op_write_char({at});"),
+            WriteStr(s) => format!("fputs({}, stdout);", string_literal(s)),
+            WriteInt => format!("op_write_int({at});"),
+            Flush => "fflush(stdout);".to_string(),
+        };
+        writeln!(out, "{pad}{statement}").unwrap();
+    }
+}
+
+/// `i32::MIN` can't be written as a literal in C.
+fn int_literal(v: i32) -> String {
+    match v {
+        i32::MIN => "INT32_MIN".to_string(),
+        v => v.to_string(),
+    }
+}
+
+fn string_literal(s: &str) -> String {
+    let mut literal = String::from("\"");
+    for byte in s.bytes() {
+        match byte {
+            // `?` is escaped so it can't start a trigraph
+            b'"' | b'\\' | b'?' => {
+                literal.push('\\');
+                literal.push(byte as char);
+            }
+            b' '..=b'~' => literal.push(byte as char),
+            _ => write!(literal, "\\{byte:03o}").unwrap(),
+        }
+    }
+    literal.push('"');
+    literal
+}
+
+fn comment(s: &str) -> String {
+    s.replace("*/", "* /")
+}
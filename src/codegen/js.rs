@@ -0,0 +1,369 @@
+//! Compiler to JavaScript.
+//!
+//! The generated ES module exports `run(input)`, which runs the program on
+//! a string and returns its output as a string, and `FalseError`, which it
+//! throws on runtime errors. Lambdas become closures and the globals an
+//! array, so every call to `run` starts afresh.
+//!
+//! A source map can be generated alongside, mapping each statement back to
+//! the instruction it came from, so stack traces point at FALSE source.
+
+use std::fmt::Write;
+
+use crate::ast::{FalseInstruction, Spanned};
+use crate::lines::LineIndex;
+
+const RUNTIME: &str = r#"
+class Name {
+  constructor(index) {
+    this.index = index;
+  }
+}
+
+const NAMES = Array.from({ length: 26 }, (_, i) => new Name(i));
+
+/** A runtime error, with the output written before it. */
+export class FalseError extends Error {
+  constructor(line, column, reason, output) {
+    super(`${FILENAME}:${line}:${column}: ${reason}`);
+    this.name = "FalseError";
+    this.line = line;
+    this.column = column;
+    this.reason = reason;
+    this.output = output;
+  }
+}
+
+function typeName(value) {
+  switch (typeof value) {
+    case "number":
+      return "Integer";
+    case "function":
+      return "Lambda";
+    default:
+      return "VariableReference";
+  }
+}
+
+/** Runs the program on `input`, and returns its output. */
+export function run(input) {
+  const bytes = new TextEncoder().encode(input);
+  let position = 0;
+  let output = "";
+  const stack = [];
+  const globals = new Array(26);
+
+  const fail = (line, column, reason) => {
+    throw new FalseError(line, column, reason, output);
+  };
+  const push = (value) => {
+    stack.push(value);
+  };
+  const pop = (line, column, empty) => {
+    if (stack.length === 0) fail(line, column, empty);
+    return stack.pop();
+  };
+  const popInt = (line, column) => {
+    const value = pop(line, column, "Stack is empty");
+    if (typeof value !== "number") {
+      fail(line, column, `Expected Integer on stack, got ${typeName(value)}`);
+    }
+    return value;
+  };
+  const binary = (line, column, op) => {
+    const a = popInt(line, column);
+    const b = popInt(line, column);
+    push(op(b, a));
+  };
+
+  const dup = (line, column) => {
+    if (stack.length === 0) fail(line, column, "Stack is empty");
+    push(stack[stack.length - 1]);
+  };
+  const drop = () => {
+    stack.pop();
+  };
+  const swap = (line, column) => {
+    const head = pop(line, column, "Stack is empty");
+    const next = pop(line, column, "Stack only has 1 value");
+    push(head);
+    push(next);
+  };
+  // Reverses the top three values, like the interpreter
+  const rot = (line, column) => {
+    const first = pop(line, column, "Stack is empty");
+    const second = pop(line, column, "Stack only has 1 value");
+    const third = pop(line, column, "Stack only has 2 values");
+    push(first);
+    push(second);
+    push(third);
+  };
+  const pick = (line, column) => {
+    const index = pop(line, column, "Stack is empty");
+    if (typeof index !== "number") {
+      fail(line, column, `Unexpected index for ø (PICK): ${typeName(index)}`);
+    }
+    if (index < 0 || index >= stack.length) {
+      fail(line, column, `Index out of range for ø (PICK): ${index}`);
+    }
+    push(stack[stack.length - 1 - index]);
+  };
+
+  const add = (line, column) => binary(line, column, (b, a) => (b + a) | 0);
+  const sub = (line, column) => binary(line, column, (b, a) => (b - a) | 0);
+  const mul = (line, column) => binary(line, column, (b, a) => Math.imul(b, a));
+  const div = (line, column) =>
+    binary(line, column, (b, a) => {
+      if (a === 0) fail(line, column, "Division by zero");
+      return (b / a) | 0;
+    });
+  const neg = (line, column) => push(-popInt(line, column) | 0);
+  const and = (line, column) => binary(line, column, (b, a) => b & a);
+  const or = (line, column) => binary(line, column, (b, a) => b | a);
+  const not = (line, column) => push(~popInt(line, column));
+  const gt = (line, column) => binary(line, column, (b, a) => (b > a ? -1 : 0));
+  const eq = (line, column) => binary(line, column, (b, a) => (b === a ? -1 : 0));
+
+  const execute = (line, column) => {
+    const lambda = pop(line, column, "Stack is empty");
+    if (typeof lambda !== "function") {
+      fail(line, column, `Expected lambda for Execute, got ${typeName(lambda)}`);
+    }
+    lambda();
+  };
+  const store = (line, column) => {
+    const name = pop(line, column, "Stack is empty");
+    if (!(name instanceof Name)) fail(line, column, "Store (:) must be preceded by a name");
+    const value = pop(line, column, "Stack only has 1 value");
+    if (value instanceof Name) fail(line, column, "Names cannot be stored in names");
+    globals[name.index] = value;
+  };
+  const fetch = (line, column) => {
+    const name = pop(line, column, "Stack is empty");
+    if (!(name instanceof Name)) fail(line, column, "Fetch (;) must be preceded by a name");
+    const value = globals[name.index];
+    if (value === undefined) {
+      fail(line, column, `Name ${String.fromCharCode(97 + name.index)} not found in global scope`);
+    }
+    push(value);
+  };
+
+  const readChar = () => {
+    push(position < bytes.length ? bytes[position++] : -1);
+  };
+  const writeChar = (line, column) => {
+    const value = popInt(line, column);
+    if (value < 0 || value > 0x10ffff || (value >= 0xd800 && value <= 0xdfff)) {
+      fail(line, column, `Can't output value ${value} as char`);
+    }
+    output += String.fromCodePoint(value);
+  };
+  const writeInt = (line, column) => {
+    output += String(popInt(line, column));
+  };
+"#;
+
+/// Compiles a program to a JavaScript module. `source` and `filename` are
+/// used to locate runtime errors.
+pub fn compile(program: &[Spanned<FalseInstruction>], source: &str, filename: &str) -> String {
+    generate(program, source, filename).0
+}
+
+/// Compiles a program to a JavaScript module to be saved as `js_filename`,
+/// and a source map for it.
+pub fn compile_with_source_map(
+    program: &[Spanned<FalseInstruction>],
+    source: &str,
+    filename: &str,
+    js_filename: &str,
+) -> (String, String) {
+    let (mut code, lines) = generate(program, source, filename);
+    writeln!(code, "//# sourceMappingURL={js_filename}.map").unwrap();
+
+    // One segment per mapped line, at the start of its statement
+    let mut mappings = String::new();
+    let (mut previous_line, mut previous_column) = (0, 0);
+    for (i, line) in lines.iter().enumerate() {
+        if i > 0 {
+            mappings.push(';');
+        }
+        if let Some(((line, column), indent)) = line {
+            vlq(&mut mappings, *indent as i64);
+            vlq(&mut mappings, 0);
+            vlq(&mut mappings, *line as i64 - previous_line);
+            vlq(&mut mappings, *column as i64 - previous_column);
+            (previous_line, previous_column) = (*line as i64, *column as i64);
+        }
+    }
+    let map = format!(
+        r#"{{"version":3,"file":{},"sources":[{}],"sourcesContent":[{}],"names":[],"mappings":"{mappings}"}}"#,
+        string_literal(js_filename),
+        string_literal(filename),
+        string_literal(source),
+    );
+    (code, map)
+}
+
+/// The module, and for each line of it, the 0-based line and UTF-16 column
+/// of the instruction it came from and the indentation of its statement.
+type Generated = (String, Vec<Option<((usize, usize), usize)>>);
+
+fn generate(program: &[Spanned<FalseInstruction>], source: &str, filename: &str) -> Generated {
+    let mut compiler = Compiler {
+        source,
+        line_index: LineIndex::new(source),
+        lines: Vec::new(),
+    };
+    compiler.block(program, 1);
+
+    let mut code = String::new();
+    writeln!(
+        code,
+        "// Generated by falsy from {}",
+        filename.replace('\n', " ")
+    )
+    .unwrap();
+    writeln!(code, "const FILENAME = {};", string_literal(filename)).unwrap();
+    code.push_str(RUNTIME);
+    code.push('\n');
+    let mut lines = vec![None; code.lines().count()];
+    for (text, origin) in compiler.lines {
+        code.push_str(&text);
+        code.push('\n');
+        let indent = text.len() - text.trim_start().len();
+        lines.push(origin.map(|origin| (origin, indent)));
+    }
+    code.push_str("  return output;\n}\n");
+    (code, lines)
+}
+
+struct Compiler<'s> {
+    source: &'s str,
+    line_index: LineIndex<'s>,
+    /// Lines of the body of `run`, with the 0-based line and UTF-16 column
+    /// of the instruction they came from.
+    lines: Vec<(String, Option<(usize, usize)>)>,
+}
+
+impl Compiler<'_> {
+    fn block(&mut self, instructions: &[Spanned<FalseInstruction>], indent: usize) {
+        for spanned in instructions {
+            self.instruction(spanned, indent);
+        }
+    }
+
+    fn line(&mut self, indent: usize, text: String, origin: Option<usize>) {
+        let origin = origin.map(|offset| {
+            let (line, line_start) = self.line_index.line(offset);
+            let column = self.source[line_start..offset].encode_utf16().count();
+            (line - 1, column)
+        });
+        self.lines
+            .push((format!("{}{text}", "  ".repeat(indent)), origin));
+    }
+
+    fn instruction(&mut self, spanned: &Spanned<FalseInstruction>, indent: usize) {
+        use FalseInstruction::*;
+
+        let offset = spanned.span().start;
+        let (line, column) = self.line_index.location(offset);
+        let at = format!("{line}, {column}");
+        let statement = match spanned.instruction() {
+            Name(c) => format!("push(NAMES[{}]);", *c as u8 - b'a'),
+            PushInt(v) => format!("push({v});"),
+            PushChar(c) => format!("push({c});"),
+            Dup => format!("dup({at});"),
+            Drop => "drop();".to_string(),
+            Swap => format!("swap({at});"),
+            Rot => format!("rot({at});"),
+            Pick => format!("pick({at});"),
+            Add => format!("add({at});"),
+            Sub => format!("sub({at});"),
+            Mul => format!("mul({at});"),
+            Div => format!("div({at});"),
+            Neg => format!("neg({at});"),
+            BitAnd => format!("and({at});"),
+            BitOr => format!("or({at});"),
+            BitNot => format!("not({at});"),
+            Gt => format!("gt({at});"),
+            Eq => format!("eq({at});"),
+            Lambda(body) => {
+                self.line(indent, "push(() => {".to_string(), Some(offset));
+                self.block(body, indent + 1);
+                self.line(indent, "});".to_string(), None);
+                return;
+            }
+            Execute => format!("execute({at});"),
+            ConditionalExecute(body) => {
+                self.line(indent, format!("if (popInt({at})) {{"), Some(offset));
+                self.block(body, indent + 1);
+                self.line(indent, "}".to_string(), None);
+                return;
+            }
+            WhileLoop(condition, body) => {
+                self.line(indent, "for (;;) {".to_string(), Some(offset));
+                self.block(condition, indent + 1);
+                self.line(
+                    indent + 1,
+                    format!("if (!popInt({at})) break;"),
+                    Some(offset),
+                );
+                self.block(body, indent + 1);
+                self.line(indent, "}".to_string(), None);
+                return;
+            }
+            Store => format!("store({at});"),
+            Fetch => format!("fetch({at});"),
+            ReadChar => "readChar();".to_string(),
+            WriteChar => format!("writeChar({at});"),
+            WriteStr(s) => format!("output += {};", string_literal(s)),
+            WriteInt => format!("writeInt({at});"),
+            // The output is only returned at the end
+            Flush => return,
+        };
+        self.line(indent, statement, Some(offset));
+    }
+}
+
+/// A string literal, valid in both JavaScript and JSON.
+fn string_literal(s: &str) -> String {
+    let mut literal = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' | '\\' => {
+                literal.push('\\');
+                literal.push(c);
+            }
+            ' '..='~' => literal.push(c),
+            _ => {
+                for unit in c.encode_utf16(&mut [0; 2]) {
+                    write!(literal, "\\u{unit:04x}").unwrap();
+                }
+            }
+        }
+    }
+    literal.push('"');
+    literal
+}
+
+/// Appends `value` in the base64 VLQ encoding of source maps.
+fn vlq(out: &mut String, value: i64) {
+    const BASE64: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut rest = if value < 0 {
+        ((-value) << 1) | 1
+    } else {
+        value << 1
+    };
+    loop {
+        let mut digit = rest & 0b11111;
+        rest >>= 5;
+        if rest > 0 {
+            digit |= 0b100000;
+        }
+        out.push(BASE64[digit as usize] as char);
+        if rest == 0 {
+            break;
+        }
+    }
+}
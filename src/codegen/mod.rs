@@ -0,0 +1,12 @@
+//! Ahead-of-time compilers from the AST to other languages and to native
+//! code.
+//!
+//! Generated programs report runtime errors with the same reasons as the
+//! interpreter, located by the line and column of the failing instruction.
+//! Their arithmetic wraps on overflow, which the interpreter only does in
+//! release builds: in debug builds `2147483647 1+` panics there instead.
+
+pub mod c;
+pub mod js;
+pub mod wasm;
+pub mod x86_64;
@@ -0,0 +1,513 @@
+//! Compiler to WebAssembly.
+//!
+//! The module keeps the value stack in linear memory, growing it a page at
+//! a time, and talks to its host through three imports from `env`:
+//!
+//! - `read() -> i32` returns the next input byte, or -1 at the end
+//! - `write(ptr: i32, len: i32)` writes bytes from memory to the output
+//! - `error(ptr: i32, len: i32)` is called with the pieces of a runtime
+//!   error message, `file:line:column: reason`, after which the module traps
+//!
+//! It exports its `memory` and a `run` function that runs the program.
+//! Lambdas are functions in a table, called indirectly by index.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use crate::ast::{FalseInstruction, Spanned};
+use crate::lines::LineIndex;
+
+/// Values are 64 bits, with a tag in the upper half: 0 for unset globals,
+/// 1 for integers, and these for lambdas and names.
+const LAMBDA: u64 = 2;
+const NAME: u64 = 3;
+
+/// Messages the runtime refers to by name.
+const MESSAGES: [(&str, &str); 17] = [
+    ("stack_empty", "Stack is empty"),
+    ("one_value", "Stack only has 1 value"),
+    ("two_values", "Stack only has 2 values"),
+    ("stack_overflow", "Stack overflow"),
+    ("division_by_zero", "Division by zero"),
+    ("expected_integer", "Expected Integer on stack, got "),
+    ("pick_index", "Unexpected index for ø (PICK): "),
+    ("pick_range", "Index out of range for ø (PICK): "),
+    ("execute", "Expected lambda for Execute, got "),
+    ("store_name", "Store (:) must be preceded by a name"),
+    ("store_names", "Names cannot be stored in names"),
+    ("fetch_name", "Fetch (;) must be preceded by a name"),
+    ("name", "Name "),
+    ("not_found", " not found in global scope"),
+    ("char", "Can't output value "),
+    ("as_char", " as char"),
+    ("empty", ""),
+];
+
+/// Type names, by tag.
+const TYPE_NAMES: [(&str, &str); 3] = [
+    ("integer", "Integer"),
+    ("lambda", "Lambda"),
+    ("variable_reference", "VariableReference"),
+];
+
+/// Strings are stored with their length in front, and passed around by
+/// address.
+const RUNTIME: &str = r#"
+  (func $put_error (param $str i32)
+    (call $error (i32.add (local.get $str) (i32.const 4)) (i32.load (local.get $str))))
+
+  (func $put (param $str i32)
+    (call $write (i32.add (local.get $str) (i32.const 4)) (i32.load (local.get $str))))
+
+  (func $fail (param $at i32) (param $message i32)
+    (call $put_error (local.get $at))
+    (call $put_error (local.get $message))
+    unreachable)
+
+  (func $fail_type (param $at i32) (param $message i32) (param $value i64)
+    (call $put_error (local.get $at))
+    (call $put_error (local.get $message))
+    (call $put_error (call $type_name (local.get $value)))
+    unreachable)
+
+  (func $fail_int (param $at i32) (param $before i32) (param $value i32) (param $after i32)
+    (call $put_error (local.get $at))
+    (call $put_error (local.get $before))
+    (call $put_error (call $format_int (local.get $value)))
+    (call $put_error (local.get $after))
+    unreachable)
+
+  (func $tag (param $value i64) (result i32)
+    (i32.wrap_i64 (i64.shr_u (local.get $value) (i64.const 32))))
+
+  (func $type_name (param $value i64) (result i32)
+    (if (result i32) (i32.eq (call $tag (local.get $value)) (i32.const 1))
+      (then (global.get $integer))
+      (else
+        (if (result i32) (i32.eq (call $tag (local.get $value)) (i32.const 2))
+          (then (global.get $lambda))
+          (else (global.get $variable_reference))))))
+
+  ;; Decimal digits of a value in the scratch space, as a string
+  (func $format_int (param $value i32) (result i32)
+    (local $end i32) (local $ptr i32) (local $magnitude i32)
+    (local.set $end (i32.add (global.get $scratch) (i32.const 24)))
+    (local.set $ptr (local.get $end))
+    ;; `MIN` negates to itself, which is its magnitude when unsigned
+    (local.set $magnitude
+      (if (result i32) (i32.lt_s (local.get $value) (i32.const 0))
+        (then (i32.sub (i32.const 0) (local.get $value)))
+        (else (local.get $value))))
+    (loop $digits
+      (local.set $ptr (i32.sub (local.get $ptr) (i32.const 1)))
+      (i32.store8 (local.get $ptr)
+        (i32.add (i32.const 48) (i32.rem_u (local.get $magnitude) (i32.const 10))))
+      (local.set $magnitude (i32.div_u (local.get $magnitude) (i32.const 10)))
+      (br_if $digits (local.get $magnitude)))
+    (if (i32.lt_s (local.get $value) (i32.const 0))
+      (then
+        (local.set $ptr (i32.sub (local.get $ptr) (i32.const 1)))
+        (i32.store8 (local.get $ptr) (i32.const 45))))
+    (local.set $ptr (i32.sub (local.get $ptr) (i32.const 4)))
+    (i32.store (local.get $ptr) (i32.sub (i32.sub (local.get $end) (local.get $ptr)) (i32.const 4)))
+    (local.get $ptr))
+
+  (func $push (param $at i32) (param $value i64)
+    (if (i32.ge_u (i32.shr_u (global.get $sp) (i32.const 16)) (memory.size))
+      (then
+        (if (i32.eq (memory.grow (i32.const 1)) (i32.const -1))
+          (then (call $fail (local.get $at) (global.get $stack_overflow))))))
+    (i64.store (global.get $sp) (local.get $value))
+    (global.set $sp (i32.add (global.get $sp) (i32.const 8))))
+
+  (func $push_int (param $at i32) (param $value i32)
+    (call $push (local.get $at)
+      (i64.or (i64.const 0x100000000) (i64.extend_i32_u (local.get $value)))))
+
+  (func $pop (param $at i32) (param $empty i32) (result i64)
+    (if (i32.eq (global.get $sp) (global.get $stack))
+      (then (call $fail (local.get $at) (local.get $empty))))
+    (global.set $sp (i32.sub (global.get $sp) (i32.const 8)))
+    (i64.load (global.get $sp)))
+
+  (func $pop_int (param $at i32) (result i32)
+    (local $value i64)
+    (local.set $value (call $pop (local.get $at) (global.get $stack_empty)))
+    (if (i32.ne (call $tag (local.get $value)) (i32.const 1))
+      (then (call $fail_type (local.get $at) (global.get $expected_integer) (local.get $value))))
+    (i32.wrap_i64 (local.get $value)))
+
+  (func $op_dup (param $at i32)
+    (if (i32.eq (global.get $sp) (global.get $stack))
+      (then (call $fail (local.get $at) (global.get $stack_empty))))
+    (call $push (local.get $at) (i64.load (i32.sub (global.get $sp) (i32.const 8)))))
+
+  (func $op_drop
+    (if (i32.ne (global.get $sp) (global.get $stack))
+      (then (global.set $sp (i32.sub (global.get $sp) (i32.const 8))))))
+
+  (func $op_swap (param $at i32)
+    (local $head i64) (local $next i64)
+    (local.set $head (call $pop (local.get $at) (global.get $stack_empty)))
+    (local.set $next (call $pop (local.get $at) (global.get $one_value)))
+    (call $push (local.get $at) (local.get $head))
+    (call $push (local.get $at) (local.get $next)))
+
+  ;; Reverses the top three values, like the interpreter
+  (func $op_rot (param $at i32)
+    (local $first i64) (local $second i64) (local $third i64)
+    (local.set $first (call $pop (local.get $at) (global.get $stack_empty)))
+    (local.set $second (call $pop (local.get $at) (global.get $one_value)))
+    (local.set $third (call $pop (local.get $at) (global.get $two_values)))
+    (call $push (local.get $at) (local.get $first))
+    (call $push (local.get $at) (local.get $second))
+    (call $push (local.get $at) (local.get $third)))
+
+  (func $op_pick (param $at i32)
+    (local $head i64) (local $index i32)
+    (local.set $head (call $pop (local.get $at) (global.get $stack_empty)))
+    (if (i32.ne (call $tag (local.get $head)) (i32.const 1))
+      (then (call $fail_type (local.get $at) (global.get $pick_index) (local.get $head))))
+    (local.set $index (i32.wrap_i64 (local.get $head)))
+    ;; Negative indices are out of range when unsigned
+    (if (i32.ge_u (local.get $index)
+          (i32.shr_u (i32.sub (global.get $sp) (global.get $stack)) (i32.const 3)))
+      (then
+        (call $fail_int (local.get $at) (global.get $pick_range) (local.get $index) (global.get $empty))))
+    (call $push (local.get $at)
+      (i64.load (i32.sub (i32.sub (global.get $sp) (i32.const 8))
+                         (i32.shl (local.get $index) (i32.const 3))))))
+
+  (func $op_add (param $at i32)
+    (local $a i32)
+    (local.set $a (call $pop_int (local.get $at)))
+    (call $push_int (local.get $at) (i32.add (call $pop_int (local.get $at)) (local.get $a))))
+
+  (func $op_sub (param $at i32)
+    (local $a i32)
+    (local.set $a (call $pop_int (local.get $at)))
+    (call $push_int (local.get $at) (i32.sub (call $pop_int (local.get $at)) (local.get $a))))
+
+  (func $op_mul (param $at i32)
+    (local $a i32)
+    (local.set $a (call $pop_int (local.get $at)))
+    (call $push_int (local.get $at) (i32.mul (call $pop_int (local.get $at)) (local.get $a))))
+
+  (func $op_div (param $at i32)
+    (local $a i32) (local $b i32)
+    (local.set $a (call $pop_int (local.get $at)))
+    (local.set $b (call $pop_int (local.get $at)))
+    (if (i32.eqz (local.get $a))
+      (then (call $fail (local.get $at) (global.get $division_by_zero))))
+    ;; `MIN / -1` overflows, and wraps to `MIN`
+    (call $push_int (local.get $at)
+      (if (result i32)
+          (i32.and (i32.eq (local.get $b) (i32.const 0x80000000))
+                   (i32.eq (local.get $a) (i32.const -1)))
+        (then (local.get $b))
+        (else (i32.div_s (local.get $b) (local.get $a))))))
+
+  (func $op_neg (param $at i32)
+    (call $push_int (local.get $at) (i32.sub (i32.const 0) (call $pop_int (local.get $at)))))
+
+  (func $op_and (param $at i32)
+    (local $a i32)
+    (local.set $a (call $pop_int (local.get $at)))
+    (call $push_int (local.get $at) (i32.and (call $pop_int (local.get $at)) (local.get $a))))
+
+  (func $op_or (param $at i32)
+    (local $a i32)
+    (local.set $a (call $pop_int (local.get $at)))
+    (call $push_int (local.get $at) (i32.or (call $pop_int (local.get $at)) (local.get $a))))
+
+  (func $op_not (param $at i32)
+    (call $push_int (local.get $at) (i32.xor (call $pop_int (local.get $at)) (i32.const -1))))
+
+  (func $op_gt (param $at i32)
+    (local $a i32)
+    (local.set $a (call $pop_int (local.get $at)))
+    (call $push_int (local.get $at)
+      (i32.sub (i32.const 0) (i32.gt_s (call $pop_int (local.get $at)) (local.get $a)))))
+
+  (func $op_eq (param $at i32)
+    (local $a i32)
+    (local.set $a (call $pop_int (local.get $at)))
+    (call $push_int (local.get $at)
+      (i32.sub (i32.const 0) (i32.eq (call $pop_int (local.get $at)) (local.get $a)))))
+
+  (func $op_execute (param $at i32)
+    (local $value i64)
+    (local.set $value (call $pop (local.get $at) (global.get $stack_empty)))
+    (if (i32.ne (call $tag (local.get $value)) (i32.const 2))
+      (then (call $fail_type (local.get $at) (global.get $execute) (local.get $value))))
+    (call_indirect (type $lambda) (i32.wrap_i64 (local.get $value))))
+
+  (func $op_store (param $at i32)
+    (local $name i64) (local $value i64)
+    (local.set $name (call $pop (local.get $at) (global.get $stack_empty)))
+    (if (i32.ne (call $tag (local.get $name)) (i32.const 3))
+      (then (call $fail (local.get $at) (global.get $store_name))))
+    (local.set $value (call $pop (local.get $at) (global.get $one_value)))
+    (if (i32.eq (call $tag (local.get $value)) (i32.const 3))
+      (then (call $fail (local.get $at) (global.get $store_names))))
+    (i64.store
+      (i32.add (global.get $globals) (i32.shl (i32.wrap_i64 (local.get $name)) (i32.const 3)))
+      (local.get $value)))
+
+  (func $op_fetch (param $at i32)
+    (local $name i32) (local $value i64)
+    (local.set $value (call $pop (local.get $at) (global.get $stack_empty)))
+    (if (i32.ne (call $tag (local.get $value)) (i32.const 3))
+      (then (call $fail (local.get $at) (global.get $fetch_name))))
+    (local.set $name (i32.wrap_i64 (local.get $value)))
+    (local.set $value
+      (i64.load (i32.add (global.get $globals) (i32.shl (local.get $name) (i32.const 3)))))
+    (if (i32.eqz (call $tag (local.get $value)))
+      (then
+        (call $put_error (local.get $at))
+        (call $put_error (global.get $name))
+        (i32.store8 (global.get $scratch) (i32.add (i32.const 97) (local.get $name)))
+        (call $error (global.get $scratch) (i32.const 1))
+        (call $put_error (global.get $not_found))
+        unreachable))
+    (call $push (local.get $at) (local.get $value)))
+
+  (func $op_read_char (param $at i32)
+    (call $push_int (local.get $at) (call $read)))
+
+  (func $op_write_int (param $at i32)
+    (call $put (call $format_int (call $pop_int (local.get $at)))))
+
+  (func $continuation (param $c i32) (param $shift i32) (result i32)
+    (i32.or (i32.const 0x80)
+      (i32.and (i32.shr_u (local.get $c) (local.get $shift)) (i32.const 0x3F))))
+
+  ;; Writes a code point as UTF-8
+  (func $op_write_char (param $at i32)
+    (local $c i32) (local $scratch i32)
+    (local.set $c (call $pop_int (local.get $at)))
+    (local.set $scratch (global.get $scratch))
+    ;; Negative values are too large when unsigned
+    (if (i32.or (i32.gt_u (local.get $c) (i32.const 0x10FFFF))
+                (i32.eq (i32.shr_u (local.get $c) (i32.const 11)) (i32.const 0x1B)))
+      (then
+        (call $fail_int (local.get $at) (global.get $char) (local.get $c) (global.get $as_char))))
+    (if (i32.lt_u (local.get $c) (i32.const 0x80))
+      (then
+        (i32.store8 (local.get $scratch) (local.get $c))
+        (call $write (local.get $scratch) (i32.const 1))
+        (return)))
+    (if (i32.lt_u (local.get $c) (i32.const 0x800))
+      (then
+        (i32.store8 (local.get $scratch)
+          (i32.or (i32.const 0xC0) (i32.shr_u (local.get $c) (i32.const 6))))
+        (i32.store8 offset=1 (local.get $scratch) (call $continuation (local.get $c) (i32.const 0)))
+        (call $write (local.get $scratch) (i32.const 2))
+        (return)))
+    (if (i32.lt_u (local.get $c) (i32.const 0x10000))
+      (then
+        (i32.store8 (local.get $scratch)
+          (i32.or (i32.const 0xE0) (i32.shr_u (local.get $c) (i32.const 12))))
+        (i32.store8 offset=1 (local.get $scratch) (call $continuation (local.get $c) (i32.const 6)))
+        (i32.store8 offset=2 (local.get $scratch) (call $continuation (local.get $c) (i32.const 0)))
+        (call $write (local.get $scratch) (i32.const 3))
+        (return)))
+    (i32.store8 (local.get $scratch)
+      (i32.or (i32.const 0xF0) (i32.shr_u (local.get $c) (i32.const 18))))
+    (i32.store8 offset=1 (local.get $scratch) (call $continuation (local.get $c) (i32.const 12)))
+    (i32.store8 offset=2 (local.get $scratch) (call $continuation (local.get $c) (i32.const 6)))
+    (i32.store8 offset=3 (local.get $scratch) (call $continuation (local.get $c) (i32.const 0)))
+    (call $write (local.get $scratch) (i32.const 4)))
+"#;
+
+/// Compiles a program to a WebAssembly module. `source` and `filename` are
+/// used to locate runtime errors.
+pub fn compile(program: &[Spanned<FalseInstruction>], source: &str, filename: &str) -> Vec<u8> {
+    wat::parse_str(text(program, source, filename)).expect("Generated module is invalid")
+}
+
+/// Compiles a program to a WebAssembly module in the text format.
+pub fn text(program: &[Spanned<FalseInstruction>], source: &str, filename: &str) -> String {
+    let mut compiler = Compiler {
+        lines: LineIndex::new(source),
+        filename,
+        data: Vec::new(),
+        strings: HashMap::new(),
+        lambdas: Vec::new(),
+        loops: 0,
+    };
+    let constants: Vec<(&str, u32)> = MESSAGES
+        .iter()
+        .chain(&TYPE_NAMES)
+        .map(|(name, text)| (*name, compiler.string(text.as_bytes())))
+        .collect();
+    let main = compiler.block(program, 2);
+
+    let globals = compiler.data.len().next_multiple_of(8);
+    let scratch = globals + 26 * 8;
+    let stack = scratch + 32;
+    let pages = stack / 65536 + 1;
+
+    let mut out = String::new();
+    writeln!(
+        out,
+        ";; Generated by falsy from {}",
+        filename.replace('\n', " ")
+    )
+    .unwrap();
+    out.push_str("(module\n  (type $lambda (func))\n");
+    out.push_str("  (import \"env\" \"read\" (func $read (result i32)))\n");
+    out.push_str("  (import \"env\" \"write\" (func $write (param i32 i32)))\n");
+    out.push_str("  (import \"env\" \"error\" (func $error (param i32 i32)))\n");
+    writeln!(out, "  (memory (export \"memory\") {pages})").unwrap();
+    writeln!(out, "  (table {} funcref)", compiler.lambdas.len()).unwrap();
+    if !compiler.lambdas.is_empty() {
+        out.push_str("  (elem (i32.const 0) func");
+        for i in 0..compiler.lambdas.len() {
+            write!(out, " $lambda_{i}").unwrap();
+        }
+        out.push_str(")\n");
+    }
+    out.push('\n');
+    writeln!(out, "  (global $globals i32 (i32.const {globals}))").unwrap();
+    writeln!(out, "  (global $scratch i32 (i32.const {scratch}))").unwrap();
+    writeln!(out, "  (global $stack i32 (i32.const {stack}))").unwrap();
+    writeln!(out, "  (global $sp (mut i32) (i32.const {stack}))").unwrap();
+    for (name, address) in constants {
+        writeln!(out, "  (global ${name} i32 (i32.const {address}))").unwrap();
+    }
+    out.push_str(RUNTIME);
+    for (i, body) in compiler.lambdas.iter().enumerate() {
+        writeln!(out, "\n  (func $lambda_{i} (type $lambda)\n{body}    )").unwrap();
+    }
+    writeln!(out, "\n  (func (export \"run\")\n{main}    )").unwrap();
+    writeln!(
+        out,
+        "\n  (data (i32.const 0) {}))",
+        string_literal(&compiler.data)
+    )
+    .unwrap();
+    out
+}
+
+struct Compiler<'s> {
+    lines: LineIndex<'s>,
+    filename: &'s str,
+    /// Contents of memory from address 0.
+    data: Vec<u8>,
+    /// Addresses of the strings in `data`.
+    strings: HashMap<Vec<u8>, u32>,
+    /// Bodies of the functions for each lambda, by table index.
+    lambdas: Vec<String>,
+    /// Loops so far, for unique labels.
+    loops: usize,
+}
+
+impl Compiler<'_> {
+    /// Address of a string in memory, stored after its length.
+    fn string(&mut self, bytes: &[u8]) -> u32 {
+        if let Some(address) = self.strings.get(bytes) {
+            return *address;
+        }
+        let address = self.data.len() as u32;
+        self.data.extend((bytes.len() as u32).to_le_bytes());
+        self.data.extend(bytes);
+        self.strings.insert(bytes.to_vec(), address);
+        address
+    }
+
+    /// Address of the `file:line:column: ` prefix for errors at `offset`.
+    fn site(&mut self, offset: usize) -> u32 {
+        let (line, column) = self.lines.location(offset);
+        let prefix = format!("{}:{line}:{column}: ", self.filename);
+        self.string(prefix.as_bytes())
+    }
+
+    fn block(&mut self, instructions: &[Spanned<FalseInstruction>], indent: usize) -> String {
+        let mut out = String::new();
+        for spanned in instructions {
+            self.instruction(spanned, indent, &mut out);
+        }
+        out
+    }
+
+    fn instruction(
+        &mut self,
+        spanned: &Spanned<FalseInstruction>,
+        indent: usize,
+        out: &mut String,
+    ) {
+        use FalseInstruction::*;
+
+        let pad = "  ".repeat(indent);
+        let at = format!("(i32.const {})", self.site(spanned.span().start));
+        let op = |name: &str| format!("(call $op_{name} {at})");
+        let statement = match spanned.instruction() {
+            Name(c) => {
+                let value = NAME << 32 | u64::from(*c as u8 - b'a');
+                format!("(call $push {at} (i64.const {value:#x}))")
+            }
+            PushInt(v) => format!("(call $push_int {at} (i32.const {v}))"),
+            PushChar(c) => format!("(call $push_int {at} (i32.const {c}))"),
+            Dup => op("dup"),
+            Drop => "(call $op_drop)".to_string(),
+            Swap => op("swap"),
+            Rot => op("rot"),
+            Pick => op("pick"),
+            Add => op("add"),
+            Sub => op("sub"),
+            Mul => op("mul"),
+            Div => op("div"),
+            Neg => op("neg"),
+            BitAnd => op("and"),
+            BitOr => op("or"),
+            BitNot => op("not"),
+            Gt => op("gt"),
+            Eq => op("eq"),
+            Lambda(body) => {
+                // Reserve the index first, so nested lambdas come after it
+                let index = self.lambdas.len();
+                self.lambdas.push(String::new());
+                self.lambdas[index] = self.block(body, 2);
+                let value = LAMBDA << 32 | index as u64;
+                format!("(call $push {at} (i64.const {value:#x}))")
+            }
+            Execute => op("execute"),
+            ConditionalExecute(body) => format!(
+                "(if (call $pop_int {at})\n{pad}  (then\n{}{pad}  ))",
+                self.block(body, indent + 2)
+            ),
+            WhileLoop(condition, body) => {
+                let n = self.loops;
+                self.loops += 1;
+                let inner = "  ".repeat(indent + 2);
+                format!(
+                    "(block $end_{n}\n{pad}  (loop $loop_{n}\n{}{inner}(br_if $end_{n} (i32.eqz (call $pop_int {at})))\n{}{inner}(br $loop_{n})))",
+                    self.block(condition, indent + 2),
+                    self.block(body, indent + 2)
+                )
+            }
+            Store => op("store"),
+            Fetch => op("fetch"),
+            ReadChar => op("read_char"),
+            WriteChar => op("write_char"),
+            WriteStr(s) => format!("(call $put (i32.const {}))", self.string(s.as_bytes())),
+            WriteInt => op("write_int"),
+            // Output is unbuffered
+            Flush => "nop".to_string(),
+        };
+        writeln!(out, "{pad}{statement}").unwrap();
+    }
+}
+
+fn string_literal(bytes: &[u8]) -> String {
+    let mut literal = String::from("\"");
+    for &byte in bytes {
+        match byte {
+            b' '..=b'~' if byte != b'"' && byte != b'\\' => literal.push(byte as char),
+            _ => write!(literal, "\\{byte:02x}").unwrap(),
+        }
+    }
+    literal.push('"');
+    literal
+}
@@ -0,0 +1,492 @@
+//! A tiny x86-64 assembler.
+//!
+//! Only the instruction forms the code generator needs are supported. Each
+//! can be encoded to machine code or printed as GNU assembler source in
+//! Intel syntax, so the two outputs always describe the same program.
+//! Memory operands always use a 32-bit displacement, and branches a 32-bit
+//! offset, so every instruction's length is known before layout.
+
+use std::fmt::Write;
+
+/// Registers in encoding order; not all of them are used.
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum Reg {
+    Rax,
+    Rcx,
+    Rdx,
+    Rbx,
+    Rsp,
+    Rbp,
+    Rsi,
+    Rdi,
+    R8,
+    R9,
+    R10,
+    R11,
+    R12,
+    R13,
+    R14,
+    R15,
+}
+
+impl Reg {
+    fn code(self) -> u8 {
+        self as u8
+    }
+
+    fn name64(self) -> &'static str {
+        [
+            "rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi", "r8", "r9", "r10", "r11",
+            "r12", "r13", "r14", "r15",
+        ][self as usize]
+    }
+
+    fn name32(self) -> &'static str {
+        [
+            "eax", "ecx", "edx", "ebx", "esp", "ebp", "esi", "edi", "r8d", "r9d", "r10d", "r11d",
+            "r12d", "r13d", "r14d", "r15d",
+        ][self as usize]
+    }
+
+    fn name8(self) -> &'static str {
+        [
+            "al", "cl", "dl", "bl", "spl", "bpl", "sil", "dil", "r8b", "r9b", "r10b", "r11b",
+            "r12b", "r13b", "r14b", "r15b",
+        ][self as usize]
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum Width {
+    W32,
+    W64,
+}
+
+impl Width {
+    fn name(self, reg: Reg) -> &'static str {
+        match self {
+            Self::W32 => reg.name32(),
+            Self::W64 => reg.name64(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum Alu {
+    Add,
+    Or,
+    And,
+    Sub,
+    Cmp,
+}
+
+impl Alu {
+    /// The `/digit` of the immediate form; the register form's opcode is
+    /// derived from it.
+    fn digit(self) -> u8 {
+        match self {
+            Self::Add => 0,
+            Self::Or => 1,
+            Self::And => 4,
+            Self::Sub => 5,
+            Self::Cmp => 7,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Add => "add",
+            Self::Or => "or",
+            Self::And => "and",
+            Self::Sub => "sub",
+            Self::Cmp => "cmp",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum Cond {
+    /// Unsigned `>=`
+    Ae,
+    E,
+    Ne,
+    L,
+    Ge,
+    Le,
+    G,
+}
+
+impl Cond {
+    fn code(self) -> u8 {
+        match self {
+            Self::Ae => 0x3,
+            Self::E => 0x4,
+            Self::Ne => 0x5,
+            Self::L => 0xC,
+            Self::Ge => 0xD,
+            Self::Le => 0xE,
+            Self::G => 0xF,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Ae => "ae",
+            Self::E => "e",
+            Self::Ne => "ne",
+            Self::L => "l",
+            Self::Ge => "ge",
+            Self::Le => "le",
+            Self::G => "g",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(super) struct Label(usize);
+
+/// Something `lea` can take the address of.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum Target {
+    Code(Label),
+    /// Offset into the initialised data.
+    Data(u32),
+    /// Offset into the zeroed data after it.
+    Bss(u32),
+    /// The table of instruction locations, placed after the rest of the
+    /// data once it is complete.
+    Sites,
+}
+
+#[derive(Clone, Debug)]
+pub(super) enum Inst {
+    Bind(Label),
+    MovRR(Width, Reg, Reg),
+    /// `mov r32, imm32`, which clears the upper half.
+    MovRI32(Reg, u32),
+    MovRI64(Reg, u64),
+    /// `mov r64, qword [base + disp]`
+    Load(Reg, Reg, i32),
+    /// `mov qword [base + disp], r64`
+    Store(Reg, i32, Reg),
+    /// `movzx r32, byte [base + disp]`
+    LoadByte(Reg, Reg, i32),
+    /// `mov byte [base + disp], r8`
+    StoreByte(Reg, i32, Reg),
+    Lea(Reg, Target),
+    AluRR(Alu, Width, Reg, Reg),
+    AluRI(Alu, Width, Reg, i32),
+    Imul32(Reg, Reg),
+    Cdq,
+    Idiv32(Reg),
+    Div64(Reg),
+    Neg32(Reg),
+    Not32(Reg),
+    Shl64(Reg, u8),
+    Shr64(Reg, u8),
+    /// `setcc r8` followed by `movzx r32, r8`
+    Set(Cond, Reg),
+    Test(Width, Reg, Reg),
+    Jmp(Label),
+    Jcc(Cond, Label),
+    Call(Label),
+    JmpReg(Reg),
+    Ret,
+    Push(Reg),
+    Pop(Reg),
+    Syscall,
+}
+
+/// A branch or address to patch once everything is laid out.
+pub(super) struct Fixup {
+    /// Offset of the 32-bit field in the code.
+    pub(super) at: usize,
+    pub(super) target: Target,
+}
+
+#[derive(Default)]
+pub(super) struct Assembler {
+    insts: Vec<Inst>,
+    labels: Vec<String>,
+}
+
+impl Assembler {
+    pub(super) fn label(&mut self, name: impl Into<String>) -> Label {
+        self.labels.push(name.into());
+        Label(self.labels.len() - 1)
+    }
+
+    /// A label with a generated name, for branches within generated code.
+    pub(super) fn local(&mut self) -> Label {
+        let id = self.labels.len();
+        self.label(format!(".L{id}"))
+    }
+
+    pub(super) fn emit(&mut self, inst: Inst) {
+        self.insts.push(inst);
+    }
+
+    pub(super) fn bind(&mut self, label: Label) {
+        self.emit(Inst::Bind(label));
+    }
+
+    /// Machine code, the offset of each label in it, and the fields that
+    /// still point at data.
+    pub(super) fn encode(&self) -> (Vec<u8>, Vec<Fixup>) {
+        let mut encoder = Encoder::default();
+        let mut offsets = vec![None; self.labels.len()];
+        for inst in &self.insts {
+            if let Inst::Bind(label) = inst {
+                offsets[label.0] = Some(encoder.code.len());
+            }
+            encoder.inst(inst);
+        }
+
+        // Patch branches within the code; data is left to the linker
+        let mut data_fixups = Vec::new();
+        for fixup in encoder.fixups {
+            match fixup.target {
+                Target::Code(label) => {
+                    let target = offsets[label.0].expect("Unbound label");
+                    let relative = target as i64 - (fixup.at as i64 + 4);
+                    encoder.code[fixup.at..fixup.at + 4]
+                        .copy_from_slice(&(relative as i32).to_le_bytes());
+                }
+                _ => data_fixups.push(fixup),
+            }
+        }
+        (encoder.code, data_fixups)
+    }
+
+    /// GNU assembler source for the code, in Intel syntax.
+    pub(super) fn text(&self) -> String {
+        let mut out = String::new();
+        for inst in &self.insts {
+            self.write_inst(&mut out, inst);
+        }
+        out
+    }
+
+    fn write_inst(&self, out: &mut String, inst: &Inst) {
+        use Inst::*;
+
+        let label = |l: &Label| &self.labels[l.0];
+        let mem =
+            |base: Reg, disp: i32| format!("[{} {:+}]", base.name64(), disp).replace("+-", "- ");
+        let target = |t: &Target| match t {
+            Target::Code(l) => label(l).to_string(),
+            Target::Data(offset) => format!("data + {offset}"),
+            Target::Bss(offset) => format!("bss + {offset}"),
+            Target::Sites => "sites".to_string(),
+        };
+        let line = match inst {
+            Bind(l) => {
+                writeln!(out, "{}:", label(l)).unwrap();
+                return;
+            }
+            MovRR(w, dst, src) => format!("mov {}, {}", w.name(*dst), w.name(*src)),
+            MovRI32(dst, imm) => format!("mov {}, {imm:#x}", dst.name32()),
+            MovRI64(dst, imm) => format!("movabs {}, {imm:#x}", dst.name64()),
+            Load(dst, base, disp) => {
+                format!("mov {}, qword ptr {}", dst.name64(), mem(*base, *disp))
+            }
+            Store(base, disp, src) => {
+                format!("mov qword ptr {}, {}", mem(*base, *disp), src.name64())
+            }
+            LoadByte(dst, base, disp) => {
+                format!("movzx {}, byte ptr {}", dst.name32(), mem(*base, *disp))
+            }
+            StoreByte(base, disp, src) => {
+                format!("mov byte ptr {}, {}", mem(*base, *disp), src.name8())
+            }
+            Lea(dst, t) => format!("lea {}, [rip + {}]", dst.name64(), target(t)),
+            AluRR(op, w, dst, src) => format!("{} {}, {}", op.name(), w.name(*dst), w.name(*src)),
+            AluRI(op, w, dst, imm) => format!("{} {}, {imm}", op.name(), w.name(*dst)),
+            Imul32(dst, src) => format!("imul {}, {}", dst.name32(), src.name32()),
+            Cdq => "cdq".to_string(),
+            Idiv32(r) => format!("idiv {}", r.name32()),
+            Div64(r) => format!("div {}", r.name64()),
+            Neg32(r) => format!("neg {}", r.name32()),
+            Not32(r) => format!("not {}", r.name32()),
+            Shl64(r, n) => format!("shl {}, {n}", r.name64()),
+            Shr64(r, n) => format!("shr {}, {n}", r.name64()),
+            Set(cond, r) => format!(
+                "set{} {}\n    movzx {}, {}",
+                cond.name(),
+                r.name8(),
+                r.name32(),
+                r.name8()
+            ),
+            Test(w, a, b) => format!("test {}, {}", w.name(*a), w.name(*b)),
+            Jmp(l) => format!("jmp {}", label(l)),
+            Jcc(cond, l) => format!("j{} {}", cond.name(), label(l)),
+            Call(l) => format!("call {}", label(l)),
+            JmpReg(r) => format!("jmp {}", r.name64()),
+            Ret => "ret".to_string(),
+            Push(r) => format!("push {}", r.name64()),
+            Pop(r) => format!("pop {}", r.name64()),
+            Syscall => "syscall".to_string(),
+        };
+        writeln!(out, "    {line}").unwrap();
+    }
+}
+
+#[derive(Default)]
+struct Encoder {
+    code: Vec<u8>,
+    fixups: Vec<Fixup>,
+}
+
+impl Encoder {
+    /// REX prefix, left out when it would be empty unless `force`d (for
+    /// byte registers past `bl`).
+    fn rex(&mut self, w: bool, reg: u8, rm: u8, force: bool) {
+        let rex = 0x40 | (u8::from(w) << 3) | ((reg >> 3) << 2) | (rm >> 3);
+        if rex != 0x40 || force {
+            self.code.push(rex);
+        }
+    }
+
+    fn modrm_reg(&mut self, reg: u8, rm: u8) {
+        self.code.push(0xC0 | ((reg & 7) << 3) | (rm & 7));
+    }
+
+    /// `[base + disp32]`, which needs a SIB byte for `rsp` and `r12`.
+    fn modrm_mem(&mut self, reg: u8, base: u8, disp: i32) {
+        self.code.push(0x80 | ((reg & 7) << 3) | (base & 7));
+        if base & 7 == 4 {
+            self.code.push(0x24);
+        }
+        self.code.extend(disp.to_le_bytes());
+    }
+
+    /// A 32-bit field to patch later.
+    fn fixup(&mut self, target: Target) {
+        self.fixups.push(Fixup {
+            at: self.code.len(),
+            target,
+        });
+        self.code.extend([0; 4]);
+    }
+
+    /// An instruction of the form `op r/m, reg` (or `op reg, r/m`, depending
+    /// on the opcode) with both operands in registers.
+    fn rr(&mut self, w: Width, opcode: &[u8], reg: Reg, rm: Reg) {
+        self.rex(w == Width::W64, reg.code(), rm.code(), false);
+        self.code.extend(opcode);
+        self.modrm_reg(reg.code(), rm.code());
+    }
+
+    fn inst(&mut self, inst: &Inst) {
+        use Inst::*;
+        use Width::*;
+
+        match *inst {
+            Bind(_) => {}
+            MovRR(w, dst, src) => self.rr(w, &[0x89], src, dst),
+            MovRI32(dst, imm) => {
+                self.rex(false, 0, dst.code(), false);
+                self.code.push(0xB8 + (dst.code() & 7));
+                self.code.extend(imm.to_le_bytes());
+            }
+            MovRI64(dst, imm) => {
+                self.rex(true, 0, dst.code(), false);
+                self.code.push(0xB8 + (dst.code() & 7));
+                self.code.extend(imm.to_le_bytes());
+            }
+            Load(dst, base, disp) => {
+                self.rex(true, dst.code(), base.code(), false);
+                self.code.push(0x8B);
+                self.modrm_mem(dst.code(), base.code(), disp);
+            }
+            Store(base, disp, src) => {
+                self.rex(true, src.code(), base.code(), false);
+                self.code.push(0x89);
+                self.modrm_mem(src.code(), base.code(), disp);
+            }
+            LoadByte(dst, base, disp) => {
+                self.rex(false, dst.code(), base.code(), false);
+                self.code.extend([0x0F, 0xB6]);
+                self.modrm_mem(dst.code(), base.code(), disp);
+            }
+            StoreByte(base, disp, src) => {
+                self.rex(false, src.code(), base.code(), src.code() >= 4);
+                self.code.push(0x88);
+                self.modrm_mem(src.code(), base.code(), disp);
+            }
+            Lea(dst, target) => {
+                self.rex(true, dst.code(), 0, false);
+                self.code.push(0x8D);
+                self.code.push(((dst.code() & 7) << 3) | 5);
+                self.fixup(target);
+            }
+            AluRR(op, w, dst, src) => self.rr(w, &[op.digit() * 8 + 1], src, dst),
+            AluRI(op, w, dst, imm) => {
+                self.rex(w == W64, 0, dst.code(), false);
+                self.code.push(0x81);
+                self.modrm_reg(op.digit(), dst.code());
+                self.code.extend(imm.to_le_bytes());
+            }
+            Imul32(dst, src) => self.rr(W32, &[0x0F, 0xAF], dst, src),
+            Cdq => self.code.push(0x99),
+            Idiv32(r) => self.unary(W32, 7, r),
+            Div64(r) => self.unary(W64, 6, r),
+            Neg32(r) => self.unary(W32, 3, r),
+            Not32(r) => self.unary(W32, 2, r),
+            Shl64(r, n) => self.shift(4, r, n),
+            Shr64(r, n) => self.shift(5, r, n),
+            Set(cond, r) => {
+                self.rex(false, 0, r.code(), r.code() >= 4);
+                self.code.extend([0x0F, 0x90 + cond.code()]);
+                self.modrm_reg(0, r.code());
+                self.rex(false, r.code(), r.code(), r.code() >= 4);
+                self.code.extend([0x0F, 0xB6]);
+                self.modrm_reg(r.code(), r.code());
+            }
+            Test(w, a, b) => self.rr(w, &[0x85], b, a),
+            Jmp(label) => {
+                self.code.push(0xE9);
+                self.fixup(Target::Code(label));
+            }
+            Jcc(cond, label) => {
+                self.code.extend([0x0F, 0x80 + cond.code()]);
+                self.fixup(Target::Code(label));
+            }
+            Call(label) => {
+                self.code.push(0xE8);
+                self.fixup(Target::Code(label));
+            }
+            JmpReg(r) => {
+                self.rex(false, 0, r.code(), false);
+                self.code.push(0xFF);
+                self.modrm_reg(4, r.code());
+            }
+            Ret => self.code.push(0xC3),
+            Push(r) => {
+                self.rex(false, 0, r.code(), false);
+                self.code.push(0x50 + (r.code() & 7));
+            }
+            Pop(r) => {
+                self.rex(false, 0, r.code(), false);
+                self.code.push(0x58 + (r.code() & 7));
+            }
+            Syscall => self.code.extend([0x0F, 0x05]),
+        }
+    }
+
+    /// `F7 /digit`
+    fn unary(&mut self, w: Width, digit: u8, r: Reg) {
+        self.rex(w == Width::W64, 0, r.code(), false);
+        self.code.push(0xF7);
+        self.modrm_reg(digit, r.code());
+    }
+
+    /// `REX.W C1 /digit ib`
+    fn shift(&mut self, digit: u8, r: Reg, n: u8) {
+        self.rex(true, 0, r.code(), false);
+        self.code.push(0xC1);
+        self.modrm_reg(digit, r.code());
+        self.code.push(n);
+    }
+}
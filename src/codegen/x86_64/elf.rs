@@ -0,0 +1,86 @@
+//! Static ELF executables for x86-64 Linux.
+//!
+//! The file has two segments and no sections: the headers and code, mapped
+//! read-only and executable, and the data, mapped writable with the zeroed
+//! data after it.
+
+use super::asm::{Fixup, Target};
+
+const BASE: u64 = 0x40_0000;
+const PAGE: u64 = 0x1000;
+/// Data is mapped this far above the file offset it is stored at.
+const DATA_BASE: u64 = 0x1000_0000;
+const HEADERS: usize = 64 + 2 * 56;
+
+/// Links `code`, which starts with the entry point, against `data` and
+/// `bss_size` zeroed bytes after it. The site table is at `sites` in `data`.
+pub(super) fn link(
+    mut code: Vec<u8>,
+    fixups: &[Fixup],
+    data: &[u8],
+    sites: u32,
+    bss_size: u64,
+) -> Vec<u8> {
+    let code_vaddr = BASE + HEADERS as u64;
+    let data_offset = (HEADERS + code.len()).next_multiple_of(PAGE as usize);
+    let data_vaddr = DATA_BASE + data_offset as u64;
+    let bss_vaddr = data_vaddr + (data.len() as u64).next_multiple_of(8);
+
+    for fixup in fixups {
+        let target = match fixup.target {
+            Target::Data(offset) => data_vaddr + u64::from(offset),
+            Target::Bss(offset) => bss_vaddr + u64::from(offset),
+            Target::Sites => data_vaddr + u64::from(sites),
+            Target::Code(_) => unreachable!("Code fixups are patched by the assembler"),
+        };
+        let next = code_vaddr + fixup.at as u64 + 4;
+        let relative = i32::try_from(target as i64 - next as i64).expect("Program too large");
+        code[fixup.at..fixup.at + 4].copy_from_slice(&relative.to_le_bytes());
+    }
+
+    let mut elf = Vec::with_capacity(data_offset + data.len());
+    // ELF header
+    elf.extend(b"\x7fELF");
+    elf.extend([2, 1, 1, 0]); // 64-bit, little-endian, version 1, System V
+    elf.extend([0; 8]);
+    elf.extend(2u16.to_le_bytes()); // executable
+    elf.extend(0x3Eu16.to_le_bytes()); // x86-64
+    elf.extend(1u32.to_le_bytes());
+    elf.extend(code_vaddr.to_le_bytes()); // entry point
+    elf.extend(64u64.to_le_bytes()); // program headers
+    elf.extend(0u64.to_le_bytes()); // section headers
+    elf.extend(0u32.to_le_bytes());
+    elf.extend(64u16.to_le_bytes());
+    elf.extend(56u16.to_le_bytes());
+    elf.extend(2u16.to_le_bytes());
+    elf.extend([0; 6]);
+
+    let code_size = (HEADERS + code.len()) as u64;
+    program_header(&mut elf, 0b101, 0, BASE, code_size, code_size);
+    let data_size = data.len() as u64;
+    let memory_size = bss_vaddr - data_vaddr + bss_size;
+    program_header(
+        &mut elf,
+        0b110,
+        data_offset as u64,
+        data_vaddr,
+        data_size,
+        memory_size,
+    );
+
+    elf.extend(code);
+    elf.resize(data_offset, 0);
+    elf.extend(data);
+    elf
+}
+
+fn program_header(elf: &mut Vec<u8>, flags: u32, offset: u64, vaddr: u64, file: u64, memory: u64) {
+    elf.extend(1u32.to_le_bytes()); // loadable
+    elf.extend(flags.to_le_bytes());
+    elf.extend(offset.to_le_bytes());
+    elf.extend(vaddr.to_le_bytes());
+    elf.extend(vaddr.to_le_bytes());
+    elf.extend(file.to_le_bytes());
+    elf.extend(memory.to_le_bytes());
+    elf.extend(PAGE.to_le_bytes());
+}
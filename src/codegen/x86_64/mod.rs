@@ -0,0 +1,800 @@
+//! Compiler to x86-64 Linux executables.
+//!
+//! Programs are assembled and linked in-process into a static ELF file, so
+//! no external toolchain is needed; [`assembly`] gives the same program as
+//! GNU assembler source for reading or for building with `as` and `ld`.
+//!
+//! A small runtime, also generated here, talks to the kernel through
+//! syscalls only. Values are 64-bit words holding a tag in the upper half
+//! and the value in the lower half. Generated code keeps the top of the
+//! value stack in `r12`, its bottom in `r13` and its end in `r14`, and loads
+//! the location of each fallible instruction into `ebx` so the runtime can
+//! report errors like the interpreter.
+
+mod asm;
+mod elf;
+
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use crate::ast::{FalseInstruction, Spanned};
+use crate::lines::LineIndex;
+use asm::{Alu, Assembler, Cond, Inst, Label, Reg, Target, Width};
+
+/// Tags in the upper half of a value. Zeroed globals are unset.
+const UNSET: u64 = 0;
+const INTEGER: u64 = 1;
+const LAMBDA: u64 = 2;
+const NAME: u64 = 3;
+
+/// Layout of the zeroed data.
+const GLOBALS: u32 = 0;
+const OUTPUT_LENGTH: u32 = GLOBALS + 26 * 8;
+const SCRATCH: u32 = OUTPUT_LENGTH + 8;
+const SCRATCH_SIZE: u32 = 32;
+const OUTPUT: u32 = SCRATCH + SCRATCH_SIZE;
+const OUTPUT_SIZE: u32 = 4096;
+const STACK: u32 = OUTPUT + OUTPUT_SIZE;
+/// Values the stack can hold before the program fails.
+const STACK_SLOTS: u32 = 1 << 20;
+const BSS_SIZE: u32 = STACK + STACK_SLOTS * 8;
+
+/// Compiles a program to a static ELF executable. `source` and `filename`
+/// are used to locate runtime errors.
+pub fn compile(program: &[Spanned<FalseInstruction>], source: &str, filename: &str) -> Vec<u8> {
+    let compiler = Compiler::compile(program, source, filename);
+    let (code, fixups) = compiler.asm.encode();
+    let (data, sites) = compiler.data();
+    elf::link(code, &fixups, &data, sites, u64::from(BSS_SIZE))
+}
+
+/// Compiles a program to GNU assembler source in Intel syntax.
+pub fn assembly(program: &[Spanned<FalseInstruction>], source: &str, filename: &str) -> String {
+    let compiler = Compiler::compile(program, source, filename);
+    let (data, sites) = compiler.data();
+
+    let mut out = String::new();
+    writeln!(
+        out,
+        "# Generated by falsy from {}",
+        filename.replace('\n', " ")
+    )
+    .unwrap();
+    out.push_str(".intel_syntax noprefix\n.globl _start\n\n.text\n");
+    out.push_str(&compiler.asm.text());
+    out.push_str("\n.data\ndata:\n");
+    for chunk in data[..sites as usize].chunks(16) {
+        let bytes: Vec<String> = chunk.iter().map(|b| b.to_string()).collect();
+        writeln!(out, "    .byte {}", bytes.join(", ")).unwrap();
+    }
+    out.push_str("    .balign 8\nsites:\n");
+    for entry in data[sites as usize..].chunks(8) {
+        writeln!(
+            out,
+            "    .quad {:#x}",
+            u64::from_le_bytes(entry.try_into().unwrap())
+        )
+        .unwrap();
+    }
+    writeln!(out, "\n.bss\nbss:\n    .zero {BSS_SIZE}").unwrap();
+    out
+}
+
+struct Compiler<'a> {
+    lines: LineIndex<'a>,
+    asm: Assembler,
+    start: Label,
+    /// Runtime routines by name, created when first referenced.
+    routines: HashMap<&'static str, Label>,
+    /// Lambdas still to be compiled.
+    lambdas: Vec<(Label, &'a [Spanned<FalseInstruction>])>,
+    /// String constants, followed by the prefixes of error messages.
+    strings: Vec<u8>,
+    interned: HashMap<Vec<u8>, u32>,
+    /// Offset and length in `strings` of the `file:line:column: ` prefix
+    /// for each site.
+    sites: Vec<(u32, u32)>,
+    site_ids: HashMap<(usize, usize), u32>,
+    filename: &'a str,
+}
+
+impl<'a> Compiler<'a> {
+    fn compile(
+        program: &'a [Spanned<FalseInstruction>],
+        source: &'a str,
+        filename: &'a str,
+    ) -> Self {
+        use Inst::*;
+        use Reg::*;
+
+        let mut asm = Assembler::default();
+        let start = asm.label("_start");
+        let mut compiler = Compiler {
+            lines: LineIndex::new(source),
+            asm,
+            start,
+            routines: HashMap::new(),
+            lambdas: Vec::new(),
+            strings: Vec::new(),
+            interned: HashMap::new(),
+            sites: Vec::new(),
+            site_ids: HashMap::new(),
+            filename,
+        };
+
+        compiler.asm.bind(start);
+        compiler.emit(Lea(R13, Target::Bss(STACK)));
+        compiler.emit(MovRR(Width::W64, R12, R13));
+        compiler.emit(Lea(R14, Target::Bss(BSS_SIZE)));
+        compiler.block(program);
+        compiler.call("flush");
+        compiler.emit(MovRI32(Rax, 60));
+        compiler.emit(MovRI32(Rdi, 0));
+        compiler.emit(Syscall);
+
+        while let Some((label, body)) = compiler.lambdas.pop() {
+            compiler.asm.bind(label);
+            compiler.block(body);
+            compiler.emit(Ret);
+        }
+        compiler.runtime();
+        compiler
+    }
+
+    /// The initialised data, and the offset of the site table in it.
+    fn data(&self) -> (Vec<u8>, u32) {
+        let mut data = self.strings.clone();
+        data.resize(data.len().next_multiple_of(8), 0);
+        let sites = data.len() as u32;
+        for &(offset, length) in &self.sites {
+            data.extend((u64::from(length) << 32 | u64::from(offset)).to_le_bytes());
+        }
+        (data, sites)
+    }
+
+    fn emit(&mut self, inst: Inst) {
+        self.asm.emit(inst);
+    }
+
+    fn routine(&mut self, name: &'static str) -> Label {
+        if let Some(label) = self.routines.get(name) {
+            return *label;
+        }
+        let label = self.asm.label(name);
+        self.routines.insert(name, label);
+        label
+    }
+
+    fn call(&mut self, name: &'static str) {
+        let label = self.routine(name);
+        self.emit(Inst::Call(label));
+    }
+
+    fn jump(&mut self, name: &'static str) {
+        let label = self.routine(name);
+        self.emit(Inst::Jmp(label));
+    }
+
+    fn jump_if(&mut self, cond: Cond, name: &'static str) {
+        let label = self.routine(name);
+        self.emit(Inst::Jcc(cond, label));
+    }
+
+    fn define(&mut self, name: &'static str) {
+        let label = self.routine(name);
+        self.asm.bind(label);
+    }
+
+    /// Offset and length of a string constant.
+    fn string(&mut self, bytes: &[u8]) -> (u32, u32) {
+        let offset = match self.interned.get(bytes) {
+            Some(offset) => *offset,
+            None => {
+                let offset = self.strings.len() as u32;
+                self.strings.extend(bytes);
+                self.interned.insert(bytes.to_vec(), offset);
+                offset
+            }
+        };
+        (offset, bytes.len() as u32)
+    }
+
+    /// Loads the location of the instruction at `offset` for error messages.
+    fn site(&mut self, offset: usize) {
+        let location = self.lines.location(offset);
+        let id = match self.site_ids.get(&location) {
+            Some(id) => *id,
+            None => {
+                let (line, column) = location;
+                let prefix = format!("{}:{line}:{column}: ", self.filename);
+                let string = self.string(prefix.as_bytes());
+                self.sites.push(string);
+                let id = self.sites.len() as u32 - 1;
+                self.site_ids.insert(location, id);
+                id
+            }
+        };
+        self.emit(Inst::MovRI32(Reg::Rbx, id));
+    }
+
+    fn block(&mut self, instructions: &'a [Spanned<FalseInstruction>]) {
+        for spanned in instructions {
+            self.instruction(spanned);
+        }
+    }
+
+    fn instruction(&mut self, spanned: &'a Spanned<FalseInstruction>) {
+        use FalseInstruction::*;
+        use Reg::*;
+
+        let routine = match spanned.instruction() {
+            Name(c) => {
+                self.site(spanned.span().start);
+                let index = u64::from(*c as u8 - b'a');
+                self.emit(Inst::MovRI64(Rax, NAME << 32 | index));
+                self.call("push_value");
+                return;
+            }
+            PushInt(v) => {
+                self.site(spanned.span().start);
+                self.emit(Inst::MovRI32(Rax, *v as u32));
+                self.call("push_int");
+                return;
+            }
+            PushChar(c) => {
+                self.site(spanned.span().start);
+                self.emit(Inst::MovRI32(Rax, u32::from(*c)));
+                self.call("push_int");
+                return;
+            }
+            Lambda(body) => {
+                self.site(spanned.span().start);
+                // Lambdas are pushed as their offset from the entry point
+                let label = self.asm.local();
+                self.lambdas.push((label, body));
+                self.emit(Inst::Lea(Rax, Target::Code(label)));
+                self.emit(Inst::Lea(Rcx, Target::Code(self.start)));
+                self.emit(Inst::AluRR(Alu::Sub, Width::W64, Rax, Rcx));
+                self.emit(Inst::MovRI64(Rcx, LAMBDA << 32));
+                self.emit(Inst::AluRR(Alu::Or, Width::W64, Rax, Rcx));
+                self.call("push_value");
+                return;
+            }
+            ConditionalExecute(body) => {
+                let end = self.asm.local();
+                self.site(spanned.span().start);
+                self.call("pop_int");
+                self.emit(Inst::Test(Width::W32, Rax, Rax));
+                self.emit(Inst::Jcc(Cond::E, end));
+                self.block(body);
+                self.asm.bind(end);
+                return;
+            }
+            WhileLoop(condition, body) => {
+                let (top, end) = (self.asm.local(), self.asm.local());
+                self.asm.bind(top);
+                self.block(condition);
+                self.site(spanned.span().start);
+                self.call("pop_int");
+                self.emit(Inst::Test(Width::W32, Rax, Rax));
+                self.emit(Inst::Jcc(Cond::E, end));
+                self.block(body);
+                self.emit(Inst::Jmp(top));
+                self.asm.bind(end);
+                return;
+            }
+            WriteStr(s) => {
+                let (offset, length) = self.string(s.as_bytes());
+                self.emit(Inst::Lea(Rsi, Target::Data(offset)));
+                self.emit(Inst::MovRI32(Rdx, length));
+                self.call("out_bytes");
+                return;
+            }
+            Drop => {
+                self.call("op_drop");
+                return;
+            }
+            ReadChar => {
+                self.site(spanned.span().start);
+                self.call("op_read_char");
+                return;
+            }
+            Flush => {
+                self.call("flush");
+                return;
+            }
+            Dup => "op_dup",
+            Swap => "op_swap",
+            Rot => "op_rot",
+            Pick => "op_pick",
+            Add => "op_add",
+            Sub => "op_sub",
+            Mul => "op_mul",
+            Div => "op_div",
+            Neg => "op_neg",
+            BitAnd => "op_and",
+            BitOr => "op_or",
+            BitNot => "op_not",
+            Gt => "op_gt",
+            Eq => "op_eq",
+            Execute => "op_execute",
+            Store => "op_store",
+            Fetch => "op_fetch",
+            WriteChar => "op_write_char",
+            WriteInt => "op_write_int",
+        };
+        self.site(spanned.span().start);
+        self.call(routine);
+    }
+
+    /// Pops the top value into `dst`, failing with `empty` if there is none.
+    fn pop(&mut self, dst: Reg, empty: &'static str) {
+        use Inst::*;
+        use Reg::*;
+
+        self.emit(AluRR(Alu::Cmp, Width::W64, R12, R13));
+        self.jump_if(Cond::E, empty);
+        self.emit(AluRI(Alu::Sub, Width::W64, R12, 8));
+        self.emit(Load(dst, R12, 0));
+    }
+
+    /// Puts the tag of the value in `src` into `ecx`.
+    fn tag(&mut self, src: Reg) {
+        self.emit(Inst::MovRR(Width::W64, Reg::Rcx, src));
+        self.emit(Inst::Shr64(Reg::Rcx, 32));
+    }
+
+    /// Writes a string constant to stderr.
+    fn write_error(&mut self, message: &str) {
+        let (offset, length) = self.string(message.as_bytes());
+        self.emit(Inst::Lea(Reg::Rsi, Target::Data(offset)));
+        self.emit(Inst::MovRI32(Reg::Rdx, length));
+        self.call("write_err");
+    }
+
+    /// An error routine that fails with a fixed message.
+    fn fail(&mut self, name: &'static str, message: &str) {
+        self.define(name);
+        self.call("fail_start");
+        self.write_error(message);
+        self.jump("fail_end");
+    }
+
+    /// An error routine that fails with `message` followed by the name of
+    /// the type whose tag is in `ecx`.
+    fn fail_type(&mut self, name: &'static str, message: &str) {
+        use Inst::*;
+        use Reg::*;
+
+        self.define(name);
+        self.emit(Push(Rcx));
+        self.call("fail_start");
+        self.write_error(message);
+        self.emit(Pop(Rcx));
+        self.call("write_type_name");
+        self.jump("fail_end");
+    }
+
+    /// An error routine that fails with the integer in `eax` between
+    /// `before` and `after`.
+    fn fail_int(&mut self, name: &'static str, before: &str, after: &str) {
+        use Inst::*;
+        use Reg::*;
+
+        self.define(name);
+        self.emit(Push(Rax));
+        self.call("fail_start");
+        self.write_error(before);
+        self.emit(Pop(Rax));
+        self.call("format_int");
+        self.call("write_err");
+        self.write_error(after);
+        self.jump("fail_end");
+    }
+
+    /// A routine popping two integers and pushing the result left in `eax`
+    /// by `body`, which starts with the lower one in `eax` and the top one
+    /// in `r8d`.
+    fn binary(&mut self, name: &'static str, body: impl FnOnce(&mut Self)) {
+        self.define(name);
+        self.call("pop_int");
+        self.emit(Inst::MovRR(Width::W32, Reg::R8, Reg::Rax));
+        self.call("pop_int");
+        body(self);
+        self.jump("push_int");
+    }
+
+    fn runtime(&mut self) {
+        use Alu::*;
+        use Cond::*;
+        use Inst::*;
+        use Reg::*;
+        use Width::*;
+
+        // Stack: values are in `rax`, and only `rax` and `rcx` are clobbered
+        self.define("push_value");
+        self.emit(AluRR(Cmp, W64, R12, R14));
+        self.jump_if(Ae, "err_overflow");
+        self.emit(Store(R12, 0, Rax));
+        self.emit(AluRI(Add, W64, R12, 8));
+        self.emit(Ret);
+
+        self.define("push_int");
+        self.emit(MovRR(W32, Rax, Rax));
+        self.emit(MovRI64(Rcx, INTEGER << 32));
+        self.emit(AluRR(Or, W64, Rax, Rcx));
+        self.jump("push_value");
+
+        self.define("pop_int");
+        self.pop(Rax, "err_empty");
+        self.tag(Rax);
+        self.emit(AluRI(Cmp, W32, Rcx, INTEGER as i32));
+        self.jump_if(Ne, "err_expected_integer");
+        self.emit(MovRR(W32, Rax, Rax));
+        self.emit(Ret);
+
+        // Stack manipulation
+        self.define("op_dup");
+        self.emit(AluRR(Cmp, W64, R12, R13));
+        self.jump_if(E, "err_empty");
+        self.emit(Load(Rax, R12, -8));
+        self.jump("push_value");
+
+        let done = self.asm.local();
+        self.define("op_drop");
+        self.emit(AluRR(Cmp, W64, R12, R13));
+        self.emit(Jcc(E, done));
+        self.emit(AluRI(Sub, W64, R12, 8));
+        self.asm.bind(done);
+        self.emit(Ret);
+
+        self.define("op_swap");
+        self.pop(Rax, "err_empty");
+        self.pop(Rcx, "err_one_value");
+        self.emit(Store(R12, 0, Rax));
+        self.emit(Store(R12, 8, Rcx));
+        self.emit(AluRI(Add, W64, R12, 16));
+        self.emit(Ret);
+
+        // Reverses the top three values, like the interpreter
+        self.define("op_rot");
+        self.pop(Rax, "err_empty");
+        self.pop(Rcx, "err_one_value");
+        self.pop(Rdx, "err_two_values");
+        self.emit(Store(R12, 0, Rax));
+        self.emit(Store(R12, 8, Rcx));
+        self.emit(Store(R12, 16, Rdx));
+        self.emit(AluRI(Add, W64, R12, 24));
+        self.emit(Ret);
+
+        self.define("op_pick");
+        self.pop(Rax, "err_empty");
+        self.tag(Rax);
+        self.emit(AluRI(Cmp, W32, Rcx, INTEGER as i32));
+        self.jump_if(Ne, "err_pick_index");
+        self.emit(AluRI(Cmp, W32, Rax, 0));
+        self.jump_if(L, "err_pick_range");
+        self.emit(MovRR(W32, Rax, Rax));
+        self.emit(MovRR(W64, Rcx, R12));
+        self.emit(AluRR(Sub, W64, Rcx, R13));
+        self.emit(Shr64(Rcx, 3));
+        self.emit(AluRR(Cmp, W64, Rax, Rcx));
+        self.jump_if(Ae, "err_pick_range");
+        self.emit(Shl64(Rax, 3));
+        self.emit(MovRR(W64, Rcx, R12));
+        self.emit(AluRR(Sub, W64, Rcx, Rax));
+        self.emit(Load(Rax, Rcx, -8));
+        self.jump("push_value");
+
+        // Arithmetic
+        self.binary("op_add", |c| c.emit(AluRR(Add, W32, Rax, R8)));
+        self.binary("op_sub", |c| c.emit(AluRR(Sub, W32, Rax, R8)));
+        self.binary("op_mul", |c| c.emit(Imul32(Rax, R8)));
+        self.binary("op_and", |c| c.emit(AluRR(And, W32, Rax, R8)));
+        self.binary("op_or", |c| c.emit(AluRR(Or, W32, Rax, R8)));
+        self.binary("op_gt", |c| {
+            c.emit(AluRR(Cmp, W32, Rax, R8));
+            c.emit(Set(G, Rax));
+            c.emit(Neg32(Rax));
+        });
+        self.binary("op_eq", |c| {
+            c.emit(AluRR(Cmp, W32, Rax, R8));
+            c.emit(Set(E, Rax));
+            c.emit(Neg32(Rax));
+        });
+        self.binary("op_div", |c| {
+            // `MIN / -1` overflows, and wraps to `MIN`
+            let done = c.asm.local();
+            c.emit(AluRI(Cmp, W32, R8, 0));
+            c.jump_if(E, "err_division_by_zero");
+            c.emit(AluRI(Cmp, W32, R8, -1));
+            let divide = c.asm.local();
+            c.emit(Jcc(Ne, divide));
+            c.emit(AluRI(Cmp, W32, Rax, i32::MIN));
+            c.emit(Jcc(E, done));
+            c.asm.bind(divide);
+            c.emit(Cdq);
+            c.emit(Idiv32(R8));
+            c.asm.bind(done);
+        });
+
+        self.define("op_neg");
+        self.call("pop_int");
+        self.emit(Neg32(Rax));
+        self.jump("push_int");
+
+        self.define("op_not");
+        self.call("pop_int");
+        self.emit(Not32(Rax));
+        self.jump("push_int");
+
+        // Lambdas and variables
+        self.define("op_execute");
+        self.pop(Rax, "err_empty");
+        self.tag(Rax);
+        self.emit(AluRI(Cmp, W32, Rcx, LAMBDA as i32));
+        self.jump_if(Ne, "err_execute");
+        self.emit(MovRR(W32, Rax, Rax));
+        self.emit(Lea(Rcx, Target::Code(self.start)));
+        self.emit(AluRR(Add, W64, Rax, Rcx));
+        self.emit(JmpReg(Rax));
+
+        self.define("op_store");
+        self.pop(Rax, "err_empty");
+        self.tag(Rax);
+        self.emit(AluRI(Cmp, W32, Rcx, NAME as i32));
+        self.jump_if(Ne, "err_store_name");
+        self.pop(Rdx, "err_one_value");
+        self.tag(Rdx);
+        self.emit(AluRI(Cmp, W32, Rcx, NAME as i32));
+        self.jump_if(E, "err_store_names");
+        self.emit(MovRR(W32, Rax, Rax));
+        self.emit(Shl64(Rax, 3));
+        self.emit(Lea(Rcx, Target::Bss(GLOBALS)));
+        self.emit(AluRR(Add, W64, Rcx, Rax));
+        self.emit(Store(Rcx, 0, Rdx));
+        self.emit(Ret);
+
+        self.define("op_fetch");
+        self.pop(Rax, "err_empty");
+        self.tag(Rax);
+        self.emit(AluRI(Cmp, W32, Rcx, NAME as i32));
+        self.jump_if(Ne, "err_fetch_name");
+        self.emit(MovRR(W32, Rax, Rax));
+        self.emit(MovRR(W32, R8, Rax));
+        self.emit(Shl64(Rax, 3));
+        self.emit(Lea(Rcx, Target::Bss(GLOBALS)));
+        self.emit(AluRR(Add, W64, Rcx, Rax));
+        self.emit(Load(Rax, Rcx, 0));
+        self.tag(Rax);
+        self.emit(AluRI(Cmp, W32, Rcx, UNSET as i32));
+        self.jump_if(E, "err_unset");
+        self.jump("push_value");
+
+        // Input and output
+        let eof = self.asm.local();
+        self.define("op_read_char");
+        self.call("flush");
+        self.emit(Lea(Rsi, Target::Bss(SCRATCH)));
+        self.emit(MovRI32(Rdx, 1));
+        self.emit(MovRI32(Rdi, 0));
+        self.emit(MovRI32(Rax, 0));
+        self.emit(Syscall);
+        self.emit(AluRI(Cmp, W64, Rax, 1));
+        self.emit(Jcc(Ne, eof));
+        self.emit(LoadByte(Rax, Rsi, 0));
+        self.jump("push_int");
+        self.asm.bind(eof);
+        self.emit(MovRI32(Rax, u32::MAX));
+        self.jump("push_int");
+
+        self.define("op_write_int");
+        self.call("pop_int");
+        self.call("format_int");
+        self.jump("out_bytes");
+
+        self.define("op_write_char");
+        self.call("pop_int");
+        self.emit(AluRI(Cmp, W32, Rax, 0));
+        self.jump_if(L, "err_char");
+        self.emit(AluRI(Cmp, W32, Rax, 0x10FFFF));
+        self.jump_if(G, "err_char");
+        let valid = self.asm.local();
+        self.emit(AluRI(Cmp, W32, Rax, 0xD800));
+        self.emit(Jcc(L, valid));
+        self.emit(AluRI(Cmp, W32, Rax, 0xDFFF));
+        self.jump_if(Le, "err_char");
+        self.asm.bind(valid);
+        // UTF-8: the lead byte, then six bits per continuation byte
+        self.emit(MovRR(W32, R8, Rax));
+        let mut next = self.asm.local();
+        for (limit, continuations, lead) in [(0x80, 0, 0), (0x800, 1, 0xC0), (0x10000, 2, 0xE0)] {
+            self.emit(AluRI(Cmp, W32, R8, limit));
+            self.emit(Jcc(Ge, next));
+            self.utf8(continuations, lead);
+            self.asm.bind(next);
+            next = self.asm.local();
+        }
+        self.utf8(3, 0xF0);
+        self.asm.bind(next);
+
+        // Buffered output: bytes are in `al`, or `rdx` bytes at `rsi`
+        self.define("out_byte");
+        self.emit(Lea(Rcx, Target::Bss(OUTPUT_LENGTH)));
+        self.emit(Load(Rdx, Rcx, 0));
+        self.emit(Lea(Rdi, Target::Bss(OUTPUT)));
+        self.emit(AluRR(Add, W64, Rdi, Rdx));
+        self.emit(StoreByte(Rdi, 0, Rax));
+        self.emit(AluRI(Add, W64, Rdx, 1));
+        self.emit(Store(Rcx, 0, Rdx));
+        self.emit(AluRI(Cmp, W64, Rdx, OUTPUT_SIZE as i32));
+        self.jump_if(E, "flush");
+        self.emit(Ret);
+
+        let done = self.asm.local();
+        self.define("out_bytes");
+        self.emit(Test(W64, Rdx, Rdx));
+        self.emit(Jcc(E, done));
+        self.emit(LoadByte(Rax, Rsi, 0));
+        self.emit(Push(Rsi));
+        self.emit(Push(Rdx));
+        self.call("out_byte");
+        self.emit(Pop(Rdx));
+        self.emit(Pop(Rsi));
+        self.emit(AluRI(Add, W64, Rsi, 1));
+        self.emit(AluRI(Sub, W64, Rdx, 1));
+        self.jump("out_bytes");
+        self.asm.bind(done);
+        self.emit(Ret);
+
+        let (write, done) = (self.asm.local(), self.asm.local());
+        self.define("flush");
+        self.emit(Lea(Rsi, Target::Bss(OUTPUT)));
+        self.emit(Lea(Rcx, Target::Bss(OUTPUT_LENGTH)));
+        self.emit(Load(Rdx, Rcx, 0));
+        self.asm.bind(write);
+        self.emit(Test(W64, Rdx, Rdx));
+        self.emit(Jcc(E, done));
+        self.emit(MovRI32(Rax, 1));
+        self.emit(MovRI32(Rdi, 1));
+        self.emit(Syscall);
+        self.emit(AluRI(Cmp, W64, Rax, 0));
+        self.emit(Jcc(Le, done));
+        self.emit(AluRR(Add, W64, Rsi, Rax));
+        self.emit(AluRR(Sub, W64, Rdx, Rax));
+        self.emit(Jmp(write));
+        self.asm.bind(done);
+        self.emit(Lea(Rcx, Target::Bss(OUTPUT_LENGTH)));
+        self.emit(MovRI32(Rdx, 0));
+        self.emit(Store(Rcx, 0, Rdx));
+        self.emit(Ret);
+
+        // Decimal digits of `eax` into the scratch buffer, as `rdx` bytes
+        // at `rsi`
+        let (digit, positive, done) = (self.asm.local(), self.asm.local(), self.asm.local());
+        self.define("format_int");
+        self.emit(MovRR(W32, R8, Rax));
+        self.emit(AluRI(Cmp, W32, Rax, 0));
+        self.emit(Jcc(Ge, positive));
+        // `MIN` negates to itself, which is its magnitude when unsigned
+        self.emit(Neg32(Rax));
+        self.asm.bind(positive);
+        self.emit(Lea(Rsi, Target::Bss(SCRATCH + SCRATCH_SIZE)));
+        self.emit(MovRI32(Rcx, 10));
+        self.asm.bind(digit);
+        self.emit(MovRI32(Rdx, 0));
+        self.emit(Div64(Rcx));
+        self.emit(AluRI(Add, W32, Rdx, i32::from(b'0')));
+        self.emit(AluRI(Sub, W64, Rsi, 1));
+        self.emit(StoreByte(Rsi, 0, Rdx));
+        self.emit(Test(W64, Rax, Rax));
+        self.emit(Jcc(Ne, digit));
+        self.emit(AluRI(Cmp, W32, R8, 0));
+        self.emit(Jcc(Ge, done));
+        self.emit(AluRI(Sub, W64, Rsi, 1));
+        self.emit(MovRI32(Rdx, u32::from(b'-')));
+        self.emit(StoreByte(Rsi, 0, Rdx));
+        self.asm.bind(done);
+        self.emit(Lea(Rdx, Target::Bss(SCRATCH + SCRATCH_SIZE)));
+        self.emit(AluRR(Sub, W64, Rdx, Rsi));
+        self.emit(Ret);
+
+        // Errors: flush the output, then write `file:line:column: reason`
+        // to stderr and exit with status 1
+        self.define("write_err");
+        self.emit(MovRI32(Rax, 1));
+        self.emit(MovRI32(Rdi, 2));
+        self.emit(Syscall);
+        self.emit(Ret);
+
+        self.define("fail_start");
+        self.call("flush");
+        self.emit(MovRR(W32, Rbx, Rbx));
+        self.emit(Shl64(Rbx, 3));
+        self.emit(Lea(Rax, Target::Sites));
+        self.emit(AluRR(Add, W64, Rax, Rbx));
+        self.emit(Load(Rax, Rax, 0));
+        self.emit(MovRR(W64, Rdx, Rax));
+        self.emit(Shr64(Rdx, 32));
+        self.emit(MovRR(W32, Rax, Rax));
+        self.emit(Lea(Rsi, Target::Data(0)));
+        self.emit(AluRR(Add, W64, Rsi, Rax));
+        self.jump("write_err");
+
+        self.define("fail_end");
+        self.write_error("\n");
+        self.emit(MovRI32(Rax, 60));
+        self.emit(MovRI32(Rdi, 1));
+        self.emit(Syscall);
+
+        let (lambda, other) = (self.asm.local(), self.asm.local());
+        self.define("write_type_name");
+        self.emit(AluRI(Cmp, W32, Rcx, INTEGER as i32));
+        self.emit(Jcc(Ne, lambda));
+        self.write_error("Integer");
+        self.emit(Ret);
+        self.asm.bind(lambda);
+        self.emit(AluRI(Cmp, W32, Rcx, LAMBDA as i32));
+        self.emit(Jcc(Ne, other));
+        self.write_error("Lambda");
+        self.emit(Ret);
+        self.asm.bind(other);
+        self.write_error("VariableReference");
+        self.emit(Ret);
+
+        self.fail("err_empty", "Stack is empty");
+        self.fail("err_one_value", "Stack only has 1 value");
+        self.fail("err_two_values", "Stack only has 2 values");
+        self.fail("err_overflow", "Stack overflow");
+        self.fail("err_division_by_zero", "Division by zero");
+        self.fail("err_store_name", "Store (:) must be preceded by a name");
+        self.fail("err_store_names", "Names cannot be stored in names");
+        self.fail("err_fetch_name", "Fetch (;) must be preceded by a name");
+        self.fail_type("err_expected_integer", "Expected Integer on stack, got ");
+        self.fail_type("err_pick_index", "Unexpected index for ø (PICK): ");
+        self.fail_type("err_execute", "Expected lambda for Execute, got ");
+        self.fail_int("err_pick_range", "Index out of range for ø (PICK): ", "");
+        self.fail_int("err_char", "Can't output value ", " as char");
+
+        // The name's index is in `r8d`
+        self.define("err_unset");
+        self.emit(Push(R8));
+        self.call("fail_start");
+        self.write_error("Name ");
+        self.emit(Pop(Rax));
+        self.emit(AluRI(Add, W32, Rax, i32::from(b'a')));
+        self.emit(Lea(Rsi, Target::Bss(SCRATCH)));
+        self.emit(StoreByte(Rsi, 0, Rax));
+        self.emit(MovRI32(Rdx, 1));
+        self.call("write_err");
+        self.write_error(" not found in global scope");
+        self.jump("fail_end");
+    }
+
+    /// Writes the code point in `r8d` as a `lead` byte followed by
+    /// `continuations` bytes, then returns.
+    fn utf8(&mut self, continuations: u8, lead: i32) {
+        use Alu::*;
+        use Inst::*;
+        use Reg::*;
+        use Width::*;
+
+        for i in (0..=continuations).rev() {
+            self.emit(MovRR(W32, Rax, R8));
+            if i > 0 {
+                self.emit(Shr64(Rax, 6 * i));
+            }
+            if i == continuations {
+                self.emit(AluRI(Or, W32, Rax, lead));
+            } else {
+                self.emit(AluRI(And, W32, Rax, 0x3F));
+                self.emit(AluRI(Or, W32, Rax, 0x80));
+            }
+            if i == 0 {
+                self.jump("out_byte");
+            } else {
+                self.call("out_byte");
+            }
+        }
+    }
+}
@@ -0,0 +1,586 @@
+//! A lossless concrete syntax tree.
+//!
+//! Unlike the AST from [`crate::parser::parse`], the tree keeps every
+//! character of the source, comments and whitespace included, so tools can
+//! rewrite source without losing any of it. Writing the tree back out gives
+//! the source it was parsed from, and [`Cst::lower`] gives the same AST,
+//! spans and all, as the parser does.
+//!
+//! Parsing never fails: anything that doesn't parse is kept in the tree, as
+//! an [`SyntaxKind::Error`] token or an unclosed comment, string or lambda,
+//! and [`Cst::errors`] lists it. Nodes only know their length, not where
+//! they are, so [`Cst::edit`] can reparse just the smallest part of the
+//! tree that an edit is inside and keep the rest as it is.
+
+use std::ops::Range;
+
+use chumsky::span::SimpleSpan;
+
+use crate::ast::{FalseInstruction, Spanned};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum SyntaxKind {
+    Whitespace,
+    /// `{...}`, which may be unclosed.
+    Comment,
+    Number,
+    /// `'c`, which may be missing the character at the end of the source.
+    Char,
+    /// `"..."`, which may be unclosed.
+    String,
+    Name,
+    /// Any one-character instruction, such as `+`, `$`, `:` or `!`.
+    Operator,
+    LBracket,
+    RBracket,
+    Question,
+    Hash,
+    /// A character that can't start an instruction here.
+    Error,
+
+    /// The whole source.
+    Program,
+    /// `[...]`, which may be unclosed.
+    Lambda,
+    /// A lambda followed by `?`.
+    Conditional,
+    /// Two lambdas followed by `#`.
+    WhileLoop,
+}
+
+impl SyntaxKind {
+    /// Whitespace and comments, which the AST doesn't have.
+    pub fn is_trivia(self) -> bool {
+        matches!(self, SyntaxKind::Whitespace | SyntaxKind::Comment)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SyntaxToken {
+    kind: SyntaxKind,
+    text: String,
+}
+
+impl SyntaxToken {
+    pub fn kind(&self) -> SyntaxKind {
+        self.kind
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SyntaxNode {
+    kind: SyntaxKind,
+    len: usize,
+    children: Vec<SyntaxElement>,
+}
+
+impl SyntaxNode {
+    fn new(kind: SyntaxKind, children: Vec<SyntaxElement>) -> Self {
+        Self {
+            kind,
+            len: children.iter().map(SyntaxElement::len).sum(),
+            children,
+        }
+    }
+
+    pub fn kind(&self) -> SyntaxKind {
+        self.kind
+    }
+
+    /// Length of the node's source, in bytes.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn children(&self) -> &[SyntaxElement] {
+        &self.children
+    }
+
+    /// Whether the node ends where it should, with the `]`, `?` or `#`
+    /// that closes it.
+    fn is_closed(&self) -> bool {
+        match self.kind {
+            SyntaxKind::Lambda => {
+                self.children.len() > 1
+                    && self.children.last().map(SyntaxElement::kind) == Some(SyntaxKind::RBracket)
+            }
+            _ => true,
+        }
+    }
+}
+
+impl std::fmt::Display for SyntaxNode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for child in &self.children {
+            match child {
+                SyntaxElement::Node(node) => write!(f, "{node}")?,
+                SyntaxElement::Token(token) => f.write_str(&token.text)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SyntaxElement {
+    Node(SyntaxNode),
+    Token(SyntaxToken),
+}
+
+impl SyntaxElement {
+    pub fn kind(&self) -> SyntaxKind {
+        match self {
+            SyntaxElement::Node(node) => node.kind,
+            SyntaxElement::Token(token) => token.kind,
+        }
+    }
+
+    /// Length of the element's source, in bytes.
+    pub fn len(&self) -> usize {
+        match self {
+            SyntaxElement::Node(node) => node.len,
+            SyntaxElement::Token(token) => token.text.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+pub struct SyntaxError {
+    span: SimpleSpan<usize>,
+    reason: String,
+}
+impl std::fmt::Display for SyntaxError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.reason)
+    }
+}
+impl std::fmt::Debug for SyntaxError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "SyntaxError {{ span: {:?}, reason: {:?} }}",
+            self.span, self.reason
+        )
+    }
+}
+impl SyntaxError {
+    pub fn span(&self) -> SimpleSpan<usize> {
+        self.span
+    }
+    pub fn reason(&self) -> &str {
+        &self.reason
+    }
+}
+impl std::error::Error for SyntaxError {}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cst {
+    root: SyntaxNode,
+}
+
+/// Parses source into a tree that keeps all of it.
+pub fn parse(source: &str) -> Cst {
+    let mut parser = Parser::new(source);
+    let children = parser.block(false);
+    Cst {
+        root: SyntaxNode::new(SyntaxKind::Program, children),
+    }
+}
+
+impl Cst {
+    /// The [`SyntaxKind::Program`] node.
+    pub fn root(&self) -> &SyntaxNode {
+        &self.root
+    }
+
+    /// Everything in the tree that the parser would reject.
+    pub fn errors(&self) -> Vec<SyntaxError> {
+        let mut errors = Vec::new();
+        collect_errors(&self.root, 0, &mut errors);
+        errors
+    }
+
+    /// The AST of the source, the same as [`crate::parser::parse`] gives
+    /// for it, or the errors if it has any.
+    pub fn lower(&self) -> Result<Vec<Spanned<FalseInstruction>>, Vec<SyntaxError>> {
+        let errors = self.errors();
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        Ok(lower_block(&self.root.children, 0))
+    }
+
+    /// Replaces `range` of the source with `replacement`, reparsing as
+    /// little as it can: a token or lambda that the edit is inside stays
+    /// one, with the same kind, if it still parses that way on its own.
+    /// Returns the span of the new source that was reparsed.
+    pub fn edit(&mut self, range: Range<usize>, replacement: &str) -> SimpleSpan<usize> {
+        assert!(
+            range.start <= range.end && range.end <= self.root.len,
+            "Edit {range:?} outside the source"
+        );
+        if let Some(span) = reparse_inside(&mut self.root, 0, &range, replacement) {
+            return span;
+        }
+        let mut source = self.to_string();
+        source.replace_range(range, replacement);
+        *self = parse(&source);
+        SimpleSpan::new(0, source.len())
+    }
+}
+
+impl std::fmt::Display for Cst {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.root)
+    }
+}
+
+struct Parser<'s> {
+    source: &'s str,
+    position: usize,
+    /// A lambda parsed while looking for a while loop that wasn't one,
+    /// kept so it isn't parsed twice. It ends at `position`.
+    pending: Option<SyntaxNode>,
+}
+
+impl<'s> Parser<'s> {
+    fn new(source: &'s str) -> Self {
+        Self {
+            source,
+            position: 0,
+            pending: None,
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.source[self.position..].chars().next()
+    }
+
+    /// Takes characters while `f` holds for them, as a token.
+    fn token_while(&mut self, kind: SyntaxKind, f: impl Fn(char) -> bool) -> SyntaxElement {
+        let start = self.position;
+        let rest = &self.source[start..];
+        self.position += rest.find(|c| !f(c)).unwrap_or(rest.len());
+        self.token_from(kind, start)
+    }
+
+    /// Takes the next character, as a token.
+    fn token(&mut self, kind: SyntaxKind) -> SyntaxElement {
+        let start = self.position;
+        self.position += self.peek().map_or(0, char::len_utf8);
+        self.token_from(kind, start)
+    }
+
+    /// Takes from the next character up to and including `close`, or to
+    /// the end of the source if it isn't there, as a token.
+    fn delimited(&mut self, kind: SyntaxKind, close: char) -> SyntaxElement {
+        let start = self.position;
+        self.position += self.peek().map_or(0, char::len_utf8);
+        let rest = &self.source[self.position..];
+        self.position += rest.find(close).map_or(rest.len(), |end| end + 1);
+        self.token_from(kind, start)
+    }
+
+    fn token_from(&self, kind: SyntaxKind, start: usize) -> SyntaxElement {
+        SyntaxElement::Token(SyntaxToken {
+            kind,
+            text: self.source[start..self.position].to_string(),
+        })
+    }
+
+    /// Elements up to the end of the source, or up to a `]` if `nested`.
+    fn block(&mut self, nested: bool) -> Vec<SyntaxElement> {
+        let mut elements = Vec::new();
+        loop {
+            match (&self.pending, self.peek()) {
+                (None, None) => break,
+                (None, Some(']')) if nested => break,
+                _ => elements.push(self.element()),
+            }
+        }
+        elements
+    }
+
+    fn element(&mut self) -> SyntaxElement {
+        if let Some(lambda) = self.pending.take() {
+            return self.after_lambda(lambda);
+        }
+        let Some(c) = self.peek() else {
+            unreachable!("Elements are only parsed before the end")
+        };
+        match c {
+            c if c.is_whitespace() => self.token_while(SyntaxKind::Whitespace, char::is_whitespace),
+            '{' => self.delimited(SyntaxKind::Comment, '}'),
+            '"' => self.delimited(SyntaxKind::String, '"'),
+            // As in the parser, a number doesn't have leading zeros
+            '0' => self.token(SyntaxKind::Number),
+            '1'..='9' => self.token_while(SyntaxKind::Number, |c| c.is_ascii_digit()),
+            '\'' => {
+                let start = self.position;
+                self.position += 1;
+                self.position += self.peek().map_or(0, char::len_utf8);
+                self.token_from(SyntaxKind::Char, start)
+            }
+            'a'..='z' => self.token(SyntaxKind::Name),
+            '[' => {
+                let lambda = self.lambda();
+                self.after_lambda(lambda)
+            }
+            c if operator(c).is_some() => self.token(SyntaxKind::Operator),
+            _ => self.token(SyntaxKind::Error),
+        }
+    }
+
+    fn lambda(&mut self) -> SyntaxNode {
+        let mut children = vec![self.token(SyntaxKind::LBracket)];
+        children.extend(self.block(true));
+        if self.peek() == Some(']') {
+            children.push(self.token(SyntaxKind::RBracket));
+        }
+        SyntaxNode::new(SyntaxKind::Lambda, children)
+    }
+
+    /// A lambda, or the conditional or while loop that it starts. Like in
+    /// the parser, nothing can come between the brackets and the `?` or
+    /// `#`, or between a while loop's two lambdas.
+    fn after_lambda(&mut self, lambda: SyntaxNode) -> SyntaxElement {
+        if !lambda.is_closed() {
+            return SyntaxElement::Node(lambda);
+        }
+        match self.peek() {
+            Some('?') => {
+                let question = self.token(SyntaxKind::Question);
+                SyntaxElement::Node(SyntaxNode::new(
+                    SyntaxKind::Conditional,
+                    vec![SyntaxElement::Node(lambda), question],
+                ))
+            }
+            Some('[') => {
+                let body = self.lambda();
+                if body.is_closed() && self.peek() == Some('#') {
+                    let hash = self.token(SyntaxKind::Hash);
+                    SyntaxElement::Node(SyntaxNode::new(
+                        SyntaxKind::WhileLoop,
+                        vec![SyntaxElement::Node(lambda), SyntaxElement::Node(body), hash],
+                    ))
+                } else {
+                    self.pending = Some(body);
+                    SyntaxElement::Node(lambda)
+                }
+            }
+            _ => SyntaxElement::Node(lambda),
+        }
+    }
+}
+
+/// The instruction written as `c`, for those that are one character.
+fn operator(c: char) -> Option<FalseInstruction> {
+    use FalseInstruction::*;
+
+    Some(match c {
+        '$' => Dup,
+        '%' => Drop,
+        '\\' => Swap,
+        '@' => Rot,
+        'ø' => Pick,
+        '+' => Add,
+        '-' => Sub,
+        '*' => Mul,
+        '/' => Div,
+        '_' => Neg,
+        '&' => BitAnd,
+        '|' => BitOr,
+        '~' => BitNot,
+        '>' => Gt,
+        '=' => Eq,
+        '!' => Execute,
+        ':' => Store,
+        ';' => Fetch,
+        '^' => ReadChar,
+        ',' => WriteChar,
+        '.' => WriteInt,
+        'ß' => Flush,
+        _ => return None,
+    })
+}
+
+fn collect_errors(node: &SyntaxNode, start: usize, errors: &mut Vec<SyntaxError>) {
+    let error = |start: usize, len: usize, reason: &str| SyntaxError {
+        span: SimpleSpan::new(start, start + len),
+        reason: reason.to_string(),
+    };
+    if !node.is_closed() {
+        errors.push(error(start, 1, "Unclosed lambda, expected ]"));
+    }
+
+    let mut offset = start;
+    for child in &node.children {
+        match child {
+            SyntaxElement::Node(inner) => collect_errors(inner, offset, errors),
+            SyntaxElement::Token(token) => {
+                let text = token.text.as_str();
+                let reason = match token.kind {
+                    SyntaxKind::Comment if !text[1..].ends_with('}') => {
+                        Some("Unclosed comment, expected }".to_string())
+                    }
+                    SyntaxKind::String if !text[1..].ends_with('"') => {
+                        Some("Unclosed string, expected \"".to_string())
+                    }
+                    SyntaxKind::Char if text.len() == 1 => {
+                        Some("Expected a character after '".to_string())
+                    }
+                    SyntaxKind::Number if text.parse::<i32>().is_err() => {
+                        Some("Number out of range".to_string())
+                    }
+                    SyntaxKind::Error => Some(format!("Unexpected {text}")),
+                    _ => None,
+                };
+                if let Some(reason) = reason {
+                    errors.push(error(offset, text.len(), &reason));
+                }
+            }
+        }
+        offset += child.len();
+    }
+}
+
+/// The instructions in a block starting at `start`, which has no errors.
+fn lower_block(elements: &[SyntaxElement], start: usize) -> Vec<Spanned<FalseInstruction>> {
+    let mut instructions = Vec::new();
+    let mut offset = start;
+    for element in elements {
+        let span = SimpleSpan::new(offset, offset + element.len());
+        offset += element.len();
+        let instruction = match element {
+            SyntaxElement::Token(token) => {
+                let text = token.text.as_str();
+                match token.kind {
+                    SyntaxKind::Number => {
+                        FalseInstruction::PushInt(text.parse().expect("Checked by errors"))
+                    }
+                    SyntaxKind::Char => FalseInstruction::PushChar(text.as_bytes()[1]),
+                    SyntaxKind::String => {
+                        FalseInstruction::WriteStr(text[1..text.len() - 1].to_string())
+                    }
+                    SyntaxKind::Name => {
+                        FalseInstruction::Name(text.chars().next().expect("Names aren't empty"))
+                    }
+                    SyntaxKind::Operator => text
+                        .chars()
+                        .next()
+                        .and_then(operator)
+                        .expect("Operators are instructions"),
+                    // Brackets, trivia and nothing else, as errors are checked
+                    _ => continue,
+                }
+            }
+            SyntaxElement::Node(node) => {
+                let body = |index: usize, start: usize| match &node.children[index] {
+                    SyntaxElement::Node(lambda) => lambda_body(lambda, start),
+                    SyntaxElement::Token(_) => unreachable!("Bodies are lambdas"),
+                };
+                match node.kind {
+                    SyntaxKind::Lambda => FalseInstruction::Lambda(lambda_body(node, span.start)),
+                    SyntaxKind::Conditional => {
+                        FalseInstruction::ConditionalExecute(body(0, span.start))
+                    }
+                    SyntaxKind::WhileLoop => FalseInstruction::WhileLoop(
+                        body(0, span.start),
+                        body(1, span.start + node.children[0].len()),
+                    ),
+                    _ => unreachable!("Only the program is a block of its own"),
+                }
+            }
+        };
+        instructions.push(Spanned::new(instruction, span));
+    }
+    instructions
+}
+
+fn lambda_body(lambda: &SyntaxNode, start: usize) -> Vec<Spanned<FalseInstruction>> {
+    // Past the `[`, and stopping at the `]`
+    let inside = &lambda.children[1..lambda.children.len() - 1];
+    lower_block(inside, start + 1)
+}
+
+/// Reparses the smallest child of `node` that `range` is strictly inside,
+/// so that the characters it starts and ends with stay, if the new source
+/// of the child parses on its own as one element of the same kind. Returns
+/// the span reparsed, in the new source.
+fn reparse_inside(
+    node: &mut SyntaxNode,
+    start: usize,
+    range: &Range<usize>,
+    replacement: &str,
+) -> Option<SimpleSpan<usize>> {
+    let mut offset = start;
+    let index = node.children.iter().position(|child| {
+        let inside = offset < range.start && range.end < offset + child.len();
+        if !inside {
+            offset += child.len();
+        }
+        inside
+    })?;
+    let child = &mut node.children[index];
+    let reparsed = match child {
+        SyntaxElement::Node(inner) => reparse_inside(inner, offset, range, replacement)
+            .or_else(|| reparse_element(child, offset, range, replacement)),
+        SyntaxElement::Token(_) => reparse_element(child, offset, range, replacement),
+    }?;
+    node.len = node.children.iter().map(SyntaxElement::len).sum();
+    Some(reparsed)
+}
+
+fn reparse_element(
+    element: &mut SyntaxElement,
+    start: usize,
+    range: &Range<usize>,
+    replacement: &str,
+) -> Option<SimpleSpan<usize>> {
+    let reparseable = matches!(
+        element.kind(),
+        SyntaxKind::Whitespace
+            | SyntaxKind::Comment
+            | SyntaxKind::String
+            | SyntaxKind::Number
+            | SyntaxKind::Lambda
+            | SyntaxKind::Conditional
+            | SyntaxKind::WhileLoop
+    );
+    if !reparseable {
+        return None;
+    }
+    let mut source = SyntaxNode::new(SyntaxKind::Program, vec![element.clone()]).to_string();
+    source.replace_range(range.start - start..range.end - start, replacement);
+
+    let mut reparsed = parse(&source).root.children;
+    let [new] = reparsed.as_mut_slice() else {
+        return None;
+    };
+    // It has to end the same way, or it would have parsed differently with
+    // what comes after it
+    let closed = match new {
+        SyntaxElement::Node(node) => node.is_closed(),
+        SyntaxElement::Token(token) => match token.kind {
+            SyntaxKind::Comment => token.text[1..].ends_with('}'),
+            SyntaxKind::String => token.text[1..].ends_with('"'),
+            _ => true,
+        },
+    };
+    if new.kind() != element.kind() || !closed {
+        return None;
+    }
+    *element = reparsed.pop().expect("Checked to be one element");
+    Some(SimpleSpan::new(start, start + source.len()))
+}
@@ -0,0 +1,551 @@
+//! The debugger started by `falsy debug`.
+//!
+//! The program runs with a step hook that decides, before each instruction
+//! and after each store, whether to stop and read commands. It starts stopped
+//! before the first instruction, and once the program ends its stack and
+//! globals can still be inspected. Reading commands and printing are left to
+//! the caller, so this can be tested without a terminal.
+
+use std::cell::RefCell;
+use std::ops::ControlFlow;
+
+use ariadne::{sources, Color, Config, Label, Report, ReportKind};
+use chumsky::span::SimpleSpan;
+
+use crate::ast::{walk_instruction, FalseInstruction, Spanned, Visitor};
+use crate::interpreter::{Interpreter, InterpreterRuntimeError, Step, StepEvent, Value};
+use crate::lines::location;
+
+pub mod dap;
+
+pub struct Debugger {
+    filename: String,
+    source: String,
+    ast: Vec<Spanned<FalseInstruction>>,
+    color: bool,
+}
+
+const HELP: &str = "\
+break LINE[:COLUMN]  stop before the instruction there (b)
+break VAR            stop after VAR is stored
+watch VAR            stop after VAR changes
+delete N             remove breakpoint or watchpoint N
+info                 list breakpoints and watchpoints
+step                 run one instruction (s)
+next                 run one instruction, over lambda calls (n)
+finish               run until the current lambda returns
+continue             run until a breakpoint (c)
+stack                show the stack
+vars                 show the variables that have been stored
+print VAR            show a variable (p)
+list                 show where the program is (l)
+quit                 stop the program (q)
+help                 show this help";
+
+impl Debugger {
+    pub fn new(
+        filename: impl Into<String>,
+        source: impl Into<String>,
+        ast: Vec<Spanned<FalseInstruction>>,
+    ) -> Self {
+        Self {
+            filename: filename.into(),
+            source: source.into(),
+            ast,
+            color: false,
+        }
+    }
+
+    /// Whether to colour the source shown where the program stops.
+    pub fn color(mut self, color: bool) -> Self {
+        self.color = color;
+        self
+    }
+
+    /// Runs the program with the interpreter's input and output, reading
+    /// commands with `read_command` until it returns `None` or `quit` is
+    /// entered. Everything else the debugger shows is passed to `print` a
+    /// line or report at a time. Returns the program's error, if it failed
+    /// rather than being stopped.
+    pub fn run(
+        &self,
+        interpreter: Interpreter,
+        read_command: impl FnMut() -> Option<String>,
+        print: impl FnMut(&str),
+    ) -> Result<(), InterpreterRuntimeError> {
+        let controller = RefCell::new(Controller {
+            debugger: self,
+            read_command: Box::new(read_command),
+            print: Box::new(print),
+            breakpoints: Vec::new(),
+            numbered: 0,
+            resume: Resume::Step,
+            last_command: String::new(),
+            quit: false,
+        });
+        let mut session = interpreter
+            .on_step(|step| controller.borrow_mut().on_step(step))
+            .session();
+        let result = session.run(self.ast.clone());
+
+        let mut controller = controller.borrow_mut();
+        if controller.quit {
+            return Ok(());
+        }
+        match &result {
+            Ok(()) => (controller.print)("Program finished"),
+            Err(e) => {
+                let report = self.report(
+                    ReportKind::Error,
+                    Color::Red,
+                    e.span(),
+                    &e.to_string(),
+                    e.reason(),
+                );
+                (controller.print)(&report);
+            }
+        }
+        let snapshot = Snapshot {
+            span: None,
+            depth: 0,
+            stack: session.stack(),
+            globals: session.globals(),
+        };
+        controller.prompt(&snapshot);
+        result
+    }
+
+    /// The source around `span`, as an ariadne report.
+    fn report(
+        &self,
+        kind: ReportKind,
+        color: Color,
+        span: SimpleSpan<usize>,
+        message: &str,
+        label: &str,
+    ) -> String {
+        let mut report = Vec::new();
+        Report::build(kind, self.filename.clone(), span.start)
+            .with_config(Config::default().with_color(self.color))
+            .with_message(message)
+            .with_label(
+                Label::new((self.filename.clone(), span.into_range()))
+                    .with_message(label)
+                    .with_color(color),
+            )
+            .finish()
+            .write(
+                sources([(self.filename.clone(), self.source.clone())]),
+                &mut report,
+            )
+            .unwrap();
+        let report = String::from_utf8_lossy(&report)
+            .lines()
+            .map(str::trim_end)
+            .collect::<Vec<_>>()
+            .join("\n");
+        if self.color {
+            return report;
+        }
+        // ariadne colours the kind of custom reports whatever the config says
+        let mut plain = String::with_capacity(report.len());
+        let mut rest = report.as_str();
+        while let Some(start) = rest.find('\x1b') {
+            plain.push_str(&rest[..start]);
+            rest = rest[start..]
+                .find('m')
+                .map_or("", |end| &rest[start + end + 1..]);
+        }
+        plain.push_str(rest);
+        plain
+    }
+
+    /// The instruction a breakpoint at `line`, and `column` if given, stops
+    /// before: the innermost one at that column, or the first on the line.
+    fn resolve(&self, line: usize, column: Option<usize>) -> Option<SimpleSpan<usize>> {
+        let line_start = if line == 1 {
+            0
+        } else {
+            self.source.match_indices('\n').nth(line.checked_sub(2)?)?.0 + 1
+        };
+        let line_end = self.source[line_start..]
+            .find('\n')
+            .map_or(self.source.len(), |end| line_start + end);
+        let mut spans = Vec::new();
+        collect_spans(&self.ast, &mut spans);
+        match column {
+            Some(column) => {
+                let offset = self.source[line_start..line_end]
+                    .char_indices()
+                    .nth(column.checked_sub(1)?)?
+                    .0
+                    + line_start;
+                spans
+                    .into_iter()
+                    .filter(|span| span.start <= offset && offset < span.end)
+                    .min_by_key(|span| span.end - span.start)
+            }
+            None => spans
+                .into_iter()
+                .filter(|span| (line_start..line_end).contains(&span.start))
+                .min_by_key(|span| span.start),
+        }
+    }
+}
+
+fn collect_spans(instructions: &[Spanned<FalseInstruction>], spans: &mut Vec<SimpleSpan<usize>>) {
+    struct Spans<'s>(&'s mut Vec<SimpleSpan<usize>>);
+
+    impl Visitor<'_> for Spans<'_> {
+        fn visit_instruction(&mut self, instruction: &Spanned<FalseInstruction>) {
+            self.0.push(instruction.span());
+            walk_instruction(self, instruction);
+        }
+    }
+
+    Spans(spans).visit_block(instructions);
+}
+
+/// When to stop next, besides at breakpoints.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Resume {
+    /// Before the next instruction.
+    Step,
+    /// Before the next instruction at most this many calls deep.
+    Next(usize),
+    /// Before the next instruction fewer than this many calls deep.
+    Finish(usize),
+    Continue,
+}
+
+impl Resume {
+    /// Whether to stop before an instruction this many calls deep.
+    fn stops_at(self, depth: usize) -> bool {
+        match self {
+            Self::Step => true,
+            Self::Next(next) => depth <= next,
+            Self::Finish(finish) => depth < finish,
+            Self::Continue => false,
+        }
+    }
+}
+
+enum Breakpoint {
+    At(SimpleSpan<usize>),
+    Store(char),
+    /// The value the variable had when last seen.
+    Watch(char, Option<Value>),
+}
+
+/// What can be inspected where the program stopped, or after it ended.
+struct Snapshot {
+    /// The instruction stopped at, while the program runs.
+    span: Option<SimpleSpan<usize>>,
+    depth: usize,
+    stack: Vec<Value>,
+    globals: Vec<(char, Value)>,
+}
+
+impl Snapshot {
+    fn global(&self, name: char) -> Option<&Value> {
+        self.globals
+            .iter()
+            .find(|(global, _)| *global == name)
+            .map(|(_, value)| value)
+    }
+}
+
+/// What a command does besides printing.
+enum Action {
+    Prompt,
+    Resume(Resume),
+    Quit,
+}
+
+struct Controller<'d, 'c> {
+    debugger: &'d Debugger,
+    read_command: Box<dyn FnMut() -> Option<String> + 'c>,
+    print: Box<dyn FnMut(&str) + 'c>,
+    /// Breakpoints and watchpoints, by number.
+    breakpoints: Vec<(usize, Breakpoint)>,
+    numbered: usize,
+    resume: Resume,
+    /// Entering nothing repeats this.
+    last_command: String,
+    quit: bool,
+}
+
+impl Controller<'_, '_> {
+    fn on_step(&mut self, step: &Step) -> ControlFlow<()> {
+        let reason = match step.event() {
+            StepEvent::Instruction => self.stops_before(step),
+            StepEvent::Store(name) => self.stops_after_store(step, name),
+        };
+        let Some(reason) = reason else {
+            return ControlFlow::Continue(());
+        };
+        let label = match step.event() {
+            StepEvent::Instruction => "next to run",
+            StepEvent::Store(_) => "stored here",
+        };
+        let report = self.debugger.report(
+            ReportKind::Custom("Stopped", Color::Cyan),
+            Color::Cyan,
+            step.span(),
+            &reason,
+            label,
+        );
+        (self.print)(&report);
+
+        let snapshot = Snapshot {
+            span: Some(step.span()),
+            depth: step.depth(),
+            stack: step.stack(),
+            globals: step.globals(),
+        };
+        match self.prompt(&snapshot) {
+            Some(resume) => {
+                self.resume = resume;
+                ControlFlow::Continue(())
+            }
+            None => {
+                self.quit = true;
+                ControlFlow::Break(())
+            }
+        }
+    }
+
+    /// Why to stop before the instruction at the step, if at all.
+    fn stops_before(&self, step: &Step) -> Option<String> {
+        let breakpoint = self.breakpoints.iter().find_map(|(number, breakpoint)| {
+            matches!(breakpoint, Breakpoint::At(span) if *span == step.span()).then_some(number)
+        });
+        if breakpoint.is_none() && !self.resume.stops_at(step.depth()) {
+            return None;
+        }
+        let (line, column) = location(&self.debugger.source, step.span().start);
+        Some(match breakpoint {
+            Some(number) => format!("breakpoint {number} at {line}:{column}"),
+            None => format!("at {line}:{column}"),
+        })
+    }
+
+    /// Why to stop after `name` was stored, if at all. Watchpoints on it
+    /// are updated either way.
+    fn stops_after_store(&mut self, step: &Step, name: char) -> Option<String> {
+        let value = step.global(name);
+        let mut reasons = Vec::new();
+        for (number, breakpoint) in &mut self.breakpoints {
+            match breakpoint {
+                Breakpoint::Store(watched) if *watched == name => {
+                    reasons.push(format!(
+                        "breakpoint {number}, stored {name} = {}",
+                        value.as_ref().expect("Stored variables have a value")
+                    ));
+                }
+                Breakpoint::Watch(watched, last) if *watched == name && *last != value => {
+                    reasons.push(format!(
+                        "watchpoint {number}, {name} changed from {} to {}",
+                        display(last.as_ref()),
+                        display(value.as_ref())
+                    ));
+                    *last = value.clone();
+                }
+                _ => {}
+            }
+        }
+        (!reasons.is_empty()).then(|| reasons.join("; "))
+    }
+
+    /// Reads and runs commands until one resumes the program, returning how
+    /// far it should run, or `None` to stop it.
+    fn prompt(&mut self, snapshot: &Snapshot) -> Option<Resume> {
+        loop {
+            let mut command = (self.read_command)()?.trim().to_string();
+            if command.is_empty() {
+                command = self.last_command.clone();
+            }
+            match self.command(&command, snapshot) {
+                Ok(Action::Prompt) => {}
+                Ok(Action::Resume(resume)) => {
+                    self.last_command = command;
+                    return Some(resume);
+                }
+                Ok(Action::Quit) => return None,
+                Err(message) => (self.print)(&message),
+            }
+        }
+    }
+
+    fn command(&mut self, command: &str, snapshot: &Snapshot) -> Result<Action, String> {
+        let (command, argument) = command
+            .split_once(char::is_whitespace)
+            .map_or((command, ""), |(command, argument)| {
+                (command, argument.trim())
+            });
+        let resume = match (command, argument) {
+            ("" | "step" | "s", "") => Resume::Step,
+            ("next" | "n", "") => Resume::Next(snapshot.depth),
+            ("finish", "") if snapshot.depth == 0 => {
+                return Err("finish isn't meaningful outside a lambda".to_string())
+            }
+            ("finish", "") => Resume::Finish(snapshot.depth),
+            ("continue" | "c", "") => Resume::Continue,
+            ("break" | "b", "") => {
+                return Err("Expected a place to break, as in break 3:5 or break a".to_string())
+            }
+            ("break" | "b", argument) => {
+                let breakpoint = match variable(argument) {
+                    Some(name) => Breakpoint::Store(name),
+                    None => Breakpoint::At(self.location(argument)?),
+                };
+                let description = self.add(breakpoint);
+                (self.print)(&description);
+                return Ok(Action::Prompt);
+            }
+            ("watch", argument) => {
+                let name = variable(argument)
+                    .ok_or_else(|| "Expected a variable to watch, as in watch a".to_string())?;
+                let description = self.add(Breakpoint::Watch(name, snapshot.global(name).cloned()));
+                (self.print)(&description);
+                return Ok(Action::Prompt);
+            }
+            ("delete", argument) => {
+                let number: usize = argument
+                    .parse()
+                    .map_err(|_| "Expected a breakpoint number, as in delete 1".to_string())?;
+                let index = self
+                    .breakpoints
+                    .iter()
+                    .position(|(n, _)| *n == number)
+                    .ok_or_else(|| format!("No breakpoint number {number}"))?;
+                self.breakpoints.remove(index);
+                return Ok(Action::Prompt);
+            }
+            ("info", "") => {
+                let info = if self.breakpoints.is_empty() {
+                    "(no breakpoints)".to_string()
+                } else {
+                    self.breakpoints
+                        .iter()
+                        .map(|(number, breakpoint)| self.describe(*number, breakpoint))
+                        .collect::<Vec<_>>()
+                        .join("\n")
+                };
+                (self.print)(&info);
+                return Ok(Action::Prompt);
+            }
+            ("stack", "") => {
+                let stack = if snapshot.stack.is_empty() {
+                    "(empty stack)".to_string()
+                } else {
+                    snapshot
+                        .stack
+                        .iter()
+                        .map(Value::to_string)
+                        .collect::<Vec<_>>()
+                        .join(" ")
+                };
+                (self.print)(&stack);
+                return Ok(Action::Prompt);
+            }
+            ("vars", "") => {
+                let vars = if snapshot.globals.is_empty() {
+                    "(no variables)".to_string()
+                } else {
+                    snapshot
+                        .globals
+                        .iter()
+                        .map(|(name, value)| format!("{name} = {value}"))
+                        .collect::<Vec<_>>()
+                        .join("\n")
+                };
+                (self.print)(&vars);
+                return Ok(Action::Prompt);
+            }
+            ("print" | "p", argument) => {
+                let name = variable(argument)
+                    .ok_or_else(|| "Expected a variable to print, as in print a".to_string())?;
+                (self.print)(&format!("{name} = {}", display(snapshot.global(name))));
+                return Ok(Action::Prompt);
+            }
+            ("list" | "l", "") => {
+                let Some(span) = snapshot.span else {
+                    return Err("The program isn't running".to_string());
+                };
+                let (line, column) = location(&self.debugger.source, span.start);
+                let report = self.debugger.report(
+                    ReportKind::Custom("Stopped", Color::Cyan),
+                    Color::Cyan,
+                    span,
+                    &format!("at {line}:{column}"),
+                    "next to run",
+                );
+                (self.print)(&report);
+                return Ok(Action::Prompt);
+            }
+            ("quit" | "q", "") => return Ok(Action::Quit),
+            ("help", "") => {
+                (self.print)(HELP);
+                return Ok(Action::Prompt);
+            }
+            (
+                "step" | "s" | "next" | "n" | "finish" | "continue" | "c" | "info" | "stack"
+                | "vars" | "list" | "l" | "quit" | "q" | "help",
+                _,
+            ) => return Err(format!("{command} doesn't take an argument")),
+            _ => return Err(format!("Unknown command {command}, see help")),
+        };
+        if snapshot.span.is_none() {
+            return Err("The program isn't running".to_string());
+        }
+        Ok(Action::Resume(resume))
+    }
+
+    /// The instruction at a `LINE[:COLUMN]` breakpoint location.
+    fn location(&self, location: &str) -> Result<SimpleSpan<usize>, String> {
+        let invalid = || format!("Expected LINE[:COLUMN] or a variable, got {location}");
+        let (line, column) = match location.split_once(':') {
+            Some((line, column)) => (
+                line.parse().map_err(|_| invalid())?,
+                Some(column.parse().map_err(|_| invalid())?),
+            ),
+            None => (location.parse().map_err(|_| invalid())?, None),
+        };
+        self.debugger
+            .resolve(line, column)
+            .ok_or_else(|| format!("No instruction at {location}"))
+    }
+
+    /// Numbers a breakpoint and keeps it, returning its description.
+    fn add(&mut self, breakpoint: Breakpoint) -> String {
+        self.numbered += 1;
+        let description = self.describe(self.numbered, &breakpoint);
+        self.breakpoints.push((self.numbered, breakpoint));
+        description
+    }
+
+    fn describe(&self, number: usize, breakpoint: &Breakpoint) -> String {
+        match breakpoint {
+            Breakpoint::At(span) => {
+                let (line, column) = location(&self.debugger.source, span.start);
+                format!("Breakpoint {number} at {line}:{column}")
+            }
+            Breakpoint::Store(name) => format!("Breakpoint {number} when {name} is stored"),
+            Breakpoint::Watch(name, _) => format!("Watchpoint {number} on {name}"),
+        }
+    }
+}
+
+/// The variable named by a command's argument, if it is one.
+fn variable(argument: &str) -> Option<char> {
+    let mut chars = argument.chars();
+    match (chars.next(), chars.next()) {
+        (Some(name), None) if name.is_ascii_lowercase() => Some(name),
+        _ => None,
+    }
+}
+
+fn display(value: Option<&Value>) -> String {
+    value.map_or_else(|| "(unset)".to_string(), Value::to_string)
+}
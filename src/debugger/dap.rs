@@ -0,0 +1,630 @@
+//! A Debug Adapter Protocol server, started by `falsy dap`.
+//!
+//! Editors talk to it over stdin and stdout, in JSON messages each after a
+//! `Content-Length` header. Requests are read on a thread of their own, so
+//! they can be answered while the program runs on the calling thread, with a
+//! step hook that stops it where the editor asks. The program's output is
+//! sent to the editor as output events, and it has no input.
+//!
+//! Each call frame is a lambda call, and every frame has the same two
+//! scopes: the data stack, top first, and the 26 globals.
+
+use std::cell::RefCell;
+use std::io::{self, BufRead, Read, Write};
+use std::ops::ControlFlow;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+
+use chumsky::span::SimpleSpan;
+use serde_json::{json, Value as Json};
+
+use super::{display, Debugger, Resume};
+use crate::ast::{FalseInstruction, Spanned, Visitor};
+use crate::interpreter::{Interpreter, Step, StepEvent};
+use crate::lines::location;
+use crate::parser::parse;
+
+/// The only thread, which runs the program.
+const THREAD_ID: i64 = 1;
+const STACK_REFERENCE: i64 = 1;
+const GLOBALS_REFERENCE: i64 = 2;
+
+/// Serves one debug session, until the client disconnects or the input
+/// ends. Fails if writing to the client does.
+pub fn serve(input: impl BufRead + Send + 'static, output: impl Write) -> io::Result<()> {
+    let (sender, requests) = mpsc::channel();
+    std::thread::spawn(move || {
+        let mut input = input;
+        // A malformed message ends the session, like the input ending
+        while let Ok(Some(message)) = read_message(&mut input) {
+            if sender.send(message).is_err() {
+                break;
+            }
+        }
+    });
+    let connection = RefCell::new(Connection {
+        output,
+        seq: 1,
+        error: None,
+    });
+    let adapter = RefCell::new(Adapter {
+        connection: &connection,
+        requests,
+        program: None,
+        lines_start_at_1: true,
+        columns_start_at_1: true,
+        breakpoints: Vec::new(),
+        numbered: 0,
+        resume: Resume::Continue,
+        entry: true,
+        pause: false,
+        running: false,
+        frames: Vec::new(),
+    });
+
+    // Launch and configuration
+    loop {
+        let Ok(request) = adapter.borrow().requests.recv() else {
+            return connection.borrow_mut().result();
+        };
+        match adapter.borrow_mut().handle(&request, None) {
+            Action::Start => break,
+            Action::Stop | Action::Disconnect => return connection.borrow_mut().result(),
+            Action::Continue | Action::Resume(_) => {}
+        }
+    }
+
+    let (ast, filename) = {
+        let mut adapter = adapter.borrow_mut();
+        adapter.running = true;
+        let program = adapter
+            .program
+            .as_ref()
+            .expect("Programs are launched first");
+        (
+            program.debugger.ast.clone(),
+            program.debugger.filename.clone(),
+        )
+    };
+    let ended = RefCell::new(None);
+    let mut session = Interpreter::new()
+        .on_input(|| None)
+        .on_output(|s: &str| {
+            connection
+                .borrow_mut()
+                .event("output", json!({ "category": "stdout", "output": s }))
+        })
+        .on_step(|step| {
+            let flow = adapter.borrow_mut().on_step(step);
+            if let ControlFlow::Break(action) = flow {
+                *ended.borrow_mut() = Some(action);
+                return ControlFlow::Break(());
+            }
+            ControlFlow::Continue(())
+        })
+        .session();
+    let result = session.run(ast);
+
+    let ended = ended.take();
+    if ended == Some(Action::Disconnect) {
+        return connection.borrow_mut().result();
+    }
+    let mut adapter = adapter.borrow_mut();
+    adapter.running = false;
+    if ended.is_none() {
+        let exit_code = match result {
+            Ok(()) => 0,
+            Err(e) => {
+                let program = adapter
+                    .program
+                    .as_ref()
+                    .expect("Programs are launched first");
+                let (line, column) = location(&program.debugger.source, e.span().start);
+                let message = format!("{filename}:{line}:{column}: {e}\n");
+                connection
+                    .borrow_mut()
+                    .event("output", json!({ "category": "stderr", "output": message }));
+                1
+            }
+        };
+        connection
+            .borrow_mut()
+            .event("exited", json!({ "exitCode": exit_code }));
+    }
+    connection.borrow_mut().event("terminated", json!({}));
+
+    // Only disconnecting is left
+    while let Ok(request) = adapter.requests.recv() {
+        if adapter.handle(&request, None) == Action::Disconnect {
+            break;
+        }
+    }
+    drop(adapter);
+    let result = connection.borrow_mut().result();
+    result
+}
+
+/// Reads a message, or `None` at the end of the input.
+fn read_message(input: &mut impl BufRead) -> io::Result<Option<Json>> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message);
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                let value = value.trim().parse();
+                length = Some(value.map_err(|_| invalid("Invalid Content-Length"))?);
+            }
+        }
+    }
+    let length: usize = length.ok_or_else(|| invalid("Missing Content-Length"))?;
+    // Read no more than was sent, rather than trusting the header with the
+    // size of a buffer
+    let mut body = Vec::new();
+    input.take(length as u64).read_to_end(&mut body)?;
+    if body.len() != length {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "Message is shorter than its Content-Length",
+        ));
+    }
+    serde_json::from_slice(&body)
+        .map(Some)
+        .map_err(|e| invalid(&e.to_string()))
+}
+
+struct Connection<W> {
+    output: W,
+    seq: i64,
+    /// The first write to fail. Nothing is sent after it.
+    error: Option<io::Error>,
+}
+
+impl<W: Write> Connection<W> {
+    fn send(&mut self, mut message: Json) {
+        if self.error.is_some() {
+            return;
+        }
+        message["seq"] = self.seq.into();
+        self.seq += 1;
+        let body = message.to_string();
+        let written = write!(self.output, "Content-Length: {}\r\n\r\n{body}", body.len())
+            .and_then(|()| self.output.flush());
+        if let Err(e) = written {
+            self.error = Some(e);
+        }
+    }
+
+    fn event(&mut self, event: &str, body: Json) {
+        self.send(json!({ "type": "event", "event": event, "body": body }));
+    }
+
+    fn respond(&mut self, request: &Json, result: Result<Json, String>) {
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": result.is_ok(),
+        });
+        match result {
+            Ok(body) => response["body"] = body,
+            Err(message) => response["message"] = message.into(),
+        }
+        self.send(response);
+    }
+
+    fn result(&mut self) -> io::Result<()> {
+        self.error.take().map_or(Ok(()), Err)
+    }
+}
+
+/// What a request asks of the session besides its response.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Action {
+    Continue,
+    /// Configuration is done, so the program can start.
+    Start,
+    Resume(Resume),
+    /// Stop the program, but keep the session.
+    Stop,
+    Disconnect,
+}
+
+/// The program being debugged.
+struct Program {
+    debugger: Debugger,
+    path: String,
+    /// The span of each lambda, and what to call its frames.
+    lambdas: Vec<(SimpleSpan<usize>, String)>,
+}
+
+struct Adapter<'c, W> {
+    connection: &'c RefCell<Connection<W>>,
+    requests: Receiver<Json>,
+    program: Option<Program>,
+    lines_start_at_1: bool,
+    columns_start_at_1: bool,
+    /// The instruction each breakpoint stops before, by id.
+    breakpoints: Vec<(i64, SimpleSpan<usize>)>,
+    numbered: i64,
+    resume: Resume,
+    /// Whether the next instruction is the first.
+    entry: bool,
+    /// Whether the client asked to pause the running program.
+    pause: bool,
+    running: bool,
+    /// The instruction each call frame is at, outermost first.
+    frames: Vec<SimpleSpan<usize>>,
+}
+
+impl<W: Write> Adapter<'_, W> {
+    /// Decides whether to stop before an instruction, answering requests
+    /// until the program should go on. Breaks with how the session ended,
+    /// if it was ended.
+    fn on_step(&mut self, step: &Step) -> ControlFlow<Action> {
+        if step.event() != StepEvent::Instruction {
+            return ControlFlow::Continue(());
+        }
+        self.frames.truncate(step.depth());
+        self.frames.push(step.span());
+        let entry = std::mem::take(&mut self.entry);
+
+        // Requests sent while the program runs
+        loop {
+            let request = match self.requests.try_recv() {
+                Ok(request) => request,
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return ControlFlow::Break(Action::Disconnect),
+            };
+            let action = self.handle(&request, None);
+            if let Action::Stop | Action::Disconnect = action {
+                return ControlFlow::Break(action);
+            }
+        }
+        let breakpoint = self
+            .breakpoints
+            .iter()
+            .find(|(_, span)| *span == step.span())
+            .map(|(id, _)| *id);
+        let reason = if breakpoint.is_some() {
+            "breakpoint"
+        } else if self.pause {
+            "pause"
+        } else if self.resume.stops_at(step.depth()) {
+            if entry {
+                "entry"
+            } else {
+                "step"
+            }
+        } else {
+            return ControlFlow::Continue(());
+        };
+        self.pause = false;
+        let mut stopped = json!({
+            "reason": reason,
+            "threadId": THREAD_ID,
+            "allThreadsStopped": true,
+        });
+        if let Some(id) = breakpoint {
+            stopped["hitBreakpointIds"] = json!([id]);
+        }
+        self.connection.borrow_mut().event("stopped", stopped);
+
+        loop {
+            let Ok(request) = self.requests.recv() else {
+                return ControlFlow::Break(Action::Disconnect);
+            };
+            match self.handle(&request, Some(step)) {
+                Action::Resume(resume) => {
+                    self.resume = resume;
+                    return ControlFlow::Continue(());
+                }
+                action @ (Action::Stop | Action::Disconnect) => return ControlFlow::Break(action),
+                Action::Continue | Action::Start => {}
+            }
+        }
+    }
+
+    /// Responds to a request. `stopped` is where the program is stopped, if
+    /// it is.
+    fn handle(&mut self, request: &Json, stopped: Option<&Step>) -> Action {
+        let arguments = &request["arguments"];
+        let mut action = Action::Continue;
+        let result = match request["command"].as_str().unwrap_or_default() {
+            "initialize" => {
+                self.lines_start_at_1 = arguments["linesStartAt1"].as_bool().unwrap_or(true);
+                self.columns_start_at_1 = arguments["columnsStartAt1"].as_bool().unwrap_or(true);
+                Ok(json!({
+                    "supportsConfigurationDoneRequest": true,
+                    "supportsTerminateRequest": true,
+                }))
+            }
+            "launch" => self.launch(arguments),
+            "setBreakpoints" => self.set_breakpoints(arguments),
+            "configurationDone" if self.program.is_none() => {
+                Err("Launch a program first".to_string())
+            }
+            "configurationDone" => {
+                action = Action::Start;
+                Ok(json!({}))
+            }
+            "threads" => Ok(json!({ "threads": [{ "id": THREAD_ID, "name": "main" }] })),
+            "pause" if self.running => {
+                self.pause = stopped.is_none();
+                Ok(json!({}))
+            }
+            "disconnect" => {
+                action = Action::Disconnect;
+                Ok(json!({}))
+            }
+            "terminate" => {
+                action = Action::Stop;
+                Ok(json!({}))
+            }
+            command => match stopped {
+                Some(step) => self.inspect(command, arguments, step, &mut action),
+                None if self.running => Err("The program isn't stopped".to_string()),
+                None => Err(format!("Can't {command} when the program isn't running")),
+            },
+        };
+        self.connection.borrow_mut().respond(request, result);
+        action
+    }
+
+    /// Responds to a request that needs the program to be stopped.
+    fn inspect(
+        &mut self,
+        command: &str,
+        arguments: &Json,
+        step: &Step,
+        action: &mut Action,
+    ) -> Result<Json, String> {
+        let depth = step.depth();
+        match command {
+            "stackTrace" => {
+                let program = self.program.as_ref().expect("Programs are launched first");
+                let frames: Vec<_> = self
+                    .frames
+                    .iter()
+                    .enumerate()
+                    .rev()
+                    .map(|(depth, span)| {
+                        let (line, column) = location(&program.debugger.source, span.start);
+                        let name = match depth {
+                            0 => "main",
+                            _ => program.frame_name(*span),
+                        };
+                        json!({
+                            "id": depth,
+                            "name": name,
+                            "line": self.line_to_client(line),
+                            "column": self.column_to_client(column),
+                            "source": {
+                                "name": program.debugger.filename,
+                                "path": program.path,
+                            },
+                        })
+                    })
+                    .collect();
+                Ok(json!({ "stackFrames": frames, "totalFrames": frames.len() }))
+            }
+            "scopes" => Ok(json!({
+                "scopes": [
+                    { "name": "Stack", "variablesReference": STACK_REFERENCE, "expensive": false },
+                    { "name": "Globals", "variablesReference": GLOBALS_REFERENCE, "expensive": false },
+                ]
+            })),
+            "variables" => {
+                let variables: Vec<_> = match arguments["variablesReference"].as_i64() {
+                    Some(STACK_REFERENCE) => step
+                        .stack()
+                        .iter()
+                        .rev()
+                        .enumerate()
+                        .map(|(index, value)| variable(&index.to_string(), value.to_string()))
+                        .collect(),
+                    Some(GLOBALS_REFERENCE) => ('a'..='z')
+                        .map(|name| {
+                            variable(&name.to_string(), display(step.global(name).as_ref()))
+                        })
+                        .collect(),
+                    _ => return Err("Unknown variables reference".to_string()),
+                };
+                Ok(json!({ "variables": variables }))
+            }
+            "continue" => {
+                *action = Action::Resume(Resume::Continue);
+                Ok(json!({ "allThreadsContinued": true }))
+            }
+            "next" => {
+                *action = Action::Resume(Resume::Next(depth));
+                Ok(json!({}))
+            }
+            "stepIn" => {
+                *action = Action::Resume(Resume::Step);
+                Ok(json!({}))
+            }
+            "stepOut" => {
+                *action = Action::Resume(Resume::Finish(depth));
+                Ok(json!({}))
+            }
+            command => Err(format!("Unsupported request {command}")),
+        }
+    }
+
+    fn launch(&mut self, arguments: &Json) -> Result<Json, String> {
+        if self.program.is_some() {
+            return Err("A program has already been launched".to_string());
+        }
+        let path = arguments["program"]
+            .as_str()
+            .ok_or("Expected the path of the program to launch")?;
+        let source =
+            std::fs::read_to_string(path).map_err(|e| format!("Failed to read {path}: {e}"))?;
+        let filename = std::path::Path::new(path)
+            .file_name()
+            .map_or_else(|| path.to_string(), |s| s.to_string_lossy().to_string());
+        let ast = match parse(&source).into_result() {
+            Ok(ast) => ast,
+            Err(errors) => {
+                let debugger = Debugger::new(filename, source.clone(), Vec::new());
+                let (line, column) = location(&debugger.source, errors[0].span().start);
+                return Err(format!(
+                    "{}:{line}:{column}: {}",
+                    debugger.filename, errors[0]
+                ));
+            }
+        };
+        let debugger = Debugger::new(filename, source, ast);
+        let mut lambdas = Vec::new();
+        name_lambdas(&debugger, &debugger.ast, &mut lambdas);
+        self.program = Some(Program {
+            debugger,
+            path: path.to_string(),
+            lambdas,
+        });
+        if arguments["stopOnEntry"].as_bool().unwrap_or(false) {
+            self.resume = Resume::Step;
+        }
+        // Breakpoints can be set now
+        self.connection.borrow_mut().event("initialized", json!({}));
+        Ok(json!({}))
+    }
+
+    fn set_breakpoints(&mut self, arguments: &Json) -> Result<Json, String> {
+        let program = self.program.as_ref().ok_or("Launch a program first")?;
+        let path = arguments["source"]["path"].as_str().unwrap_or_default();
+        let same_file = match (
+            std::fs::canonicalize(path),
+            std::fs::canonicalize(&program.path),
+        ) {
+            (Ok(path), Ok(program)) => path == program,
+            _ => path == program.path,
+        };
+        let requested = arguments["breakpoints"]
+            .as_array()
+            .cloned()
+            .unwrap_or_default();
+
+        let mut breakpoints = Vec::new();
+        let mut resolved = Vec::new();
+        for requested in &requested {
+            let line = requested["line"]
+                .as_u64()
+                .map(|line| self.line_from_client(line));
+            let column = requested["column"]
+                .as_u64()
+                .map(|column| self.column_from_client(column));
+            let span = line
+                .filter(|_| same_file)
+                .and_then(|line| program.debugger.resolve(line, column));
+            self.numbered += 1;
+            let Some(span) = span else {
+                let message = if same_file {
+                    "No instruction here"
+                } else {
+                    "Not the program being debugged"
+                };
+                breakpoints.push(json!({
+                    "id": self.numbered,
+                    "verified": false,
+                    "message": message,
+                }));
+                continue;
+            };
+            let (line, column) = location(&program.debugger.source, span.start);
+            breakpoints.push(json!({
+                "id": self.numbered,
+                "verified": true,
+                "line": self.line_to_client(line),
+                "column": self.column_to_client(column),
+            }));
+            resolved.push((self.numbered, span));
+        }
+        // Breakpoints are set a whole file at a time
+        if same_file {
+            self.breakpoints = resolved;
+        }
+        Ok(json!({ "breakpoints": breakpoints }))
+    }
+
+    fn line_to_client(&self, line: usize) -> usize {
+        line - usize::from(!self.lines_start_at_1)
+    }
+
+    fn column_to_client(&self, column: usize) -> usize {
+        column - usize::from(!self.columns_start_at_1)
+    }
+
+    fn line_from_client(&self, line: u64) -> usize {
+        line as usize + usize::from(!self.lines_start_at_1)
+    }
+
+    fn column_from_client(&self, column: u64) -> usize {
+        column as usize + usize::from(!self.columns_start_at_1)
+    }
+}
+
+impl Program {
+    /// What to call the frame of a call that is at `span`: the name of the
+    /// innermost lambda around it.
+    fn frame_name(&self, span: SimpleSpan<usize>) -> &str {
+        self.lambdas
+            .iter()
+            .filter(|(lambda, _)| lambda.start <= span.start && span.end <= lambda.end)
+            .min_by_key(|(lambda, _)| lambda.end - lambda.start)
+            .map_or("main", |(_, name)| name)
+    }
+}
+
+/// Names every lambda: after the variable it is stored in straight away, as
+/// in `[...]f:`, or else after where it is.
+fn name_lambdas(
+    debugger: &Debugger,
+    instructions: &[Spanned<FalseInstruction>],
+    lambdas: &mut Vec<(SimpleSpan<usize>, String)>,
+) {
+    struct Names<'a> {
+        source: &'a str,
+        lambdas: &'a mut Vec<(SimpleSpan<usize>, String)>,
+    }
+
+    impl Visitor<'_> for Names<'_> {
+        fn visit_block(&mut self, block: &[Spanned<FalseInstruction>]) {
+            use FalseInstruction::*;
+
+            for (index, spanned) in block.iter().enumerate() {
+                if let Lambda(_) = spanned.instruction() {
+                    let following = block.get(index + 1..index + 3);
+                    let name = match following
+                        .map(|following| [following[0].instruction(), following[1].instruction()])
+                    {
+                        Some([Name(name), Store]) => name.to_string(),
+                        _ => {
+                            let (line, column) = location(self.source, spanned.span().start);
+                            format!("lambda at {line}:{column}")
+                        }
+                    };
+                    self.lambdas.push((spanned.span(), name));
+                }
+                self.visit_instruction(spanned);
+            }
+        }
+    }
+
+    Names {
+        source: &debugger.source,
+        lambdas,
+    }
+    .visit_block(instructions);
+}
+
+fn variable(name: &str, value: String) -> Json {
+    json!({ "name": name, "value": value, "variablesReference": 0 })
+}
@@ -0,0 +1,256 @@
+//! The source formatter behind `falsy fmt`.
+//!
+//! Works on the [concrete syntax tree](crate::cst), so comments are kept.
+//! Instructions are separated by one space, except that a variable's name
+//! is written together with the `:`, `;` or `;!` after it, and a lambda
+//! with the name it is stored in or the `!` that calls it, as in `[2*]f:`
+//! and `f;!`. Line breaks in the source are kept, with blank lines kept as
+//! one, and lines are wrapped to fit in [`WIDTH`] columns. A lambda that
+//! doesn't fit on its line, or that has line breaks between what is in it,
+//! is written over several lines with its body indented.
+//!
+//! Formatting only ever adds or removes whitespace between instructions, so
+//! the formatted source parses to the same instructions. Formatting it again
+//! doesn't change it: the line breaks added are kept, and break it in the
+//! same places.
+
+use crate::cst::{self, SyntaxElement, SyntaxError, SyntaxKind, SyntaxNode};
+
+/// Columns that lines are wrapped to fit in.
+pub const WIDTH: usize = 80;
+const INDENT: usize = 4;
+
+/// Formats a program, or gives the syntax errors that stop it being
+/// formatted.
+pub fn format(source: &str) -> Result<String, Vec<SyntaxError>> {
+    let tree = cst::parse(source);
+    let errors = tree.errors();
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    let mut formatter = Formatter {
+        output: String::new(),
+        column: 0,
+        line_start: true,
+    };
+    formatter.block(tree.root().children(), 0);
+    let mut formatted = formatter.output;
+    if !formatted.is_empty() {
+        formatted.push('\n');
+    }
+    Ok(formatted)
+}
+
+/// What a block is laid out from.
+enum Item<'t> {
+    /// Elements written together.
+    Word(Vec<&'t SyntaxElement>),
+    /// A line break in the source, and whether there was a blank line.
+    Break { blank: bool },
+}
+
+/// Splits a block into words and the line breaks between them.
+fn items(elements: &[SyntaxElement]) -> Vec<Item<'_>> {
+    let instructions: Vec<_> = elements.iter().filter(|e| !e.kind().is_trivia()).collect();
+    let mut items = Vec::new();
+    let mut next: usize = 0;
+    for element in elements {
+        match element {
+            SyntaxElement::Token(token) if token.kind() == SyntaxKind::Whitespace => {
+                let newlines = token.text().matches('\n').count();
+                if newlines > 0 {
+                    items.push(Item::Break {
+                        blank: newlines > 1,
+                    });
+                }
+            }
+            _ if element.kind() == SyntaxKind::Comment => items.push(Item::Word(vec![element])),
+            _ => {
+                let previous = next.checked_sub(1).map(|i| instructions[i]);
+                let after = instructions.get(next + 1).copied();
+                next += 1;
+                // Only whitespace on one line can come between joined ones
+                if let (Some(previous), Some(Item::Word(word))) = (previous, items.last_mut()) {
+                    if std::ptr::eq(*word.last().unwrap(), previous)
+                        && joined(previous, element, after)
+                    {
+                        word.push(element);
+                        continue;
+                    }
+                }
+                items.push(Item::Word(vec![element]));
+            }
+        }
+    }
+
+    // Breaks only count between words
+    while matches!(items.last(), Some(Item::Break { .. })) {
+        items.pop();
+    }
+    let leading = items
+        .iter()
+        .take_while(|item| matches!(item, Item::Break { .. }))
+        .count();
+    items.drain(..leading);
+    items
+}
+
+/// Whether `element` is written straight after `previous`, with `after`
+/// the instruction after it.
+fn joined(
+    previous: &SyntaxElement,
+    element: &SyntaxElement,
+    after: Option<&SyntaxElement>,
+) -> bool {
+    let text = |element: &SyntaxElement| match element {
+        SyntaxElement::Token(token) => token.text().to_string(),
+        SyntaxElement::Node(_) => String::new(),
+    };
+    let lambda = previous.kind() == SyntaxKind::Lambda;
+    match (previous.kind(), element.kind(), text(element).as_str()) {
+        (SyntaxKind::Name, SyntaxKind::Operator, ":" | ";") => true,
+        (SyntaxKind::Operator, SyntaxKind::Operator, "!") => text(previous) == ";",
+        (_, SyntaxKind::Operator, "!") => lambda,
+        (_, SyntaxKind::Name, _) => lambda && after.is_some_and(|after| text(after) == ":"),
+        _ => false,
+    }
+}
+
+struct Formatter {
+    output: String,
+    /// Characters on the current line so far.
+    column: usize,
+    /// Whether the current line has nothing on it yet.
+    line_start: bool,
+}
+
+impl Formatter {
+    fn write(&mut self, text: &str) {
+        self.output.push_str(text);
+        match text.rfind('\n') {
+            Some(newline) => self.column = text[newline + 1..].chars().count(),
+            None => self.column += text.chars().count(),
+        }
+        self.line_start = false;
+    }
+
+    /// Starts a new line at `indent`, with a blank line before it if asked.
+    fn newline(&mut self, indent: usize, blank: bool) {
+        self.output.push('\n');
+        if blank {
+            self.output.push('\n');
+        }
+        self.output.extend(std::iter::repeat_n(' ', indent));
+        self.column = indent;
+        self.line_start = true;
+    }
+
+    /// Lays out a block's words, starting at the current column, with
+    /// lines after the first at `indent`.
+    fn block(&mut self, elements: &[SyntaxElement], indent: usize) {
+        for item in items(elements) {
+            match item {
+                Item::Break { blank } => self.newline(indent, blank),
+                Item::Word(word) => {
+                    if !self.line_start {
+                        let fits = word_width(&word).is_some_and(|w| self.column + 1 + w <= WIDTH);
+                        if fits {
+                            self.write(" ");
+                        } else {
+                            self.newline(indent, false);
+                        }
+                    }
+                    for element in word {
+                        self.element(element, indent);
+                    }
+                }
+            }
+        }
+    }
+
+    fn element(&mut self, element: &SyntaxElement, indent: usize) {
+        match element {
+            SyntaxElement::Token(token) => self.write(token.text()),
+            SyntaxElement::Node(lambda) if lambda.kind() == SyntaxKind::Lambda => {
+                self.lambda(lambda, indent)
+            }
+            SyntaxElement::Node(node) => {
+                for child in node.children() {
+                    match child {
+                        SyntaxElement::Node(lambda) => self.lambda(lambda, indent),
+                        // The `?` or `#`
+                        SyntaxElement::Token(token) => self.write(token.text()),
+                    }
+                }
+            }
+        }
+    }
+
+    fn lambda(&mut self, lambda: &SyntaxNode, indent: usize) {
+        let flat = flat_width(lambda).is_some_and(|w| self.column + w <= WIDTH);
+        let body = &lambda.children()[1..lambda.children().len() - 1];
+        if flat || items(body).is_empty() {
+            self.write("[");
+            self.flat(body);
+            self.write("]");
+        } else {
+            self.write("[");
+            self.newline(indent + INDENT, false);
+            self.block(body, indent + INDENT);
+            self.newline(indent, false);
+            self.write("]");
+        }
+    }
+
+    /// Writes a block on one line, which it fits on, and so do the
+    /// lambdas in it.
+    fn flat(&mut self, elements: &[SyntaxElement]) {
+        for (i, item) in items(elements).into_iter().enumerate() {
+            let Item::Word(word) = item else {
+                unreachable!("Blocks with line breaks aren't flat")
+            };
+            if i > 0 {
+                self.write(" ");
+            }
+            for element in word {
+                self.element(element, 0);
+            }
+        }
+    }
+}
+
+/// Width of a word written on one line, or `None` if it can't be.
+fn word_width(word: &[&SyntaxElement]) -> Option<usize> {
+    word.iter().map(|element| element_width(element)).sum()
+}
+
+fn element_width(element: &SyntaxElement) -> Option<usize> {
+    match element {
+        SyntaxElement::Token(token) => {
+            (!token.text().contains('\n')).then(|| token.text().chars().count())
+        }
+        SyntaxElement::Node(lambda) if lambda.kind() == SyntaxKind::Lambda => flat_width(lambda),
+        SyntaxElement::Node(node) => node
+            .children()
+            .iter()
+            .map(|child| match child {
+                SyntaxElement::Node(lambda) => flat_width(lambda),
+                SyntaxElement::Token(token) => Some(token.text().chars().count()),
+            })
+            .sum(),
+    }
+}
+
+/// Width of a lambda written on one line, or `None` if it has line breaks.
+fn flat_width(lambda: &SyntaxNode) -> Option<usize> {
+    let body = &lambda.children()[1..lambda.children().len() - 1];
+    let items = items(body);
+    let mut width = 2 + items.len().saturating_sub(1);
+    for item in &items {
+        match item {
+            Item::Word(word) => width += word_width(word)?,
+            Item::Break { .. } => return None,
+        }
+    }
+    Some(width)
+}
@@ -1,9 +1,21 @@
-use std::{collections::HashMap, io::Read};
+use std::{collections::HashMap, io::Read, ops::ControlFlow};
 
 use chumsky::span::SimpleSpan;
 
 use crate::ast::{FalseInstruction, Spanned};
 
+mod closure;
+mod code;
+#[cfg(feature = "jit")]
+mod jit;
+mod memo;
+
+#[cfg(feature = "jit")]
+pub use jit::JitStats;
+pub use memo::MemoStats;
+
+use code::{CodeTable, LambdaRef, Program};
+
 pub struct InterpreterRuntimeError {
     span: SimpleSpan<usize>,
     reason: String,
@@ -23,7 +35,7 @@ impl InterpreterRuntimeError {
         Self { span, reason }
     }
     pub fn span(&self) -> SimpleSpan<usize> {
-        self.span.clone()
+        self.span
     }
     pub fn reason(&self) -> &str {
         &self.reason
@@ -32,12 +44,12 @@ impl InterpreterRuntimeError {
 impl std::error::Error for InterpreterRuntimeError {}
 
 #[derive(Debug, Clone)]
-enum FalseStoreableValue<'a> {
+enum FalseStoreableValue {
     StoredInteger(i32),
-    StoredLambda(&'a [Spanned<FalseInstruction>]),
+    StoredLambda(LambdaRef),
 }
 
-impl<'a> FalseStoreableValue<'a> {
+impl FalseStoreableValue {
     fn type_name(&self) -> &str {
         match self {
             Self::StoredInteger(_) => "Integer",
@@ -47,11 +59,11 @@ impl<'a> FalseStoreableValue<'a> {
 }
 
 #[derive(Debug, Clone)]
-enum FalseStackEntry<'a> {
+enum FalseStackEntry {
     VariableReference(char),
-    StoredValue(FalseStoreableValue<'a>),
+    StoredValue(FalseStoreableValue),
 }
-impl<'a> FalseStackEntry<'a> {
+impl FalseStackEntry {
     fn type_name(&self) -> &str {
         match self {
             Self::VariableReference(_) => "VariableReference",
@@ -60,23 +72,91 @@ impl<'a> FalseStackEntry<'a> {
     }
 }
 
-struct FalseContext<'input_closure, 'output_closure, 'a> {
-    stack: Vec<FalseStackEntry<'a>>,
-    global_scope: HashMap<char, FalseStoreableValue<'a>>,
-    on_input: Box<dyn 'input_closure + FnMut() -> Option<u8>>,
-    on_output: Box<dyn 'output_closure + FnMut(&str)>,
+type InputFn<'closure> = Box<dyn 'closure + FnMut() -> Option<u8>>;
+type OutputFn<'closure> = Box<dyn 'closure + FnMut(&str)>;
+type StepFn<'closure> = Box<dyn 'closure + FnMut(&Step) -> ControlFlow<()>>;
+
+struct FalseContext<'input_closure, 'output_closure> {
+    stack: Vec<FalseStackEntry>,
+    global_scope: HashMap<char, FalseStoreableValue>,
+    on_input: InputFn<'input_closure>,
+    on_output: OutputFn<'output_closure>,
+    memo: Option<memo::Memo>,
+    code: CodeTable,
+    on_step: Option<StepFn<'output_closure>>,
+    /// How many lambda calls deep the running instruction is.
+    depth: usize,
+    #[cfg(feature = "jit")]
+    jit: Option<jit::Jit>,
+}
+
+/// What a successful run leaves behind besides its output.
+#[derive(Debug, Clone, Default)]
+pub struct RunOutcome {
+    memo_stats: Option<MemoStats>,
+    #[cfg(feature = "jit")]
+    jit_stats: Option<JitStats>,
+}
+impl RunOutcome {
+    /// Cache statistics, if the run had memoisation turned on.
+    pub fn memo_stats(&self) -> Option<MemoStats> {
+        self.memo_stats
+    }
+    /// JIT statistics, if the run used the JIT backend.
+    #[cfg(feature = "jit")]
+    pub fn jit_stats(&self) -> Option<JitStats> {
+        self.jit_stats
+    }
+}
+
+/// How `run_program` runs the program.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Backend {
+    /// Walk the AST, matching on each instruction as it runs.
+    #[default]
+    Tree,
+    /// Compile the AST into closures once, then run those.
+    Closures,
+    /// Walk the AST, but compile hot integer code to native code.
+    #[cfg(feature = "jit")]
+    Jit,
+}
+
+impl std::str::FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tree" => Ok(Self::Tree),
+            "closures" => Ok(Self::Closures),
+            #[cfg(feature = "jit")]
+            "jit" => Ok(Self::Jit),
+            other => Err(format!("Unknown backend {other:?}")),
+        }
+    }
 }
 
 // Just a builder
 pub struct Interpreter<'input_closure, 'output_closure> {
-    on_input: Option<Box<dyn 'input_closure + FnMut() -> Option<u8>>>,
-    on_output: Option<Box<dyn 'output_closure + FnMut(&str)>>,
+    on_input: Option<InputFn<'input_closure>>,
+    on_output: Option<OutputFn<'output_closure>>,
+    on_step: Option<StepFn<'output_closure>>,
+    memo_max_entries: Option<usize>,
+    backend: Backend,
+}
+impl Default for Interpreter<'_, '_> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 impl<'input_closure, 'output_closure> Interpreter<'input_closure, 'output_closure> {
     pub fn new() -> Self {
         Self {
             on_input: None,
             on_output: None,
+            on_step: None,
+            memo_max_entries: None,
+            backend: Backend::Tree,
         }
     }
     pub fn on_input<F: 'input_closure + FnMut() -> Option<u8>>(mut self, f: F) -> Self {
@@ -87,10 +167,31 @@ impl<'input_closure, 'output_closure> Interpreter<'input_closure, 'output_closur
         self.on_output = Some(Box::new(f));
         self
     }
+    /// Calls `f` before every instruction runs, and after every store to a
+    /// global, so a debugger can follow the program. Breaking from `f` stops
+    /// the program with an error. Programs that are stepped through always
+    /// walk the AST, without memoization.
+    pub fn on_step<F: 'output_closure + FnMut(&Step) -> ControlFlow<()>>(mut self, f: F) -> Self {
+        self.on_step = Some(Box::new(f));
+        self
+    }
+    /// Caches the results of pure lambdas with a fixed stack effect, keeping
+    /// at most `max_entries` results.
+    pub fn memoize(mut self, max_entries: usize) -> Self {
+        self.memo_max_entries = Some(max_entries);
+        self
+    }
+    pub fn backend(mut self, backend: Backend) -> Self {
+        self.backend = backend;
+        self
+    }
     pub fn run_program(
         self,
         ast: Vec<Spanned<FalseInstruction>>,
-    ) -> Result<(), InterpreterRuntimeError> {
+    ) -> Result<RunOutcome, InterpreterRuntimeError> {
+        let mut code = CodeTable::default();
+        let program = code.load(ast);
+        let stepping = self.on_step.is_some();
         let mut ctx = {
             FalseContext {
                 on_input: self
@@ -99,209 +200,544 @@ impl<'input_closure, 'output_closure> Interpreter<'input_closure, 'output_closur
                 on_output: self.on_output.unwrap_or_else(|| Box::new(default_output)),
                 stack: Vec::new(),
                 global_scope: HashMap::new(),
+                memo: self
+                    .memo_max_entries
+                    .filter(|_| !stepping)
+                    .map(|max_entries| memo::Memo::new(&program, max_entries)),
+                code,
+                on_step: self.on_step,
+                depth: 0,
+                #[cfg(feature = "jit")]
+                jit: None,
             }
         };
-        run_instructions(&ast, &mut ctx)
+        let backend = if stepping {
+            Backend::Tree
+        } else {
+            self.backend
+        };
+        match backend {
+            Backend::Tree => run_instructions(program.instructions(), &program, &mut ctx)?,
+            Backend::Closures => closure::Code::compile(&program).run(&mut ctx)?,
+            #[cfg(feature = "jit")]
+            Backend::Jit => {
+                ctx.jit = Some(jit::Jit::default());
+                run_instructions(program.instructions(), &program, &mut ctx)?
+            }
+        }
+        Ok(RunOutcome {
+            memo_stats: ctx.memo.map(|memo| memo.stats()),
+            #[cfg(feature = "jit")]
+            jit_stats: ctx.jit.map(|jit| jit.stats()),
+        })
+    }
+}
+
+/// A value on the stack or in a global, as seen from outside the
+/// interpreter.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Value {
+    Integer(i32),
+    Lambda,
+    Name(char),
+}
+
+impl std::fmt::Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Integer(v) => write!(f, "{v}"),
+            Self::Lambda => write!(f, "[…]"),
+            Self::Name(c) => write!(f, "{c}"),
+        }
+    }
+}
+
+impl From<&FalseStoreableValue> for Value {
+    fn from(value: &FalseStoreableValue) -> Self {
+        match value {
+            FalseStoreableValue::StoredInteger(v) => Self::Integer(*v),
+            FalseStoreableValue::StoredLambda(_) => Self::Lambda,
+        }
+    }
+}
+
+impl From<&FalseStackEntry> for Value {
+    fn from(entry: &FalseStackEntry) -> Self {
+        match entry {
+            FalseStackEntry::VariableReference(c) => Self::Name(*c),
+            FalseStackEntry::StoredValue(value) => value.into(),
+        }
+    }
+}
+
+/// Runs programs one after another against the same stack and globals, as
+/// in a REPL.
+///
+/// Lambdas can outlive the program that defined them, so every program run
+/// is kept until the session is reset.
+pub struct Session<'input_closure, 'output_closure> {
+    ctx: FalseContext<'input_closure, 'output_closure>,
+}
+
+impl<'input_closure, 'output_closure> Interpreter<'input_closure, 'output_closure> {
+    /// Starts a session with this interpreter's input and output. Sessions
+    /// always walk the AST, without memoization.
+    pub fn session(self) -> Session<'input_closure, 'output_closure> {
+        Session {
+            ctx: FalseContext {
+                on_input: self
+                    .on_input
+                    .unwrap_or_else(|| Box::new(default_read_input)),
+                on_output: self.on_output.unwrap_or_else(|| Box::new(default_output)),
+                stack: Vec::new(),
+                global_scope: HashMap::new(),
+                memo: None,
+                code: CodeTable::default(),
+                on_step: self.on_step,
+                depth: 0,
+                #[cfg(feature = "jit")]
+                jit: None,
+            },
+        }
+    }
+}
+
+impl Session<'_, '_> {
+    /// Runs a program from where the previous one left off. If it fails,
+    /// the stack and globals are left as they were at the error.
+    pub fn run(
+        &mut self,
+        ast: Vec<Spanned<FalseInstruction>>,
+    ) -> Result<(), InterpreterRuntimeError> {
+        let program = self.ctx.code.load(ast);
+        self.ctx.depth = 0;
+        run_instructions(program.instructions(), &program, &mut self.ctx)
+    }
+
+    /// The stack, from the bottom up.
+    pub fn stack(&self) -> Vec<Value> {
+        stack_values(&self.ctx.stack)
+    }
+
+    /// The globals that have been stored, by name.
+    pub fn globals(&self) -> Vec<(char, Value)> {
+        global_values(&self.ctx.global_scope)
+    }
+
+    /// Empties the stack and forgets every global.
+    pub fn reset(&mut self) {
+        self.ctx.stack.clear();
+        self.ctx.global_scope.clear();
+        // Nothing refers to the programs run so far any more
+        self.ctx.code = CodeTable::default();
+    }
+}
+
+fn stack_values(stack: &[FalseStackEntry]) -> Vec<Value> {
+    stack.iter().map(Value::from).collect()
+}
+
+fn global_values(global_scope: &HashMap<char, FalseStoreableValue>) -> Vec<(char, Value)> {
+    let mut globals: Vec<_> = global_scope
+        .iter()
+        .map(|(name, value)| (*name, value.into()))
+        .collect();
+    globals.sort_by_key(|(name, _)| *name);
+    globals
+}
+
+/// What a program being stepped through is about to do, or has just done.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StepEvent {
+    /// The instruction at the step's span is about to run.
+    Instruction,
+    /// The instruction at the step's span stored to this global.
+    Store(char),
+}
+
+/// Where a program being stepped through has got to, as passed to the hook
+/// set with [`Interpreter::on_step`].
+pub struct Step<'c> {
+    event: StepEvent,
+    span: SimpleSpan<usize>,
+    depth: usize,
+    stack: &'c [FalseStackEntry],
+    global_scope: &'c HashMap<char, FalseStoreableValue>,
+}
+
+impl Step<'_> {
+    pub fn event(&self) -> StepEvent {
+        self.event
+    }
+
+    pub fn span(&self) -> SimpleSpan<usize> {
+        self.span
+    }
+
+    /// How many lambda calls deep the instruction is, 0 at the top level.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// The stack, from the bottom up.
+    pub fn stack(&self) -> Vec<Value> {
+        stack_values(self.stack)
+    }
+
+    /// The globals that have been stored, by name.
+    pub fn globals(&self) -> Vec<(char, Value)> {
+        global_values(self.global_scope)
+    }
+
+    /// The value of a global, if it has been stored.
+    pub fn global(&self, name: char) -> Option<Value> {
+        self.global_scope.get(&name).map(Value::from)
     }
 }
 
-fn run_instructions<'a>(
-    instructions: &'a [Spanned<FalseInstruction>],
-    ctx: &mut FalseContext<'_, '_, 'a>,
+/// Calls the step hook, if there is one.
+#[cold]
+#[inline(never)]
+fn step(
+    ctx: &mut FalseContext,
+    event: StepEvent,
+    span: SimpleSpan,
+) -> Result<(), InterpreterRuntimeError> {
+    // Taken out for the call, so the hook can see the rest of the context
+    let Some(mut on_step) = ctx.on_step.take() else {
+        return Ok(());
+    };
+    let flow = on_step(&Step {
+        event,
+        span,
+        depth: ctx.depth,
+        stack: &ctx.stack,
+        global_scope: &ctx.global_scope,
+    });
+    ctx.on_step = Some(on_step);
+    match flow {
+        ControlFlow::Continue(()) => Ok(()),
+        ControlFlow::Break(()) => Err(InterpreterRuntimeError::new(
+            span,
+            "Stopped by the debugger".to_string(),
+        )),
+    }
+}
+
+/// Runs `instructions`, which are part of `program`.
+fn run_instructions(
+    instructions: &[Spanned<FalseInstruction>],
+    program: &Program,
+    ctx: &mut FalseContext,
 ) -> Result<(), InterpreterRuntimeError> {
     for instruction in instructions {
-        apply_instruction(instruction, ctx)?
+        apply_instruction(instruction, program, ctx)?
     }
     Ok(())
 }
 
-fn apply_instruction<'a>(
-    spanned: &'a Spanned<FalseInstruction>,
-    ctx: &mut FalseContext<'_, '_, 'a>,
+fn run_lambda(lambda: LambdaRef, ctx: &mut FalseContext) -> Result<(), InterpreterRuntimeError> {
+    let program = ctx.code.program(lambda).clone();
+    let body = program.body(lambda);
+    #[cfg(feature = "jit")]
+    if let Some(jit) = ctx.jit.as_mut() {
+        if jit.run_lambda(body, &mut ctx.stack) {
+            return Ok(());
+        }
+    }
+    ctx.depth += 1;
+    let result = run_instructions(body, &program, ctx);
+    ctx.depth -= 1;
+    result
+}
+
+macro_rules! runtime_error {
+    ($span:expr, $($reason:tt)*) => {
+        InterpreterRuntimeError::new($span, format!($($reason)*))
+    };
+}
+
+macro_rules! error_factory {
+    ($span:expr, $($reason:tt)*) => {
+        || runtime_error!($span, $($reason)*)
+    };
+}
+
+fn apply_instruction(
+    spanned: &Spanned<FalseInstruction>,
+    program: &Program,
+    ctx: &mut FalseContext,
 ) -> Result<(), InterpreterRuntimeError> {
     use FalseInstruction::*;
     use FalseStackEntry::*;
     use FalseStoreableValue::*;
 
     let span = spanned.span();
-
-    macro_rules! runtime_error {
-        ($reason:expr) => {
-            InterpreterRuntimeError::new(span, format!($reason))
-        };
-        ($reason:expr, $($arg:expr),*) => {
-            InterpreterRuntimeError::new(span, format!($reason, $($arg),*))
-        };
-    }
-
-    macro_rules! error_factory {
-        ($reason:expr) => {
-            || runtime_error!($reason)
-        };
-        ($reason:expr, $($arg:expr),*) => {
-            || runtime_error!($reason, $($arg),*)
-        };
+    if ctx.on_step.is_some() {
+        step(ctx, StepEvent::Instruction, span)?;
     }
 
     match spanned.instruction() {
         Name(c) => ctx.stack.push(VariableReference(*c)),
         PushInt(v) => ctx.stack.push(StoredValue(StoredInteger(*v))),
         PushChar(c) => ctx.stack.push(StoredValue(StoredInteger((*c).into()))),
-        Dup => ctx.stack.push(
-            ctx.stack
-                .last()
-                .ok_or_else(error_factory!("Stack is empty"))?
-                .clone(),
-        ),
+        Dup => dup(ctx, span)?,
         Drop => {
             let _ = ctx.stack.pop();
         }
-        Swap => {
-            let head = ctx
-                .stack
-                .pop()
-                .ok_or_else(error_factory!("Stack is empty"))?;
-            let next = ctx
-                .stack
-                .pop()
-                .ok_or_else(error_factory!("Stack only has 1 value"))?;
-            ctx.stack.extend_from_slice(&[head, next]);
-        }
-        Rot => {
-            let first = ctx
-                .stack
-                .pop()
-                .ok_or_else(error_factory!("Stack is empty"))?;
-            let second = ctx
-                .stack
-                .pop()
-                .ok_or_else(error_factory!("Stack only has 1 value"))?;
-            let third = ctx
-                .stack
-                .pop()
-                .ok_or_else(error_factory!("Stack only has 2 values"))?;
-            ctx.stack.extend_from_slice(&[first, second, third]);
-        }
-        Pick => {
-            let head = ctx
-                .stack
-                .pop()
-                .ok_or_else(error_factory!("Stack is empty"))?;
-            let index = match head {
-                StoredValue(StoredInteger(i)) => i,
-                other => return Err(runtime_error!("Unexpected index for ø (PICK): {other:#?}")),
-            };
-            if index < 0 || index as usize >= ctx.stack.len() {
-                return Err(runtime_error!("Index out of range for ø (PICK): {index}"));
-            }
-            let index = ctx.stack.len() - 1 - index as usize;
-            ctx.stack.push(ctx.stack[index as usize].clone());
-        }
+        Swap => swap(ctx, span)?,
+        Rot => rot(ctx, span)?,
+        Pick => pick(ctx, span)?,
         Add => binary_op(ctx, span, |a, b| a + b)?,
         Sub => binary_op(ctx, span, |a, b| a - b)?,
         Mul => binary_op(ctx, span, |a, b| a * b)?,
-        Div => binary_op(ctx, span, |a, b| a / b)?,
+        Div => div(ctx, span)?,
         Neg => unary_op(ctx, span, |x| -x)?,
         BitAnd => binary_op(ctx, span, |a, b| a & b)?,
         BitOr => binary_op(ctx, span, |a, b| a | b)?,
         BitNot => unary_op(ctx, span, |x| !x)?,
         Gt => binary_op(ctx, span, |a, b| if a > b { -1 } else { 0 })?,
         Eq => binary_op(ctx, span, |a, b| if a == b { -1 } else { 0 })?,
-        Lambda(instructions) => ctx.stack.push(StoredValue(StoredLambda(instructions))),
-        Execute => {
-            let lambda = match ctx
-                .stack
-                .pop()
-                .ok_or_else(error_factory!("Stack is empty"))?
-            {
-                StoredValue(StoredLambda(v)) => v,
-                other => {
-                    return Err(runtime_error!(
-                        "Expected lambda for Execute, got {}",
-                        other.type_name()
-                    ));
-                }
-            };
-            run_instructions(lambda, ctx)?;
-        }
+        Lambda(body) => ctx
+            .stack
+            .push(StoredValue(StoredLambda(program.lambda(body)))),
+        Execute => execute(ctx, span, run_lambda)?,
         ConditionalExecute(vec) => {
             let condition = pop_int(ctx, span)?;
             if condition != 0 {
-                run_instructions(vec, ctx)?;
+                run_instructions(vec, program, ctx)?;
             }
         }
-        WhileLoop(condition, body) => loop {
-            run_instructions(&condition, ctx)?;
-            let condition_result = pop_int(ctx, span)?;
-            if condition_result == 0 {
-                break;
-            }
-            run_instructions(&body, ctx)?;
-        },
-        Store => {
-            let reference = match ctx
-                .stack
-                .pop()
-                .ok_or_else(error_factory!("Stack is empty"))?
-            {
-                VariableReference(v) => v,
-                _ => {
-                    return Err(runtime_error!("Store (:) must be preceded by a name"));
+        WhileLoop(condition, body) => {
+            #[cfg(feature = "jit")]
+            if let Some(jit) = ctx.jit.as_mut() {
+                if jit.run_loop(spanned, &mut ctx.stack) {
+                    return Ok(());
                 }
-            };
-            let value = match ctx
-                .stack
-                .pop()
-                .ok_or_else(error_factory!("Stack only has 1 value"))?
-            {
-                StoredValue(v) => v,
-                VariableReference(_) => {
-                    return Err(runtime_error!("Names cannot be stored in names"));
+            }
+            loop {
+                run_instructions(condition, program, ctx)?;
+                let condition_result = pop_int(ctx, span)?;
+                if condition_result == 0 {
+                    break;
                 }
-            };
-            ctx.global_scope.insert(reference, value);
+                run_instructions(body, program, ctx)?;
+            }
         }
-        Fetch => {
-            let reference = match ctx
-                .stack
-                .pop()
-                .ok_or_else(error_factory!("Stack is empty"))?
-            {
-                VariableReference(v) => v,
-                _ => {
-                    return Err(runtime_error!("Fetch (;) must be preceded by a name"));
-                }
+        Store if ctx.on_step.is_some() => {
+            let name = match ctx.stack.last() {
+                Some(VariableReference(name)) => Some(*name),
+                _ => None,
             };
-            let value = ctx
-                .global_scope
-                .get(&reference)
-                .ok_or_else(|| runtime_error!("Name {reference} not found in global scope"))?
-                .clone();
-            ctx.stack.push(StoredValue(value));
+            store(ctx, span)?;
+            if let Some(name) = name {
+                step(ctx, StepEvent::Store(name), span)?;
+            }
         }
-        ReadChar => {
-            let value = match (*ctx.on_input)() {
-                Some(v) => v as i32,
-                None => -1,
-            };
-            ctx.stack.push(StoredValue(StoredInteger(value)));
+        Store => store(ctx, span)?,
+        Fetch => fetch(ctx, span)?,
+        ReadChar => read_char(ctx),
+        WriteChar => write_char(ctx, span)?,
+        WriteStr(s) => (*ctx.on_output)(s),
+        WriteInt => write_int(ctx, span)?,
+        Flush => {}
+    };
+
+    Ok(())
+}
+
+// The instructions below are shared by every backend, so they all fail
+// with the same errors.
+
+fn dup(ctx: &mut FalseContext, span: SimpleSpan) -> Result<(), InterpreterRuntimeError> {
+    ctx.stack.push(
+        ctx.stack
+            .last()
+            .ok_or_else(error_factory!(span, "Stack is empty"))?
+            .clone(),
+    );
+    Ok(())
+}
+
+fn swap(ctx: &mut FalseContext, span: SimpleSpan) -> Result<(), InterpreterRuntimeError> {
+    let head = ctx
+        .stack
+        .pop()
+        .ok_or_else(error_factory!(span, "Stack is empty"))?;
+    let next = ctx
+        .stack
+        .pop()
+        .ok_or_else(error_factory!(span, "Stack only has 1 value"))?;
+    ctx.stack.extend_from_slice(&[head, next]);
+    Ok(())
+}
+
+fn rot(ctx: &mut FalseContext, span: SimpleSpan) -> Result<(), InterpreterRuntimeError> {
+    let first = ctx
+        .stack
+        .pop()
+        .ok_or_else(error_factory!(span, "Stack is empty"))?;
+    let second = ctx
+        .stack
+        .pop()
+        .ok_or_else(error_factory!(span, "Stack only has 1 value"))?;
+    let third = ctx
+        .stack
+        .pop()
+        .ok_or_else(error_factory!(span, "Stack only has 2 values"))?;
+    ctx.stack.extend_from_slice(&[first, second, third]);
+    Ok(())
+}
+
+fn pick(ctx: &mut FalseContext, span: SimpleSpan) -> Result<(), InterpreterRuntimeError> {
+    let head = ctx
+        .stack
+        .pop()
+        .ok_or_else(error_factory!(span, "Stack is empty"))?;
+    let index = match head {
+        FalseStackEntry::StoredValue(FalseStoreableValue::StoredInteger(i)) => i,
+        other => {
+            return Err(runtime_error!(
+                span,
+                "Unexpected index for ø (PICK): {other:#?}"
+            ))
         }
-        WriteChar => {
-            let value = pop_int(ctx, span)?;
-            (*ctx.on_output)(
-                &std::char::from_u32(value as u32)
-                    .ok_or_else(error_factory!("Can't output value {} as char", value))?
-                    .to_string(),
-            );
+    };
+    if index < 0 || index as usize >= ctx.stack.len() {
+        return Err(runtime_error!(
+            span,
+            "Index out of range for ø (PICK): {index}"
+        ));
+    }
+    let index = ctx.stack.len() - 1 - index as usize;
+    ctx.stack.push(ctx.stack[index].clone());
+    Ok(())
+}
+
+fn div(ctx: &mut FalseContext, span: SimpleSpan) -> Result<(), InterpreterRuntimeError> {
+    let (a, b) = pop_two(ctx, span)?;
+    if a == 0 {
+        return Err(runtime_error!(span, "Division by zero"));
+    }
+    ctx.stack.push(FalseStackEntry::StoredValue(
+        FalseStoreableValue::StoredInteger(b / a),
+    ));
+    Ok(())
+}
+
+/// Pops a lambda and runs its body with `run`, going through the memo cache
+/// if there is one.
+fn execute<'i, 'o>(
+    ctx: &mut FalseContext<'i, 'o>,
+    span: SimpleSpan,
+    run: impl FnOnce(LambdaRef, &mut FalseContext<'i, 'o>) -> Result<(), InterpreterRuntimeError>,
+) -> Result<(), InterpreterRuntimeError> {
+    let lambda = match ctx
+        .stack
+        .pop()
+        .ok_or_else(error_factory!(span, "Stack is empty"))?
+    {
+        FalseStackEntry::StoredValue(FalseStoreableValue::StoredLambda(v)) => v,
+        other => {
+            return Err(runtime_error!(
+                span,
+                "Expected lambda for Execute, got {}",
+                other.type_name()
+            ));
         }
-        WriteStr(s) => (*ctx.on_output)(&s),
-        WriteInt => {
-            let value = pop_int(ctx, span)?;
-            (*ctx.on_output)(&value.to_string());
+    };
+    let lookup = match ctx.memo.as_mut() {
+        Some(memo) => memo.lookup(lambda, &ctx.stack, &ctx.global_scope),
+        None => memo::Lookup::Skip,
+    };
+    match lookup {
+        memo::Lookup::Hit { inputs, outputs } => {
+            ctx.stack.truncate(ctx.stack.len() - inputs);
+            ctx.stack.extend(outputs);
+        }
+        memo::Lookup::Miss { key, outputs } => {
+            run(lambda, ctx)?;
+            let outputs = ctx.stack[ctx.stack.len() - outputs..].to_vec();
+            if let Some(memo) = ctx.memo.as_mut() {
+                memo.insert(lambda, key, outputs);
+            }
+        }
+        memo::Lookup::Skip => run(lambda, ctx)?,
+    }
+    Ok(())
+}
+
+fn store(ctx: &mut FalseContext, span: SimpleSpan) -> Result<(), InterpreterRuntimeError> {
+    let reference = match ctx
+        .stack
+        .pop()
+        .ok_or_else(error_factory!(span, "Stack is empty"))?
+    {
+        FalseStackEntry::VariableReference(v) => v,
+        _ => {
+            return Err(runtime_error!(span, "Store (:) must be preceded by a name"));
+        }
+    };
+    let value = match ctx
+        .stack
+        .pop()
+        .ok_or_else(error_factory!(span, "Stack only has 1 value"))?
+    {
+        FalseStackEntry::StoredValue(v) => v,
+        FalseStackEntry::VariableReference(_) => {
+            return Err(runtime_error!(span, "Names cannot be stored in names"));
         }
-        Flush => {}
     };
+    ctx.global_scope.insert(reference, value);
+    Ok(())
+}
+
+fn fetch(ctx: &mut FalseContext, span: SimpleSpan) -> Result<(), InterpreterRuntimeError> {
+    let reference = match ctx
+        .stack
+        .pop()
+        .ok_or_else(error_factory!(span, "Stack is empty"))?
+    {
+        FalseStackEntry::VariableReference(v) => v,
+        _ => {
+            return Err(runtime_error!(span, "Fetch (;) must be preceded by a name"));
+        }
+    };
+    let value = ctx
+        .global_scope
+        .get(&reference)
+        .ok_or_else(error_factory!(
+            span,
+            "Name {reference} not found in global scope"
+        ))?
+        .clone();
+    ctx.stack.push(FalseStackEntry::StoredValue(value));
+    Ok(())
+}
+
+fn read_char(ctx: &mut FalseContext) {
+    let value = match (*ctx.on_input)() {
+        Some(v) => v as i32,
+        None => -1,
+    };
+    ctx.stack.push(FalseStackEntry::StoredValue(
+        FalseStoreableValue::StoredInteger(value),
+    ));
+}
+
+fn write_char(ctx: &mut FalseContext, span: SimpleSpan) -> Result<(), InterpreterRuntimeError> {
+    let value = pop_int(ctx, span)?;
+    (*ctx.on_output)(
+        &std::char::from_u32(value as u32)
+            .ok_or_else(error_factory!(span, "Can't output value {} as char", value))?
+            .to_string(),
+    );
+    Ok(())
+}
 
+fn write_int(ctx: &mut FalseContext, span: SimpleSpan) -> Result<(), InterpreterRuntimeError> {
+    let value = pop_int(ctx, span)?;
+    (*ctx.on_output)(&value.to_string());
     Ok(())
 }
 
@@ -0,0 +1,162 @@
+//! Closure-compilation backend.
+//!
+//! Every instruction is compiled once into a boxed closure, so running the
+//! program no longer matches on `FalseInstruction`. Lambda bodies are
+//! compiled up front into a table indexed like the program's lambdas, which
+//! is what the lambda values on the stack refer to. The closures call the
+//! same instruction helpers as the tree-walking interpreter, so errors and
+//! their spans are identical.
+
+use super::code::Program;
+use super::{
+    binary_op, div, dup, execute, fetch, pick, pop_int, read_char, rot, store, swap, unary_op,
+    write_char, write_int, FalseContext, FalseStackEntry, FalseStoreableValue,
+    InterpreterRuntimeError,
+};
+use crate::ast::{FalseInstruction, Spanned};
+
+type Op<'a> =
+    Box<dyn Fn(&mut FalseContext<'_, '_>, &Code<'a>) -> Result<(), InterpreterRuntimeError> + 'a>;
+
+type LambdaTable<'a> = Vec<Vec<Op<'a>>>;
+
+pub(super) struct Code<'a> {
+    main: Vec<Op<'a>>,
+    lambdas: LambdaTable<'a>,
+}
+
+impl<'a> Code<'a> {
+    pub(super) fn compile(program: &'a Program) -> Self {
+        // Empty bodies all share one address, and so one entry, which is
+        // left empty
+        let mut lambdas: LambdaTable = (0..program.lambda_count()).map(|_| Vec::new()).collect();
+        let main = compile_block(program.instructions(), program, &mut lambdas);
+        Self { main, lambdas }
+    }
+
+    pub(super) fn run(&self, ctx: &mut FalseContext) -> Result<(), InterpreterRuntimeError> {
+        run_block(&self.main, ctx, self)
+    }
+}
+
+fn run_block<'a>(
+    ops: &[Op<'a>],
+    ctx: &mut FalseContext,
+    code: &Code<'a>,
+) -> Result<(), InterpreterRuntimeError> {
+    for op in ops {
+        op(ctx, code)?;
+    }
+    Ok(())
+}
+
+fn compile_block<'a>(
+    instructions: &'a [Spanned<FalseInstruction>],
+    program: &Program,
+    lambdas: &mut LambdaTable<'a>,
+) -> Vec<Op<'a>> {
+    instructions
+        .iter()
+        .map(|spanned| compile_instruction(spanned, program, lambdas))
+        .collect()
+}
+
+fn compile_instruction<'a>(
+    spanned: &'a Spanned<FalseInstruction>,
+    program: &Program,
+    lambdas: &mut LambdaTable<'a>,
+) -> Op<'a> {
+    use FalseInstruction::*;
+    use FalseStackEntry::*;
+    use FalseStoreableValue::*;
+
+    let span = spanned.span();
+
+    match spanned.instruction() {
+        Name(c) => {
+            let c = *c;
+            Box::new(move |ctx, _| {
+                ctx.stack.push(VariableReference(c));
+                Ok(())
+            })
+        }
+        PushInt(v) => {
+            let v = *v;
+            Box::new(move |ctx, _| {
+                ctx.stack.push(StoredValue(StoredInteger(v)));
+                Ok(())
+            })
+        }
+        PushChar(c) => {
+            let v = (*c).into();
+            Box::new(move |ctx, _| {
+                ctx.stack.push(StoredValue(StoredInteger(v)));
+                Ok(())
+            })
+        }
+        Dup => Box::new(move |ctx, _| dup(ctx, span)),
+        Drop => Box::new(|ctx, _| {
+            let _ = ctx.stack.pop();
+            Ok(())
+        }),
+        Swap => Box::new(move |ctx, _| swap(ctx, span)),
+        Rot => Box::new(move |ctx, _| rot(ctx, span)),
+        Pick => Box::new(move |ctx, _| pick(ctx, span)),
+        Add => Box::new(move |ctx, _| binary_op(ctx, span, |a, b| a + b)),
+        Sub => Box::new(move |ctx, _| binary_op(ctx, span, |a, b| a - b)),
+        Mul => Box::new(move |ctx, _| binary_op(ctx, span, |a, b| a * b)),
+        Div => Box::new(move |ctx, _| div(ctx, span)),
+        Neg => Box::new(move |ctx, _| unary_op(ctx, span, |x| -x)),
+        BitAnd => Box::new(move |ctx, _| binary_op(ctx, span, |a, b| a & b)),
+        BitOr => Box::new(move |ctx, _| binary_op(ctx, span, |a, b| a | b)),
+        BitNot => Box::new(move |ctx, _| unary_op(ctx, span, |x| !x)),
+        Gt => Box::new(move |ctx, _| binary_op(ctx, span, |a, b| if a > b { -1 } else { 0 })),
+        Eq => Box::new(move |ctx, _| binary_op(ctx, span, |a, b| if a == b { -1 } else { 0 })),
+        Lambda(body) => {
+            let lambda = program.lambda(body);
+            lambdas[lambda.index()] = compile_block(body, program, lambdas);
+            Box::new(move |ctx, _| {
+                ctx.stack.push(StoredValue(StoredLambda(lambda)));
+                Ok(())
+            })
+        }
+        Execute => Box::new(move |ctx, code| {
+            execute(ctx, span, |lambda, ctx| {
+                run_block(&code.lambdas[lambda.index()], ctx, code)
+            })
+        }),
+        ConditionalExecute(body) => {
+            let body = compile_block(body, program, lambdas);
+            Box::new(move |ctx, code| {
+                if pop_int(ctx, span)? != 0 {
+                    run_block(&body, ctx, code)?;
+                }
+                Ok(())
+            })
+        }
+        WhileLoop(condition, body) => {
+            let condition = compile_block(condition, program, lambdas);
+            let body = compile_block(body, program, lambdas);
+            Box::new(move |ctx, code| loop {
+                run_block(&condition, ctx, code)?;
+                if pop_int(ctx, span)? == 0 {
+                    return Ok(());
+                }
+                run_block(&body, ctx, code)?;
+            })
+        }
+        Store => Box::new(move |ctx, _| store(ctx, span)),
+        Fetch => Box::new(move |ctx, _| fetch(ctx, span)),
+        ReadChar => Box::new(|ctx, _| {
+            read_char(ctx);
+            Ok(())
+        }),
+        WriteChar => Box::new(move |ctx, _| write_char(ctx, span)),
+        WriteStr(s) => Box::new(move |ctx, _| {
+            (*ctx.on_output)(s);
+            Ok(())
+        }),
+        WriteInt => Box::new(move |ctx, _| write_int(ctx, span)),
+        Flush => Box::new(|_, _| Ok(())),
+    }
+}
@@ -0,0 +1,181 @@
+//! Programs owned by the interpreter, and handles to the lambdas in them.
+//!
+//! A lambda value is a [`LambdaRef`]: which program in the [`CodeTable`] it
+//! was defined in, and which of that program's lambdas it is. The table
+//! owns every program that values may refer to, so values don't borrow the
+//! AST, and can outlive the run or REPL entry that made them. Handles are
+//! plain numbers, so stack values are cheap to copy and drop.
+
+use std::collections::HashMap;
+use std::hash::{BuildHasherDefault, Hasher};
+use std::sync::Arc;
+
+use crate::ast::{FalseInstruction, Spanned};
+
+/// The address of a lambda body.
+type BodyKey = usize;
+
+/// Lambda bodies are looked up by address whenever one is pushed, and their
+/// addresses are already unique, so they are used as the hash directly.
+#[derive(Default)]
+struct AddressHasher(u64);
+
+impl Hasher for AddressHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, _: &[u8]) {
+        unreachable!("Only addresses are hashed")
+    }
+
+    fn write_usize(&mut self, address: usize) {
+        self.0 = address as u64;
+    }
+}
+
+/// Every program loaded into an interpreter, for as long as values may
+/// refer to them.
+#[derive(Debug, Default)]
+pub(super) struct CodeTable {
+    programs: Vec<Arc<Program>>,
+}
+
+impl CodeTable {
+    pub(super) fn load(&mut self, instructions: Vec<Spanned<FalseInstruction>>) -> Arc<Program> {
+        let program = Arc::new(Program::new(self.programs.len() as u32, instructions));
+        self.programs.push(program.clone());
+        program
+    }
+
+    /// The program a lambda is in.
+    pub(super) fn program(&self, lambda: LambdaRef) -> &Arc<Program> {
+        &self.programs[lambda.program as usize]
+    }
+}
+
+/// A lambda, by the program it is in and its index there.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(super) struct LambdaRef {
+    program: u32,
+    index: u32,
+}
+
+impl LambdaRef {
+    pub(super) fn index(self) -> usize {
+        self.index as usize
+    }
+}
+
+/// A step from a block into one nested in its instruction at `index`: the
+/// body of a lambda or `?`, or the condition (0) or body (1) of a loop.
+#[derive(Clone, Copy, Debug)]
+struct Step {
+    index: u32,
+    block: u8,
+}
+
+#[derive(Debug)]
+pub(super) struct Program {
+    id: u32,
+    instructions: Vec<Spanned<FalseInstruction>>,
+    /// How to reach the body of each lambda from the top level.
+    paths: Vec<Box<[Step]>>,
+    /// The index of each lambda, by the address of its body.
+    indices: HashMap<BodyKey, u32, BuildHasherDefault<AddressHasher>>,
+}
+
+impl Program {
+    fn new(id: u32, instructions: Vec<Spanned<FalseInstruction>>) -> Self {
+        let mut program = Self {
+            id,
+            instructions,
+            paths: Vec::new(),
+            indices: HashMap::default(),
+        };
+        let mut lambdas = Vec::new();
+        find_lambdas(&program.instructions, &mut Vec::new(), &mut lambdas);
+        for (path, key) in lambdas {
+            program.indices.insert(key, program.paths.len() as u32);
+            program.paths.push(path);
+        }
+        program
+    }
+
+    pub(super) fn instructions(&self) -> &[Spanned<FalseInstruction>] {
+        &self.instructions
+    }
+
+    /// How many lambdas the program has.
+    pub(super) fn lambda_count(&self) -> usize {
+        self.paths.len()
+    }
+
+    /// The handle to the lambda with `body`, which must be in this program.
+    pub(super) fn lambda(&self, body: &[Spanned<FalseInstruction>]) -> LambdaRef {
+        let index = *self
+            .indices
+            .get(&(body.as_ptr() as BodyKey))
+            .expect("Lambda is not in this program");
+        LambdaRef {
+            program: self.id,
+            index,
+        }
+    }
+
+    /// The body of a lambda in this program.
+    pub(super) fn body(&self, lambda: LambdaRef) -> &[Spanned<FalseInstruction>] {
+        use FalseInstruction::*;
+
+        let mut block = &self.instructions[..];
+        for step in &self.paths[lambda.index()] {
+            block = match (block[step.index as usize].instruction(), step.block) {
+                (Lambda(body) | ConditionalExecute(body), 0) => body,
+                (WhileLoop(condition, _), 0) => condition,
+                (WhileLoop(_, body), 1) => body,
+                _ => unreachable!("Paths only lead into nested blocks"),
+            };
+        }
+        block
+    }
+}
+
+fn find_lambdas(
+    instructions: &[Spanned<FalseInstruction>],
+    path: &mut Vec<Step>,
+    lambdas: &mut Vec<(Box<[Step]>, BodyKey)>,
+) {
+    use FalseInstruction::*;
+
+    for (index, spanned) in instructions.iter().enumerate() {
+        let index = index as u32;
+        match spanned.instruction() {
+            Lambda(body) => {
+                path.push(Step { index, block: 0 });
+                lambdas.push((path.as_slice().into(), body.as_ptr() as BodyKey));
+                find_lambdas(body, path, lambdas);
+                path.pop();
+            }
+            ConditionalExecute(body) => {
+                path.push(Step { index, block: 0 });
+                find_lambdas(body, path, lambdas);
+                path.pop();
+            }
+            WhileLoop(condition, body) => {
+                path.push(Step { index, block: 0 });
+                find_lambdas(condition, path, lambdas);
+                path.pop();
+                path.push(Step { index, block: 1 });
+                find_lambdas(body, path, lambdas);
+                path.pop();
+            }
+            _ => {}
+        }
+    }
+}
+
+// Programs, and so the interpreter's state, can be sent between threads
+const _: () = {
+    const fn send_and_sync<T: Send + Sync>() {}
+    send_and_sync::<CodeTable>();
+};
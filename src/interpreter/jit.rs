@@ -0,0 +1,435 @@
+//! JIT compilation of hot code with Cranelift (the `jit` feature).
+//!
+//! Lambda bodies and loops that only do integer arithmetic and control flow,
+//! with a fixed stack effect, are compiled to native code. Stack slots become
+//! Cranelift variables, and only the values the code takes and leaves are
+//! copied between the interpreter's stack and a buffer.
+//!
+//! Compiled code never reports errors itself. If its inputs aren't all
+//! integers, or it would divide by zero or overflow, it bails out before the
+//! interpreter's stack is touched, and the interpreter runs the same code
+//! instead. Compiled code has no side effects, so running it again from the
+//! start reports exactly the error the interpreter would have.
+
+use std::collections::HashMap;
+
+use cranelift_codegen::ir::{
+    condcodes::IntCC, types, AbiParam, Block, InstBuilder, MemFlags, Value,
+};
+use cranelift_codegen::settings::{self, Configurable};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext, Variable};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::Module;
+
+use super::{FalseStackEntry, FalseStoreableValue};
+use crate::analysis::stack_effect::{StackEffect, StackEffects};
+use crate::ast::{FalseInstruction, Spanned};
+
+/// Calls to a lambda before it is compiled. Loops are compiled the first
+/// time they run.
+const HOT_CALLS: u32 = 10;
+
+type CompiledFn = unsafe extern "C" fn(*mut i32) -> i32;
+
+/// Statistics for a run with the JIT turned on.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct JitStats {
+    /// Lambdas and loops compiled to native code.
+    pub compiled: usize,
+    /// Runs of compiled code that finished natively.
+    pub native_runs: u64,
+    /// Runs of compiled code that were handed back to the interpreter.
+    pub fallbacks: u64,
+}
+
+impl std::fmt::Display for JitStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} compiled, {} native runs, {} fallbacks",
+            self.compiled, self.native_runs, self.fallbacks
+        )
+    }
+}
+
+struct Compiled {
+    function: CompiledFn,
+    effect: StackEffect,
+}
+
+enum Entry {
+    Counting(u32),
+    Compiled(Compiled),
+    Unsupported,
+}
+
+#[derive(Default)]
+pub(super) struct Jit {
+    /// Created when the first piece of code is compiled.
+    module: Option<JITModule>,
+    /// Keyed by the code's address and length, as a lambda whose body is just
+    /// a loop is the same code as that loop.
+    entries: HashMap<(*const Spanned<FalseInstruction>, usize), Entry>,
+    buffer: Vec<i32>,
+    stats: JitStats,
+}
+
+impl Jit {
+    pub(super) fn stats(&self) -> JitStats {
+        self.stats
+    }
+
+    /// Runs a lambda body natively, if it is hot and could be compiled.
+    /// Returns false if the interpreter should run it instead.
+    pub(super) fn run_lambda(
+        &mut self,
+        body: &[Spanned<FalseInstruction>],
+        stack: &mut Vec<FalseStackEntry>,
+    ) -> bool {
+        self.run(body, HOT_CALLS, stack)
+    }
+
+    /// Runs a `WhileLoop` natively, if it could be compiled. Returns false if
+    /// the interpreter should run it instead.
+    pub(super) fn run_loop(
+        &mut self,
+        instruction: &Spanned<FalseInstruction>,
+        stack: &mut Vec<FalseStackEntry>,
+    ) -> bool {
+        self.run(std::slice::from_ref(instruction), 1, stack)
+    }
+
+    fn run(
+        &mut self,
+        code: &[Spanned<FalseInstruction>],
+        hot_after: u32,
+        stack: &mut Vec<FalseStackEntry>,
+    ) -> bool {
+        let key = (code.as_ptr(), code.len());
+        let entry = self.entries.entry(key).or_insert(Entry::Counting(0));
+        if let Entry::Counting(calls) = entry {
+            *calls += 1;
+            if *calls < hot_after {
+                return false;
+            }
+            let module = self.module.get_or_insert_with(new_module);
+            let compiled = match compile(module, code) {
+                Some(compiled) => {
+                    self.stats.compiled += 1;
+                    Entry::Compiled(compiled)
+                }
+                None => Entry::Unsupported,
+            };
+            self.entries.insert(key, compiled);
+        }
+        let Some(Entry::Compiled(compiled)) = self.entries.get(&key) else {
+            return false;
+        };
+
+        let StackEffect { inputs, outputs } = compiled.effect;
+        let Some(base) = stack.len().checked_sub(inputs) else {
+            self.stats.fallbacks += 1;
+            return false;
+        };
+        self.buffer.clear();
+        for entry in &stack[base..] {
+            match entry {
+                FalseStackEntry::StoredValue(FalseStoreableValue::StoredInteger(i)) => {
+                    self.buffer.push(*i)
+                }
+                _ => {
+                    self.stats.fallbacks += 1;
+                    return false;
+                }
+            }
+        }
+        self.buffer.resize(inputs.max(outputs), 0);
+
+        // SAFETY: the buffer holds every slot the function reads or writes,
+        // and the module that owns the code lives as long as `self`
+        let status = unsafe { (compiled.function)(self.buffer.as_mut_ptr()) };
+        if status != 0 {
+            self.stats.fallbacks += 1;
+            return false;
+        }
+        stack.truncate(base);
+        stack.extend(
+            self.buffer[..outputs]
+                .iter()
+                .map(|&i| FalseStackEntry::StoredValue(FalseStoreableValue::StoredInteger(i))),
+        );
+        self.stats.native_runs += 1;
+        true
+    }
+}
+
+impl Drop for Jit {
+    fn drop(&mut self) {
+        if let Some(module) = self.module.take() {
+            // SAFETY: compiled functions are only reachable through `self`
+            unsafe { module.free_memory() };
+        }
+    }
+}
+
+fn new_module() -> JITModule {
+    let mut flags = settings::builder();
+    flags.set("opt_level", "speed").unwrap();
+    let isa = cranelift_native::builder()
+        .expect("Host machine is not supported by Cranelift")
+        .finish(settings::Flags::new(flags))
+        .unwrap();
+    JITModule::new(JITBuilder::with_isa(
+        isa,
+        cranelift_module::default_libcall_names(),
+    ))
+}
+
+fn supported(code: &[Spanned<FalseInstruction>]) -> bool {
+    use FalseInstruction::*;
+    code.iter().all(|spanned| match spanned.instruction() {
+        PushInt(_) | PushChar(_) | Dup | Drop | Swap | Rot | Add | Sub | Mul | Div | Neg
+        | BitAnd | BitOr | BitNot | Gt | Eq | Flush => true,
+        ConditionalExecute(body) => supported(body),
+        WhileLoop(condition, body) => supported(condition) && supported(body),
+        _ => false,
+    })
+}
+
+fn compile(module: &mut JITModule, code: &[Spanned<FalseInstruction>]) -> Option<Compiled> {
+    if !supported(code) {
+        return None;
+    }
+    // Compiled code makes no calls, so the rest of the program doesn't matter
+    let effect = StackEffects::new(&[]).of_block(code)?;
+
+    let pointer = module.target_config().pointer_type();
+    let mut ctx = module.make_context();
+    ctx.func.signature.params.push(AbiParam::new(pointer));
+    ctx.func.signature.returns.push(AbiParam::new(types::I32));
+
+    let mut function_ctx = FunctionBuilderContext::new();
+    let mut builder = FunctionBuilder::new(&mut ctx.func, &mut function_ctx);
+    let entry = builder.create_block();
+    let bail = builder.create_block();
+    builder.append_block_params_for_function_params(entry);
+    builder.switch_to_block(entry);
+    let slots = builder.block_params(entry)[0];
+
+    let mut codegen = Codegen {
+        builder,
+        depth: 0,
+        declared: 0,
+        bail,
+    };
+    for i in 0..effect.inputs {
+        let value =
+            codegen
+                .builder
+                .ins()
+                .load(types::I32, MemFlags::trusted(), slots, (i * 4) as i32);
+        codegen.push(value);
+    }
+    codegen.block(code);
+    for i in 0..effect.outputs {
+        let value = codegen.builder.use_var(slot(i));
+        codegen
+            .builder
+            .ins()
+            .store(MemFlags::trusted(), value, slots, (i * 4) as i32);
+    }
+    let ok = codegen.builder.ins().iconst(types::I32, 0);
+    codegen.builder.ins().return_(&[ok]);
+
+    codegen.builder.switch_to_block(bail);
+    let failed = codegen.builder.ins().iconst(types::I32, 1);
+    codegen.builder.ins().return_(&[failed]);
+
+    codegen.builder.seal_all_blocks();
+    codegen.builder.finalize();
+
+    let id = module
+        .declare_anonymous_function(&ctx.func.signature)
+        .ok()?;
+    module.define_function(id, &mut ctx).ok()?;
+    module.clear_context(&mut ctx);
+    module.finalize_definitions().ok()?;
+    let code = module.get_finalized_function(id);
+    Some(Compiled {
+        // SAFETY: the function was built with exactly this signature
+        function: unsafe { std::mem::transmute::<*const u8, CompiledFn>(code) },
+        effect,
+    })
+}
+
+fn slot(index: usize) -> Variable {
+    Variable::from_u32(index as u32)
+}
+
+/// Builds code for a block whose stack height at each instruction is known,
+/// keeping stack slot `i` in variable `i`.
+struct Codegen<'f> {
+    builder: FunctionBuilder<'f>,
+    depth: usize,
+    declared: usize,
+    bail: Block,
+}
+
+impl Codegen<'_> {
+    fn push(&mut self, value: Value) {
+        while self.declared <= self.depth {
+            self.builder.declare_var(slot(self.declared), types::I32);
+            self.declared += 1;
+        }
+        self.builder.def_var(slot(self.depth), value);
+        self.depth += 1;
+    }
+
+    fn pop(&mut self) -> Value {
+        self.depth -= 1;
+        self.builder.use_var(slot(self.depth))
+    }
+
+    /// Carries on in a new block if `condition` is zero, and bails out
+    /// otherwise.
+    fn bail_if(&mut self, condition: Value) {
+        let next = self.builder.create_block();
+        self.builder
+            .ins()
+            .brif(condition, self.bail, &[], next, &[]);
+        self.builder.switch_to_block(next);
+    }
+
+    fn block(&mut self, code: &[Spanned<FalseInstruction>]) {
+        for spanned in code {
+            self.instruction(spanned.instruction());
+        }
+    }
+
+    fn instruction(&mut self, instruction: &FalseInstruction) {
+        use FalseInstruction::*;
+
+        match instruction {
+            PushInt(v) => {
+                let value = self.builder.ins().iconst(types::I32, i64::from(*v));
+                self.push(value);
+            }
+            PushChar(c) => {
+                let value = self.builder.ins().iconst(types::I32, i64::from(*c));
+                self.push(value);
+            }
+            Dup => {
+                let top = self.builder.use_var(slot(self.depth - 1));
+                self.push(top);
+            }
+            Drop => {
+                self.pop();
+            }
+            Swap => {
+                let a = self.pop();
+                let b = self.pop();
+                self.push(a);
+                self.push(b);
+            }
+            Rot => {
+                // Matches the interpreter, which reverses the top three
+                let first = self.pop();
+                let second = self.pop();
+                let third = self.pop();
+                self.push(first);
+                self.push(second);
+                self.push(third);
+            }
+            Add | Sub | Mul => {
+                let a = self.pop();
+                let b = self.pop();
+                let ins = self.builder.ins();
+                let (result, overflow) = match instruction {
+                    Add => ins.sadd_overflow(b, a),
+                    Sub => ins.ssub_overflow(b, a),
+                    _ => ins.smul_overflow(b, a),
+                };
+                self.bail_if(overflow);
+                self.push(result);
+            }
+            Div => {
+                let a = self.pop();
+                let b = self.pop();
+                let zero = self.builder.ins().icmp_imm(IntCC::Equal, a, 0);
+                self.bail_if(zero);
+                let min = self
+                    .builder
+                    .ins()
+                    .icmp_imm(IntCC::Equal, b, i64::from(i32::MIN));
+                let minus_one = self.builder.ins().icmp_imm(IntCC::Equal, a, -1);
+                let overflow = self.builder.ins().band(min, minus_one);
+                self.bail_if(overflow);
+                let result = self.builder.ins().sdiv(b, a);
+                self.push(result);
+            }
+            Neg => {
+                let a = self.pop();
+                let overflow = self
+                    .builder
+                    .ins()
+                    .icmp_imm(IntCC::Equal, a, i64::from(i32::MIN));
+                self.bail_if(overflow);
+                let result = self.builder.ins().ineg(a);
+                self.push(result);
+            }
+            BitAnd | BitOr => {
+                let a = self.pop();
+                let b = self.pop();
+                let result = match instruction {
+                    BitAnd => self.builder.ins().band(b, a),
+                    _ => self.builder.ins().bor(b, a),
+                };
+                self.push(result);
+            }
+            BitNot => {
+                let a = self.pop();
+                let result = self.builder.ins().bnot(a);
+                self.push(result);
+            }
+            Gt | Eq => {
+                let a = self.pop();
+                let b = self.pop();
+                let cc = match instruction {
+                    Gt => IntCC::SignedGreaterThan,
+                    _ => IntCC::Equal,
+                };
+                let holds = self.builder.ins().icmp(cc, b, a);
+                let true_ = self.builder.ins().iconst(types::I32, -1);
+                let false_ = self.builder.ins().iconst(types::I32, 0);
+                let result = self.builder.ins().select(holds, true_, false_);
+                self.push(result);
+            }
+            ConditionalExecute(body) => {
+                let condition = self.pop();
+                let taken = self.builder.create_block();
+                let after = self.builder.create_block();
+                self.builder.ins().brif(condition, taken, &[], after, &[]);
+                self.builder.switch_to_block(taken);
+                self.block(body);
+                self.builder.ins().jump(after, &[]);
+                self.builder.switch_to_block(after);
+            }
+            WhileLoop(condition, body) => {
+                let header = self.builder.create_block();
+                let taken = self.builder.create_block();
+                let after = self.builder.create_block();
+                self.builder.ins().jump(header, &[]);
+                self.builder.switch_to_block(header);
+                self.block(condition);
+                let result = self.pop();
+                self.builder.ins().brif(result, taken, &[], after, &[]);
+                self.builder.switch_to_block(taken);
+                self.block(body);
+                self.builder.ins().jump(header, &[]);
+                self.builder.switch_to_block(after);
+            }
+            Flush => {}
+            other => unreachable!("Not supported by the JIT: {other:?}"),
+        }
+    }
+}
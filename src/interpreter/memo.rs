@@ -0,0 +1,183 @@
+//! Opt-in memoisation of pure lambdas.
+//!
+//! A lambda is memoised when it has no I/O, never stores to a global, and
+//! always takes and leaves the same number of stack values. Its results are
+//! cached by the values it takes from the stack, together with the globals
+//! it may read, so a later call with the same inputs skips running it.
+
+use std::collections::HashMap;
+
+use super::code::{LambdaRef, Program};
+use super::{FalseStackEntry, FalseStoreableValue};
+use crate::analysis::effects::{EffectAnalysis, VariableSet};
+use crate::analysis::stack_effect::{StackEffect, StackEffects};
+use crate::ast::FalseInstruction;
+
+/// Cache statistics for a run with memoisation turned on.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MemoStats {
+    /// Lambdas in the program that qualified for memoisation.
+    pub memoized_lambdas: usize,
+    pub hits: u64,
+    pub misses: u64,
+    /// Results in the cache at the end of the run.
+    pub entries: usize,
+}
+
+impl std::fmt::Display for MemoStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} memoized lambdas, {} hits, {} misses, {} cached results",
+            self.memoized_lambdas, self.hits, self.misses, self.entries
+        )
+    }
+}
+
+struct MemoizedLambda {
+    effect: StackEffect,
+    fetches: VariableSet,
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub(super) enum KeyValue {
+    Integer(i32),
+    Lambda(LambdaRef),
+    Name(char),
+    Unset,
+}
+
+pub(super) enum Lookup {
+    /// Replace the top `inputs` values with `outputs`.
+    Hit {
+        inputs: usize,
+        outputs: Vec<FalseStackEntry>,
+    },
+    /// Run the lambda, then hand its top `outputs` values to `Memo::insert`.
+    Miss { key: Vec<KeyValue>, outputs: usize },
+    /// The lambda can't be memoised, or the stack is too short.
+    Skip,
+}
+
+pub(super) struct Memo {
+    lambdas: HashMap<LambdaRef, MemoizedLambda>,
+    cache: HashMap<(LambdaRef, Vec<KeyValue>), Vec<FalseStackEntry>>,
+    max_entries: usize,
+    stats: MemoStats,
+}
+
+impl Memo {
+    pub(super) fn new(program: &Program, max_entries: usize) -> Self {
+        let effects = EffectAnalysis::analyze(program.instructions());
+        let stack_effects = StackEffects::new(program.instructions());
+        let mut lambdas = HashMap::new();
+        let mut pending = vec![program.instructions()];
+        while let Some(instructions) = pending.pop() {
+            for spanned in instructions {
+                match spanned.instruction() {
+                    FalseInstruction::Lambda(body) => {
+                        let pure = effects
+                            .lambda_at(spanned.span())
+                            .map(|l| l.effects())
+                            .filter(|e| e.is_pure());
+                        let effect = stack_effects.of_lambda(body);
+                        if let (Some(pure), Some(effect)) = (pure, effect) {
+                            lambdas.insert(
+                                program.lambda(body),
+                                MemoizedLambda {
+                                    effect,
+                                    fetches: pure.fetches,
+                                },
+                            );
+                        }
+                        pending.push(body);
+                    }
+                    FalseInstruction::ConditionalExecute(body) => pending.push(body),
+                    FalseInstruction::WhileLoop(condition, body) => {
+                        pending.push(condition);
+                        pending.push(body);
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        Self {
+            stats: MemoStats {
+                memoized_lambdas: lambdas.len(),
+                ..MemoStats::default()
+            },
+            lambdas,
+            cache: HashMap::new(),
+            max_entries,
+        }
+    }
+
+    pub(super) fn lookup(
+        &mut self,
+        lambda: LambdaRef,
+        stack: &[FalseStackEntry],
+        global_scope: &HashMap<char, FalseStoreableValue>,
+    ) -> Lookup {
+        let Some(memoized) = self.lambdas.get(&lambda) else {
+            return Lookup::Skip;
+        };
+        let StackEffect { inputs, outputs } = memoized.effect;
+        if stack.len() < inputs {
+            // Let the interpreter report the underflow
+            return Lookup::Skip;
+        }
+
+        let arguments = stack[stack.len() - inputs..]
+            .iter()
+            .map(|entry| match entry {
+                FalseStackEntry::VariableReference(c) => KeyValue::Name(*c),
+                FalseStackEntry::StoredValue(value) => key_value(value),
+            });
+        let globals = memoized
+            .fetches
+            .iter()
+            .map(|c| global_scope.get(&c).map_or(KeyValue::Unset, key_value));
+        let key: Vec<KeyValue> = arguments.chain(globals).collect();
+
+        match self.cache.get(&(lambda, key.clone())) {
+            Some(cached) => {
+                self.stats.hits += 1;
+                Lookup::Hit {
+                    inputs,
+                    outputs: cached.clone(),
+                }
+            }
+            None => {
+                self.stats.misses += 1;
+                Lookup::Miss { key, outputs }
+            }
+        }
+    }
+
+    pub(super) fn insert(
+        &mut self,
+        lambda: LambdaRef,
+        key: Vec<KeyValue>,
+        outputs: Vec<FalseStackEntry>,
+    ) {
+        // Once the cache is full, keep what is already there
+        if self.cache.len() < self.max_entries {
+            self.cache.insert((lambda, key), outputs);
+        }
+    }
+
+    pub(super) fn stats(&self) -> MemoStats {
+        MemoStats {
+            entries: self.cache.len(),
+            ..self.stats
+        }
+    }
+}
+
+fn key_value(value: &FalseStoreableValue) -> KeyValue {
+    match value {
+        FalseStoreableValue::StoredInteger(i) => KeyValue::Integer(*i),
+        FalseStoreableValue::StoredLambda(lambda) => KeyValue::Lambda(*lambda),
+    }
+}
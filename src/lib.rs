@@ -1,3 +1,17 @@
+pub mod analysis;
 pub mod ast;
+pub mod bundle;
+pub mod bytecode;
+pub mod codegen;
+pub mod cst;
+pub mod debugger;
+pub mod formatter;
 pub mod interpreter;
+pub mod lines;
+pub mod lint;
+pub mod lsp;
+pub mod minifier;
+pub mod optimizer;
 pub mod parser;
+pub mod partial_eval;
+pub mod repl;
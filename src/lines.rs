@@ -0,0 +1,39 @@
+//! Line and column numbers of byte offsets in source, as errors and
+//! compiled programs report them.
+
+/// 1-based line and column of a byte offset. Columns count characters.
+pub fn location(source: &str, offset: usize) -> (usize, usize) {
+    let before = &source[..offset];
+    let line = before.matches('\n').count() + 1;
+    let column = before.chars().rev().take_while(|c| *c != '\n').count() + 1;
+    (line, column)
+}
+
+/// Where each line of a source starts, for looking up the locations of many
+/// offsets without rescanning the source for each.
+pub struct LineIndex<'s> {
+    source: &'s str,
+    /// Byte offset of the start of each line.
+    starts: Vec<usize>,
+}
+
+impl<'s> LineIndex<'s> {
+    pub fn new(source: &'s str) -> Self {
+        let starts = std::iter::once(0)
+            .chain(source.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        Self { source, starts }
+    }
+
+    /// 1-based line of a byte offset, and the offset that line starts at.
+    pub fn line(&self, offset: usize) -> (usize, usize) {
+        let line = self.starts.partition_point(|start| *start <= offset);
+        (line, self.starts[line - 1])
+    }
+
+    /// 1-based line and column of a byte offset, as [`location`] gives them.
+    pub fn location(&self, offset: usize) -> (usize, usize) {
+        let (line, start) = self.line(offset);
+        (line, self.source[start..offset].chars().count() + 1)
+    }
+}
@@ -0,0 +1,361 @@
+//! Lints over the parsed AST.
+//!
+//! Every rule has a stable code (`L001`), a name (`undefined-variable`) and a
+//! default severity. Severities can be overridden from the `[lint]` table of
+//! a `falsy.toml` file, keyed by either the code or the name:
+//!
+//! ```toml
+//! [lint]
+//! L002 = "off"
+//! dropped-lambda = "error"
+//! ```
+
+use std::collections::HashMap;
+
+use chumsky::span::SimpleSpan;
+
+use crate::ast::{FalseInstruction, Spanned};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Severity {
+    Off,
+    Warning,
+    Error,
+}
+
+impl std::str::FromStr for Severity {
+    type Err = LintConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" | "allow" => Ok(Self::Off),
+            "warn" | "warning" => Ok(Self::Warning),
+            "error" | "deny" => Ok(Self::Error),
+            other => Err(LintConfigError(format!(
+                "Unknown severity {other:?}, expected \"off\", \"warn\" or \"error\""
+            ))),
+        }
+    }
+}
+
+impl std::fmt::Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Off => write!(f, "off"),
+            Self::Warning => write!(f, "warning"),
+            Self::Error => write!(f, "error"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Hash)]
+pub struct Rule {
+    pub code: &'static str,
+    pub name: &'static str,
+    pub default_severity: Severity,
+    pub description: &'static str,
+}
+
+pub const UNDEFINED_VARIABLE: Rule = Rule {
+    code: "L001",
+    name: "undefined-variable",
+    default_severity: Severity::Error,
+    description: "A variable is fetched but never stored anywhere in the program",
+};
+
+pub const UNUSED_VARIABLE: Rule = Rule {
+    code: "L002",
+    name: "unused-variable",
+    default_severity: Severity::Warning,
+    description: "A variable is stored but never fetched",
+};
+
+pub const DROPPED_LAMBDA: Rule = Rule {
+    code: "L003",
+    name: "dropped-lambda",
+    default_severity: Severity::Warning,
+    description: "A lambda is pushed and immediately dropped",
+};
+
+pub const UNREACHABLE_CODE: Rule = Rule {
+    code: "L004",
+    name: "unreachable-code",
+    default_severity: Severity::Warning,
+    description: "Code guarded by a condition that is always false",
+};
+
+pub const NO_OP_PAIR: Rule = Rule {
+    code: "L005",
+    name: "no-op-pair",
+    default_severity: Severity::Warning,
+    description: "Two adjacent instructions that cancel each other out",
+};
+
+pub const RULES: &[&Rule] = &[
+    &UNDEFINED_VARIABLE,
+    &UNUSED_VARIABLE,
+    &DROPPED_LAMBDA,
+    &UNREACHABLE_CODE,
+    &NO_OP_PAIR,
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LintConfigError(String);
+
+impl std::fmt::Display for LintConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for LintConfigError {}
+
+/// Severity of every rule, starting from the defaults.
+#[derive(Debug, Clone, Default)]
+pub struct LintConfig {
+    overrides: HashMap<&'static str, Severity>,
+}
+
+impl LintConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reads the `[lint]` table of a `falsy.toml` file. A file without that
+    /// table gives the default configuration.
+    pub fn from_toml(contents: &str) -> Result<Self, LintConfigError> {
+        let table: toml::Table = contents
+            .parse()
+            .map_err(|e: toml::de::Error| LintConfigError(e.message().to_string()))?;
+        let mut config = Self::new();
+        let Some(lint) = table.get("lint") else {
+            return Ok(config);
+        };
+        let lint = lint
+            .as_table()
+            .ok_or_else(|| LintConfigError("Expected [lint] to be a table".to_string()))?;
+        for (key, value) in lint {
+            let severity = value
+                .as_str()
+                .ok_or_else(|| LintConfigError(format!("Expected a string severity for {key}")))?
+                .parse()?;
+            config.set(key, severity)?;
+        }
+        Ok(config)
+    }
+
+    /// Sets the severity of a rule, by code or by name.
+    pub fn set(&mut self, rule: &str, severity: Severity) -> Result<(), LintConfigError> {
+        let rule = RULES
+            .iter()
+            .find(|r| r.code == rule || r.name == rule)
+            .ok_or_else(|| LintConfigError(format!("Unknown lint rule {rule:?}")))?;
+        self.overrides.insert(rule.code, severity);
+        Ok(())
+    }
+
+    pub fn severity(&self, rule: &Rule) -> Severity {
+        self.overrides
+            .get(rule.code)
+            .copied()
+            .unwrap_or(rule.default_severity)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LintDiagnostic {
+    rule: &'static Rule,
+    severity: Severity,
+    span: SimpleSpan<usize>,
+    message: String,
+}
+
+impl LintDiagnostic {
+    pub fn rule(&self) -> &'static Rule {
+        self.rule
+    }
+    pub fn severity(&self) -> Severity {
+        self.severity
+    }
+    pub fn span(&self) -> SimpleSpan<usize> {
+        self.span
+    }
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl std::fmt::Display for LintDiagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[{}] {}", self.rule.code, self.message)
+    }
+}
+
+/// Runs every enabled rule over the program and returns the diagnostics
+/// ordered by position.
+pub fn lint(program: &[Spanned<FalseInstruction>], config: &LintConfig) -> Vec<LintDiagnostic> {
+    let mut linter = Linter {
+        config,
+        diagnostics: Vec::new(),
+    };
+
+    let mut usage = VariableUsage::default();
+    usage.collect(program);
+    linter.check_variables(&usage);
+    linter.check_block(program);
+
+    linter
+        .diagnostics
+        .sort_by_key(|d| (d.span.start, d.span.end, d.rule.code));
+    linter.diagnostics
+}
+
+/// Where each variable is stored and fetched, by name.
+#[derive(Default)]
+struct VariableUsage {
+    stores: HashMap<char, Vec<SimpleSpan<usize>>>,
+    fetches: HashMap<char, Vec<SimpleSpan<usize>>>,
+    /// A `:` not directly preceded by a name, which could store anything.
+    dynamic_store: bool,
+    /// A `;` not directly preceded by a name, which could fetch anything.
+    dynamic_fetch: bool,
+}
+
+impl VariableUsage {
+    fn collect(&mut self, instructions: &[Spanned<FalseInstruction>]) {
+        use FalseInstruction::*;
+
+        let mut previous: Option<&Spanned<FalseInstruction>> = None;
+        for spanned in instructions {
+            let name = previous.and_then(|p| match p.instruction() {
+                Name(c) => Some((*c, SimpleSpan::from(p.span().start..spanned.span().end))),
+                _ => None,
+            });
+            match (spanned.instruction(), name) {
+                (Store, Some((c, span))) => self.stores.entry(c).or_default().push(span),
+                (Fetch, Some((c, span))) => self.fetches.entry(c).or_default().push(span),
+                (Store, None) => self.dynamic_store = true,
+                (Fetch, None) => self.dynamic_fetch = true,
+                (Lambda(body), _) | (ConditionalExecute(body), _) => self.collect(body),
+                (WhileLoop(condition, body), _) => {
+                    self.collect(condition);
+                    self.collect(body);
+                }
+                _ => {}
+            }
+            previous = Some(spanned);
+        }
+    }
+}
+
+struct Linter<'c> {
+    config: &'c LintConfig,
+    diagnostics: Vec<LintDiagnostic>,
+}
+
+impl Linter<'_> {
+    fn report(&mut self, rule: &'static Rule, span: SimpleSpan<usize>, message: String) {
+        let severity = self.config.severity(rule);
+        if severity != Severity::Off {
+            self.diagnostics.push(LintDiagnostic {
+                rule,
+                severity,
+                span,
+                message,
+            });
+        }
+    }
+
+    fn check_variables(&mut self, usage: &VariableUsage) {
+        if !usage.dynamic_store {
+            for (name, spans) in &usage.fetches {
+                if !usage.stores.contains_key(name) {
+                    for span in spans {
+                        self.report(
+                            &UNDEFINED_VARIABLE,
+                            *span,
+                            format!("Variable {name} is fetched but never stored"),
+                        );
+                    }
+                }
+            }
+        }
+        if !usage.dynamic_fetch {
+            for (name, spans) in &usage.stores {
+                if !usage.fetches.contains_key(name) {
+                    for span in spans {
+                        self.report(
+                            &UNUSED_VARIABLE,
+                            *span,
+                            format!("Variable {name} is stored but never fetched"),
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    fn check_block(&mut self, instructions: &[Spanned<FalseInstruction>]) {
+        use FalseInstruction::*;
+
+        for pair in instructions.windows(2) {
+            let (first, second) = (&pair[0], &pair[1]);
+            let span = SimpleSpan::from(first.span().start..second.span().end);
+            match (first.instruction(), second.instruction()) {
+                (Lambda(_), Drop) => self.report(
+                    &DROPPED_LAMBDA,
+                    span,
+                    "Lambda is pushed and immediately dropped".to_string(),
+                ),
+                (PushInt(0), ConditionalExecute(_)) | (PushChar(0), ConditionalExecute(_)) => self
+                    .report(
+                        &UNREACHABLE_CODE,
+                        second.span(),
+                        "Condition is always false, so this never runs".to_string(),
+                    ),
+                (Dup, Drop) | (Swap, Swap) | (Neg, Neg) | (BitNot, BitNot) => self.report(
+                    &NO_OP_PAIR,
+                    span,
+                    format!(
+                        "{} followed by {} has no effect",
+                        symbol(first.instruction()),
+                        symbol(second.instruction())
+                    ),
+                ),
+                _ => {}
+            }
+        }
+
+        for spanned in instructions {
+            match spanned.instruction() {
+                Lambda(body) | ConditionalExecute(body) => self.check_block(body),
+                WhileLoop(condition, body) => {
+                    if let [only] = condition.as_slice() {
+                        if matches!(only.instruction(), PushInt(0) | PushChar(0)) {
+                            self.report(
+                                &UNREACHABLE_CODE,
+                                spanned.span(),
+                                "Loop condition is always false, so the body never runs"
+                                    .to_string(),
+                            );
+                        }
+                    }
+                    self.check_block(condition);
+                    self.check_block(body);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+fn symbol(instruction: &FalseInstruction) -> &'static str {
+    match instruction {
+        FalseInstruction::Dup => "$",
+        FalseInstruction::Drop => "%",
+        FalseInstruction::Swap => "\\",
+        FalseInstruction::Neg => "_",
+        FalseInstruction::BitNot => "~",
+        _ => unreachable!("No symbol needed for {instruction:?}"),
+    }
+}
@@ -0,0 +1,644 @@
+//! A Language Server Protocol server, started by `falsy lsp`.
+//!
+//! Editors send each document's full text as it changes, and get back the
+//! parse errors as diagnostics. Everything else works from the AST of the
+//! last parse, which still has what the parser recovered when there are
+//! errors: hover shows the inferred stack effect of what is under the
+//! cursor, definitions of `x` are its `x:` stores, references are every
+//! `x`, and the document's symbols are the lambdas stored straight into a
+//! variable, as in `[...]f:`.
+//!
+//! Positions are in UTF-16 code units, as the protocol has them by default.
+
+use std::collections::HashMap;
+use std::error::Error;
+
+use chumsky::span::SimpleSpan;
+use lsp_server::{Connection, ErrorCode, Message, Notification, Request, Response};
+use lsp_types::notification::{
+    DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument,
+    Notification as NotificationKind, PublishDiagnostics,
+};
+use lsp_types::request::{
+    DocumentSymbolRequest, GotoDefinition, HoverRequest, References, Request as RequestKind,
+    SemanticTokensFullRequest,
+};
+use lsp_types::{
+    Diagnostic, DiagnosticSeverity, DocumentSymbol, DocumentSymbolParams, DocumentSymbolResponse,
+    GotoDefinitionParams, GotoDefinitionResponse, Hover, HoverContents, HoverParams,
+    HoverProviderCapability, Location, MarkupContent, MarkupKind, OneOf, Position,
+    PublishDiagnosticsParams, Range, ReferenceParams, SemanticToken, SemanticTokenModifier,
+    SemanticTokenType, SemanticTokens, SemanticTokensFullOptions, SemanticTokensLegend,
+    SemanticTokensOptions, SemanticTokensParams, SemanticTokensResult,
+    SemanticTokensServerCapabilities, ServerCapabilities, SymbolKind, TextDocumentIdentifier,
+    TextDocumentSyncCapability, TextDocumentSyncKind, Uri,
+};
+
+use crate::analysis::stack_effect::StackEffects;
+use crate::ast::{walk_block, FalseInstruction, Spanned, Visitor};
+use crate::parser::parse;
+
+type Instructions = [Spanned<FalseInstruction>];
+
+/// Token types in the order of the legend, which tokens refer to by index.
+const TOKEN_TYPES: [SemanticTokenType; 6] = [
+    SemanticTokenType::VARIABLE,
+    SemanticTokenType::NUMBER,
+    SemanticTokenType::STRING,
+    SemanticTokenType::OPERATOR,
+    SemanticTokenType::KEYWORD,
+    SemanticTokenType::COMMENT,
+];
+const VARIABLE: u32 = 0;
+const NUMBER: u32 = 1;
+const STRING: u32 = 2;
+const OPERATOR: u32 = 3;
+const KEYWORD: u32 = 4;
+const COMMENT: u32 = 5;
+/// The bit for the only token modifier, on the `x` of `x:`.
+const DECLARATION: u32 = 1;
+
+/// Serves one client over `connection`, until it shuts the server down or
+/// disconnects. Fails if the client breaks the protocol.
+pub fn run(connection: Connection) -> Result<(), Box<dyn Error + Send + Sync>> {
+    connection.initialize(serde_json::to_value(capabilities())?)?;
+    let mut server = Server {
+        connection: &connection,
+        documents: HashMap::new(),
+    };
+    for message in &connection.receiver {
+        match message {
+            Message::Request(request) => {
+                if connection.handle_shutdown(&request)? {
+                    return Ok(());
+                }
+                server.request(request)?;
+            }
+            Message::Notification(notification) => server.notify(notification)?,
+            Message::Response(_) => {}
+        }
+    }
+    Ok(())
+}
+
+fn capabilities() -> ServerCapabilities {
+    ServerCapabilities {
+        text_document_sync: Some(TextDocumentSyncCapability::Kind(TextDocumentSyncKind::FULL)),
+        hover_provider: Some(HoverProviderCapability::Simple(true)),
+        definition_provider: Some(OneOf::Left(true)),
+        references_provider: Some(OneOf::Left(true)),
+        document_symbol_provider: Some(OneOf::Left(true)),
+        semantic_tokens_provider: Some(SemanticTokensServerCapabilities::SemanticTokensOptions(
+            SemanticTokensOptions {
+                legend: SemanticTokensLegend {
+                    token_types: TOKEN_TYPES.to_vec(),
+                    token_modifiers: vec![SemanticTokenModifier::DECLARATION],
+                },
+                full: Some(SemanticTokensFullOptions::Bool(true)),
+                ..SemanticTokensOptions::default()
+            },
+        )),
+        ..ServerCapabilities::default()
+    }
+}
+
+struct Server<'c> {
+    connection: &'c Connection,
+    documents: HashMap<Uri, Document>,
+}
+
+impl Server<'_> {
+    fn notify(&mut self, notification: Notification) -> Result<(), Box<dyn Error + Send + Sync>> {
+        match notification.method.as_str() {
+            DidOpenTextDocument::METHOD => {
+                let params = notification
+                    .extract::<<DidOpenTextDocument as NotificationKind>::Params>(
+                        DidOpenTextDocument::METHOD,
+                    )?;
+                let document = params.text_document;
+                self.open(document.uri, document.text, Some(document.version))
+            }
+            DidChangeTextDocument::METHOD => {
+                let params = notification
+                    .extract::<<DidChangeTextDocument as NotificationKind>::Params>(
+                        DidChangeTextDocument::METHOD,
+                    )?;
+                // With full sync the last change has the whole text
+                match params.content_changes.into_iter().last() {
+                    Some(change) => self.open(
+                        params.text_document.uri,
+                        change.text,
+                        Some(params.text_document.version),
+                    ),
+                    None => Ok(()),
+                }
+            }
+            DidCloseTextDocument::METHOD => {
+                let params = notification
+                    .extract::<<DidCloseTextDocument as NotificationKind>::Params>(
+                        DidCloseTextDocument::METHOD,
+                    )?;
+                let uri = params.text_document.uri;
+                self.documents.remove(&uri);
+                self.publish(uri, Vec::new(), None)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Parses a new or changed document and publishes its diagnostics.
+    fn open(
+        &mut self,
+        uri: Uri,
+        text: String,
+        version: Option<i32>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let (document, diagnostics) = Document::new(text);
+        self.documents.insert(uri.clone(), document);
+        self.publish(uri, diagnostics, version)
+    }
+
+    fn publish(
+        &self,
+        uri: Uri,
+        diagnostics: Vec<Diagnostic>,
+        version: Option<i32>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let params = PublishDiagnosticsParams::new(uri, diagnostics, version);
+        let notification = Notification::new(PublishDiagnostics::METHOD.to_string(), params);
+        self.connection.sender.send(notification.into())?;
+        Ok(())
+    }
+
+    fn request(&self, request: Request) -> Result<(), Box<dyn Error + Send + Sync>> {
+        match request.method.as_str() {
+            HoverRequest::METHOD => self.respond::<HoverRequest>(request, Self::hover),
+            GotoDefinition::METHOD => self.respond::<GotoDefinition>(request, Self::definition),
+            References::METHOD => self.respond::<References>(request, Self::references),
+            DocumentSymbolRequest::METHOD => {
+                self.respond::<DocumentSymbolRequest>(request, Self::symbols)
+            }
+            SemanticTokensFullRequest::METHOD => {
+                self.respond::<SemanticTokensFullRequest>(request, Self::tokens)
+            }
+            method => {
+                let response = Response::new_err(
+                    request.id,
+                    ErrorCode::MethodNotFound as i32,
+                    format!("Unsupported request {method}"),
+                );
+                self.connection.sender.send(response.into())?;
+                Ok(())
+            }
+        }
+    }
+
+    fn respond<R: RequestKind>(
+        &self,
+        request: Request,
+        handle: impl FnOnce(&Self, R::Params) -> R::Result,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let response = match serde_json::from_value(request.params) {
+            Ok(params) => Response::new_ok(request.id, handle(self, params)),
+            Err(e) => Response::new_err(
+                request.id,
+                ErrorCode::InvalidParams as i32,
+                format!("Invalid parameters for {}: {e}", R::METHOD),
+            ),
+        };
+        self.connection.sender.send(response.into())?;
+        Ok(())
+    }
+
+    fn document(&self, document: &TextDocumentIdentifier) -> Option<&Document> {
+        self.documents.get(&document.uri)
+    }
+
+    fn hover(&self, params: HoverParams) -> Option<Hover> {
+        let position = params.text_document_position_params;
+        let document = self.document(&position.text_document)?;
+        let (block, index) = find(&document.ast, document.offset(position.position))?;
+        let spanned = &block[index];
+        let effects = StackEffects::new(&document.ast);
+        let effect = |effect: Option<_>| {
+            effect.map_or_else(
+                || "unknown, it differs between paths".to_string(),
+                |effect| format!("`{effect}`"),
+            )
+        };
+
+        // The effect of a call is that of what it calls
+        let effect_here = match spanned.instruction() {
+            FalseInstruction::Execute => match index.checked_sub(1).map(|i| block[i].instruction())
+            {
+                Some(FalseInstruction::Lambda(body)) => effects.of_lambda(body),
+                Some(FalseInstruction::Fetch) => {
+                    called_variable(block, index).and_then(|name| effects.of_variable(name))
+                }
+                _ => None,
+            },
+            _ => effects.of_block(std::slice::from_ref(spanned)),
+        };
+        let mut lines = vec![
+            describe(spanned.instruction()),
+            format!("Stack effect: {}", effect(effect_here)),
+        ];
+        let when_called = match spanned.instruction() {
+            FalseInstruction::Lambda(body) => Some(effects.of_lambda(body)),
+            FalseInstruction::Name(name) => effects.of_variable(*name).map(Some),
+            _ => None,
+        };
+        if let Some(when_called) = when_called {
+            lines.push(format!("When called: {}", effect(when_called)));
+        }
+        Some(Hover {
+            contents: HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value: lines.join("\n\n"),
+            }),
+            range: Some(document.range(spanned.span())),
+        })
+    }
+
+    /// The `x:` stores of the variable at the cursor.
+    fn definition(&self, params: GotoDefinitionParams) -> Option<GotoDefinitionResponse> {
+        let position = params.text_document_position_params;
+        let document = self.document(&position.text_document)?;
+        let name = document.variable_at(position.position)?;
+        let mut stores = Vec::new();
+        blocks(&document.ast, &mut |block| {
+            for pair in block.windows(2) {
+                if let (FalseInstruction::Name(c), FalseInstruction::Store) =
+                    (pair[0].instruction(), pair[1].instruction())
+                {
+                    if *c == name {
+                        let span = SimpleSpan::new(pair[0].span().start, pair[1].span().end);
+                        stores.push(document.location(&position.text_document, span));
+                    }
+                }
+            }
+        });
+        (!stores.is_empty()).then_some(GotoDefinitionResponse::Array(stores))
+    }
+
+    /// Every use of the variable at the cursor, stores included only if
+    /// asked for.
+    fn references(&self, params: ReferenceParams) -> Option<Vec<Location>> {
+        let position = params.text_document_position;
+        let document = self.document(&position.text_document)?;
+        let name = document.variable_at(position.position)?;
+        let mut references = Vec::new();
+        blocks(&document.ast, &mut |block| {
+            for (i, spanned) in block.iter().enumerate() {
+                let stored = matches!(
+                    block.get(i + 1).map(Spanned::instruction),
+                    Some(FalseInstruction::Store)
+                );
+                if *spanned.instruction() == FalseInstruction::Name(name)
+                    && (params.context.include_declaration || !stored)
+                {
+                    references.push(document.location(&position.text_document, spanned.span()));
+                }
+            }
+        });
+        Some(references)
+    }
+
+    fn symbols(&self, params: DocumentSymbolParams) -> Option<DocumentSymbolResponse> {
+        let document = self.document(&params.text_document)?;
+        let effects = StackEffects::new(&document.ast);
+        Some(DocumentSymbolResponse::Nested(
+            document.symbols(&document.ast, &effects),
+        ))
+    }
+
+    fn tokens(&self, params: SemanticTokensParams) -> Option<SemanticTokensResult> {
+        let document = self.document(&params.text_document)?;
+        Some(SemanticTokensResult::Tokens(SemanticTokens {
+            result_id: None,
+            data: document.tokens(),
+        }))
+    }
+}
+
+struct Document {
+    text: String,
+    /// Byte offset of the start of each line.
+    lines: Vec<usize>,
+    ast: Vec<Spanned<FalseInstruction>>,
+}
+
+impl Document {
+    fn new(text: String) -> (Self, Vec<Diagnostic>) {
+        let (ast, errors) = parse(&text).into_output_errors();
+        let errors: Vec<_> = errors.iter().map(|e| (*e.span(), e.to_string())).collect();
+        let lines = std::iter::once(0)
+            .chain(text.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        let document = Self {
+            text,
+            lines,
+            ast: ast.unwrap_or_default(),
+        };
+        let diagnostics = errors
+            .into_iter()
+            .map(|(span, message)| Diagnostic {
+                range: document.range(span),
+                severity: Some(DiagnosticSeverity::ERROR),
+                source: Some("falsy".to_string()),
+                message,
+                ..Diagnostic::default()
+            })
+            .collect();
+        (document, diagnostics)
+    }
+
+    fn position(&self, offset: usize) -> Position {
+        let line = self.lines.partition_point(|start| *start <= offset) - 1;
+        let character = self.text[self.lines[line]..offset].encode_utf16().count();
+        Position::new(line as u32, character as u32)
+    }
+
+    /// The byte offset of a position, clamped to the end of its line.
+    fn offset(&self, position: Position) -> usize {
+        let Some(start) = self.lines.get(position.line as usize).copied() else {
+            return self.text.len();
+        };
+        let mut units = 0;
+        for (i, c) in self.text[start..].char_indices() {
+            if c == '\n' || units >= position.character as usize {
+                return start + i;
+            }
+            units += c.len_utf16();
+        }
+        self.text.len()
+    }
+
+    fn range(&self, span: SimpleSpan<usize>) -> Range {
+        Range::new(self.position(span.start), self.position(span.end))
+    }
+
+    fn location(&self, document: &TextDocumentIdentifier, span: SimpleSpan<usize>) -> Location {
+        Location::new(document.uri.clone(), self.range(span))
+    }
+
+    /// The variable named at a position, on its name or the `:` or `;`
+    /// after it.
+    fn variable_at(&self, position: Position) -> Option<char> {
+        let (block, index) = find(&self.ast, self.offset(position))?;
+        let index = match block[index].instruction() {
+            FalseInstruction::Store | FalseInstruction::Fetch => index.checked_sub(1)?,
+            _ => index,
+        };
+        match block[index].instruction() {
+            FalseInstruction::Name(name) => Some(*name),
+            _ => None,
+        }
+    }
+
+    /// Lambdas stored straight into a variable, with the ones stored
+    /// inside them as children.
+    fn symbols(&self, block: &Instructions, effects: &StackEffects) -> Vec<DocumentSymbol> {
+        struct Symbols<'d> {
+            document: &'d Document,
+            effects: &'d StackEffects<'d>,
+            symbols: Vec<DocumentSymbol>,
+        }
+
+        impl Visitor<'_> for Symbols<'_> {
+            fn visit_block(&mut self, block: &Instructions) {
+                use FalseInstruction::*;
+
+                for (i, spanned) in block.iter().enumerate() {
+                    let stored = match block.get(i + 1..i + 3) {
+                        Some([name, store]) if *store.instruction() == Store => {
+                            match name.instruction() {
+                                Name(name_char) => Some((*name_char, name.span(), store.span())),
+                                _ => None,
+                            }
+                        }
+                        _ => None,
+                    };
+                    let (Lambda(body), Some((name, name_span, store_span))) =
+                        (spanned.instruction(), stored)
+                    else {
+                        self.visit_instruction(spanned);
+                        continue;
+                    };
+                    let outer = std::mem::take(&mut self.symbols);
+                    self.visit_block(body);
+                    let children = std::mem::replace(&mut self.symbols, outer);
+                    #[allow(deprecated)]
+                    self.symbols.push(DocumentSymbol {
+                        name: name.to_string(),
+                        detail: self
+                            .effects
+                            .of_lambda(body)
+                            .map(|effect| effect.to_string()),
+                        kind: SymbolKind::FUNCTION,
+                        tags: None,
+                        deprecated: None,
+                        range: self
+                            .document
+                            .range(SimpleSpan::new(spanned.span().start, store_span.end)),
+                        selection_range: self.document.range(name_span),
+                        children: Some(children),
+                    });
+                }
+            }
+        }
+
+        let mut symbols = Symbols {
+            document: self,
+            effects,
+            symbols: Vec::new(),
+        };
+        symbols.visit_block(block);
+        symbols.symbols
+    }
+
+    /// Semantic tokens for every instruction and comment, delta-encoded.
+    fn tokens(&self) -> Vec<SemanticToken> {
+        let mut tokens = Vec::new();
+        blocks(&self.ast, &mut |block| {
+            for (i, spanned) in block.iter().enumerate() {
+                let span = spanned.span();
+                let kind = match spanned.instruction() {
+                    FalseInstruction::Name(_) => {
+                        let stored = matches!(
+                            block.get(i + 1).map(Spanned::instruction),
+                            Some(FalseInstruction::Store)
+                        );
+                        tokens.push((span, VARIABLE, if stored { DECLARATION } else { 0 }));
+                        continue;
+                    }
+                    FalseInstruction::PushInt(_) => NUMBER,
+                    FalseInstruction::PushChar(_) | FalseInstruction::WriteStr(_) => STRING,
+                    FalseInstruction::Execute => KEYWORD,
+                    // Bodies are blocks of their own; the `?` or `#` that
+                    // runs them is the last character
+                    FalseInstruction::ConditionalExecute(_) | FalseInstruction::WhileLoop(..) => {
+                        tokens.push((SimpleSpan::new(span.end - 1, span.end), KEYWORD, 0));
+                        continue;
+                    }
+                    FalseInstruction::Lambda(_) => continue,
+                    _ => OPERATOR,
+                };
+                tokens.push((span, kind, 0));
+            }
+        });
+        tokens.extend(
+            self.comments(&tokens)
+                .into_iter()
+                .map(|span| (span, COMMENT, 0)),
+        );
+        tokens.sort_by_key(|(span, _, _)| span.start);
+
+        let mut data = Vec::new();
+        let mut last = Position::new(0, 0);
+        for (span, kind, modifiers) in tokens {
+            // Tokens can't span lines, so strings and comments are split
+            let mut start = span.start;
+            for piece in self.text[span.into_range()].split_inclusive('\n') {
+                let length = piece.trim_end_matches('\n').encode_utf16().count() as u32;
+                let position = self.position(start);
+                start += piece.len();
+                if length == 0 {
+                    continue;
+                }
+                data.push(SemanticToken {
+                    delta_line: position.line - last.line,
+                    delta_start: if position.line == last.line {
+                        position.character - last.character
+                    } else {
+                        position.character
+                    },
+                    length,
+                    token_type: kind,
+                    token_modifiers_bitset: modifiers,
+                });
+                last = position;
+            }
+        }
+        data
+    }
+
+    /// Spans of the comments, found between the instructions' tokens,
+    /// where only brackets, `?`, `#` and whitespace can be besides.
+    fn comments(&self, tokens: &[(SimpleSpan<usize>, u32, u32)]) -> Vec<SimpleSpan<usize>> {
+        let mut taken: Vec<_> = tokens.iter().map(|(span, _, _)| *span).collect();
+        taken.sort_by_key(|span| span.start);
+        let gaps = std::iter::once(0)
+            .chain(taken.iter().map(|span| span.end))
+            .zip(
+                taken
+                    .iter()
+                    .map(|span| span.start)
+                    .chain(std::iter::once(self.text.len())),
+            );
+
+        let mut comments = Vec::new();
+        for (mut start, end) in gaps {
+            while let Some(open) = self.text[start..end].find('{') {
+                let open = start + open;
+                let close = self.text[open..end]
+                    .find('}')
+                    .map_or(end, |close| open + close + 1);
+                comments.push(SimpleSpan::new(open, close));
+                start = close;
+            }
+        }
+        comments
+    }
+}
+
+/// The innermost instruction at `offset`, as the block it is in and its
+/// index there.
+fn find(block: &Instructions, offset: usize) -> Option<(&Instructions, usize)> {
+    struct Find<'a> {
+        offset: usize,
+        found: Option<(&'a Instructions, usize)>,
+    }
+
+    impl<'a> Visitor<'a> for Find<'a> {
+        fn visit_block(&mut self, block: &'a Instructions) {
+            let contains = |spanned: &Spanned<FalseInstruction>| {
+                (spanned.span().start..spanned.span().end).contains(&self.offset)
+            };
+            if let Some(index) = block.iter().position(contains) {
+                self.found = Some((block, index));
+                self.visit_instruction(&block[index]);
+            }
+        }
+    }
+
+    let mut find = Find {
+        offset,
+        found: None,
+    };
+    find.visit_block(block);
+    find.found
+}
+
+/// Calls `f` with the program and every body in it.
+fn blocks<'a>(block: &'a Instructions, f: &mut impl FnMut(&'a Instructions)) {
+    struct Blocks<F>(F);
+
+    impl<'a, F: FnMut(&'a Instructions)> Visitor<'a> for Blocks<F> {
+        fn visit_block(&mut self, block: &'a Instructions) {
+            (self.0)(block);
+            walk_block(self, block);
+        }
+    }
+
+    Blocks(f).visit_block(block);
+}
+
+/// The variable called by the `x;!` ending at `index`.
+fn called_variable(block: &Instructions, index: usize) -> Option<char> {
+    match block.get(index.checked_sub(2)?)?.instruction() {
+        FalseInstruction::Name(name) => Some(*name),
+        _ => None,
+    }
+}
+
+fn describe(instruction: &FalseInstruction) -> String {
+    use FalseInstruction::*;
+
+    match instruction {
+        Name(name) => return format!("Pushes a reference to variable `{name}`"),
+        PushInt(n) => return format!("Pushes {n}"),
+        PushChar(c) => return format!("Pushes the character code {c}"),
+        WriteStr(s) => return format!("Writes {s:?}"),
+        _ => {}
+    }
+    match instruction {
+        Dup => "Duplicates the top value",
+        Drop => "Drops the top value",
+        Swap => "Swaps the top two values",
+        Rot => "Reverses the top three values",
+        Pick => "Copies the value the top value's number of places down",
+        Add => "Adds",
+        Sub => "Subtracts",
+        Mul => "Multiplies",
+        Div => "Divides",
+        Neg => "Negates",
+        BitAnd => "Bitwise and",
+        BitOr => "Bitwise or",
+        BitNot => "Bitwise not",
+        Gt => "Greater than, -1 if true and 0 if false",
+        Eq => "Equal, -1 if true and 0 if false",
+        Lambda(_) => "Pushes a lambda",
+        Execute => "Calls the lambda on top of the stack",
+        ConditionalExecute(_) => "Runs the lambda if the value below it is true",
+        WhileLoop(..) => "Runs the body while the condition leaves true",
+        Store => "Stores into a variable",
+        Fetch => "Fetches from a variable",
+        ReadChar => "Reads a character",
+        WriteChar => "Writes a character",
+        WriteInt => "Writes an integer",
+        Flush => "Flushes output",
+        Name(_) | PushInt(_) | PushChar(_) | WriteStr(_) => unreachable!("Described above"),
+    }
+    .to_string()
+}
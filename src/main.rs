@@ -1,47 +1,667 @@
-use std::path::PathBuf;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 
 use ariadne::{sources, Color, Label, Report, ReportKind};
+use chumsky::span::SimpleSpan;
+use falsy::analysis::effects::{EffectAnalysis, Effects, VariableSet};
+use falsy::analysis::ranges;
+use falsy::ast::{FalseInstruction, Spanned};
+use falsy::bundle::Bundle;
+use falsy::bytecode;
+use falsy::codegen;
+use falsy::debugger::Debugger;
+use falsy::formatter;
 use falsy::interpreter;
+use falsy::lines::location;
+use falsy::lint::{self, LintConfig, Severity};
+use falsy::minifier;
+use falsy::optimizer::{self, OptimizationLevel};
 use falsy::parser::parse;
+use falsy::partial_eval::{self, Specialized};
+use falsy::repl::{self, Repl, ReplError};
 
 fn main() {
-    let path = std::env::args()
-        .nth(1)
-        .expect("Expected path to source file");
-    let filename = PathBuf::from(&path)
-        .file_name()
-        .map(|s| s.to_string_lossy().to_string())
-        .unwrap_or_else(|| path.clone());
-    let contents = std::fs::read_to_string(path).expect("Failed to read file");
-    let ast = match parse(&contents).into_result() {
-        Ok(ast) => ast,
-        Err(errors) => {
-            for e in errors {
-                Report::build(ReportKind::Error, filename.clone(), e.span().start)
-                    .with_message(e.to_string())
-                    .with_label(
-                        Label::new((filename.clone(), e.span().into_range()))
-                            .with_message(e.reason().to_string())
-                            .with_color(Color::Red),
-                    )
-                    .finish()
-                    .print(sources([(filename.clone(), contents.clone())]))
-                    .unwrap();
+    // A copy made by `falsy build` runs its bundled program
+    if let Some(bundle) = std::env::current_exe()
+        .ok()
+        .and_then(|path| Bundle::read(&path).ok().flatten())
+    {
+        let source = SourceFile {
+            filename: bundle.filename,
+            contents: bundle.source,
+        };
+        let ast = optimizer::optimize(source.parse_or_exit(), bundle.level);
+        if let Err(e) = execute(ast, interpreter::Interpreter::new()) {
+            source.report(ReportKind::Error, e.span(), &e.to_string(), e.reason());
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let mut args = std::env::args().skip(1);
+    let Some(first) = args.next() else {
+        return repl();
+    };
+    match first.as_str() {
+        "check" => check(&args.next().expect("Expected path to source file")),
+        "lint" => lint(args.collect()),
+        "effects" => effects(&args.next().expect("Expected path to source file")),
+        "specialize" => specialize(args.collect()),
+        "compile" => compile(args.collect()),
+        "build" => build(args.collect()),
+        "debug" => debug(&args.next().expect("Expected path to source file")),
+        "dap" => dap(),
+        "lsp" => lsp(),
+        "fmt" => fmt(args.collect()),
+        "minify" => minify(&args.next().expect("Expected path to source file")),
+        _ => run(std::iter::once(first).chain(args).collect()),
+    }
+}
+
+/// Results cached by `--memoize` when no limit is given.
+const DEFAULT_MEMO_ENTRIES: usize = 100_000;
+
+fn run(args: Vec<String>) {
+    let mut level = OptimizationLevel::None;
+    let mut memo_entries = None;
+    let mut backend = interpreter::Backend::Tree;
+    let mut path = None;
+    for arg in args {
+        if let Some(n) = arg.strip_prefix("-O") {
+            level = n.parse().unwrap_or_else(|e| {
+                eprintln!("{e}");
+                std::process::exit(1);
+            });
+        } else if arg == "--memoize" {
+            memo_entries = Some(DEFAULT_MEMO_ENTRIES);
+        } else if let Some(n) = arg.strip_prefix("--memoize=") {
+            memo_entries = Some(n.parse().unwrap_or_else(|_| {
+                eprintln!("Expected a cache size, got {n:?}");
+                std::process::exit(1);
+            }));
+        } else if let Some(name) = arg.strip_prefix("--backend=") {
+            backend = name.parse().unwrap_or_else(|e| {
+                eprintln!("{e}");
+                std::process::exit(1);
+            });
+        } else {
+            path = Some(arg);
+        }
+    }
+    let path = path.expect("Expected path to source file");
+    let bytes = std::fs::read(&path).expect("Failed to read file");
+    // A damaged .fbc still goes to the loader, to be reported as bytecode
+    let fbc = Path::new(&path).extension().is_some_and(|e| e == "fbc");
+    let (ast, source, bytecode) = if fbc || bytecode::is_bytecode(&bytes) {
+        let bytecode = bytecode::load(&bytes).unwrap_or_else(|e| {
+            eprintln!("{path}: {e}");
+            std::process::exit(1);
+        });
+        // Errors are shown in context if the source is still next to it
+        let source = std::fs::read_to_string(Path::new(&path).with_file_name(&bytecode.filename))
+            .ok()
+            .filter(|contents| bytecode.matches_source(contents))
+            .map(|contents| SourceFile {
+                filename: bytecode.filename.clone(),
+                contents,
+            });
+        (bytecode.program.clone(), source, Some(bytecode))
+    } else {
+        let contents = String::from_utf8(bytes).unwrap_or_else(|_| {
+            eprintln!("{path}: Source isn't valid UTF-8");
+            std::process::exit(1);
+        });
+        let source = SourceFile::new(&path, contents);
+        (source.parse_or_exit(), Some(source), None)
+    };
+
+    let ast = optimizer::optimize(ast, level);
+    let mut interpreter = interpreter::Interpreter::new().backend(backend);
+    if let Some(max_entries) = memo_entries {
+        interpreter = interpreter.memoize(max_entries);
+    }
+    if let Err(e) = execute(ast, interpreter) {
+        match (source, bytecode) {
+            (Some(source), _) => {
+                source.report(ReportKind::Error, e.span(), &e.to_string(), e.reason())
+            }
+            (None, Some(bytecode)) => {
+                let (line, column) = bytecode.location(e.span().start).unwrap_or((1, 1));
+                eprintln!("{}:{line}:{column}: {e}", bytecode.filename);
+            }
+            (None, None) => unreachable!("Programs are loaded from source or bytecode"),
+        }
+        std::process::exit(1);
+    }
+}
+
+/// Runs a program, and prints memoization statistics if it succeeds.
+fn execute(
+    ast: Vec<Spanned<FalseInstruction>>,
+    interpreter: interpreter::Interpreter,
+) -> Result<(), interpreter::InterpreterRuntimeError> {
+    let outcome = interpreter.run_program(ast)?;
+    if let Some(stats) = outcome.memo_stats() {
+        std::io::stdout().flush().unwrap();
+        eprintln!();
+        eprintln!("Memoization: {stats}");
+    }
+    Ok(())
+}
+
+fn repl() {
+    // Program output doesn't always end in a newline, so one is added before
+    // the stack when it didn't
+    let at_line_start = std::cell::Cell::new(true);
+    let mut repl = Repl::new(interpreter::Interpreter::new().on_output(|s: &str| {
+        print!("{s}");
+        std::io::stdout().flush().unwrap();
+        if let Some(last) = s.chars().last() {
+            at_line_start.set(last == '\n');
+        }
+    }));
+    let mut editor = rustyline::DefaultEditor::new().expect("Failed to start line editor");
+    println!("falsy {}, :help for commands", env!("CARGO_PKG_VERSION"));
+
+    let mut entry = String::new();
+    loop {
+        let prompt = if entry.is_empty() { "> " } else { ". " };
+        match editor.readline(prompt) {
+            Ok(line) => {
+                entry.push_str(&line);
+                if repl::is_unfinished(&entry) {
+                    entry.push('\n');
+                    continue;
+                }
+            }
+            // Ctrl-C abandons the entry, and Ctrl-D quits
+            Err(rustyline::error::ReadlineError::Interrupted) => {
+                entry.clear();
+                continue;
+            }
+            Err(rustyline::error::ReadlineError::Eof) => break,
+            Err(e) => panic!("Failed to read line: {e}"),
+        }
+        let entry = std::mem::take(&mut entry);
+        if entry.trim().is_empty() {
+            continue;
+        }
+        let _ = editor.add_history_entry(&entry);
+
+        let result = repl.eval(&entry);
+        if !at_line_start.replace(true) {
+            println!();
+        }
+        match result {
+            Ok(reply) => println!("{reply}"),
+            Err(ReplError::Program {
+                filename,
+                source,
+                diagnostics,
+            }) => {
+                let source = SourceFile {
+                    filename,
+                    contents: source,
+                };
+                for d in diagnostics {
+                    source.report(ReportKind::Error, d.span, &d.message, &d.label);
+                }
             }
+            Err(ReplError::Command(message)) => eprintln!("{message}"),
+        }
+    }
+}
+
+fn debug(path: &str) {
+    let source = SourceFile::read(path);
+    let ast = source.parse_or_exit();
+    // As in the REPL, what the debugger shows starts on a line of its own
+    let at_line_start = std::cell::Cell::new(true);
+    let interpreter = interpreter::Interpreter::new().on_output(|s: &str| {
+        print!("{s}");
+        std::io::stdout().flush().unwrap();
+        if let Some(last) = s.chars().last() {
+            at_line_start.set(last == '\n');
+        }
+    });
+    let debugger = Debugger::new(&source.filename, &source.contents, ast)
+        .color(std::io::IsTerminal::is_terminal(&std::io::stdout()));
+    let mut editor = rustyline::DefaultEditor::new().expect("Failed to start line editor");
+    let result = debugger.run(
+        interpreter,
+        || loop {
+            match editor.readline("(falsy) ") {
+                Ok(line) => {
+                    if !line.trim().is_empty() {
+                        let _ = editor.add_history_entry(&line);
+                    }
+                    return Some(line);
+                }
+                Err(rustyline::error::ReadlineError::Interrupted) => continue,
+                Err(rustyline::error::ReadlineError::Eof) => return None,
+                Err(e) => panic!("Failed to read line: {e}"),
+            }
+        },
+        |message| {
+            if !at_line_start.replace(true) {
+                println!();
+            }
+            println!("{message}");
+        },
+    );
+    if result.is_err() {
+        std::process::exit(1);
+    }
+}
+
+fn dap() {
+    let input = std::io::BufReader::new(std::io::stdin());
+    if let Err(e) = falsy::debugger::dap::serve(input, std::io::stdout()) {
+        eprintln!("Failed to write to the client: {e}");
+        std::process::exit(1);
+    }
+}
+
+fn lsp() {
+    let (connection, io_threads) = lsp_server::Connection::stdio();
+    let result = falsy::lsp::run(connection).and_then(|()| Ok(io_threads.join()?));
+    if let Err(e) = result {
+        eprintln!("Language server failed: {e}");
+        std::process::exit(1);
+    }
+}
+
+fn fmt(args: Vec<String>) {
+    let mut check = false;
+    let mut paths = Vec::new();
+    for arg in args {
+        match arg.as_str() {
+            "--check" => check = true,
+            _ => paths.push(arg),
+        }
+    }
+
+    // Without paths it's a filter, for editors
+    if paths.is_empty() {
+        let mut contents = String::new();
+        std::io::Read::read_to_string(&mut std::io::stdin(), &mut contents)
+            .expect("Failed to read stdin");
+        let source = SourceFile::new("<stdin>", contents);
+        let formatted = source.format_or_exit();
+        if check {
+            if formatted != source.contents {
+                println!("<stdin> isn't formatted");
+                std::process::exit(1);
+            }
+        } else {
+            print!("{formatted}");
+        }
+        return;
+    }
+
+    let mut unformatted = false;
+    for path in paths {
+        let source = SourceFile::read(&path);
+        let formatted = source.format_or_exit();
+        if formatted == source.contents {
+            continue;
+        }
+        if check {
+            println!("{path} isn't formatted");
+            unformatted = true;
+        } else {
+            std::fs::write(&path, formatted)
+                .unwrap_or_else(|e| panic!("Failed to write {path}: {e}"));
+        }
+    }
+    if unformatted {
+        std::process::exit(1);
+    }
+}
+
+fn minify(path: &str) {
+    let ast = SourceFile::read(path).parse_or_exit();
+    print!("{}", minifier::minify(&ast));
+}
+
+fn check(path: &str) {
+    let source = SourceFile::read(path);
+    let ast = source.parse_or_exit();
+    let warnings = ranges::analyze(&ast);
+    for warning in &warnings {
+        let kind = if warning.is_certain() {
+            ReportKind::Error
+        } else {
+            ReportKind::Warning
+        };
+        let message = warning.to_string();
+        source.report(kind, warning.span(), &message, &message);
+    }
+    if warnings.iter().any(|w| w.is_certain()) {
+        std::process::exit(1);
+    }
+}
+
+fn lint(args: Vec<String>) {
+    let mut config_path = None;
+    let mut path = None;
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--config" => config_path = Some(args.next().expect("Expected path to config file")),
+            _ => path = Some(arg),
+        }
+    }
+    let path = path.expect("Expected path to source file");
+
+    // Without --config, pick up falsy.toml from the working directory if there is one
+    let config = match config_path.or_else(|| {
+        std::path::Path::new("falsy.toml")
+            .exists()
+            .then(|| "falsy.toml".to_string())
+    }) {
+        Some(config_path) => {
+            let contents = std::fs::read_to_string(&config_path).expect("Failed to read config");
+            LintConfig::from_toml(&contents).unwrap_or_else(|e| {
+                eprintln!("Invalid lint config {config_path}: {e}");
+                std::process::exit(1);
+            })
+        }
+        None => LintConfig::new(),
+    };
+
+    let source = SourceFile::read(&path);
+    let ast = source.parse_or_exit();
+    let diagnostics = lint::lint(&ast, &config);
+    for diagnostic in &diagnostics {
+        let kind = match diagnostic.severity() {
+            Severity::Error => ReportKind::Error,
+            _ => ReportKind::Warning,
+        };
+        source.report(
+            kind,
+            diagnostic.span(),
+            &diagnostic.to_string(),
+            diagnostic.rule().name,
+        );
+    }
+    if diagnostics.iter().any(|d| d.severity() == Severity::Error) {
+        std::process::exit(1);
+    }
+}
+
+fn specialize(args: Vec<String>) {
+    let mut input = String::new();
+    let mut input_complete = false;
+    let mut path = None;
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--input" => input = args.next().expect("Expected input after --input"),
+            "--eof" => input_complete = true,
+            _ => path = Some(arg),
+        }
+    }
+    let source = SourceFile::read(&path.expect("Expected path to source file"));
+    let ast = source.parse_or_exit();
+    match partial_eval::specialize(&ast, &input, input_complete) {
+        Specialized::Finished { output } => print!("{output}"),
+        residual => println!("{}", residual.to_source()),
+    }
+}
+
+fn compile(args: Vec<String>) {
+    let mut target = None;
+    let mut emit = None;
+    let mut source_map = false;
+    let mut output = None;
+    let mut path = None;
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--target" => target = Some(args.next().expect("Expected target after --target")),
+            "--emit" => emit = Some(args.next().expect("Expected output kind after --emit")),
+            "--source-map" => source_map = true,
+            "-o" => output = Some(args.next().expect("Expected path after -o")),
+            _ => path = Some(arg),
+        }
+    }
+    let source = SourceFile::read(&path.expect("Expected path to source file"));
+    let ast = source.parse_or_exit();
+    let (compiled, executable) = match (target.as_deref(), emit.as_deref()) {
+        (None, Some("bytecode")) => (
+            bytecode::compile(&ast, &source.contents, &source.filename),
+            false,
+        ),
+        (Some("c"), None) => (
+            codegen::c::compile(&ast, &source.contents, &source.filename).into_bytes(),
+            false,
+        ),
+        (Some("x86_64"), None) => (
+            codegen::x86_64::compile(&ast, &source.contents, &source.filename),
+            true,
+        ),
+        (Some("x86_64"), Some("asm")) => (
+            codegen::x86_64::assembly(&ast, &source.contents, &source.filename).into_bytes(),
+            false,
+        ),
+        (Some("wasm"), None) => (
+            codegen::wasm::compile(&ast, &source.contents, &source.filename),
+            false,
+        ),
+        (Some("wasm"), Some("wat")) => (
+            codegen::wasm::text(&ast, &source.contents, &source.filename).into_bytes(),
+            false,
+        ),
+        (Some("js"), None) if source_map => {
+            let Some(output) = &output else {
+                eprintln!("Expected -o with --source-map");
+                std::process::exit(1);
+            };
+            let js_filename = std::path::Path::new(output).file_name().map_or_else(
+                || output.clone(),
+                |name| name.to_string_lossy().into_owned(),
+            );
+            let (code, map) = codegen::js::compile_with_source_map(
+                &ast,
+                &source.contents,
+                &source.filename,
+                &js_filename,
+            );
+            std::fs::write(format!("{output}.map"), map).expect("Failed to write source map");
+            (code.into_bytes(), false)
+        }
+        (Some("js"), None) => (
+            codegen::js::compile(&ast, &source.contents, &source.filename).into_bytes(),
+            false,
+        ),
+        (Some(target @ ("c" | "x86_64" | "wasm" | "js")), Some(other)) => {
+            eprintln!("Can't emit {other:?} for target {target}");
+            std::process::exit(1);
+        }
+        (Some(other), _) => {
+            eprintln!("Unknown target {other:?}, expected c, x86_64, wasm or js");
+            std::process::exit(1);
+        }
+        (None, _) => {
+            eprintln!("Expected --target c, x86_64, wasm or js, or --emit bytecode");
             std::process::exit(1);
         }
     };
-    if let Err(e) = interpreter::Interpreter::new().run_program(ast) {
-        Report::build(ReportKind::Error, filename.clone(), e.span().start)
-            .with_message(e.to_string())
+    match output {
+        Some(output) => {
+            std::fs::write(&output, compiled).expect("Failed to write output");
+            #[cfg(unix)]
+            if executable {
+                use std::os::unix::fs::PermissionsExt;
+                std::fs::set_permissions(&output, std::fs::Permissions::from_mode(0o755))
+                    .expect("Failed to make output executable");
+            }
+        }
+        None if executable => {
+            eprintln!("Expected -o for an executable");
+            std::process::exit(1);
+        }
+        None => std::io::stdout().write_all(&compiled).unwrap(),
+    }
+}
+
+fn build(args: Vec<String>) {
+    let mut level = OptimizationLevel::None;
+    let mut output = None;
+    let mut path = None;
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if let Some(n) = arg.strip_prefix("-O") {
+            level = n.parse().unwrap_or_else(|e| {
+                eprintln!("{e}");
+                std::process::exit(1);
+            });
+        } else if arg == "-o" {
+            output = Some(args.next().expect("Expected path after -o"));
+        } else {
+            path = Some(arg);
+        }
+    }
+    let source = SourceFile::read(&path.expect("Expected path to source file"));
+    // Catch syntax errors now rather than when the executable runs
+    source.parse_or_exit();
+    let Some(output) = output else {
+        eprintln!("Expected -o for an executable");
+        std::process::exit(1);
+    };
+
+    let executable = std::env::current_exe()
+        .and_then(std::fs::read)
+        .expect("Failed to read the falsy executable");
+    let bundle = Bundle {
+        filename: source.filename,
+        source: source.contents,
+        level,
+    };
+    std::fs::write(&output, bundle.append_to(&executable)).expect("Failed to write output");
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&output, std::fs::Permissions::from_mode(0o755))
+            .expect("Failed to make output executable");
+    }
+}
+
+fn effects(path: &str) {
+    let source = SourceFile::read(path);
+    let ast = source.parse_or_exit();
+    let analysis = EffectAnalysis::analyze(&ast);
+
+    let yes_no = |b: bool| if b { "yes" } else { "-" };
+    let variables = |set: VariableSet| {
+        if set.is_empty() {
+            "-".to_string()
+        } else {
+            set.to_string()
+        }
+    };
+    let row = |name: String, stored_in: String, effects: Effects| {
+        println!(
+            "{:<12} {:<10} {:<6} {:<7} {:<8} {:<8} {}",
+            name,
+            stored_in,
+            yes_no(effects.reads_input),
+            yes_no(effects.writes_output),
+            variables(effects.stores),
+            variables(effects.fetches),
+            yes_no(effects.is_pure()),
+        )
+    };
+
+    println!(
+        "{:<12} {:<10} {:<6} {:<7} {:<8} {:<8} Pure",
+        "Lambda", "Stored in", "Input", "Output", "Stores", "Fetches"
+    );
+    row("program".to_string(), "-".to_string(), analysis.program());
+    for lambda in analysis.lambdas() {
+        let (line, column) = location(&source.contents, lambda.span().start);
+        row(
+            format!("{line}:{column}"),
+            variables(lambda.stored_in()),
+            lambda.effects(),
+        );
+    }
+    // Variables that hold lambdas, for the effect of calling them with x;!
+    let holding_lambdas: VariableSet = analysis
+        .lambdas()
+        .iter()
+        .fold(VariableSet::EMPTY, |set, l| set.union(l.stored_in()));
+    for name in holding_lambdas.iter() {
+        row(
+            format!("{name};!"),
+            "-".to_string(),
+            analysis.variable(name),
+        );
+    }
+}
+
+struct SourceFile {
+    filename: String,
+    contents: String,
+}
+
+impl SourceFile {
+    fn read(path: &str) -> Self {
+        Self::new(
+            path,
+            std::fs::read_to_string(path).expect("Failed to read file"),
+        )
+    }
+
+    fn new(path: &str, contents: String) -> Self {
+        let filename = PathBuf::from(path)
+            .file_name()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_else(|| path.to_string());
+        Self { filename, contents }
+    }
+
+    fn parse_or_exit(&self) -> Vec<Spanned<FalseInstruction>> {
+        match parse(&self.contents).into_result() {
+            Ok(ast) => ast,
+            Err(errors) => {
+                for e in errors {
+                    self.report(
+                        ReportKind::Error,
+                        *e.span(),
+                        &e.to_string(),
+                        &e.reason().to_string(),
+                    );
+                }
+                std::process::exit(1);
+            }
+        }
+    }
+
+    fn format_or_exit(&self) -> String {
+        formatter::format(&self.contents).unwrap_or_else(|errors| {
+            for e in errors {
+                self.report(ReportKind::Error, e.span(), &e.to_string(), e.reason());
+            }
+            std::process::exit(1);
+        })
+    }
+
+    fn report(&self, kind: ReportKind, span: SimpleSpan<usize>, message: &str, label: &str) {
+        let color = match kind {
+            ReportKind::Error => Color::Red,
+            _ => Color::Yellow,
+        };
+        Report::build(kind, self.filename.clone(), span.start)
+            .with_message(message)
             .with_label(
-                Label::new((filename.clone(), e.span().into_range()))
-                    .with_message(e.reason().to_string())
-                    .with_color(Color::Red),
+                Label::new((self.filename.clone(), span.into_range()))
+                    .with_message(label)
+                    .with_color(color),
             )
             .finish()
-            .print(sources([(filename.clone(), contents.clone())]))
+            .print(sources([(self.filename.clone(), self.contents.clone())]))
             .unwrap();
-        std::process::exit(1);
     }
 }
@@ -0,0 +1,171 @@
+//! The minifier behind `falsy minify`, for code golf.
+//!
+//! Writes a program back out as the shortest source it can find. Comments
+//! and whitespace are dropped, except for the space between two numbers.
+//! Each integer is written as a number or a character literal, whichever is
+//! shorter: 120 is `'x`, while 65 stays `65` unless `'A` saves the space
+//! next to another number. Characters written with `,` join the strings next
+//! to them, and neighbouring strings become one, so `"a"10,"b"` is written
+//! as a single string.
+//!
+//! The minified source parses to the same program, except that integers may
+//! be character literals and output may be merged into fewer strings, so it
+//! always prints the same.
+
+use chumsky::span::SimpleSpan;
+
+use crate::ast::{fold_block, FalseInstruction, Fold, Spanned};
+
+/// The shortest source found for `program`.
+pub fn minify(program: &[Spanned<FalseInstruction>]) -> String {
+    let mut minifier = Minifier {
+        output: String::new(),
+        after_number: false,
+    };
+    minifier.block(&MergeStrings.fold_block(program.to_vec()));
+    minifier.output
+}
+
+/// Turns characters written with `,` into strings, and joins strings that
+/// are next to each other.
+struct MergeStrings;
+
+impl Fold for MergeStrings {
+    fn fold_block(
+        &mut self,
+        block: Vec<Spanned<FalseInstruction>>,
+    ) -> Vec<Spanned<FalseInstruction>> {
+        use FalseInstruction::*;
+
+        let mut out: Vec<Spanned<FalseInstruction>> = Vec::with_capacity(block.len());
+        for spanned in fold_block(self, block) {
+            let (instruction, span) = match spanned.into_parts() {
+                (WriteChar, span) => {
+                    match out.last().and_then(|last| string_char(last.instruction())) {
+                        Some(c) => {
+                            let (_, pushed) = out.pop().unwrap().into_parts();
+                            (WriteStr(c.to_string()), merge_spans(pushed, span))
+                        }
+                        None => (WriteChar, span),
+                    }
+                }
+                other => other,
+            };
+
+            let merged = match (out.last().map(Spanned::instruction), instruction) {
+                (Some(WriteStr(_)), WriteStr(text)) => {
+                    let (previous, previous_span) = out.pop().unwrap().into_parts();
+                    let WriteStr(previous) = previous else {
+                        unreachable!("Checked to be a string")
+                    };
+                    Spanned::new(WriteStr(previous + &text), merge_spans(previous_span, span))
+                }
+                (_, instruction) => Spanned::new(instruction, span),
+            };
+            out.push(merged);
+        }
+        out
+    }
+}
+
+/// The character an integer is, if writing it with `,` can be a string
+/// instead: ASCII, and not the `"` that ends strings.
+fn string_char(instruction: &FalseInstruction) -> Option<char> {
+    let value = match instruction {
+        FalseInstruction::PushInt(value) => u8::try_from(*value).ok()?,
+        FalseInstruction::PushChar(value) => *value,
+        _ => return None,
+    };
+    (value.is_ascii() && value != b'"').then_some(char::from(value))
+}
+
+fn merge_spans(first: SimpleSpan<usize>, second: SimpleSpan<usize>) -> SimpleSpan<usize> {
+    SimpleSpan::new(first.start, second.end)
+}
+
+struct Minifier {
+    output: String,
+    /// Whether the last token written was a number, which another one
+    /// can't follow straight after.
+    after_number: bool,
+}
+
+impl Minifier {
+    /// Writes a token, with the space that keeps two numbers apart.
+    fn token(&mut self, token: &str) {
+        if self.after_number && token.starts_with(|c: char| c.is_ascii_digit()) {
+            self.output.push(' ');
+        }
+        self.output.push_str(token);
+        self.after_number =
+            !token.starts_with('\'') && token.ends_with(|c: char| c.is_ascii_digit());
+    }
+
+    fn block(&mut self, block: &[Spanned<FalseInstruction>]) {
+        use FalseInstruction::*;
+
+        for (i, spanned) in block.iter().enumerate() {
+            let next = block.get(i + 1).map(Spanned::instruction);
+            let number_next = matches!(next, Some(PushInt(_) | PushChar(_)));
+            match spanned.instruction() {
+                PushInt(value) => self.integer(*value, number_next),
+                PushChar(value) => self.integer(i32::from(*value), number_next),
+                WriteStr(text) => self.string(text),
+                Lambda(body) => self.lambda(body),
+                ConditionalExecute(body) => {
+                    self.lambda(body);
+                    self.token("?");
+                }
+                WhileLoop(condition, body) => {
+                    self.lambda(condition);
+                    self.lambda(body);
+                    self.token("#");
+                }
+                other => self.token(&other.to_string()),
+            }
+        }
+    }
+
+    fn lambda(&mut self, body: &[Spanned<FalseInstruction>]) {
+        self.token("[");
+        self.block(body);
+        self.token("]");
+    }
+
+    /// Writes an integer, as a character literal where that is shorter, or
+    /// as short and saves a space next to another number.
+    fn integer(&mut self, value: i32, number_next: bool) {
+        if value == i32::MIN {
+            return self.token("2147483647_1-");
+        }
+        if value < 0 {
+            self.integer(-value, false);
+            return self.token("_");
+        }
+
+        let decimal = value.to_string();
+        let Some(c) = u8::try_from(value)
+            .ok()
+            .filter(|c| c.is_ascii_graphic() || *c == b' ')
+        else {
+            return self.token(&decimal);
+        };
+        if decimal.len() > 2 || (decimal.len() == 2 && (self.after_number || number_next)) {
+            self.token(&format!("'{}", char::from(c)));
+        } else {
+            self.token(&decimal);
+        }
+    }
+
+    /// Writes a string, or a digit-sized character with `,` where that is
+    /// shorter.
+    fn string(&mut self, text: &str) {
+        match text.as_bytes() {
+            [c] if *c < 10 => {
+                self.integer(i32::from(*c), false);
+                self.token(",");
+            }
+            _ => self.token(&format!("\"{text}\"")),
+        }
+    }
+}
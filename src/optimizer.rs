@@ -0,0 +1,221 @@
+//! AST optimiser.
+//!
+//! Rewrites a program into an equivalent one that does less work at runtime.
+//! Instructions are fed one at a time into an output buffer, and every
+//! rewrite looks at the end of that buffer, so folds cascade: `1 2 + 3 *`
+//! becomes `9` and then `9 .` becomes `"9"`.
+//!
+//! Rewritten nodes span everything they replace, so runtime errors still
+//! point at the original source. Like the lint for no-op pairs, removing `$%`
+//! and `\\` assumes the program doesn't rely on them failing on a short stack,
+//! and removing `__` and `~~` assumes the top of the stack is an integer and
+//! not a lambda or variable name, which would make them fail.
+
+use chumsky::span::SimpleSpan;
+
+use crate::ast::{FalseInstruction, Spanned};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum OptimizationLevel {
+    /// `-O0`: run the program as written.
+    #[default]
+    None,
+    /// `-O1`: constant folding and peephole rewrites.
+    Basic,
+    /// `-O2`: also remove branches that can never run, and inline lambdas
+    /// that are executed right where they are defined.
+    Full,
+}
+
+impl std::str::FromStr for OptimizationLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "0" => Ok(Self::None),
+            "1" => Ok(Self::Basic),
+            "2" => Ok(Self::Full),
+            other => Err(format!("Unknown optimization level {other:?}")),
+        }
+    }
+}
+
+pub fn optimize(
+    program: Vec<Spanned<FalseInstruction>>,
+    level: OptimizationLevel,
+) -> Vec<Spanned<FalseInstruction>> {
+    if level == OptimizationLevel::None {
+        return program;
+    }
+    Optimizer { level }.block(program)
+}
+
+struct Optimizer {
+    level: OptimizationLevel,
+}
+
+impl Optimizer {
+    fn block(
+        &self,
+        instructions: Vec<Spanned<FalseInstruction>>,
+    ) -> Vec<Spanned<FalseInstruction>> {
+        let mut out = Vec::with_capacity(instructions.len());
+        for spanned in instructions {
+            let (instruction, span) = spanned.into_parts();
+            self.push(&mut out, Spanned::new(self.children(instruction), span));
+        }
+        out
+    }
+
+    fn children(&self, instruction: FalseInstruction) -> FalseInstruction {
+        use FalseInstruction::*;
+        match instruction {
+            Lambda(body) => Lambda(self.block(body)),
+            ConditionalExecute(body) => ConditionalExecute(self.block(body)),
+            WhileLoop(condition, body) => WhileLoop(self.block(condition), self.block(body)),
+            other => other,
+        }
+    }
+
+    /// Appends `next` to `out`, rewriting it together with the instructions
+    /// before it where possible.
+    fn push(&self, out: &mut Vec<Spanned<FalseInstruction>>, next: Spanned<FalseInstruction>) {
+        use FalseInstruction::*;
+
+        let full = self.level >= OptimizationLevel::Full;
+        let span = next.span();
+        let last = out.last().map(|s| s.instruction());
+
+        match next.instruction() {
+            Add | Sub | Mul | Div | BitAnd | BitOr | Gt | Eq => {
+                if let [.., b, a] = out.as_slice() {
+                    if let (Some(b_value), Some(a_value)) = (constant(b), constant(a)) {
+                        if let Some(result) = fold_binary(next.instruction(), b_value, a_value) {
+                            let span = join(b.span(), span);
+                            out.truncate(out.len() - 2);
+                            return self.push(out, Spanned::new(PushInt(result), span));
+                        }
+                    }
+                }
+            }
+            Neg | BitNot => {
+                if let Some(value) = out.last().and_then(constant) {
+                    let result = match next.instruction() {
+                        Neg => value.checked_neg(),
+                        _ => Some(!value),
+                    };
+                    if let Some(result) = result {
+                        let span = join(out.pop().unwrap().span(), span);
+                        return self.push(out, Spanned::new(PushInt(result), span));
+                    }
+                }
+                if last == Some(next.instruction()) {
+                    out.pop();
+                    return;
+                }
+            }
+            Drop => {
+                if matches!(
+                    last,
+                    Some(PushInt(_) | PushChar(_) | Name(_) | Lambda(_) | Dup)
+                ) {
+                    out.pop();
+                    return;
+                }
+            }
+            Swap if last == Some(&Swap) => {
+                out.pop();
+                return;
+            }
+            WriteChar => {
+                let printable = out
+                    .last()
+                    .and_then(constant)
+                    .and_then(|value| char::from_u32(value as u32));
+                if let Some(c) = printable {
+                    let span = join(out.pop().unwrap().span(), span);
+                    return self.push(out, Spanned::new(WriteStr(c.to_string()), span));
+                }
+            }
+            WriteInt => {
+                if let Some(value) = out.last().and_then(constant) {
+                    let span = join(out.pop().unwrap().span(), span);
+                    return self.push(out, Spanned::new(WriteStr(value.to_string()), span));
+                }
+            }
+            WriteStr(s) => {
+                if let Some(WriteStr(previous)) = last {
+                    let merged = format!("{previous}{s}");
+                    let span = join(out.pop().unwrap().span(), span);
+                    out.push(Spanned::new(WriteStr(merged), span));
+                    return;
+                }
+            }
+            ConditionalExecute(_) if full => {
+                if let Some(condition) = out.last().and_then(constant) {
+                    out.pop();
+                    if condition != 0 {
+                        let (ConditionalExecute(body), _) = next.into_parts() else {
+                            unreachable!()
+                        };
+                        for spanned in body {
+                            self.push(out, spanned);
+                        }
+                    }
+                    return;
+                }
+            }
+            Execute if full => {
+                if let Some(Lambda(_)) = last {
+                    let (Lambda(body), _) = out.pop().unwrap().into_parts() else {
+                        unreachable!()
+                    };
+                    for spanned in body {
+                        self.push(out, spanned);
+                    }
+                    return;
+                }
+            }
+            WhileLoop(condition, _) if full => {
+                // The condition only pushes false, so the loop is a no-op
+                if let [only] = condition.as_slice() {
+                    if constant(only) == Some(0) {
+                        return;
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        out.push(next);
+    }
+}
+
+fn constant(spanned: &Spanned<FalseInstruction>) -> Option<i32> {
+    match spanned.instruction() {
+        FalseInstruction::PushInt(v) => Some(*v),
+        FalseInstruction::PushChar(c) => Some((*c).into()),
+        _ => None,
+    }
+}
+
+/// Folds `b a op`, leaving alone anything that would fail or overflow at
+/// runtime.
+fn fold_binary(op: &FalseInstruction, b: i32, a: i32) -> Option<i32> {
+    use FalseInstruction::*;
+    match op {
+        Add => b.checked_add(a),
+        Sub => b.checked_sub(a),
+        Mul => b.checked_mul(a),
+        Div => b.checked_div(a),
+        BitAnd => Some(b & a),
+        BitOr => Some(b | a),
+        Gt => Some(if b > a { -1 } else { 0 }),
+        Eq => Some(if b == a { -1 } else { 0 }),
+        _ => None,
+    }
+}
+
+fn join(first: SimpleSpan<usize>, last: SimpleSpan<usize>) -> SimpleSpan<usize> {
+    SimpleSpan::from(first.start.min(last.start)..first.end.max(last.end))
+}
@@ -115,10 +115,12 @@ fn parser<'a>(
             .padded()
             .repeated()
             .collect()
+            // For blocks with no instructions, only whitespace and comments
+            .padded_by(comment.padded())
     })
 }
 
-pub fn parse(input: &str) -> ParseResult<Vec<Spanned<FalseInstruction>>, Rich<char>> {
+pub fn parse(input: &str) -> ParseResult<Vec<Spanned<FalseInstruction>>, Rich<'_, char>> {
     parser().parse(input)
 }
 
@@ -132,7 +134,7 @@ where
         // Use try_map over filter to get a better error on failure
         .try_map(move |c: C, span| {
             let as_char = c.to_char();
-            if ('a'..='z').contains(&as_char) {
+            if as_char.is_ascii_lowercase() {
                 Ok(c)
             } else {
                 Err(chumsky::error::Error::expected_found(
@@ -0,0 +1,420 @@
+//! Partial evaluation for a known input prefix.
+//!
+//! The program runs at compile time for as long as it only needs known data.
+//! Execution stops at the first `^` past the known input, or anything the
+//! evaluator won't do ahead of time (a runtime error, or running out of
+//! steps). The residual program rebuilds the output, globals and stack
+//! produced so far, and then carries on from where execution stopped.
+//!
+//! Continuations keep their original spans, so a runtime error in the
+//! residual program still points at the original source.
+
+use chumsky::span::SimpleSpan;
+
+use crate::ast::{to_source, FalseInstruction, Spanned};
+
+/// Instructions run at compile time before giving up and leaving the rest
+/// for runtime.
+const MAX_STEPS: usize = 10_000_000;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Specialized {
+    /// The program finished at compile time.
+    Finished { output: String },
+    /// What is left to run, which starts by printing the output so far.
+    Residual {
+        program: Vec<Spanned<FalseInstruction>>,
+    },
+}
+
+impl Specialized {
+    /// FALSE source for the result: the residual program, or a program that
+    /// just prints the output.
+    pub fn to_source(&self) -> String {
+        match self {
+            Self::Finished { output } => to_source(&write_output(output, SimpleSpan::from(0..0))),
+            Self::Residual { program } => to_source(program),
+        }
+    }
+}
+
+/// Specialises `program` for input that starts with `input`. If
+/// `input_complete` is set, reading past `input` gives end of input (-1)
+/// instead of stopping.
+pub fn specialize(
+    program: &[Spanned<FalseInstruction>],
+    input: &str,
+    input_complete: bool,
+) -> Specialized {
+    let mut machine = Machine {
+        stack: Vec::new(),
+        globals: std::array::from_fn(|_| None),
+        input: input.bytes(),
+        input_complete,
+        output: String::new(),
+        frames: vec![Frame::Block {
+            instructions: program,
+            next: 0,
+        }],
+    };
+
+    for _ in 0..MAX_STEPS {
+        match machine.step() {
+            Step::Continue => {}
+            Step::Finished => {
+                return Specialized::Finished {
+                    output: machine.output,
+                }
+            }
+            Step::Stuck => break,
+        }
+    }
+    Specialized::Residual {
+        program: machine.residual(),
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Value<'a> {
+    Integer(i32),
+    Lambda(&'a [Spanned<FalseInstruction>]),
+    Name(char),
+}
+
+enum Frame<'a> {
+    Block {
+        instructions: &'a [Spanned<FalseInstruction>],
+        next: usize,
+    },
+    /// The loop condition has run; its result is on the stack.
+    AfterCondition {
+        loop_instruction: &'a Spanned<FalseInstruction>,
+    },
+    /// The loop body has run; the condition is next.
+    AfterBody {
+        loop_instruction: &'a Spanned<FalseInstruction>,
+    },
+}
+
+enum Step {
+    Continue,
+    Finished,
+    Stuck,
+}
+
+struct Machine<'a, 'i> {
+    stack: Vec<Value<'a>>,
+    globals: [Option<Value<'a>>; 26],
+    input: std::str::Bytes<'i>,
+    input_complete: bool,
+    output: String,
+    frames: Vec<Frame<'a>>,
+}
+
+impl<'a> Machine<'a, '_> {
+    fn step(&mut self) -> Step {
+        let Some(frame) = self.frames.last_mut() else {
+            return Step::Finished;
+        };
+        match frame {
+            Frame::Block { instructions, next } => {
+                let Some(spanned) = instructions.get(*next) else {
+                    self.frames.pop();
+                    return Step::Continue;
+                };
+                *next += 1;
+                if !self.apply(spanned) {
+                    // Nothing changed, so leave the instruction for runtime
+                    if let Some(Frame::Block { next, .. }) = self.frames.last_mut() {
+                        *next -= 1;
+                    }
+                    return Step::Stuck;
+                }
+            }
+            Frame::AfterCondition { loop_instruction } => {
+                let loop_instruction = *loop_instruction;
+                let Some(condition) = self.peek_int(0) else {
+                    return Step::Stuck;
+                };
+                self.stack.pop();
+                self.frames.pop();
+                if condition != 0 {
+                    let FalseInstruction::WhileLoop(_, body) = loop_instruction.instruction()
+                    else {
+                        unreachable!()
+                    };
+                    self.frames.push(Frame::AfterBody { loop_instruction });
+                    self.frames.push(Frame::Block {
+                        instructions: body,
+                        next: 0,
+                    });
+                }
+            }
+            Frame::AfterBody { loop_instruction } => {
+                let loop_instruction = *loop_instruction;
+                self.frames.pop();
+                self.enter_loop(loop_instruction);
+            }
+        }
+        Step::Continue
+    }
+
+    fn enter_loop(&mut self, loop_instruction: &'a Spanned<FalseInstruction>) {
+        let FalseInstruction::WhileLoop(condition, _) = loop_instruction.instruction() else {
+            unreachable!()
+        };
+        self.frames.push(Frame::AfterCondition { loop_instruction });
+        self.frames.push(Frame::Block {
+            instructions: condition,
+            next: 0,
+        });
+    }
+
+    fn peek(&self, depth: usize) -> Option<&Value<'a>> {
+        self.stack
+            .len()
+            .checked_sub(depth + 1)
+            .map(|i| &self.stack[i])
+    }
+
+    fn peek_int(&self, depth: usize) -> Option<i32> {
+        match self.peek(depth)? {
+            Value::Integer(i) => Some(*i),
+            _ => None,
+        }
+    }
+
+    fn pop_int(&mut self) -> i32 {
+        match self.stack.pop() {
+            Some(Value::Integer(i)) => i,
+            _ => unreachable!("Checked before popping"),
+        }
+    }
+
+    /// Runs one instruction, or returns false without changing anything if
+    /// it can't run at compile time. The block it came from has already moved
+    /// past it.
+    fn apply(&mut self, spanned: &'a Spanned<FalseInstruction>) -> bool {
+        use FalseInstruction::*;
+
+        match spanned.instruction() {
+            Name(c) => self.stack.push(Value::Name(*c)),
+            PushInt(v) => self.stack.push(Value::Integer(*v)),
+            PushChar(c) => self.stack.push(Value::Integer((*c).into())),
+            Dup => match self.peek(0) {
+                Some(top) => self.stack.push(top.clone()),
+                None => return false,
+            },
+            Drop => {
+                self.stack.pop();
+            }
+            Swap => {
+                if self.stack.len() < 2 {
+                    return false;
+                }
+                let len = self.stack.len();
+                self.stack.swap(len - 1, len - 2);
+            }
+            Rot => {
+                if self.stack.len() < 3 {
+                    return false;
+                }
+                // Matches the interpreter, which reverses the top three
+                let len = self.stack.len();
+                self.stack.swap(len - 1, len - 3);
+            }
+            Pick => {
+                let Some(index) = self.peek_int(0) else {
+                    return false;
+                };
+                if index < 0 || index as usize >= self.stack.len() - 1 {
+                    return false;
+                }
+                self.stack.pop();
+                let picked = self.stack[self.stack.len() - 1 - index as usize].clone();
+                self.stack.push(picked);
+            }
+            Add | Sub | Mul | Div | BitAnd | BitOr | Gt | Eq => {
+                let (Some(a), Some(b)) = (self.peek_int(0), self.peek_int(1)) else {
+                    return false;
+                };
+                let Some(result) = binary(spanned.instruction(), b, a) else {
+                    return false;
+                };
+                self.stack.truncate(self.stack.len() - 2);
+                self.stack.push(Value::Integer(result));
+            }
+            Neg | BitNot => {
+                let Some(a) = self.peek_int(0) else {
+                    return false;
+                };
+                let result = match spanned.instruction() {
+                    Neg => a.checked_neg(),
+                    _ => Some(!a),
+                };
+                let Some(result) = result else {
+                    return false;
+                };
+                self.stack.pop();
+                self.stack.push(Value::Integer(result));
+            }
+            Lambda(body) => self.stack.push(Value::Lambda(body)),
+            Execute => {
+                let Some(Value::Lambda(body)) = self.peek(0) else {
+                    return false;
+                };
+                let body = *body;
+                self.stack.pop();
+                self.frames.push(Frame::Block {
+                    instructions: body,
+                    next: 0,
+                });
+            }
+            ConditionalExecute(body) => {
+                let Some(condition) = self.peek_int(0) else {
+                    return false;
+                };
+                self.stack.pop();
+                if condition != 0 {
+                    self.frames.push(Frame::Block {
+                        instructions: body,
+                        next: 0,
+                    });
+                }
+            }
+            WhileLoop(..) => self.enter_loop(spanned),
+            Store => {
+                let (Some(Value::Name(name)), Some(value)) = (self.peek(0), self.peek(1)) else {
+                    return false;
+                };
+                if matches!(value, Value::Name(_)) {
+                    return false;
+                }
+                let index = variable_index(*name);
+                self.stack.pop();
+                self.globals[index] = self.stack.pop();
+            }
+            Fetch => {
+                let Some(Value::Name(name)) = self.peek(0) else {
+                    return false;
+                };
+                let Some(value) = self.globals[variable_index(*name)].clone() else {
+                    return false;
+                };
+                self.stack.pop();
+                self.stack.push(value);
+            }
+            ReadChar => {
+                let value = match self.input.next() {
+                    Some(byte) => byte.into(),
+                    None if self.input_complete => -1,
+                    None => return false,
+                };
+                self.stack.push(Value::Integer(value));
+            }
+            WriteChar => {
+                let Some(c) = self.peek_int(0).and_then(|v| char::from_u32(v as u32)) else {
+                    return false;
+                };
+                self.pop_int();
+                self.output.push(c);
+            }
+            WriteStr(s) => self.output.push_str(s),
+            WriteInt => {
+                if self.peek_int(0).is_none() {
+                    return false;
+                }
+                let value = self.pop_int();
+                self.output.push_str(&value.to_string());
+            }
+            Flush => {}
+        }
+        true
+    }
+
+    /// The program that picks up from the current state.
+    fn residual(&self) -> Vec<Spanned<FalseInstruction>> {
+        use FalseInstruction::*;
+
+        // Rebuilt state has no source of its own
+        let span = SimpleSpan::from(0..0);
+        let mut program = write_output(&self.output, span);
+
+        let value = |value: &Value| match value {
+            Value::Integer(i) => PushInt(*i),
+            Value::Lambda(body) => Lambda(body.to_vec()),
+            Value::Name(c) => Name(*c),
+        };
+        for (name, global) in ('a'..='z').zip(&self.globals) {
+            if let Some(global) = global {
+                program.push(Spanned::new(value(global), span));
+                program.push(Spanned::new(Name(name), span));
+                program.push(Spanned::new(Store, span));
+            }
+        }
+        for entry in &self.stack {
+            program.push(Spanned::new(value(entry), span));
+        }
+
+        for frame in self.frames.iter().rev() {
+            match frame {
+                Frame::Block { instructions, next } => {
+                    program.extend(instructions[*next..].iter().cloned());
+                }
+                Frame::AfterCondition { loop_instruction } => {
+                    let WhileLoop(_, body) = loop_instruction.instruction() else {
+                        unreachable!()
+                    };
+                    let mut taken = body.clone();
+                    taken.push((*loop_instruction).clone());
+                    program.push(Spanned::new(
+                        ConditionalExecute(taken),
+                        loop_instruction.span(),
+                    ));
+                }
+                Frame::AfterBody { loop_instruction } => {
+                    program.push((*loop_instruction).clone());
+                }
+            }
+        }
+        program
+    }
+}
+
+fn binary(op: &FalseInstruction, b: i32, a: i32) -> Option<i32> {
+    use FalseInstruction::*;
+    match op {
+        Add => b.checked_add(a),
+        Sub => b.checked_sub(a),
+        Mul => b.checked_mul(a),
+        Div => b.checked_div(a),
+        BitAnd => Some(b & a),
+        BitOr => Some(b | a),
+        Gt => Some(if b > a { -1 } else { 0 }),
+        Eq => Some(if b == a { -1 } else { 0 }),
+        _ => unreachable!("Not a binary operator: {op:?}"),
+    }
+}
+
+/// Instructions that print `output`. Strings can't hold `"`, so those are
+/// written as characters.
+fn write_output(output: &str, span: SimpleSpan<usize>) -> Vec<Spanned<FalseInstruction>> {
+    let mut program = Vec::new();
+    for (i, part) in output.split('"').enumerate() {
+        if i > 0 {
+            program.push(Spanned::new(FalseInstruction::PushChar(b'"'), span));
+            program.push(Spanned::new(FalseInstruction::WriteChar, span));
+        }
+        if !part.is_empty() {
+            program.push(Spanned::new(
+                FalseInstruction::WriteStr(part.to_string()),
+                span,
+            ));
+        }
+    }
+    program
+}
+
+fn variable_index(name: char) -> usize {
+    (name as u8 - b'a') as usize
+}
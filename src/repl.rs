@@ -0,0 +1,170 @@
+//! The REPL started by running `falsy` with no file.
+//!
+//! Each entry is either a command, a `:` followed by its name, or a program,
+//! run in a [`Session`] so the stack and globals carry over from one entry
+//! to the next. Line editing is left to the binary; this is everything else,
+//! so it can be tested without a terminal.
+
+use chumsky::span::SimpleSpan;
+
+use crate::interpreter::{Interpreter, Session, Value};
+use crate::parser::parse;
+
+pub struct Repl<'input_closure, 'output_closure> {
+    session: Session<'input_closure, 'output_closure>,
+}
+
+/// Why an entry failed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ReplError {
+    /// The program failed to parse, or to run. The diagnostics are located
+    /// in `source`.
+    Program {
+        filename: String,
+        source: String,
+        diagnostics: Vec<Diagnostic>,
+    },
+    /// A command was misused, or couldn't be carried out.
+    Command(String),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub span: SimpleSpan<usize>,
+    pub message: String,
+    pub label: String,
+}
+
+const HELP: &str = "\
+:stack       show the stack
+:vars        show the variables that have been stored
+:reset       empty the stack and forget every variable
+:load FILE   run a file
+:help        show this help";
+
+impl<'input_closure, 'output_closure> Repl<'input_closure, 'output_closure> {
+    /// A REPL running programs with the interpreter's input and output.
+    pub fn new(interpreter: Interpreter<'input_closure, 'output_closure>) -> Self {
+        Self {
+            session: interpreter.session(),
+        }
+    }
+
+    /// Runs an entry, and returns what to show after anything it printed.
+    /// Programs show the stack they leave behind.
+    pub fn eval(&mut self, entry: &str) -> Result<String, ReplError> {
+        // A `:` on its own is a store
+        let command = entry
+            .trim()
+            .strip_prefix(':')
+            .filter(|command| command.starts_with(|c: char| c.is_ascii_alphabetic()));
+        let Some(command) = command else {
+            self.run("repl", entry)?;
+            return Ok(self.stack());
+        };
+        let (command, argument) = command
+            .split_once(char::is_whitespace)
+            .map_or((command, ""), |(command, argument)| {
+                (command, argument.trim())
+            });
+        match (command, argument) {
+            ("stack", "") => Ok(self.stack()),
+            ("vars", "") => Ok(self.vars()),
+            ("reset", "") => {
+                self.session.reset();
+                Ok(self.stack())
+            }
+            ("help", "") => Ok(HELP.to_string()),
+            ("load", "") => Err(ReplError::Command(
+                "Expected a file to load, as in :load file.false".to_string(),
+            )),
+            ("load", path) => {
+                let source = std::fs::read_to_string(path)
+                    .map_err(|e| ReplError::Command(format!("Failed to read {path}: {e}")))?;
+                let filename = std::path::Path::new(path)
+                    .file_name()
+                    .map_or_else(|| path.to_string(), |s| s.to_string_lossy().to_string());
+                self.run(&filename, &source)?;
+                Ok(self.stack())
+            }
+            ("stack" | "vars" | "reset" | "help", _) => Err(ReplError::Command(format!(
+                ":{command} doesn't take an argument"
+            ))),
+            _ => Err(ReplError::Command(format!(
+                "Unknown command :{command}, see :help"
+            ))),
+        }
+    }
+
+    fn run(&mut self, filename: &str, source: &str) -> Result<(), ReplError> {
+        let error = |diagnostics| ReplError::Program {
+            filename: filename.to_string(),
+            source: source.to_string(),
+            diagnostics,
+        };
+        let ast = parse(source).into_result().map_err(|errors| {
+            error(
+                errors
+                    .into_iter()
+                    .map(|e| Diagnostic {
+                        span: *e.span(),
+                        message: e.to_string(),
+                        label: e.reason().to_string(),
+                    })
+                    .collect(),
+            )
+        })?;
+        self.session.run(ast).map_err(|e| {
+            error(vec![Diagnostic {
+                span: e.span(),
+                message: e.to_string(),
+                label: e.reason().to_string(),
+            }])
+        })
+    }
+
+    fn stack(&self) -> String {
+        let stack = self.session.stack();
+        if stack.is_empty() {
+            return "(empty stack)".to_string();
+        }
+        stack
+            .iter()
+            .map(Value::to_string)
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    fn vars(&self) -> String {
+        let globals = self.session.globals();
+        if globals.is_empty() {
+            return "(no variables)".to_string();
+        }
+        globals
+            .iter()
+            .map(|(name, value)| format!("{name} = {value}"))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+/// Whether an entry has a lambda, comment or string left open, and so
+/// continues on the next line.
+pub fn is_unfinished(entry: &str) -> bool {
+    let mut depth = 0usize;
+    let mut chars = entry.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '[' => depth += 1,
+            ']' => depth = depth.saturating_sub(1),
+            '{' if !chars.by_ref().any(|c| c == '}') => return true,
+            '"' if !chars.by_ref().any(|c| c == '"') => return true,
+            // A character literal, which might be a bracket
+            '\'' => {
+                chars.next();
+            }
+            _ => {}
+        }
+    }
+    depth > 0
+}
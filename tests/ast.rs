@@ -0,0 +1,256 @@
+use chumsky::span::SimpleSpan;
+use falsy::ast::{
+    fold_block, fold_instruction, to_source, variable_names, walk_block, walk_block_mut,
+    FalseInstruction, Fold, Spanned, Visitor, VisitorMut,
+};
+use falsy::parser::parse;
+
+mod common;
+
+use common::{without_spans, Random};
+
+test_each_file::test_each_file! { for ["false"] in "./tests/samples" => test_round_trip_samples }
+
+fn spanned(instructions: Vec<FalseInstruction>) -> Vec<Spanned<FalseInstruction>> {
+    instructions
+        .into_iter()
+        .map(|instruction| Spanned::new(instruction, SimpleSpan::new(0, 0)))
+        .collect()
+}
+
+/// Parses what `program` is written as.
+fn reparse(program: &[Spanned<FalseInstruction>]) -> Vec<Spanned<FalseInstruction>> {
+    let source = to_source(program);
+    let ast = parse(&source)
+        .into_result()
+        .unwrap_or_else(|e| panic!("{source:?} doesn't parse: {e:?}"));
+    without_spans(ast)
+}
+
+fn test_round_trip_samples([source]: [&str; 1]) {
+    let ast = without_spans(parse(source).into_result().unwrap());
+    assert_eq!(reparse(&ast), ast);
+}
+
+#[test]
+fn writes_instructions() {
+    use FalseInstruction::*;
+
+    let program = spanned(vec![
+        PushInt(42),
+        PushChar(b'a'),
+        Name('x'),
+        Store,
+        Lambda(spanned(vec![Dup, Mul])),
+        ConditionalExecute(spanned(vec![])),
+        WhileLoop(spanned(vec![Name('c'), Fetch]), spanned(vec![Flush])),
+        WriteStr("hi there".to_string()),
+        Pick,
+    ]);
+    assert_eq!(
+        to_source(&program),
+        "42 'a x : [$ *] []? [c ;][ß]# \"hi there\" ø"
+    );
+    assert_eq!(Pick.to_string(), "ø");
+    assert_eq!(program[4].to_string(), "[$ *]");
+}
+
+#[test]
+fn writes_characters_that_parse_back() {
+    use FalseInstruction::*;
+
+    // Whitespace and quotes are characters like any other after `'`
+    for c in [b' ', b'\n', b'\'', b'"', b'[', b'{', b'0'] {
+        let program = spanned(vec![PushChar(c), PushInt(1)]);
+        assert_eq!(reparse(&program), program);
+    }
+    // Bytes that start a multi-byte character
+    assert_eq!(PushChar(0xc3).to_string(), "'À");
+    for byte in (0xc2..=0xf4).chain([0x7f]) {
+        let program = spanned(vec![PushChar(byte)]);
+        assert_eq!(reparse(&program), program, "{byte:#x}");
+    }
+    // And ones that can't, which are pushed as numbers
+    assert_eq!(PushChar(0x80).to_string(), "128");
+    assert_eq!(PushChar(0xff).to_string(), "255");
+}
+
+#[test]
+fn writes_what_the_parser_cant_give_as_code_doing_the_same() {
+    use FalseInstruction::*;
+
+    assert_eq!(WriteStr(String::new()).to_string(), "\"\"");
+    assert_eq!(PushInt(-5).to_string(), "5_");
+    assert_eq!(PushInt(i32::MIN).to_string(), "2147483647_ 1-");
+
+    let quoted = WriteStr("say \"hi\"".to_string());
+    assert_eq!(quoted.to_string(), "\"say \" '\" , \"hi\" '\" ,");
+    assert_eq!(
+        reparse(&spanned(vec![quoted])),
+        spanned(vec![
+            WriteStr("say ".to_string()),
+            PushChar(b'"'),
+            WriteChar,
+            WriteStr("hi".to_string()),
+            PushChar(b'"'),
+            WriteChar,
+        ])
+    );
+}
+
+impl Random {
+    /// A block of up to eight instructions, nested at most `depth` deep.
+    fn block(&mut self, depth: usize) -> Vec<Spanned<FalseInstruction>> {
+        let length = self.below(9);
+        spanned((0..length).map(|_| self.instruction(depth)).collect())
+    }
+
+    /// An instruction the parser could give.
+    fn instruction(&mut self, depth: usize) -> FalseInstruction {
+        use FalseInstruction::*;
+
+        const OPERATORS: [FalseInstruction; 22] = [
+            Dup, Drop, Swap, Rot, Pick, Add, Sub, Mul, Div, Neg, BitAnd, BitOr, BitNot, Gt, Eq,
+            Execute, Store, Fetch, ReadChar, WriteChar, WriteInt, Flush,
+        ];
+        let kinds = if depth == 0 { 5 } else { 8 };
+        match self.below(kinds) {
+            0 => OPERATORS[self.below(OPERATORS.len())].clone(),
+            1 => Name(char::from(b'a' + self.below(26) as u8)),
+            2 => PushInt(match self.below(3) {
+                0 => self.below(10) as i32,
+                1 => self.below(100_000) as i32,
+                _ => i32::MAX,
+            }),
+            3 => {
+                let bytes: Vec<u8> = (0..=0x7f).chain(0xc2..=0xf4).collect();
+                PushChar(bytes[self.below(bytes.len())])
+            }
+            4 => {
+                let pieces = ["a", " ", "\n", "{", "}", "[", "'", "é", "ß", "\\"];
+                let length = self.below(5);
+                WriteStr(
+                    (0..length)
+                        .map(|_| pieces[self.below(pieces.len())])
+                        .collect(),
+                )
+            }
+            5 => Lambda(self.block(depth - 1)),
+            6 => ConditionalExecute(self.block(depth - 1)),
+            _ => WhileLoop(self.block(depth - 1), self.block(depth - 1)),
+        }
+    }
+}
+
+#[test]
+fn generated_programs_round_trip() {
+    let mut random = Random(0x6c07_8965);
+    for _ in 0..2000 {
+        let program = random.block(3);
+        assert_eq!(reparse(&program), program, "{:?}", to_source(&program));
+    }
+}
+
+#[test]
+fn visitors_reach_every_block() {
+    struct Stores(Vec<(char, SimpleSpan<usize>)>);
+
+    impl<'ast> Visitor<'ast> for Stores {
+        fn visit_block(&mut self, block: &'ast [Spanned<FalseInstruction>]) {
+            for pair in block.windows(2) {
+                if let (FalseInstruction::Name(name), FalseInstruction::Store) =
+                    (pair[0].instruction(), pair[1].instruction())
+                {
+                    self.0.push((*name, pair[0].span()));
+                }
+            }
+            walk_block(self, block);
+        }
+    }
+
+    let program = parse("1a: [2b:]! [[3c:]?][4d:]#").into_result().unwrap();
+    let mut stores = Stores(Vec::new());
+    stores.visit_block(&program);
+    assert_eq!(
+        stores.0,
+        [
+            ('a', SimpleSpan::new(1, 2)),
+            ('b', SimpleSpan::new(6, 7)),
+            ('c', SimpleSpan::new(14, 15)),
+            ('d', SimpleSpan::new(21, 22)),
+        ]
+    );
+    assert_eq!(
+        variable_names(&program).into_iter().collect::<String>(),
+        "abcd"
+    );
+}
+
+#[test]
+fn mutable_visitors_change_programs_in_place() {
+    use FalseInstruction::*;
+
+    /// Renames `a` to `z`, and drops every `ß`.
+    struct Rewrite;
+
+    impl VisitorMut for Rewrite {
+        fn visit_block_mut(&mut self, block: &mut Vec<Spanned<FalseInstruction>>) {
+            block.retain(|spanned| *spanned.instruction() != Flush);
+            walk_block_mut(self, block);
+        }
+
+        fn visit_name_mut(&mut self, name: &mut char, _span: SimpleSpan<usize>) {
+            if *name == 'a' {
+                *name = 'z';
+            }
+        }
+    }
+
+    let mut program = parse("a;ß[ßa;b;]!").into_result().unwrap();
+    Rewrite.visit_block_mut(&mut program);
+    assert_eq!(to_source(&program), "z ; [z ; b ;] !");
+    // Spans are left as they were
+    assert_eq!(program[2].span(), SimpleSpan::new(4, 12));
+}
+
+#[test]
+fn folds_rebuild_programs() {
+    use FalseInstruction::*;
+
+    /// Writes characters as integers, and `_` as `0\\-`.
+    struct Unsugar;
+
+    impl Fold for Unsugar {
+        fn fold_block(
+            &mut self,
+            block: Vec<Spanned<FalseInstruction>>,
+        ) -> Vec<Spanned<FalseInstruction>> {
+            fold_block(self, block)
+                .into_iter()
+                .flat_map(|spanned| match spanned.into_parts() {
+                    (Neg, span) => vec![
+                        Spanned::new(PushInt(0), span),
+                        Spanned::new(Swap, span),
+                        Spanned::new(Sub, span),
+                    ],
+                    (instruction, span) => vec![Spanned::new(instruction, span)],
+                })
+                .collect()
+        }
+
+        fn fold_instruction(
+            &mut self,
+            instruction: Spanned<FalseInstruction>,
+        ) -> Spanned<FalseInstruction> {
+            match fold_instruction(self, instruction).into_parts() {
+                (PushChar(c), span) => Spanned::new(PushInt(c.into()), span),
+                (instruction, span) => Spanned::new(instruction, span),
+            }
+        }
+    }
+
+    let program = parse("'a_ [[1_]['b]#]?").into_result().unwrap();
+    let folded = Unsugar.fold_block(program);
+    assert_eq!(to_source(&folded), "97 0 \\ - [[1 0 \\ -][98]#]?");
+    assert_eq!(folded[1].span(), SimpleSpan::new(2, 3));
+}
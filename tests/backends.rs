@@ -0,0 +1,54 @@
+use falsy::interpreter::{Backend, Interpreter, InterpreterRuntimeError};
+use falsy::parser::parse;
+
+fn run(source: &str, backend: Backend) -> (String, Result<(), InterpreterRuntimeError>) {
+    let ast = parse(source).into_result().expect("Failed to parse");
+    let mut output = String::new();
+    let mut input = "ab".bytes();
+    let result = Interpreter::new()
+        .on_input(|| input.next())
+        .on_output(|s| output.push_str(s))
+        .backend(backend)
+        .run_program(ast)
+        .map(|_| ());
+    (output, result)
+}
+
+#[test]
+fn closures_match_tree_walker() {
+    let programs = [
+        "1 2 + 3 * .",
+        "[$ 1 > [1- $ f;! \\ 1- f;! +]?]f: 10 f;! .",
+        "^ ^ \\ , ,",
+        "1 2 3 @ . . .",
+        "0 [$ 5 >~][$ . 1+]# %",
+        "\"hi\" 10 , 7 3 / . 5 _ .",
+    ];
+    for program in programs {
+        let (tree, tree_result) = run(program, Backend::Tree);
+        let (closures, closures_result) = run(program, Backend::Closures);
+        assert!(tree_result.is_ok() && closures_result.is_ok(), "{program}");
+        assert_eq!(tree, closures, "{program}");
+    }
+}
+
+#[test]
+fn closures_report_the_same_errors() {
+    let programs = [
+        "\"x\" 1 0 /",
+        "[1 +]f: f;! .",
+        "a;",
+        "1 2 5 ø",
+        "[1 [+]?] !",
+        "1 a: a; !",
+    ];
+    for program in programs {
+        let (tree, tree_result) = run(program, Backend::Tree);
+        let (closures, closures_result) = run(program, Backend::Closures);
+        let tree_error = tree_result.unwrap_err();
+        let closures_error = closures_result.unwrap_err();
+        assert_eq!(tree, closures, "{program}");
+        assert_eq!(tree_error.reason(), closures_error.reason(), "{program}");
+        assert_eq!(tree_error.span(), closures_error.span(), "{program}");
+    }
+}
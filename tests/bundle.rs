@@ -0,0 +1,103 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+
+use falsy::bundle::{self, Bundle};
+use falsy::optimizer::OptimizationLevel;
+
+fn bundle(source: &str) -> Bundle {
+    Bundle {
+        filename: "program.false".to_string(),
+        source: source.to_string(),
+        level: OptimizationLevel::Basic,
+    }
+}
+
+/// Builds `source` into an executable with `falsy build`.
+fn build(name: &str, source: &str) -> PathBuf {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("bundle");
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(format!("{name}.false"));
+    std::fs::write(&path, source).unwrap();
+    let executable = dir.join(name);
+    let status = Command::new(env!("CARGO_BIN_EXE_falsy"))
+        .arg("build")
+        .arg(&path)
+        .arg("-o")
+        .arg(&executable)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    executable
+}
+
+fn run(executable: &Path, input: &str) -> Output {
+    let mut child = Command::new(executable)
+        .arg("ignored.false")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    child.wait_with_output().unwrap()
+}
+
+#[test]
+fn round_trips_through_an_executable() {
+    let executable = b"\x7fELF and the rest";
+    let bundled = bundle("\"héllo\"").append_to(executable);
+    assert!(bundled.starts_with(executable));
+    assert_eq!(Bundle::from_bytes(&bundled), Some(bundle("\"héllo\"")));
+    assert_eq!(bundle::strip(&bundled), executable);
+}
+
+#[test]
+fn replaces_an_existing_bundle() {
+    let executable = b"\x7fELF and the rest";
+    let bundled = bundle("1.").append_to(&bundle("2.").append_to(executable));
+    assert_eq!(Bundle::from_bytes(&bundled), Some(bundle("1.")));
+    assert_eq!(bundle::strip(&bundled), executable);
+}
+
+#[test]
+fn ignores_executables_without_a_bundle() {
+    assert_eq!(Bundle::from_bytes(b""), None);
+    assert_eq!(Bundle::from_bytes(b"\x7fELF and the rest"), None);
+    // A trailer claiming more payload than there is
+    let mut truncated = b"abc".to_vec();
+    truncated.extend(100u64.to_le_bytes());
+    truncated.extend(b"FALSYPKG");
+    assert_eq!(Bundle::from_bytes(&truncated), None);
+    assert_eq!(bundle::strip(&truncated), truncated);
+}
+
+#[test]
+fn runs_the_bundled_program() {
+    let executable = build("cat", "[^$1_=~][,]#");
+    let output = run(&executable, "some input");
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "some input");
+
+    // Reading only the end of the file finds the same bundle
+    let read = Bundle::read(&executable).unwrap().unwrap();
+    assert_eq!(read.filename, "cat.false");
+    assert_eq!(read.source, "[^$1_=~][,]#");
+}
+
+#[test]
+fn reports_errors_against_the_bundled_source() {
+    let executable = build("error", "\"x\"\n 1 0/");
+    let output = run(&executable, "");
+    assert!(!output.status.success());
+    // Reports are printed after the program's output
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.starts_with('x'), "{stdout}");
+    assert!(stdout.contains("Division by zero"), "{stdout}");
+    assert!(stdout.contains("error.false:2:5"), "{stdout}");
+}
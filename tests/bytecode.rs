@@ -0,0 +1,240 @@
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+
+use falsy::bytecode::{self, LoadError, VERSION};
+use falsy::parser::parse;
+
+const PROGRAM: &str = "{ count down }\n3[$0>][$.\" \"1-]#%\n[\"x\"]f: f;! 'a,";
+
+fn compile(source: &str) -> Vec<u8> {
+    let ast = parse(source).into_result().expect("Failed to parse");
+    bytecode::compile(&ast, source, "program.false")
+}
+
+/// Recomputes the checksum after the body has been tampered with.
+fn reseal(bytes: &mut [u8]) {
+    let checksum = bytes[14..]
+        .iter()
+        .fold(0xcbf2_9ce4_8422_2325u64, |hash, &b| {
+            (hash ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3)
+        });
+    bytes[6..14].copy_from_slice(&checksum.to_le_bytes());
+}
+
+#[test]
+fn round_trips_programs() {
+    let ast = parse(PROGRAM).into_result().unwrap();
+    let loaded = bytecode::load(&compile(PROGRAM)).unwrap();
+    assert_eq!(loaded.program, ast);
+    assert_eq!(loaded.filename, "program.false");
+    assert!(loaded.matches_source(PROGRAM));
+    assert!(!loaded.matches_source("3[$0>][$.1-]#%"));
+}
+
+#[test]
+fn locates_instructions_without_the_source() {
+    let loaded = bytecode::load(&compile(PROGRAM)).unwrap();
+    // The `3` at the start of the second line
+    assert_eq!(loaded.location(15), Some((2, 1)));
+    // Within the string, the closest instruction before it
+    assert_eq!(loaded.location(25), Some((2, 10)));
+    assert_eq!(loaded.location(0), None);
+}
+
+#[test]
+fn rejects_other_files() {
+    assert_eq!(bytecode::load(b"").unwrap_err(), LoadError::NotBytecode);
+    assert_eq!(
+        bytecode::load(PROGRAM.as_bytes()).unwrap_err(),
+        LoadError::NotBytecode
+    );
+    assert!(!bytecode::is_bytecode(PROGRAM.as_bytes()));
+    assert!(bytecode::is_bytecode(&compile(PROGRAM)));
+}
+
+#[test]
+fn rejects_other_versions() {
+    let mut bytes = compile(PROGRAM);
+    bytes[4..6].copy_from_slice(&(VERSION + 1).to_le_bytes());
+    assert_eq!(
+        bytecode::load(&bytes).unwrap_err(),
+        LoadError::UnsupportedVersion(VERSION + 1)
+    );
+}
+
+#[test]
+fn detects_corruption() {
+    let bytes = compile(PROGRAM);
+    for i in 14..bytes.len() {
+        let mut corrupt = bytes.clone();
+        corrupt[i] ^= 0x20;
+        assert_eq!(
+            bytecode::load(&corrupt).unwrap_err(),
+            LoadError::ChecksumMismatch,
+            "byte {i}"
+        );
+    }
+}
+
+#[test]
+fn never_panics_on_malformed_files() {
+    let bytes = compile(PROGRAM);
+    // Truncated anywhere
+    for length in 0..bytes.len() {
+        let mut truncated = bytes[..length].to_vec();
+        if length >= 14 {
+            reseal(&mut truncated);
+        }
+        assert!(bytecode::load(&truncated).is_err(), "length {length}");
+    }
+    // Any byte changed, with a checksum to match
+    for i in 14..bytes.len() {
+        for value in [0, 1, 18, 21, 26, 0x7f, 0xff] {
+            let mut corrupt = bytes.clone();
+            corrupt[i] = value;
+            reseal(&mut corrupt);
+            let _ = bytecode::load(&corrupt);
+        }
+    }
+}
+
+#[test]
+fn reports_malformed_files() {
+    let mut bytes = compile("1");
+    // The opcode of the only instruction, after the header, source hash,
+    // filename and instruction count
+    let opcode = 14 + 8 + 4 + "program.false".len() + 4;
+    assert_eq!(bytes[opcode], 1);
+    bytes[opcode] = 0xee;
+    reseal(&mut bytes);
+    assert_eq!(
+        bytecode::load(&bytes).unwrap_err().to_string(),
+        "Bytecode file is corrupt (unknown opcode)"
+    );
+
+    let mut bytes = compile("1");
+    bytes.push(0);
+    reseal(&mut bytes);
+    assert_eq!(
+        bytecode::load(&bytes).unwrap_err(),
+        LoadError::Malformed("trailing data")
+    );
+}
+
+/// Compiles `source` to bytecode with the CLI, in a directory of its own.
+fn compile_cli(name: &str, source: &str) -> PathBuf {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR"))
+        .join("bytecode")
+        .join(name);
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(format!("{name}.false"));
+    std::fs::write(&path, source).unwrap();
+    let fbc = dir.join(format!("{name}.fbc"));
+    let status = Command::new(env!("CARGO_BIN_EXE_falsy"))
+        .args(["compile", "--emit", "bytecode"])
+        .arg(&path)
+        .arg("-o")
+        .arg(&fbc)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    fbc
+}
+
+fn run(path: &Path) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_falsy"))
+        .arg(path)
+        .stdin(Stdio::null())
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn runs_bytecode_files() {
+    let fbc = compile_cli("countdown", "3[$0>][$.1-]#%");
+    let output = run(&fbc);
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "321");
+}
+
+#[test]
+fn reports_runtime_errors_with_and_without_the_source() {
+    let fbc = compile_cli("division", "\"x\"\n 1 0/");
+    let output = run(&fbc);
+    assert!(!output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("division.false:2:5"), "{stdout}");
+
+    // Changed source can't be shown, so only the location is reported
+    std::fs::write(fbc.with_extension("false"), "changed").unwrap();
+    let output = run(&fbc);
+    assert!(!output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "x");
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "division.false:2:5: Division by zero\n"
+    );
+}
+
+#[test]
+fn reports_corrupt_files() {
+    let fbc = compile_cli("corrupt", "1 2+.");
+    let mut bytes = std::fs::read(&fbc).unwrap();
+    let last = bytes.len() - 1;
+    bytes[last] ^= 1;
+    std::fs::write(&fbc, bytes).unwrap();
+    let output = run(&fbc);
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.ends_with("corrupt.fbc: Bytecode file is corrupt (checksum mismatch)\n"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn reports_damaged_headers_and_binary_source() {
+    let fbc = compile_cli("magic", "1 2+.");
+    let mut bytes = std::fs::read(&fbc).unwrap();
+    bytes[0] ^= 0xff;
+    std::fs::write(&fbc, bytes).unwrap();
+    let output = run(&fbc);
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.ends_with("magic.fbc: Not a falsy bytecode file\n"),
+        "{stderr}"
+    );
+
+    let binary = fbc.with_file_name("binary.false");
+    std::fs::write(&binary, [b'1', 0xff, b'.']).unwrap();
+    let output = run(&binary);
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.ends_with("binary.false: Source isn't valid UTF-8\n"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn never_panics_on_flipped_bytes_from_the_cli() {
+    let fbc = compile_cli("flipped", PROGRAM);
+    let original = std::fs::read(&fbc).unwrap();
+    let damaged = fbc.with_file_name("damaged.fbc");
+    // Every byte of the header, and a spread of the rest
+    let step = (original.len() / 40).max(1);
+    for i in (0..16).chain((16..original.len()).step_by(step)) {
+        for flip in [0x01, 0x80, 0xff] {
+            let mut bytes = original.clone();
+            bytes[i] ^= flip;
+            std::fs::write(&damaged, &bytes).unwrap();
+            let output = run(&damaged);
+            assert!(
+                matches!(output.status.code(), Some(0 | 1)),
+                "byte {i} ^ {flip:#x}: {}",
+                String::from_utf8_lossy(&output.stderr)
+            );
+        }
+    }
+}
@@ -0,0 +1,82 @@
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+
+use falsy::codegen;
+use falsy::parser::parse;
+
+/// Compiles `source` to C and builds it, if there is a C compiler.
+fn build(name: &str, source: &str) -> Option<PathBuf> {
+    let ast = parse(source).into_result().expect("Failed to parse");
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("codegen_c");
+    std::fs::create_dir_all(&dir).unwrap();
+    let c_file = dir.join(format!("{name}.c"));
+    let binary = dir.join(name);
+    std::fs::write(
+        &c_file,
+        codegen::c::compile(&ast, source, &format!("{name}.false")),
+    )
+    .unwrap();
+
+    let status = Command::new(std::env::var("CC").unwrap_or_else(|_| "cc".to_string()))
+        .args(["-std=c99", "-Wall", "-Wextra", "-pedantic", "-Werror", "-o"])
+        .arg(&binary)
+        .arg(&c_file)
+        .status()
+        .ok()?;
+    assert!(status.success(), "Failed to compile {c_file:?}");
+    Some(binary)
+}
+
+fn run(binary: &Path) -> Output {
+    Command::new(binary).stdin(Stdio::null()).output().unwrap()
+}
+
+#[test]
+fn writes_values_and_strings() {
+    let Some(binary) = build(
+        "values",
+        "\"a\\?*/\" 10 , 'é, 1_ . 2147483647 1+ . '' , 65,",
+    ) else {
+        return;
+    };
+    let output = run(&binary);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "a\\?*/\n\u{c3}-1-2147483648'A"
+    );
+}
+
+#[test]
+fn reports_runtime_errors() {
+    let cases = [
+        (
+            "division",
+            "\"x\" 1\n 0 /",
+            "division.false:2:4: Division by zero",
+        ),
+        (
+            "fetch",
+            "a;",
+            "fetch.false:1:2: Name a not found in global scope",
+        ),
+        (
+            "execute",
+            "1 !",
+            "execute.false:1:3: Expected lambda for Execute, got Integer",
+        ),
+        ("swap", "[1 \\]!", "swap.false:1:4: Stack only has 1 value"),
+    ];
+    for (name, source, expected) in cases {
+        let Some(binary) = build(name, source) else {
+            return;
+        };
+        let output = run(&binary);
+        assert_eq!(output.status.code(), Some(1), "{source}");
+        assert_eq!(
+            String::from_utf8(output.stderr).unwrap().trim_end(),
+            expected,
+            "{source}"
+        );
+    }
+}
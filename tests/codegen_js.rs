@@ -0,0 +1,104 @@
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+use falsy::codegen;
+use falsy::parser::parse;
+
+/// Compiles `source` to `<name>.mjs`, with a source map.
+fn build(name: &str, source: &str) -> PathBuf {
+    let ast = parse(source).into_result().expect("Failed to parse");
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("codegen_js");
+    std::fs::create_dir_all(&dir).unwrap();
+    let js_filename = format!("{name}.mjs");
+    let (code, map) =
+        codegen::js::compile_with_source_map(&ast, source, &format!("{name}.false"), &js_filename);
+    std::fs::write(dir.join(&js_filename), code).unwrap();
+    std::fs::write(dir.join(format!("{js_filename}.map")), map).unwrap();
+    dir
+}
+
+/// Runs the module with Node.js, if it is installed, printing the output
+/// and then the message and stack of any error.
+fn run(name: &str, source: &str) -> Option<Output> {
+    let dir = build(name, source);
+    let driver = format!(
+        "import {{ run }} from './{name}.mjs';
+         try {{ process.stdout.write(run('')); }}
+         catch (e) {{ process.stdout.write(e.output); console.error(e.stack); process.exit(1); }}"
+    );
+    Command::new("node")
+        .current_dir(dir)
+        .args(["--enable-source-maps", "--input-type=module", "-e", &driver])
+        .output()
+        .ok()
+}
+
+#[test]
+fn writes_values_and_strings() {
+    let Some(output) = run(
+        "values",
+        "\"a\\\n\" 10 , 'é, 1_ . 2147483647 1+ . 7_ 2/ . 1 2 1ø . . . 233, 128512, ^.",
+    ) else {
+        return;
+    };
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "a\\\n\n\u{c3}-1-2147483648-3121é😀-1"
+    );
+}
+
+#[test]
+fn reports_runtime_errors() {
+    let cases = [
+        (
+            "division",
+            "\"x\" 1\n 0 /",
+            "division.false:2:4: Division by zero",
+        ),
+        (
+            "fetch",
+            "a;",
+            "fetch.false:1:2: Name a not found in global scope",
+        ),
+        (
+            "execute",
+            "1 !",
+            "execute.false:1:3: Expected lambda for Execute, got Integer",
+        ),
+        ("swap", "[1 \\]!", "swap.false:1:4: Stack only has 1 value"),
+        (
+            "pick",
+            "1 2 1_ ø",
+            "pick.false:1:8: Index out of range for ø (PICK): -1",
+        ),
+    ];
+    for (name, source, expected) in cases {
+        let Some(output) = run(name, source) else {
+            return;
+        };
+        assert_eq!(output.status.code(), Some(1), "{source}");
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert_eq!(
+            stderr.lines().next(),
+            Some(format!("FalseError: {expected}").as_str()),
+            "{source}"
+        );
+    }
+}
+
+#[test]
+fn source_map_points_at_false_source() {
+    let Some(output) = run("mapped", "[1 0/]f:\n\"x\"\n  f;!") else {
+        return;
+    };
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "x");
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    // The division, inside the lambda, and the call to it
+    assert!(stderr.contains("mapped.false:1:5"), "{stderr}");
+    assert!(stderr.contains("mapped.false:3:5"), "{stderr}");
+}
@@ -0,0 +1,137 @@
+use wasmi::{Caller, Engine, Extern, Linker, Module, Store};
+
+use falsy::codegen;
+use falsy::parser::parse;
+
+struct Host {
+    output: Vec<u8>,
+    error: Vec<u8>,
+}
+
+/// Compiles and runs `source` with no input, and returns its output and
+/// error message, if it failed.
+fn run(name: &str, source: &str) -> (String, Option<String>) {
+    fn memory(caller: &Caller<'_, Host>, ptr: i32, len: i32) -> Vec<u8> {
+        let memory = caller
+            .get_export("memory")
+            .and_then(Extern::into_memory)
+            .unwrap();
+        memory.data(caller)[ptr as usize..][..len as usize].to_vec()
+    }
+
+    let ast = parse(source).into_result().expect("Failed to parse");
+    let wasm = codegen::wasm::compile(&ast, source, &format!("{name}.false"));
+    let engine = Engine::default();
+    let module = Module::new(&engine, &wasm[..]).unwrap();
+    let host = Host {
+        output: Vec::new(),
+        error: Vec::new(),
+    };
+    let mut store = Store::new(&engine, host);
+    let mut linker = Linker::new(&engine);
+    linker.func_wrap("env", "read", || -1).unwrap();
+    linker
+        .func_wrap(
+            "env",
+            "write",
+            |mut caller: Caller<'_, Host>, ptr: i32, len: i32| {
+                let bytes = memory(&caller, ptr, len);
+                caller.data_mut().output.extend(bytes);
+            },
+        )
+        .unwrap();
+    linker
+        .func_wrap(
+            "env",
+            "error",
+            |mut caller: Caller<'_, Host>, ptr: i32, len: i32| {
+                let bytes = memory(&caller, ptr, len);
+                caller.data_mut().error.extend(bytes);
+            },
+        )
+        .unwrap();
+    let instance = linker
+        .instantiate(&mut store, &module)
+        .unwrap()
+        .start(&mut store)
+        .unwrap();
+    let result = instance
+        .get_typed_func::<(), ()>(&store, "run")
+        .unwrap()
+        .call(&mut store, ());
+
+    let host = store.into_data();
+    let output = String::from_utf8(host.output).unwrap();
+    let error = result.err().map(|_| String::from_utf8(host.error).unwrap());
+    (output, error)
+}
+
+#[test]
+fn writes_values_and_strings() {
+    let (output, error) = run(
+        "values",
+        "\"a\\\" 10 , 'é, 1_ . 2147483647 1+ . 7_ 2/ . 1 2 1ø . . . 233, 128512, ^.",
+    );
+    assert_eq!(error, None);
+    assert_eq!(output, "a\\\n\u{c3}-1-2147483648-3121é😀-1");
+}
+
+#[test]
+fn grows_memory_for_the_stack() {
+    let (output, error) = run("grow", "0i:[i;20000>~][i;i;1+i:]# [1-$0>][]# 0=.");
+    assert_eq!(error, None);
+    assert_eq!(output, "-1");
+}
+
+#[test]
+fn reports_runtime_errors() {
+    let cases = [
+        (
+            "division",
+            "\"x\" 1\n 0 /",
+            "division.false:2:4: Division by zero",
+        ),
+        (
+            "fetch",
+            "a;",
+            "fetch.false:1:2: Name a not found in global scope",
+        ),
+        (
+            "execute",
+            "1 !",
+            "execute.false:1:3: Expected lambda for Execute, got Integer",
+        ),
+        ("swap", "[1 \\]!", "swap.false:1:4: Stack only has 1 value"),
+        (
+            "pick",
+            "1 2 1_ ø",
+            "pick.false:1:8: Index out of range for ø (PICK): -1",
+        ),
+        (
+            "add",
+            "[] 1 +",
+            "add.false:1:6: Expected Integer on stack, got Lambda",
+        ),
+        (
+            "char",
+            "55296 ,",
+            "char.false:1:7: Can't output value 55296 as char",
+        ),
+    ];
+    for (name, source, expected) in cases {
+        let (_, error) = run(name, source);
+        assert_eq!(error.as_deref(), Some(expected), "{source}");
+    }
+}
+
+#[test]
+fn text_format_is_the_same_module() {
+    let source = "[$1>[1-f;!]?]f: 3f;!";
+    let ast = parse(source).into_result().unwrap();
+    let text = codegen::wasm::text(&ast, source, "text.false");
+    assert!(text.contains("(export \"run\")"));
+    assert_eq!(
+        wat::parse_str(&text).unwrap(),
+        codegen::wasm::compile(&ast, source, "text.false")
+    );
+}
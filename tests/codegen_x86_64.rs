@@ -0,0 +1,132 @@
+#![cfg(all(target_arch = "x86_64", target_os = "linux"))]
+
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+
+use falsy::codegen;
+use falsy::parser::parse;
+
+fn dir() -> PathBuf {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("codegen_x86_64");
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Compiles `source` straight to an executable.
+fn build(name: &str, source: &str) -> PathBuf {
+    let ast = parse(source).into_result().expect("Failed to parse");
+    let binary = dir().join(name);
+    std::fs::write(
+        &binary,
+        codegen::x86_64::compile(&ast, source, &format!("{name}.false")),
+    )
+    .unwrap();
+    std::fs::set_permissions(&binary, std::fs::Permissions::from_mode(0o755)).unwrap();
+    binary
+}
+
+fn run(binary: &Path) -> Output {
+    Command::new(binary).stdin(Stdio::null()).output().unwrap()
+}
+
+const VALUES: &str = "\"a\\\" 10 , 'é, 1_ . 2147483647 1+ . 7_ 2/ . 1 2 1ø . . . 233, 128512,";
+const VALUES_OUTPUT: &str = "a\\\n\u{c3}-1-2147483648-3121é😀";
+
+#[test]
+fn writes_values_and_strings() {
+    let output = run(&build("values", VALUES));
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), VALUES_OUTPUT);
+}
+
+#[test]
+fn runs_deep_recursion_and_large_stacks() {
+    let source = "[$0>[1-f;!]?]f: 100000f;! 0i:[i;100000>~][i;i;1+i:]# [1-$0>][]# 0=.";
+    let output = run(&build("deep", source));
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "-1");
+}
+
+#[test]
+fn reports_runtime_errors() {
+    let cases = [
+        (
+            "division",
+            "\"x\" 1\n 0 /",
+            "division.false:2:4: Division by zero",
+        ),
+        (
+            "fetch",
+            "a;",
+            "fetch.false:1:2: Name a not found in global scope",
+        ),
+        (
+            "execute",
+            "1 !",
+            "execute.false:1:3: Expected lambda for Execute, got Integer",
+        ),
+        ("swap", "[1 \\]!", "swap.false:1:4: Stack only has 1 value"),
+        (
+            "pick",
+            "1 2 5 ø",
+            "pick.false:1:7: Index out of range for ø (PICK): 5",
+        ),
+        (
+            "add",
+            "[] 1 +",
+            "add.false:1:6: Expected Integer on stack, got Lambda",
+        ),
+        (
+            "char",
+            "1_ ,",
+            "char.false:1:4: Can't output value -1 as char",
+        ),
+        ("overflow", "[1][1]#", "overflow.false:1:2: Stack overflow"),
+    ];
+    for (name, source, expected) in cases {
+        let output = run(&build(name, source));
+        assert_eq!(output.status.code(), Some(1), "{source}");
+        assert_eq!(
+            String::from_utf8(output.stderr).unwrap().trim_end(),
+            expected,
+            "{source}"
+        );
+    }
+}
+
+/// The assembly listing builds to the same program with the GNU tools, if
+/// they are installed.
+#[test]
+fn assembly_matches_executable() {
+    let ast = parse(VALUES).into_result().unwrap();
+    let dir = dir();
+    let asm = dir.join("listing.s");
+    let object = dir.join("listing.o");
+    let binary = dir.join("listing");
+    std::fs::write(
+        &asm,
+        codegen::x86_64::assembly(&ast, VALUES, "listing.false"),
+    )
+    .unwrap();
+
+    let Ok(status) = Command::new("as").arg("-o").arg(&object).arg(&asm).status() else {
+        eprintln!("No assembler found, skipping");
+        return;
+    };
+    assert!(status.success(), "Failed to assemble {asm:?}");
+    let Ok(status) = Command::new("ld")
+        .arg("-o")
+        .arg(&binary)
+        .arg(&object)
+        .status()
+    else {
+        eprintln!("No linker found, skipping");
+        return;
+    };
+    assert!(status.success(), "Failed to link {object:?}");
+
+    let output = run(&binary);
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), VALUES_OUTPUT);
+}
@@ -0,0 +1,52 @@
+//! Helpers shared by the integration tests.
+
+// Each test uses only some of them
+#![allow(dead_code)]
+
+use chumsky::span::SimpleSpan;
+use falsy::ast::{FalseInstruction, Spanned};
+use falsy::parser::parse;
+
+/// The instructions of a program, with every span emptied.
+pub fn without_spans(ast: Vec<Spanned<FalseInstruction>>) -> Vec<Spanned<FalseInstruction>> {
+    use FalseInstruction::*;
+
+    ast.into_iter()
+        .map(|spanned| {
+            let instruction = match spanned.into_parts().0 {
+                Lambda(body) => Lambda(without_spans(body)),
+                ConditionalExecute(body) => ConditionalExecute(without_spans(body)),
+                WhileLoop(condition, body) => {
+                    WhileLoop(without_spans(condition), without_spans(body))
+                }
+                other => other,
+            };
+            Spanned::new(instruction, SimpleSpan::new(0, 0))
+        })
+        .collect()
+}
+
+/// A fixed linear congruential generator, for tests that are the same every
+/// run.
+pub struct Random(pub u64);
+
+impl Random {
+    /// A number below `n`.
+    pub fn below(&mut self, n: usize) -> usize {
+        self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1);
+        (self.0 >> 33) as usize % n
+    }
+}
+
+/// Programs of up to 59 random `pieces` each, leaving out those that don't
+/// parse.
+pub fn random_programs<'p>(seed: u64, pieces: &'p [&str]) -> impl Iterator<Item = String> + 'p {
+    let mut random = Random(seed);
+    std::iter::repeat_with(move || {
+        let length = random.below(60);
+        (0..length)
+            .map(|_| pieces[random.below(pieces.len())])
+            .collect::<String>()
+    })
+    .filter(|source| parse(source).into_result().is_ok())
+}
@@ -0,0 +1,188 @@
+use chumsky::span::SimpleSpan;
+use falsy::cst::{self, SyntaxElement, SyntaxKind};
+use falsy::parser::parse;
+
+mod common;
+
+use common::Random;
+
+test_each_file::test_each_file! { for ["false"] in "./tests/samples" => test_lowering_samples }
+
+/// Parses `source` both ways, checking that the tree keeps all of it and
+/// lowers to what the parser gives, or fails where it does.
+fn assert_lowers_like_parser(source: &str) {
+    let tree = cst::parse(source);
+    assert_eq!(tree.to_string(), source);
+    match (tree.lower(), parse(source).into_result()) {
+        (Ok(lowered), Ok(parsed)) => assert_eq!(lowered, parsed, "{source:?}"),
+        (Err(_), Err(_)) => {}
+        (lowered, parsed) => panic!("{source:?} lowered to {lowered:?}, parsed to {parsed:?}"),
+    }
+}
+
+fn test_lowering_samples([source]: [&str; 1]) {
+    assert_lowers_like_parser(source);
+}
+
+#[test]
+fn lowers_like_the_parser() {
+    for source in [
+        "",
+        "  ",
+        "{ only a comment }",
+        "[ ] [{ empty }] {a}{b}",
+        "  { comment } 1 2+ {another}\n.",
+        "007",
+        "'a'\n '[ 'é",
+        "\"multi\nline\" \"\"",
+        "[1][2]",
+        "[1][2]?",
+        "[1][2][3]#",
+        "[1] [2]#",
+        "[1]\n?",
+        "[[$1>][1-]#]f: 3f;!ø ß",
+        "1 ]",
+        "?",
+        "#",
+        "[1",
+        "[1]]",
+        "{ unclosed",
+        "\"unclosed",
+        "'",
+        "A",
+    ] {
+        assert_lowers_like_parser(source);
+    }
+}
+
+#[test]
+fn keeps_trivia_in_the_tree() {
+    let tree = cst::parse("{ add } [1 +]?");
+    let kinds: Vec<_> = tree.root().children().iter().map(|c| c.kind()).collect();
+    assert_eq!(
+        kinds,
+        [
+            SyntaxKind::Comment,
+            SyntaxKind::Whitespace,
+            SyntaxKind::Conditional
+        ]
+    );
+    let SyntaxElement::Node(conditional) = &tree.root().children()[2] else {
+        panic!("Expected a node");
+    };
+    let SyntaxElement::Node(lambda) = &conditional.children()[0] else {
+        panic!("Expected a node");
+    };
+    assert_eq!(lambda.to_string(), "[1 +]");
+    assert_eq!(lambda.len(), 5);
+    let kinds: Vec<_> = lambda.children().iter().map(|c| c.kind()).collect();
+    assert_eq!(
+        kinds,
+        [
+            SyntaxKind::LBracket,
+            SyntaxKind::Number,
+            SyntaxKind::Whitespace,
+            SyntaxKind::Operator,
+            SyntaxKind::RBracket
+        ]
+    );
+}
+
+#[test]
+fn reports_errors() {
+    let errors: Vec<_> = cst::parse("1 ] {x\n")
+        .errors()
+        .into_iter()
+        .map(|e| (e.span(), e.reason().to_string()))
+        .collect();
+    assert_eq!(
+        errors,
+        [
+            (SimpleSpan::new(2, 3), "Unexpected ]".to_string()),
+            (
+                SimpleSpan::new(4, 7),
+                "Unclosed comment, expected }".to_string()
+            ),
+        ]
+    );
+
+    let errors: Vec<_> = cst::parse("[[1] '")
+        .errors()
+        .into_iter()
+        .map(|e| (e.span(), e.reason().to_string()))
+        .collect();
+    assert_eq!(
+        errors,
+        [
+            (
+                SimpleSpan::new(0, 1),
+                "Unclosed lambda, expected ]".to_string()
+            ),
+            (
+                SimpleSpan::new(5, 6),
+                "Expected a character after '".to_string()
+            ),
+        ]
+    );
+    assert_eq!(
+        cst::parse("99999999999").errors()[0].reason(),
+        "Number out of range"
+    );
+}
+
+#[test]
+fn reparses_only_what_an_edit_is_inside() {
+    let source = "{ double } [$+]d:\n[1 2]f: \"hi\"";
+    let mut tree = cst::parse(source);
+
+    // Inside a comment
+    assert_eq!(tree.edit(2..8, "twice"), SimpleSpan::new(0, 9));
+    // Inside a lambda, which is still one
+    assert_eq!(tree.edit(12..13, "2*"), SimpleSpan::new(10, 15));
+    // Inside a string
+    assert_eq!(tree.edit(27..29, "bye"), SimpleSpan::new(26, 31));
+    // Replacing all of a token reparses what it is in
+    assert_eq!(tree.edit(20..21, "   "), SimpleSpan::new(18, 25));
+    assert_eq!(tree.to_string(), "{ twice } [$2*]d:\n[1   2]f: \"bye\"");
+    assert_eq!(tree, cst::parse(&tree.to_string()));
+
+    // Closing a lambda early changes what follows, so it all goes
+    assert_eq!(tree.edit(13..13, "]"), SimpleSpan::new(0, 34));
+    assert_eq!(tree.to_string(), "{ twice } [$2]*]d:\n[1   2]f: \"bye\"");
+    assert_eq!(tree, cst::parse(&tree.to_string()));
+}
+
+#[test]
+fn edits_give_the_same_tree_as_parsing_again() {
+    let mut source = std::fs::read_to_string("tests/samples/factorial.false").unwrap();
+    let mut tree = cst::parse(&source);
+    let pieces = [
+        "[", "]", "?", "#", "{", "}", "\"", "'", " ", "1", "a", "+", "",
+    ];
+    let mut random = Random(0x2545_f491);
+
+    let mut partial = 0;
+    for _ in 0..2000 {
+        let boundaries: Vec<_> = (0..=source.len())
+            .filter(|i| source.is_char_boundary(*i))
+            .collect();
+        let start = boundaries[random.below(boundaries.len())];
+        let end = boundaries
+            .iter()
+            .copied()
+            .filter(|end| *end >= start && *end <= start + 3)
+            .nth(random.below(2))
+            .unwrap_or(start);
+        let replacement = pieces[random.below(pieces.len())];
+
+        let reparsed = tree.edit(start..end, replacement);
+        source.replace_range(start..end, replacement);
+        assert_eq!(tree.to_string(), source);
+        assert_eq!(tree, cst::parse(&source), "{source:?}");
+        if reparsed.end - reparsed.start < source.len() {
+            partial += 1;
+        }
+    }
+    // Most edits land inside something that can be reparsed on its own
+    assert!(partial > 1000, "Only {partial} edits reparsed part of it");
+}
@@ -0,0 +1,372 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::PathBuf;
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+
+use serde_json::{json, Value};
+
+/// A scripted Debug Adapter Protocol client, talking to `falsy dap`.
+struct Client {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+    seq: i64,
+    /// Every event so far.
+    events: Vec<Value>,
+    /// How many events `wait_for` has gone past.
+    waited: usize,
+}
+
+impl Client {
+    fn start() -> Self {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_falsy"))
+            .arg("dap")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        Self {
+            stdin: child.stdin.take().unwrap(),
+            stdout: BufReader::new(child.stdout.take().unwrap()),
+            child,
+            seq: 0,
+            events: Vec::new(),
+            waited: 0,
+        }
+    }
+
+    /// Starts a client debugging `source`, stopped on entry or not, with
+    /// configuration left to do.
+    fn launch(name: &str, source: &str, stop_on_entry: bool) -> (Self, PathBuf) {
+        let path = program(name, source);
+        let mut client = Self::start();
+        let initialized = client.request("initialize", json!({ "adapterID": "falsy" }));
+        assert_eq!(initialized["success"], true);
+        let launched = client.request(
+            "launch",
+            json!({ "program": path, "stopOnEntry": stop_on_entry }),
+        );
+        assert_eq!(launched["success"], true, "{launched}");
+        client.wait_for("initialized");
+        (client, path)
+    }
+
+    fn send(&mut self, command: &str, arguments: Value) -> i64 {
+        self.seq += 1;
+        let body = json!({
+            "seq": self.seq,
+            "type": "request",
+            "command": command,
+            "arguments": arguments,
+        })
+        .to_string();
+        write!(self.stdin, "Content-Length: {}\r\n\r\n{body}", body.len()).unwrap();
+        self.stdin.flush().unwrap();
+        self.seq
+    }
+
+    fn read(&mut self) -> Value {
+        let mut length = None;
+        loop {
+            let mut line = String::new();
+            assert_ne!(
+                self.stdout.read_line(&mut line).unwrap(),
+                0,
+                "Adapter exited"
+            );
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            if let Some(value) = line.strip_prefix("Content-Length: ") {
+                length = Some(value.parse().unwrap());
+            }
+        }
+        let mut body = vec![0; length.expect("Expected a Content-Length header")];
+        self.stdout.read_exact(&mut body).unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    /// Sends a request and returns its response.
+    fn request(&mut self, command: &str, arguments: Value) -> Value {
+        let seq = self.send(command, arguments);
+        loop {
+            let message = self.read();
+            match message["type"].as_str() {
+                Some("response") if message["request_seq"] == seq => {
+                    assert_eq!(message["command"], command);
+                    return message;
+                }
+                Some("event") => self.events.push(message),
+                _ => panic!("Unexpected message {message}"),
+            }
+        }
+    }
+
+    /// The body of the next event called `event`, skipping others.
+    fn wait_for(&mut self, event: &str) -> Value {
+        loop {
+            if let Some(index) = self.events[self.waited..]
+                .iter()
+                .position(|e| e["event"] == event)
+            {
+                self.waited += index + 1;
+                return self.events[self.waited - 1]["body"].clone();
+            }
+            let message = self.read();
+            assert_eq!(message["type"], "event", "Unexpected message {message}");
+            self.events.push(message);
+        }
+    }
+
+    /// The output events seen so far, of a category.
+    fn output(&self, category: &str) -> String {
+        self.events
+            .iter()
+            .filter(|e| e["event"] == "output" && e["body"]["category"] == category)
+            .map(|e| e["body"]["output"].as_str().unwrap())
+            .collect()
+    }
+
+    /// The name, line and column of each frame, innermost first.
+    fn frames(&mut self) -> Vec<(String, u64, u64)> {
+        let response = self.request("stackTrace", json!({ "threadId": 1 }));
+        response["body"]["stackFrames"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|frame| {
+                (
+                    frame["name"].as_str().unwrap().to_string(),
+                    frame["line"].as_u64().unwrap(),
+                    frame["column"].as_u64().unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    /// The names and values of the variables in a scope.
+    fn variables(&mut self, scope: &str) -> Vec<(String, String)> {
+        let scopes = self.request("scopes", json!({ "frameId": 0 }));
+        let reference = scopes["body"]["scopes"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|s| s["name"] == scope)
+            .unwrap()["variablesReference"]
+            .clone();
+        let response = self.request("variables", json!({ "variablesReference": reference }));
+        response["body"]["variables"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|v| {
+                (
+                    v["name"].as_str().unwrap().to_string(),
+                    v["value"].as_str().unwrap().to_string(),
+                )
+            })
+            .collect()
+    }
+
+    /// Disconnects, and checks that the adapter exits.
+    fn disconnect(mut self) {
+        let response = self.request("disconnect", json!({}));
+        assert_eq!(response["success"], true);
+        drop(self.stdin);
+        assert!(self.child.wait().unwrap().success());
+    }
+}
+
+fn program(name: &str, source: &str) -> PathBuf {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("dap");
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(format!("{name}.false"));
+    std::fs::write(&path, source).unwrap();
+    path
+}
+
+const CALLS: &str = "[1+]f:\n3 f;!\nf;!.";
+
+#[test]
+fn runs_programs_to_the_end() {
+    let (mut client, _) = Client::launch("to_the_end", CALLS, false);
+    let response = client.request("configurationDone", json!({}));
+    assert_eq!(response["success"], true);
+    assert_eq!(client.wait_for("exited")["exitCode"], 0);
+    assert_eq!(client.output("stdout"), "5");
+    client.wait_for("terminated");
+    client.disconnect();
+}
+
+#[test]
+fn stops_at_breakpoints() {
+    let (mut client, path) = Client::launch("breakpoints", CALLS, false);
+    let response = client.request(
+        "setBreakpoints",
+        json!({ "source": { "path": path }, "breakpoints": [{ "line": 1, "column": 2 }] }),
+    );
+    let breakpoint = &response["body"]["breakpoints"][0];
+    assert_eq!(breakpoint["verified"], true);
+    assert_eq!(
+        (&breakpoint["line"], &breakpoint["column"]),
+        (&json!(1), &json!(2))
+    );
+    client.request("configurationDone", json!({}));
+
+    let stopped = client.wait_for("stopped");
+    assert_eq!(stopped["reason"], "breakpoint");
+    assert_eq!(stopped["hitBreakpointIds"], json!([breakpoint["id"]]));
+    assert_eq!(
+        client.frames(),
+        [("f".to_string(), 1, 2), ("main".to_string(), 2, 5)]
+    );
+    assert_eq!(
+        client.variables("Stack"),
+        [("0".to_string(), "3".to_string())]
+    );
+    let globals = client.variables("Globals");
+    assert_eq!(globals.len(), 26);
+    assert_eq!(globals[0], ("a".to_string(), "(unset)".to_string()));
+    assert_eq!(globals[5], ("f".to_string(), "[…]".to_string()));
+
+    // The second call
+    client.request("continue", json!({ "threadId": 1 }));
+    client.wait_for("stopped");
+    assert_eq!(
+        client.frames(),
+        [("f".to_string(), 1, 2), ("main".to_string(), 3, 3)]
+    );
+    assert_eq!(
+        client.variables("Stack"),
+        [("0".to_string(), "4".to_string())]
+    );
+
+    let response = client.request(
+        "setBreakpoints",
+        json!({ "source": { "path": path }, "breakpoints": [] }),
+    );
+    assert_eq!(response["body"]["breakpoints"], json!([]));
+    client.request("continue", json!({ "threadId": 1 }));
+    assert_eq!(client.wait_for("exited")["exitCode"], 0);
+    client.disconnect();
+}
+
+#[test]
+fn steps_in_over_and_out() {
+    let (mut client, _) = Client::launch("stepping", "[1+]f:3f;!.", true);
+    client.request("configurationDone", json!({}));
+    assert_eq!(client.wait_for("stopped")["reason"], "entry");
+    assert_eq!(client.frames(), [("main".to_string(), 1, 1)]);
+
+    let mut columns = Vec::new();
+    for _ in 0..6 {
+        client.request("next", json!({ "threadId": 1 }));
+        assert_eq!(client.wait_for("stopped")["reason"], "step");
+        columns.push(client.frames()[0].2);
+    }
+    // Past the lambda and its store, to the call
+    assert_eq!(columns, [5, 6, 7, 8, 9, 10]);
+
+    client.request("stepIn", json!({ "threadId": 1 }));
+    client.wait_for("stopped");
+    assert_eq!(
+        client.frames(),
+        [("f".to_string(), 1, 2), ("main".to_string(), 1, 10)]
+    );
+    client.request("stepOut", json!({ "threadId": 1 }));
+    client.wait_for("stopped");
+    assert_eq!(client.frames(), [("main".to_string(), 1, 11)]);
+
+    client.request("next", json!({ "threadId": 1 }));
+    client.wait_for("exited");
+    assert_eq!(client.output("stdout"), "4");
+    client.disconnect();
+}
+
+#[test]
+fn sends_program_output_and_errors() {
+    let (mut client, _) = Client::launch("errors", "\"hi\" 1 0/", false);
+    client.request("configurationDone", json!({}));
+    assert_eq!(client.wait_for("exited")["exitCode"], 1);
+    assert_eq!(client.output("stdout"), "hi");
+    assert_eq!(
+        client.output("stderr"),
+        "errors.false:1:9: Division by zero\n"
+    );
+    client.wait_for("terminated");
+    client.disconnect();
+}
+
+#[test]
+fn rejects_breakpoints_without_instructions() {
+    let (mut client, path) = Client::launch("unverified", CALLS, false);
+    let response = client.request(
+        "setBreakpoints",
+        json!({ "source": { "path": path }, "breakpoints": [{ "line": 9 }, { "line": 3 }] }),
+    );
+    let breakpoints = &response["body"]["breakpoints"];
+    assert_eq!(breakpoints[0]["verified"], false);
+    assert_eq!(breakpoints[0]["message"], "No instruction here");
+    assert_eq!(breakpoints[1]["verified"], true);
+
+    let response = client.request(
+        "setBreakpoints",
+        json!({ "source": { "path": "elsewhere.false" }, "breakpoints": [{ "line": 1 }] }),
+    );
+    let breakpoint = &response["body"]["breakpoints"][0];
+    assert_eq!(breakpoint["verified"], false);
+    assert_eq!(breakpoint["message"], "Not the program being debugged");
+
+    // Still stops at the breakpoint set for the program
+    client.request("configurationDone", json!({}));
+    client.wait_for("stopped");
+    assert_eq!(client.frames(), [("main".to_string(), 3, 1)]);
+    client.disconnect();
+}
+
+#[test]
+fn pauses_and_terminates_running_programs() {
+    let (mut client, _) = Client::launch("forever", "[1][]#", false);
+    client.request("configurationDone", json!({}));
+    let response = client.request("stackTrace", json!({ "threadId": 1 }));
+    assert_eq!(response["success"], false);
+    assert_eq!(response["message"], "The program isn't stopped");
+
+    client.request("pause", json!({ "threadId": 1 }));
+    assert_eq!(client.wait_for("stopped")["reason"], "pause");
+    let response = client.request("terminate", json!({}));
+    assert_eq!(response["success"], true);
+    client.wait_for("terminated");
+    client.disconnect();
+}
+
+#[test]
+fn reports_programs_that_fail_to_launch() {
+    let mut client = Client::start();
+    client.request("initialize", json!({}));
+    let response = client.request("launch", json!({ "program": "does/not/exist.false" }));
+    assert_eq!(response["success"], false);
+    assert!(response["message"]
+        .as_str()
+        .unwrap()
+        .starts_with("Failed to read does/not/exist.false"));
+
+    let path = program("syntax_error", "1 ]");
+    let response = client.request("launch", json!({ "program": path }));
+    assert_eq!(response["success"], false);
+    assert!(response["message"]
+        .as_str()
+        .unwrap()
+        .starts_with("syntax_error.false:1:3: "));
+    client.disconnect();
+}
+
+#[test]
+fn ends_the_session_on_truncated_messages() {
+    let mut client = Client::start();
+    client.request("initialize", json!({}));
+    // Far more than was sent, or could be allocated up front
+    write!(client.stdin, "Content-Length: 1000000000000\r\n\r\n{{}}").unwrap();
+    drop(client.stdin);
+    assert!(client.child.wait().unwrap().success());
+}
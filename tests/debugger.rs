@@ -0,0 +1,231 @@
+use std::cell::RefCell;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use falsy::debugger::Debugger;
+use falsy::interpreter::{Interpreter, InterpreterRuntimeError};
+use falsy::parser::parse;
+
+/// Debugs `source` with scripted commands, returning everything shown, with
+/// the commands as if typed at the prompt.
+fn debug(source: &str, commands: &[&str]) -> (String, Result<(), InterpreterRuntimeError>) {
+    let ast = parse(source).into_result().expect("Failed to parse");
+    let transcript = RefCell::new(String::new());
+    let mut commands = commands.iter();
+    let result = Debugger::new("test.false", source, ast).run(
+        Interpreter::new().on_output(|s: &str| transcript.borrow_mut().push_str(s)),
+        || {
+            let command = commands.next()?;
+            transcript.borrow_mut().push_str(&format!("> {command}\n"));
+            Some(command.to_string())
+        },
+        |message| {
+            let mut transcript = transcript.borrow_mut();
+            transcript.push_str(message);
+            transcript.push('\n');
+        },
+    );
+    (transcript.into_inner(), result)
+}
+
+/// Why the debugger stopped each time.
+fn stops(transcript: &str) -> Vec<&str> {
+    transcript
+        .lines()
+        .filter_map(|line| Some(line.split_once("Stopped: ")?.1))
+        .collect()
+}
+
+const CALLS: &str = "[1+]f:\n3 f;!\nf;!.";
+
+#[test]
+fn steps_into_and_over_lambdas() {
+    let (transcript, result) = debug(
+        CALLS,
+        &[
+            "s", "s", "s", "s", "s", "s", "s", "finish", "n", "n", "n", "c",
+        ],
+    );
+    assert!(result.is_ok());
+    assert_eq!(
+        stops(&transcript),
+        [
+            "at 1:1", "at 1:5", "at 1:6", "at 2:1", "at 2:3", "at 2:4", "at 2:5",
+            // Into the call
+            "at 1:2", // Out of it
+            "at 3:1", "at 3:2", "at 3:3", // Over the call
+            "at 3:4",
+        ]
+    );
+    assert!(
+        transcript.ends_with("> c\n5Program finished\n"),
+        "{transcript}"
+    );
+}
+
+#[test]
+fn shows_where_it_stopped() {
+    let (transcript, _) = debug(CALLS, &["b 2:4", "c", "list"]);
+    let shown = "
+Stopped: breakpoint 1 at 2:4
+   ╭─[test.false:2:4]
+   │
+ 2 │ 3 f;!
+   │    ┬
+   │    ╰── next to run
+───╯
+";
+    assert_eq!(transcript.matches(&shown[1..]).count(), 1, "{transcript}");
+    assert!(
+        transcript.contains("> list\nStopped: at 2:4\n"),
+        "{transcript}"
+    );
+}
+
+#[test]
+fn repeats_the_last_command() {
+    let (transcript, _) = debug(CALLS, &["n", "", "", "c"]);
+    assert_eq!(stops(&transcript), ["at 1:1", "at 1:5", "at 1:6", "at 2:1"]);
+}
+
+#[test]
+fn breaks_at_lines_and_columns() {
+    let (transcript, result) = debug(
+        "1 2+\n[$*]s:\n3s;!.",
+        &["break 3", "break 2:3", "info", "c", "c", "stack", "c"],
+    );
+    assert!(result.is_ok());
+    assert!(transcript.contains(
+        "> break 3\nBreakpoint 1 at 3:1\n\
+         > break 2:3\nBreakpoint 2 at 2:3\n\
+         > info\nBreakpoint 1 at 3:1\nBreakpoint 2 at 2:3\n"
+    ));
+    assert_eq!(
+        stops(&transcript),
+        ["at 1:1", "breakpoint 1 at 3:1", "breakpoint 2 at 2:3"]
+    );
+    assert!(transcript.contains("> stack\n3 3 3\n"), "{transcript}");
+    assert!(transcript.ends_with("9Program finished\n"), "{transcript}");
+}
+
+#[test]
+fn breaks_on_stores_and_watches_changes() {
+    let (transcript, _) = debug(
+        "1a: 1a: 2a: 3b:",
+        &["break b", "watch a", "c", "c", "c", "print a", "vars", "c"],
+    );
+    assert!(transcript.contains("> break b\nBreakpoint 1 when b is stored\n"));
+    assert!(transcript.contains("> watch a\nWatchpoint 2 on a\n"));
+    assert_eq!(
+        stops(&transcript),
+        [
+            "at 1:1",
+            "watchpoint 2, a changed from (unset) to 1",
+            // Storing 1 again isn't a change
+            "watchpoint 2, a changed from 1 to 2",
+            "breakpoint 1, stored b = 3",
+        ]
+    );
+    assert!(transcript.contains("> print a\na = 2\n> vars\na = 2\nb = 3\n"));
+    assert!(transcript.contains("stored here"));
+}
+
+#[test]
+fn deletes_breakpoints() {
+    let (transcript, _) = debug("1a: 2a:", &["b a", "delete 1", "delete 1", "c"]);
+    assert!(transcript.contains("> delete 1\nNo breakpoint number 1\n"));
+    assert_eq!(stops(&transcript), ["at 1:1"]);
+    assert!(transcript.ends_with("Program finished\n"));
+}
+
+#[test]
+fn inspects_programs_that_failed() {
+    let (transcript, result) = debug("5 1 0/", &["c", "stack", "s"]);
+    assert_eq!(result.unwrap_err().reason(), "Division by zero");
+    assert!(
+        transcript.contains("Error: Division by zero"),
+        "{transcript}"
+    );
+    // The operands were already popped when it failed
+    assert!(transcript.ends_with(
+        "> stack\n5\n\
+         > s\nThe program isn't running\n"
+    ));
+}
+
+#[test]
+fn quits() {
+    let (transcript, result) = debug("\"a\" \"b\"", &["s", "q"]);
+    assert!(result.is_ok());
+    assert_eq!(stops(&transcript), ["at 1:1", "at 1:5"]);
+    assert!(transcript.contains("> s\naStopped: at 1:5\n"));
+    assert!(transcript.ends_with("> q\n"));
+
+    // Running out of commands is the same as quitting
+    let (transcript, result) = debug("\"a\" \"b\"", &[]);
+    assert!(result.is_ok());
+    assert_eq!(stops(&transcript), ["at 1:1"]);
+    assert!(!transcript.contains("Program finished"));
+}
+
+#[test]
+fn rejects_bad_commands() {
+    let (transcript, _) = debug(
+        CALLS,
+        &[
+            "frobnicate",
+            "finish",
+            "step 2",
+            "break",
+            "break 9",
+            "break 1:20",
+            "break x:y",
+            "watch 1",
+            "print",
+        ],
+    );
+    let replies: Vec<_> = transcript
+        .lines()
+        .skip_while(|line| !line.starts_with('>'))
+        .filter(|line| !line.starts_with('>'))
+        .collect();
+    assert_eq!(
+        replies,
+        [
+            "Unknown command frobnicate, see help",
+            "finish isn't meaningful outside a lambda",
+            "step doesn't take an argument",
+            "Expected a place to break, as in break 3:5 or break a",
+            "No instruction at 9",
+            "No instruction at 1:20",
+            "Expected LINE[:COLUMN] or a variable, got x:y",
+            "Expected a variable to watch, as in watch a",
+            "Expected a variable to print, as in print a",
+        ]
+    );
+}
+
+#[test]
+fn debugs_from_the_command_line() {
+    let path = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("debugged.false");
+    std::fs::write(&path, CALLS).unwrap();
+    let mut child = Command::new(env!("CARGO_BIN_EXE_falsy"))
+        .arg("debug")
+        .arg(&path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(b"break 3:3\ncontinue\nstack\ncontinue\n")
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("Stopped: breakpoint 1 at 3:3"), "{stdout}");
+    assert!(stdout.contains("\n4 […]\n"), "{stdout}");
+    assert!(stdout.ends_with("5\nProgram finished\n"), "{stdout}");
+}
@@ -0,0 +1,48 @@
+use falsy::analysis::effects::{EffectAnalysis, VariableSet};
+use falsy::parser::parse;
+
+fn analyze(source: &str) -> EffectAnalysis {
+    let ast = parse(source).into_result().expect("Failed to parse");
+    EffectAnalysis::analyze(&ast)
+}
+
+#[test]
+fn direct_effects() {
+    let analysis = analyze("[1 2 +] [^] [.] [x;] [1 x:]");
+    let effects: Vec<_> = analysis.lambdas().iter().map(|l| l.effects()).collect();
+    assert!(effects[0].is_pure());
+    assert!(effects[1].reads_input && !effects[1].writes_output);
+    assert!(effects[2].writes_output && !effects[2].reads_input);
+    assert!(effects[3].is_pure() && effects[3].fetches.contains('x'));
+    assert!(!effects[4].is_pure() && effects[4].stores.contains('x'));
+}
+
+#[test]
+fn effects_follow_stored_lambdas() {
+    let analysis = analyze("[\"hi\"] p: [p;!] q: [q;!] r: [1 [2] !] s:");
+    assert!(analysis.variable('p').writes_output);
+    assert!(analysis.variable('q').writes_output);
+    assert!(analysis.variable('r').writes_output);
+    assert!(analysis.variable('s').is_pure());
+    assert_eq!(
+        analysis.lambdas()[0].stored_in(),
+        ['p'].into_iter().collect::<VariableSet>()
+    );
+}
+
+#[test]
+fn aliases_and_unknown_calls() {
+    // b is a copy of a, and the last lambda calls whatever is on the stack
+    let analysis = analyze("[^] a: a; b: [!] c: [1] d:");
+    assert!(analysis.variable('b').reads_input);
+    assert!(analysis.variable('c').reads_input);
+    assert!(analysis.variable('d').is_pure());
+}
+
+#[test]
+fn recursion_terminates() {
+    let analysis = analyze("[$ 1 > [1- $ f;! \\ 1- f;! +]?]f:");
+    let f = analysis.variable('f');
+    assert!(f.is_pure());
+    assert_eq!(f.fetches, ['f'].into_iter().collect());
+}
@@ -0,0 +1,130 @@
+use std::process::Command;
+
+use falsy::formatter::{format, WIDTH};
+use falsy::parser::parse;
+
+mod common;
+
+use common::{random_programs, without_spans};
+
+test_each_file::test_each_file! { for ["false"] in "./tests/samples" => test_formatting_samples }
+
+/// Formats `source`, checking that it means the same and that formatting
+/// it again changes nothing.
+fn assert_formats(source: &str) -> String {
+    let formatted = format(source).unwrap();
+    let before = parse(source).into_result().unwrap();
+    let after = parse(&formatted)
+        .into_result()
+        .unwrap_or_else(|e| panic!("{formatted:?} doesn't parse: {e:?}"));
+    assert_eq!(without_spans(after), without_spans(before), "{formatted}");
+    assert_eq!(format(&formatted).unwrap(), formatted, "{source:?}");
+    formatted
+}
+
+fn test_formatting_samples([source]: [&str; 1]) {
+    let formatted = assert_formats(source);
+    for line in formatted.lines() {
+        assert!(line.chars().count() <= WIDTH, "{line}");
+        assert_eq!(line, line.trim_end());
+    }
+}
+
+#[test]
+fn spaces_instructions() {
+    assert_eq!(
+        assert_formats("1 2+$*.  \"hi\"'a,"),
+        "1 2 + $ * . \"hi\" 'a ,\n"
+    );
+    // Names go with their `:`, `;` and `;!`, and lambdas with what they are
+    // stored in or called by
+    assert_eq!(
+        assert_formats("[ 2 * ] d :3d ; ! [1]! d;"),
+        "[2 *]d: 3 d;! [1]! d;\n"
+    );
+    assert_eq!(assert_formats("[1][2][ 3 ]#[4]?"), "[1] [2][3]# [4]?\n");
+    assert_eq!(assert_formats("007 ' 1"), "0 0 7 '  1\n");
+    assert_eq!(assert_formats(""), "");
+}
+
+#[test]
+fn keeps_comments_and_line_breaks() {
+    assert_eq!(
+        assert_formats("  {double}[$+]d:{twice}\n\n\n\n3 d;!{ print }\n.\n\n"),
+        "{double} [$ +]d: {twice}\n\n3 d;! { print }\n.\n"
+    );
+    // A comment between a name and its `:` stays there
+    assert_eq!(assert_formats("x{here}:"), "x {here} :\n");
+}
+
+#[test]
+fn breaks_long_lambdas() {
+    let numbers: Vec<_> = (1..=30).map(|n| n.to_string()).collect();
+    let source = format!("[$ 0 > [{} +]?]f: 1 f;!", numbers.join(" "));
+    assert_eq!(
+        assert_formats(&source),
+        "\
+[
+    $ 0 >
+    [
+        1 2 3 4 5 6 7 8 9 10 11 12 13 14 15 16 17 18 19 20 21 22 23 24 25 26 27
+        28 29 30 +
+    ]?
+]f: 1 f;!
+"
+    );
+
+    // As do lambdas with line breaks between instructions
+    assert_eq!(
+        assert_formats("[1\n2][\n{ body }\n]#"),
+        "[\n    1\n    2\n][{ body }]#\n"
+    );
+    assert_eq!(assert_formats("[\n]"), "[]\n");
+}
+
+#[test]
+fn refuses_programs_with_errors() {
+    let errors = format("1 ] [").unwrap_err();
+    let reasons: Vec<_> = errors.iter().map(|e| e.reason()).collect();
+    assert_eq!(reasons, ["Unexpected ]", "Unclosed lambda, expected ]"]);
+}
+
+#[test]
+fn checks_and_rewrites_files() {
+    let path = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("unformatted.false");
+    std::fs::write(&path, "1 2+.").unwrap();
+    let falsy = || Command::new(env!("CARGO_BIN_EXE_falsy"));
+
+    let output = falsy()
+        .args(["fmt", "--check"])
+        .arg(&path)
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        format!("{} isn't formatted\n", path.display())
+    );
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "1 2+.");
+
+    assert!(falsy().arg("fmt").arg(&path).status().unwrap().success());
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "1 2 + .\n");
+    let output = falsy()
+        .args(["fmt", "--check"])
+        .arg(&path)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(output.stdout.is_empty());
+}
+
+#[test]
+fn formats_random_programs() {
+    let pieces = [
+        "[", "]", "]?", "][", "]#", "{c}", "\"s\"", "'x", "' ", " ", "\n", "\n\n", "1", "23", "a",
+        ":", ";", "!", "+", "$",
+    ];
+    for source in random_programs(0x9e37_79b9, &pieces).take(500) {
+        assert_formats(&source);
+    }
+}
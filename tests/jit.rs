@@ -0,0 +1,71 @@
+#![cfg(feature = "jit")]
+
+use falsy::interpreter::{Backend, Interpreter, JitStats};
+use falsy::parser::parse;
+
+type Outcome = (
+    String,
+    Result<Option<JitStats>, (String, std::ops::Range<usize>)>,
+);
+
+fn run(source: &str, backend: Backend) -> Outcome {
+    let ast = parse(source).into_result().expect("Failed to parse");
+    let mut output = String::new();
+    let result = Interpreter::new()
+        .on_input(|| None)
+        .on_output(|s| output.push_str(s))
+        .backend(backend)
+        .run_program(ast)
+        .map(|outcome| outcome.jit_stats())
+        .map_err(|e| (e.reason().to_string(), e.span().into_range()));
+    (output, result)
+}
+
+fn jit_stats(source: &str) -> JitStats {
+    let (jit_output, jit) = run(source, Backend::Jit);
+    let (tree_output, tree) = run(source, Backend::Tree);
+    assert_eq!(jit_output, tree_output, "{source}");
+    assert_eq!(tree, Ok(None));
+    jit.unwrap().unwrap()
+}
+
+#[test]
+fn compiles_integer_loops_and_lambdas() {
+    // Sums 1..=1000 in a loop
+    let stats = jit_stats("0 1000 [$ 0 >][$ @ + \\ 1-]# % .");
+    assert_eq!(stats.compiled, 1);
+    assert_eq!(stats.native_runs, 1);
+
+    // Only becomes hot after a few calls
+    let stats = jit_stats("[$ * 1+]s: 0 [$ 20 >~][$ s;! . 1+]# %");
+    assert_eq!(stats.compiled, 1);
+    assert!(stats.native_runs > 0);
+}
+
+#[test]
+fn leaves_other_code_to_the_interpreter() {
+    // Writes output
+    let stats = jit_stats("0 [$ 5 >~][$ . 1+]# %");
+    assert_eq!(stats.compiled, 0);
+    // No fixed stack effect
+    let stats = jit_stats("0 [$ 5 >~][$ 1+]# ...");
+    assert_eq!(stats.compiled, 0);
+}
+
+#[test]
+fn falls_back_for_errors() {
+    let programs = [
+        // Division by zero inside a compiled loop
+        "\"a\" 5 [$ 1_ >][$ 100 \\ / % 1-]#",
+        // A compiled lambda called on something that isn't an integer
+        "[1+]f: 1 f;! 2 f;! 3 f;! 4 f;! 5 f;! 6 f;! 7 f;! 8 f;! 9 f;! 10 f;! f f;!",
+        // Too few values for a compiled lambda
+        "[+]f: 1 2 f;! 3 f;! 4 f;! 5 f;! 6 f;! 7 f;! 8 f;! 9 f;! 10 f;! 11 f;! %% f;!",
+    ];
+    for program in programs {
+        let (jit_output, jit) = run(program, Backend::Jit);
+        let (tree_output, tree) = run(program, Backend::Tree);
+        assert_eq!(jit_output, tree_output, "{program}");
+        assert_eq!(jit.unwrap_err(), tree.unwrap_err(), "{program}");
+    }
+}
@@ -0,0 +1,13 @@
+use falsy::lines::{location, LineIndex};
+
+#[test]
+fn indexes_lines_like_the_helper_locates_them() {
+    let source = "1 2+\n\n'ß $.\n{ é }\"\n\"";
+    let lines = LineIndex::new(source);
+    for (offset, _) in source.char_indices().chain([(source.len(), ' ')]) {
+        assert_eq!(lines.location(offset), location(source, offset), "{offset}");
+    }
+    assert_eq!(lines.location(9), (3, 3));
+    assert_eq!(lines.line(9), (3, 6));
+    assert_eq!(lines.location(source.len()), (5, 2));
+}
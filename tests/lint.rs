@@ -0,0 +1,48 @@
+use falsy::lint::{self, LintConfig, Severity};
+use falsy::parser::parse;
+
+fn codes(source: &str, config: &LintConfig) -> Vec<(&'static str, Severity)> {
+    let ast = parse(source).into_result().expect("Failed to parse");
+    lint::lint(&ast, config)
+        .into_iter()
+        .map(|d| (d.rule().code, d.severity()))
+        .collect()
+}
+
+#[test]
+fn variables() {
+    let config = LintConfig::new();
+    assert_eq!(codes("x;.", &config), [("L001", Severity::Error)]);
+    assert_eq!(codes("1 x:", &config), [("L002", Severity::Warning)]);
+    assert!(codes("1 x: x;.", &config).is_empty());
+    // Names pushed without a direct store or fetch could go anywhere
+    assert!(codes("x; y 1 \\ :", &config).is_empty());
+}
+
+#[test]
+fn adjacent_pairs() {
+    let config = LintConfig::new();
+    assert_eq!(codes("[1]%", &config), [("L003", Severity::Warning)]);
+    assert_eq!(codes("1 2 $% \\\\ __ ~~ . .", &config).len(), 4);
+    assert_eq!(codes("0[1.]? [0][1.]#", &config).len(), 2);
+    assert!(codes("1[1.]? [1 $%]!", &config)
+        .iter()
+        .all(|(code, _)| *code == "L005"));
+}
+
+#[test]
+fn config_overrides_severity() {
+    let config = LintConfig::from_toml(
+        r#"
+        [lint]
+        L001 = "off"
+        unused-variable = "error"
+        "#,
+    )
+    .unwrap();
+    assert!(codes("x;.", &config).is_empty());
+    assert_eq!(codes("1 x:", &config), [("L002", Severity::Error)]);
+
+    assert!(LintConfig::from_toml("[lint]\nL999 = \"warn\"").is_err());
+    assert!(LintConfig::from_toml("[lint]\nL001 = \"loud\"").is_err());
+}
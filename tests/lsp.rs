@@ -0,0 +1,390 @@
+use std::io::BufReader;
+use std::process::{Command, Stdio};
+use std::thread::JoinHandle;
+
+use lsp_server::{Connection, Message, Notification, Request, RequestId};
+use lsp_types::notification::{
+    DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument, Exit, Initialized,
+    Notification as NotificationKind, PublishDiagnostics,
+};
+use lsp_types::request::{
+    DocumentSymbolRequest, GotoDefinition, HoverRequest, Initialize, References,
+    Request as RequestKind, SemanticTokensFullRequest, Shutdown,
+};
+use lsp_types::{
+    DidChangeTextDocumentParams, DidCloseTextDocumentParams, DidOpenTextDocumentParams,
+    DocumentSymbolParams, DocumentSymbolResponse, GotoDefinitionParams, GotoDefinitionResponse,
+    HoverContents, HoverParams, InitializeParams, InitializedParams, Position,
+    PublishDiagnosticsParams, Range, ReferenceContext, ReferenceParams, SemanticTokensParams,
+    SemanticTokensResult, TextDocumentContentChangeEvent, TextDocumentIdentifier, TextDocumentItem,
+    TextDocumentPositionParams, Uri, VersionedTextDocumentIdentifier,
+};
+
+/// A language client talking to the server on a thread of its own.
+struct Client {
+    connection: Connection,
+    server: JoinHandle<()>,
+    next_id: i32,
+}
+
+impl Client {
+    fn start() -> Self {
+        let (server, connection) = Connection::memory();
+        let server = std::thread::spawn(move || falsy::lsp::run(server).unwrap());
+        let mut client = Self {
+            connection,
+            server,
+            next_id: 0,
+        };
+        let initialized = client.request::<Initialize>(InitializeParams::default());
+        assert!(initialized.capabilities.hover_provider.is_some());
+        client.notify::<Initialized>(InitializedParams {});
+        client
+    }
+
+    /// Starts a client with `source` open, checking that it parses.
+    fn open(source: &str) -> Self {
+        let client = Self::start();
+        client.notify::<DidOpenTextDocument>(DidOpenTextDocumentParams {
+            text_document: TextDocumentItem::new(uri(), "false".to_string(), 1, source.to_string()),
+        });
+        let published = client.diagnostics();
+        assert_eq!(published.diagnostics, [], "{source}");
+        client
+    }
+
+    fn request<R: RequestKind>(&mut self, params: R::Params) -> R::Result {
+        self.next_id += 1;
+        let id = RequestId::from(self.next_id);
+        let request = Request::new(id.clone(), R::METHOD.to_string(), params);
+        self.connection.sender.send(request.into()).unwrap();
+        loop {
+            match self.connection.receiver.recv().unwrap() {
+                Message::Response(response) if response.id == id => {
+                    assert!(response.error.is_none(), "{:?}", response.error);
+                    return serde_json::from_value(response.result.unwrap()).unwrap();
+                }
+                Message::Notification(_) => {}
+                message => panic!("Unexpected message {message:?}"),
+            }
+        }
+    }
+
+    fn notify<N: NotificationKind>(&self, params: N::Params) {
+        let notification = Notification::new(N::METHOD.to_string(), params);
+        self.connection.sender.send(notification.into()).unwrap();
+    }
+
+    /// The next diagnostics published.
+    fn diagnostics(&self) -> PublishDiagnosticsParams {
+        match self.connection.receiver.recv().unwrap() {
+            Message::Notification(notification) => {
+                notification.extract(PublishDiagnostics::METHOD).unwrap()
+            }
+            message => panic!("Unexpected message {message:?}"),
+        }
+    }
+
+    fn hover(&mut self, line: u32, character: u32) -> Option<(String, Range)> {
+        let hover = self.request::<HoverRequest>(HoverParams {
+            text_document_position_params: at(line, character),
+            work_done_progress_params: Default::default(),
+        })?;
+        let HoverContents::Markup(contents) = hover.contents else {
+            panic!("Expected markup, got {:?}", hover.contents);
+        };
+        Some((contents.value, hover.range.unwrap()))
+    }
+
+    fn definition(&mut self, line: u32, character: u32) -> Vec<Range> {
+        let response = self.request::<GotoDefinition>(GotoDefinitionParams {
+            text_document_position_params: at(line, character),
+            work_done_progress_params: Default::default(),
+            partial_result_params: Default::default(),
+        });
+        match response {
+            Some(GotoDefinitionResponse::Array(locations)) => {
+                locations.into_iter().map(|l| l.range).collect()
+            }
+            None => Vec::new(),
+            Some(other) => panic!("Expected locations, got {other:?}"),
+        }
+    }
+
+    fn references(&mut self, line: u32, character: u32, include_declaration: bool) -> Vec<Range> {
+        let locations = self.request::<References>(ReferenceParams {
+            text_document_position: at(line, character),
+            work_done_progress_params: Default::default(),
+            partial_result_params: Default::default(),
+            context: ReferenceContext {
+                include_declaration,
+            },
+        });
+        locations.unwrap().into_iter().map(|l| l.range).collect()
+    }
+
+    /// Shuts the server down, and checks that it stops.
+    fn shutdown(mut self) {
+        self.request::<Shutdown>(());
+        self.notify::<Exit>(());
+        self.server.join().unwrap();
+    }
+}
+
+fn uri() -> Uri {
+    "file:///test.false".parse().unwrap()
+}
+
+fn document() -> TextDocumentIdentifier {
+    TextDocumentIdentifier::new(uri())
+}
+
+fn at(line: u32, character: u32) -> TextDocumentPositionParams {
+    TextDocumentPositionParams::new(document(), Position::new(line, character))
+}
+
+fn range(line: u32, start: u32, end: u32) -> Range {
+    Range::new(Position::new(line, start), Position::new(line, end))
+}
+
+const PROGRAM: &str = "{ increment }\n[1+]f:\n3 f;!\n[f;! f;!]g: 0 g;!.";
+
+#[test]
+fn publishes_parse_errors_as_you_type() {
+    let mut client = Client::open("1 2+");
+    client.notify::<DidChangeTextDocument>(DidChangeTextDocumentParams {
+        text_document: VersionedTextDocumentIdentifier::new(uri(), 2),
+        content_changes: vec![TextDocumentContentChangeEvent {
+            range: None,
+            range_length: None,
+            text: "1 2+\n]".to_string(),
+        }],
+    });
+    let published = client.diagnostics();
+    assert_eq!(published.version, Some(2));
+    assert_eq!(published.diagnostics.len(), 1);
+    let diagnostic = &published.diagnostics[0];
+    assert_eq!(diagnostic.range, range(1, 0, 1));
+    assert!(
+        diagnostic.message.starts_with("found ] expected"),
+        "{}",
+        diagnostic.message
+    );
+
+    // Fixing it clears them
+    client.notify::<DidChangeTextDocument>(DidChangeTextDocumentParams {
+        text_document: VersionedTextDocumentIdentifier::new(uri(), 3),
+        content_changes: vec![TextDocumentContentChangeEvent {
+            range: None,
+            range_length: None,
+            text: "1 2+.".to_string(),
+        }],
+    });
+    assert_eq!(client.diagnostics().diagnostics, []);
+
+    client.notify::<DidCloseTextDocument>(DidCloseTextDocumentParams {
+        text_document: document(),
+    });
+    assert_eq!(client.diagnostics().diagnostics, []);
+    assert_eq!(client.hover(0, 0), None);
+    client.shutdown();
+}
+
+#[test]
+fn hovers_with_stack_effects() {
+    let mut client = Client::open(PROGRAM);
+    let (contents, hovered) = client.hover(1, 2).unwrap();
+    assert_eq!(contents, "Adds\n\nStack effect: `(2 -- 1)`");
+    assert_eq!(hovered, range(1, 2, 3));
+
+    // On a bracket is the lambda itself
+    let (contents, hovered) = client.hover(1, 0).unwrap();
+    assert_eq!(
+        contents,
+        "Pushes a lambda\n\nStack effect: `(0 -- 1)`\n\nWhen called: `(1 -- 1)`"
+    );
+    assert_eq!(hovered, range(1, 0, 4));
+
+    let (contents, _) = client.hover(3, 1).unwrap();
+    assert_eq!(
+        contents,
+        "Pushes a reference to variable `f`\n\nStack effect: `(0 -- 1)`\n\nWhen called: `(1 -- 1)`"
+    );
+    // A call has the effect of what it calls
+    let (contents, _) = client.hover(3, 16).unwrap();
+    assert_eq!(
+        contents,
+        "Calls the lambda on top of the stack\n\nStack effect: `(1 -- 1)`"
+    );
+
+    // Whitespace and comments have nothing
+    assert_eq!(client.hover(0, 3), None);
+    assert_eq!(client.hover(2, 1), None);
+    client.shutdown();
+
+    let mut client = Client::open("1 2 3@");
+    let (contents, _) = client.hover(0, 5).unwrap();
+    assert_eq!(
+        contents,
+        "Reverses the top three values\n\nStack effect: `(3 -- 3)`"
+    );
+    client.shutdown();
+}
+
+#[test]
+fn hovers_over_unknown_effects() {
+    let mut client = Client::open("^$0>[1]?");
+    let (contents, _) = client.hover(0, 7).unwrap();
+    assert_eq!(
+        contents,
+        "Runs the lambda if the value below it is true\n\n\
+         Stack effect: unknown, it differs between paths"
+    );
+    client.shutdown();
+}
+
+#[test]
+fn goes_to_stores() {
+    let mut client = Client::open("[1]a: a;!\n[2]a:");
+    let stores = [range(0, 3, 5), range(1, 3, 5)];
+    // From the name and from the `;`
+    assert_eq!(client.definition(0, 6), stores);
+    assert_eq!(client.definition(0, 7), stores);
+    assert_eq!(client.definition(0, 3), stores);
+    // Not a variable
+    assert_eq!(client.definition(0, 8), []);
+    client.shutdown();
+}
+
+#[test]
+fn finds_references() {
+    let mut client = Client::open(PROGRAM);
+    assert_eq!(
+        client.references(2, 2, true),
+        [
+            range(1, 4, 5),
+            range(2, 2, 3),
+            range(3, 1, 2),
+            range(3, 5, 6)
+        ]
+    );
+    assert_eq!(
+        client.references(1, 4, false),
+        [range(2, 2, 3), range(3, 1, 2), range(3, 5, 6)]
+    );
+    client.shutdown();
+}
+
+#[test]
+fn lists_stored_lambdas_as_symbols() {
+    let mut client = Client::open("[[1]h: 2]g:\n[[$*]s:]!\n[3]");
+    let response = client.request::<DocumentSymbolRequest>(DocumentSymbolParams {
+        text_document: document(),
+        work_done_progress_params: Default::default(),
+        partial_result_params: Default::default(),
+    });
+    let Some(DocumentSymbolResponse::Nested(symbols)) = response else {
+        panic!("Expected nested symbols, got {response:?}");
+    };
+    let names: Vec<_> = symbols.iter().map(|s| s.name.as_str()).collect();
+    // Lambdas in lambdas that aren't stored are at the top
+    assert_eq!(names, ["g", "s"]);
+    assert_eq!(symbols[0].range, range(0, 0, 11));
+    assert_eq!(symbols[0].selection_range, range(0, 9, 10));
+    assert_eq!(symbols[0].detail.as_deref(), Some("(0 -- 1)"));
+    let children = symbols[0].children.as_ref().unwrap();
+    assert_eq!(children.len(), 1);
+    assert_eq!(children[0].name, "h");
+    assert_eq!(symbols[1].detail.as_deref(), Some("(1 -- 1)"));
+    client.shutdown();
+}
+
+#[test]
+fn highlights_tokens() {
+    let mut client = Client::open("{ double }\n[$+]d:\n\"a\nb\" 'c 2d;!");
+    let response = client.request::<SemanticTokensFullRequest>(SemanticTokensParams {
+        text_document: document(),
+        work_done_progress_params: Default::default(),
+        partial_result_params: Default::default(),
+    });
+    let Some(SemanticTokensResult::Tokens(tokens)) = response else {
+        panic!("Expected tokens, got {response:?}");
+    };
+    let tokens: Vec<_> = tokens
+        .data
+        .iter()
+        .map(|t| {
+            (
+                t.delta_line,
+                t.delta_start,
+                t.length,
+                t.token_type,
+                t.token_modifiers_bitset,
+            )
+        })
+        .collect();
+    let (variable, number, string, operator, keyword, comment) = (0, 1, 2, 3, 4, 5);
+    assert_eq!(
+        tokens,
+        [
+            (0, 0, 10, comment, 0),
+            (1, 1, 1, operator, 0),
+            (0, 1, 1, operator, 0),
+            (0, 2, 1, variable, 1),
+            (0, 1, 1, operator, 0),
+            // A string over two lines is two tokens
+            (1, 0, 2, string, 0),
+            (1, 0, 2, string, 0),
+            (0, 3, 2, string, 0),
+            (0, 3, 1, number, 0),
+            (0, 1, 1, variable, 0),
+            (0, 1, 1, operator, 0),
+            (0, 1, 1, keyword, 0),
+        ]
+    );
+    client.shutdown();
+}
+
+#[test]
+fn counts_columns_in_utf16() {
+    let mut client = Client::open("\"é😀\" 1a: a;");
+    // The emoji is two code units
+    assert_eq!(
+        client.references(0, 8, true),
+        [range(0, 7, 8), range(0, 10, 11)]
+    );
+    client.shutdown();
+}
+
+#[test]
+fn serves_over_stdio() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_falsy"))
+        .arg("lsp")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    let mut stdout = BufReader::new(child.stdout.take().unwrap());
+    let messages: [Message; 4] = [
+        Request::new(
+            1.into(),
+            Initialize::METHOD.to_string(),
+            InitializeParams::default(),
+        )
+        .into(),
+        Notification::new(Initialized::METHOD.to_string(), InitializedParams {}).into(),
+        Request::new(2.into(), Shutdown::METHOD.to_string(), ()).into(),
+        Notification::new(Exit::METHOD.to_string(), ()).into(),
+    ];
+    for message in messages {
+        message.write(&mut stdin).unwrap();
+    }
+    for id in [1, 2] {
+        match Message::read(&mut stdout).unwrap() {
+            Some(Message::Response(response)) => assert_eq!(response.id, id.into()),
+            message => panic!("Expected a response, got {message:?}"),
+        }
+    }
+    assert!(child.wait().unwrap().success());
+}
@@ -0,0 +1,72 @@
+use falsy::analysis::stack_effect::{StackEffect, StackEffects};
+use falsy::ast::FalseInstruction;
+use falsy::interpreter::{Interpreter, MemoStats};
+use falsy::parser::parse;
+
+fn lambda_effect(source: &str) -> Option<StackEffect> {
+    let ast = parse(source).into_result().expect("Failed to parse");
+    let effects = StackEffects::new(&ast);
+    match ast[0].instruction() {
+        FalseInstruction::Lambda(body) => effects.of_lambda(body),
+        other => panic!("Expected a lambda first, got {other:?}"),
+    }
+}
+
+fn run_memoized(source: &str, max_entries: usize) -> (String, MemoStats) {
+    let ast = parse(source).into_result().expect("Failed to parse");
+    let mut output = String::new();
+    let outcome = Interpreter::new()
+        .on_input(|| None)
+        .on_output(|s| output.push_str(s))
+        .memoize(max_entries)
+        .run_program(ast)
+        .unwrap();
+    (output, outcome.memo_stats().unwrap())
+}
+
+const FIB: &str = "[$ 1 > [1- $ f;! \\ 1- f;! +]?]f: ";
+
+#[test]
+fn stack_effects() {
+    assert_eq!(lambda_effect("[2 *]"), Some(StackEffect::new(1, 1)));
+    assert_eq!(lambda_effect("[\\ % $]"), Some(StackEffect::new(2, 2)));
+    assert_eq!(lambda_effect("[1 2 ø]"), Some(StackEffect::new(2, 4)));
+    assert_eq!(lambda_effect("[$ [1+]?]"), Some(StackEffect::new(1, 1)));
+    // The two branches leave different stack heights
+    assert_eq!(lambda_effect("[$ [1]?]"), None);
+    // Depends on a value pushed by the caller
+    assert_eq!(lambda_effect("[$ ø]"), None);
+    assert_eq!(lambda_effect(FIB), Some(StackEffect::new(1, 1)));
+}
+
+#[test]
+fn recursive_fib_hits_the_cache() {
+    let (output, stats) = run_memoized(&format!("{FIB} 25 f;! ."), 1000);
+    assert_eq!(output, "75025");
+    assert_eq!(stats.memoized_lambdas, 1);
+    assert_eq!(stats.misses, 26);
+    assert_eq!(stats.entries, 26);
+    assert!(stats.hits > 0);
+}
+
+#[test]
+fn cache_size_is_limited() {
+    let (output, stats) = run_memoized(&format!("{FIB} 20 f;! ."), 5);
+    assert_eq!(output, "6765");
+    assert_eq!(stats.entries, 5);
+}
+
+#[test]
+fn impure_lambdas_are_not_memoized() {
+    let (output, stats) =
+        run_memoized("[$ .] p: 1 p;! p;! % [1 c; + c:] i: 0 c: i;! i;! c; .", 100);
+    assert_eq!(output, "112");
+    assert_eq!(stats.memoized_lambdas, 0);
+}
+
+#[test]
+fn fetched_globals_are_part_of_the_key() {
+    let (output, stats) = run_memoized("[n; +] a: 1 n: 1 a;! . 2 n: 1 a;! .", 100);
+    assert_eq!(output, "23");
+    assert_eq!((stats.hits, stats.misses), (0, 2));
+}
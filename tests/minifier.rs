@@ -0,0 +1,134 @@
+use std::process::Command;
+
+use chumsky::span::SimpleSpan;
+use falsy::ast::{FalseInstruction, Spanned};
+use falsy::minifier::minify;
+use falsy::parser::parse;
+
+mod common;
+
+use common::random_programs;
+
+test_each_file::test_each_file! { for ["false"] in "./tests/samples" => test_minifying_samples }
+
+/// A program with the differences minifying may make taken out: spans,
+/// how integers are written, and how output is split into strings.
+fn canonical(ast: Vec<Spanned<FalseInstruction>>) -> Vec<FalseInstruction> {
+    use FalseInstruction::*;
+
+    let mut out: Vec<FalseInstruction> = Vec::new();
+    for spanned in ast {
+        let instruction = match spanned.into_parts().0 {
+            PushChar(c) => PushInt(i32::from(c)),
+            Lambda(body) => Lambda(spanless(canonical(body))),
+            ConditionalExecute(body) => ConditionalExecute(spanless(canonical(body))),
+            WhileLoop(condition, body) => {
+                WhileLoop(spanless(canonical(condition)), spanless(canonical(body)))
+            }
+            other => other,
+        };
+        match (out.last_mut(), instruction) {
+            (Some(PushInt(c @ 0..=127)), WriteChar) if *c != i32::from(b'"') => {
+                let c = char::from(*c as u8).to_string();
+                out.pop();
+                match out.last_mut() {
+                    Some(WriteStr(previous)) => previous.push_str(&c),
+                    _ => out.push(WriteStr(c)),
+                }
+            }
+            (Some(WriteStr(previous)), WriteStr(text)) => previous.push_str(&text),
+            (_, instruction) => out.push(instruction),
+        }
+    }
+    out
+}
+
+fn spanless(instructions: Vec<FalseInstruction>) -> Vec<Spanned<FalseInstruction>> {
+    instructions
+        .into_iter()
+        .map(|instruction| Spanned::new(instruction, SimpleSpan::new(0, 0)))
+        .collect()
+}
+
+/// Minifies `source`, checking that it is the same program and that
+/// minifying it again changes nothing.
+fn assert_minifies(source: &str) -> String {
+    let before = parse(source).into_result().unwrap();
+    let minified = minify(&before);
+    let after = parse(&minified)
+        .into_result()
+        .unwrap_or_else(|e| panic!("{minified:?} doesn't parse: {e:?}"));
+    assert_eq!(minify(&after), minified, "{source:?}");
+    assert_eq!(canonical(after), canonical(before), "{minified:?}");
+    minified
+}
+
+fn test_minifying_samples([source]: [&str; 1]) {
+    let minified = assert_minifies(source);
+    assert!(minified.len() <= source.trim_end().len());
+}
+
+#[test]
+fn removes_comments_and_whitespace() {
+    assert_eq!(
+        assert_minifies("{ square } [ $ * ] s :\n\n3 s ; ! .  { done }"),
+        "[$*]s:3s;!."
+    );
+    // Except between numbers
+    assert_eq!(assert_minifies("1 2 + 3 [4] 5"), "1 2+3[4]5");
+    assert_eq!(assert_minifies("[1][2][3]#[4][5]?"), "[1][2][3]#[4][5]?");
+    assert_eq!(assert_minifies("{ nothing }"), "");
+}
+
+#[test]
+fn writes_integers_the_shortest_way() {
+    // Three digits are a character, where there is one to write
+    assert_eq!(assert_minifies("120 126 127 1000"), "'x'~127 1000");
+    // Two digits are only a character when that saves a space
+    assert_eq!(assert_minifies("65."), "65.");
+    assert_eq!(assert_minifies("1 65 2"), "1'A2");
+    assert_eq!(assert_minifies("65 1"), "'A1");
+    // And character literals are numbers where those are shorter
+    assert_eq!(assert_minifies("'1 . 'A. '\n."), "49.65.10.");
+    assert_eq!(assert_minifies("'1 'A 1"), "'1'A1");
+    assert_eq!(assert_minifies("'é."), "195.");
+}
+
+#[test]
+fn merges_output_into_strings() {
+    assert_eq!(assert_minifies("\"a\" \"b\""), "\"ab\"");
+    assert_eq!(assert_minifies("\"a\"10,'b,"), "\"a\nb\"");
+    assert_eq!(assert_minifies("72,105,"), "\"Hi\"");
+    // A string on its own is written as a character where that is shorter
+    assert_eq!(assert_minifies("5,"), "5,");
+    assert_eq!(assert_minifies("\"\u{5}\""), "5,");
+    // `"` can't be in a string, and other writes stay in between
+    assert_eq!(assert_minifies("\"a\"34,\"b\""), "\"a\"34,\"b\"");
+    assert_eq!(assert_minifies("\"a\"1.\"b\"ß\"c\""), "\"a\"1.\"b\"ß\"c\"");
+    // Lambdas are merged on their own
+    assert_eq!(assert_minifies("\"a\"[\"b\" 'c,]!"), "\"a\"[\"bc\"]!");
+}
+
+#[test]
+fn prints_minified_source() {
+    let path = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("golf.false");
+    std::fs::write(&path, "{ add } 1 2 + .\n").unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_falsy"))
+        .arg("minify")
+        .arg(&path)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "1 2+.");
+}
+
+#[test]
+fn minifies_random_programs() {
+    let pieces = [
+        "[", "]", "]?", "][", "]#", "{c}", "\"s\"", "\"\"", "'x", "' ", "'\"", " ", "1 ", "65 ",
+        "120 ", "10 ", "34 ", "a", ":", ";", "!", ",", ".", "+", "_",
+    ];
+    for source in random_programs(0x5851_f42d, &pieces).take(500) {
+        assert_minifies(&source);
+    }
+}
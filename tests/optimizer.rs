@@ -0,0 +1,61 @@
+use falsy::ast::FalseInstruction::{self, *};
+use falsy::optimizer::{optimize, OptimizationLevel};
+use falsy::parser::parse;
+
+fn optimized(
+    source: &str,
+    level: OptimizationLevel,
+) -> Vec<(FalseInstruction, std::ops::Range<usize>)> {
+    let ast = parse(source).into_result().expect("Failed to parse");
+    optimize(ast, level)
+        .into_iter()
+        .map(|s| (s.instruction().clone(), s.span().into_range()))
+        .collect()
+}
+
+#[test]
+fn folds_constants_and_keeps_spans() {
+    assert_eq!(
+        optimized("1 2 + 3 * ^", OptimizationLevel::Basic),
+        [(PushInt(9), 0..9), (ReadChar, 10..11)]
+    );
+    assert_eq!(
+        optimized("3 2 > 'a 'a = 5_ ~", OptimizationLevel::Basic),
+        [
+            (PushInt(-1), 0..5),
+            (PushInt(-1), 6..13),
+            (PushInt(4), 14..18)
+        ]
+    );
+    // Division by zero and overflow are left for the runtime to report
+    assert_eq!(optimized("1 0 /", OptimizationLevel::Basic).len(), 3);
+    assert_eq!(
+        optimized("2147483647 1 +", OptimizationLevel::Basic).len(),
+        3
+    );
+}
+
+#[test]
+fn peephole_rewrites() {
+    assert_eq!(
+        optimized("'h , 'i , \"!\" 42 .", OptimizationLevel::Basic),
+        [(WriteStr("hi!42".to_string()), 0..18)]
+    );
+    assert_eq!(
+        optimized("^ $ % \\ \\ _ _ 1 %", OptimizationLevel::Basic),
+        [(ReadChar, 0..1)]
+    );
+}
+
+#[test]
+fn removes_dead_branches_at_full() {
+    assert_eq!(
+        optimized("^ 0 [1.]? 1 [2.]? [0][3.]# [4.]!", OptimizationLevel::Full),
+        [(ReadChar, 0..1), (WriteStr("24".to_string()), 13..30)]
+    );
+    assert_eq!(
+        optimized("0 [1.]?", OptimizationLevel::Basic).len(),
+        2,
+        "dead branches are only removed at -O2"
+    );
+}
@@ -0,0 +1,30 @@
+use falsy::ast::FalseInstruction;
+use falsy::parser::parse;
+
+#[test]
+fn accepts_empty_and_comment_only_blocks() {
+    for source in [
+        "",
+        "  \n",
+        "{ only a comment }",
+        "{a}{b}",
+        "[]",
+        "[ ]",
+        "[{ empty }]",
+        "[\n  {a}\n  {b}\n]?",
+    ] {
+        assert!(parse(source).into_result().is_ok(), "{source:?}");
+    }
+
+    let ast = parse("[ { nothing } ]! {end}").into_result().unwrap();
+    assert_eq!(ast.len(), 2);
+    assert_eq!(*ast[0].instruction(), FalseInstruction::Lambda(Vec::new()));
+    assert_eq!(*ast[1].instruction(), FalseInstruction::Execute);
+}
+
+#[test]
+fn rejects_unclosed_blocks_and_comments() {
+    for source in ["[", "[{ empty }", "[1 2 + .", "{ unclosed", "[{ unclosed ]"] {
+        assert!(parse(source).into_result().is_err(), "{source:?}");
+    }
+}
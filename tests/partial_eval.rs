@@ -0,0 +1,73 @@
+use falsy::interpreter::Interpreter;
+use falsy::parser::parse;
+use falsy::partial_eval::{specialize, Specialized};
+
+fn specialize_source(source: &str, input: &str, input_complete: bool) -> Specialized {
+    let ast = parse(source).into_result().expect("Failed to parse");
+    specialize(&ast, input, input_complete)
+}
+
+fn residual_source(source: &str, input: &str) -> String {
+    match specialize_source(source, input, false) {
+        residual @ Specialized::Residual { .. } => residual.to_source(),
+        other => panic!("Expected a residual program, got {other:?}"),
+    }
+}
+
+#[test]
+fn runs_to_completion() {
+    assert_eq!(
+        specialize_source("\"Hi \" 6 7 * . '!,", "", false),
+        Specialized::Finished {
+            output: "Hi 42!".to_string()
+        }
+    );
+    // Reading past the end of complete input gives -1
+    assert_eq!(
+        specialize_source("^ ^ + .", "a", true),
+        Specialized::Finished {
+            output: "96".to_string()
+        }
+    );
+}
+
+#[test]
+fn stops_at_unknown_input() {
+    assert_eq!(
+        residual_source("\"a\" 1 x: ^ x; + .", ""),
+        "\"a\" 1 x : ^ x ; + ."
+    );
+    assert_eq!(residual_source("^ ^ + .", "a"), "97 ^ + .");
+}
+
+#[test]
+fn resumes_inside_loops_and_lambdas() {
+    // Stuck in the loop condition, after the body has run once
+    assert_eq!(
+        residual_source("[^ $ 1_ = ~][,]#", "x"),
+        "\"x\" ^ $ 1 _ = ~ [, [^ $ 1 _ = ~][,]#]?"
+    );
+    // Stuck inside a lambda called from a variable
+    assert_eq!(
+        residual_source("[^ 1+]f: f;! f;! .", "a"),
+        "[^ 1 +] f : 98 ^ 1 + ."
+    );
+}
+
+#[test]
+fn leaves_runtime_errors_to_runtime() {
+    let source = "\"a\" 1 0 / .";
+    let residual = residual_source(source, "");
+    assert_eq!(residual, "\"a\" 1 0 / .");
+
+    // The residual program keeps the original span of the failing division
+    let Specialized::Residual { program } = specialize_source(source, "", false) else {
+        unreachable!()
+    };
+    let error = Interpreter::new()
+        .on_output(|_| {})
+        .run_program(program)
+        .unwrap_err();
+    assert_eq!(error.reason(), "Division by zero");
+    assert_eq!(error.span().into_range(), 8..9);
+}
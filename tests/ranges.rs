@@ -0,0 +1,102 @@
+use falsy::analysis::ranges::{self, RangeWarningKind};
+use falsy::parser::parse;
+
+fn warnings(source: &str) -> Vec<(usize, RangeWarningKind, bool)> {
+    let ast = parse(source).into_result().expect("Failed to parse");
+    ranges::analyze(&ast)
+        .into_iter()
+        .map(|w| (w.span().start, w.kind().clone(), w.is_certain()))
+        .collect()
+}
+
+#[test]
+fn constant_division_by_zero() {
+    let found = warnings("5 0 /");
+    assert_eq!(found.len(), 1);
+    assert!(matches!(
+        found[0],
+        (4, RangeWarningKind::DivisionByZero { .. }, true)
+    ));
+}
+
+#[test]
+fn division_by_input_may_be_zero() {
+    let found = warnings("10 ^ '0 - /");
+    assert_eq!(found.len(), 1);
+    assert!(matches!(
+        found[0],
+        (10, RangeWarningKind::DivisionByZero { .. }, false)
+    ));
+    assert!(warnings("10 ^ 1 + 1 | /").is_empty());
+}
+
+#[test]
+fn or_of_zeros_is_zero() {
+    assert!(warnings("0 0 |").is_empty());
+    let found = warnings("5 0 0 | /");
+    assert_eq!(found.len(), 1);
+    assert!(matches!(
+        found[0],
+        (8, RangeWarningKind::DivisionByZero { .. }, true)
+    ));
+}
+
+#[test]
+fn invalid_chars() {
+    assert!(warnings("65 , 'a ,").is_empty());
+    let found = warnings("1_ , 55296 ,");
+    assert_eq!(found.len(), 2);
+    assert!(found
+        .iter()
+        .all(
+            |(_, kind, certain)| matches!(kind, RangeWarningKind::InvalidChar { .. }) && *certain
+        ));
+}
+
+#[test]
+fn pick_out_of_range() {
+    assert!(warnings("1 2 1 ø").is_empty());
+    let found = warnings("1 2 2 ø");
+    assert!(matches!(
+        found[0],
+        (
+            6,
+            RangeWarningKind::PickOutOfRange { depth: Some(2), .. },
+            true
+        )
+    ));
+}
+
+#[test]
+fn values_flow_through_variables_and_lambdas() {
+    assert!(warnings("[ 2 * ] d: 3 d;! x: 12 x; /").is_empty());
+    assert_eq!(warnings("[ 0 ] d: 3 d;! /").len(), 1);
+}
+
+#[test]
+fn loops_are_widened() {
+    // n counts down to zero, and is then used as a divisor
+    let found = warnings("3 n: [n; 0 >][n; 1 - n:]# 10 n; /");
+    assert_eq!(found.len(), 1);
+    assert!(matches!(
+        found[0].1,
+        RangeWarningKind::DivisionByZero { .. }
+    ));
+}
+
+#[test]
+fn check_fails_only_on_certain_errors() {
+    let check = |name: &str, source: &str| {
+        let path = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join(name);
+        std::fs::write(&path, source).unwrap();
+        std::process::Command::new(env!("CARGO_BIN_EXE_falsy"))
+            .arg("check")
+            .arg(&path)
+            .output()
+            .unwrap()
+            .status
+    };
+    assert!(!check("certain.false", "5 0 /").success());
+    assert!(check("possible.false", "10 ^ '0 - /").success());
+    assert!(check("fine.false", "1 2 + .").success());
+}
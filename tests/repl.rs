@@ -0,0 +1,133 @@
+use std::cell::RefCell;
+
+use falsy::interpreter::Interpreter;
+use falsy::repl::{self, Repl, ReplError};
+
+#[test]
+fn keeps_the_stack_and_variables_between_entries() {
+    let mut repl = Repl::new(Interpreter::new());
+    assert_eq!(repl.eval("1 2").unwrap(), "1 2");
+    assert_eq!(repl.eval("[$*]s:").unwrap(), "1 2");
+    assert_eq!(repl.eval("+ s;!").unwrap(), "9");
+    assert_eq!(repl.eval("a").unwrap(), "9 a");
+    assert_eq!(repl.eval(":").unwrap(), "(empty stack)");
+    assert_eq!(repl.eval(":vars").unwrap(), "a = 9\ns = […]");
+}
+
+#[test]
+fn runs_lambdas_defined_on_earlier_lines() {
+    let output = RefCell::new(String::new());
+    let mut repl =
+        Repl::new(Interpreter::new().on_output(|s: &str| output.borrow_mut().push_str(s)));
+    repl.eval("[\"hello \".]f:").unwrap();
+    repl.eval("[f;!]g:").unwrap();
+    assert_eq!(repl.eval("1 g;! 2 g;!").unwrap(), "(empty stack)");
+    assert_eq!(output.borrow().as_str(), "hello 1hello 2");
+}
+
+#[test]
+fn frees_programs_on_reset_and_keeps_running() {
+    let mut repl = Repl::new(Interpreter::new());
+    repl.eval("[1+]f: 1 f;!").unwrap();
+    assert_eq!(repl.eval(":reset").unwrap(), "(empty stack)");
+    repl.eval("[2*]f:").unwrap();
+    assert_eq!(repl.eval("3 f;! [f;!]").unwrap(), "6 […]");
+    assert_eq!(repl.eval("!").unwrap(), "12");
+}
+
+#[test]
+fn runs_lambdas_made_by_lambdas_from_earlier_entries() {
+    let mut repl = Repl::new(Interpreter::new());
+    repl.eval("[[1+]]m:").unwrap();
+    repl.eval("m;!i:").unwrap();
+    repl.eval("[i;!i;!]j:").unwrap();
+    assert_eq!(repl.eval("5j;!").unwrap(), "7");
+    // Only `i` still refers to the first entry
+    repl.eval("0m:").unwrap();
+    assert_eq!(repl.eval("j;!").unwrap(), "9");
+}
+
+#[test]
+fn runs_commands() {
+    let mut repl = Repl::new(Interpreter::new());
+    assert_eq!(repl.eval(":stack").unwrap(), "(empty stack)");
+    assert_eq!(repl.eval(":vars").unwrap(), "(no variables)");
+    repl.eval("1 x:2").unwrap();
+    assert_eq!(repl.eval("  :stack  ").unwrap(), "2");
+    assert_eq!(repl.eval(":reset").unwrap(), "(empty stack)");
+    assert_eq!(repl.eval(":vars").unwrap(), "(no variables)");
+    assert!(repl.eval(":help").unwrap().contains(":load FILE"));
+
+    assert_eq!(
+        repl.eval(":frobnicate"),
+        Err(ReplError::Command(
+            "Unknown command :frobnicate, see :help".to_string()
+        ))
+    );
+    assert_eq!(
+        repl.eval(":stack 1"),
+        Err(ReplError::Command(
+            ":stack doesn't take an argument".to_string()
+        ))
+    );
+    assert!(matches!(repl.eval(":load"), Err(ReplError::Command(_))));
+    assert!(matches!(
+        repl.eval(":load does/not/exist.false"),
+        Err(ReplError::Command(message)) if message.starts_with("Failed to read does/not/exist.false")
+    ));
+}
+
+#[test]
+fn loads_files() {
+    let output = RefCell::new(String::new());
+    let mut repl =
+        Repl::new(Interpreter::new().on_output(|s: &str| output.borrow_mut().push_str(s)));
+    repl.eval("7").unwrap();
+    assert_eq!(
+        repl.eval(":load tests/samples/hello_world.false").unwrap(),
+        "7"
+    );
+    assert_eq!(output.borrow().as_str(), "Hello, World!");
+}
+
+#[test]
+fn reports_errors_and_keeps_going() {
+    let mut repl = Repl::new(Interpreter::new());
+    repl.eval("5").unwrap();
+    let Err(ReplError::Program {
+        filename,
+        source,
+        diagnostics,
+    }) = repl.eval("1 0/")
+    else {
+        panic!("Expected a runtime error");
+    };
+    assert_eq!(filename, "repl");
+    assert_eq!(source, "1 0/");
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].message, "Division by zero");
+    assert_eq!(diagnostics[0].span.into_range(), 3..4);
+    // The operands were already popped when it failed
+    assert_eq!(repl.eval(":stack").unwrap(), "5");
+
+    assert!(matches!(
+        repl.eval("1 ]"),
+        Err(ReplError::Program { diagnostics, .. }) if !diagnostics.is_empty()
+    ));
+    assert_eq!(repl.eval("1+").unwrap(), "6");
+}
+
+#[test]
+fn continues_entries_with_open_brackets() {
+    assert!(!repl::is_unfinished("1 2+"));
+    assert!(!repl::is_unfinished("[1][2]#"));
+    assert!(repl::is_unfinished("[1"));
+    assert!(repl::is_unfinished("[[1]"));
+    assert!(repl::is_unfinished("{ a comment"));
+    assert!(repl::is_unfinished("\"a string"));
+    assert!(!repl::is_unfinished("{ [ } \"[\" '["));
+    assert!(!repl::is_unfinished("1]"));
+
+    let mut repl = Repl::new(Interpreter::new());
+    assert_eq!(repl.eval("[1\n2+]!").unwrap(), "3");
+}
@@ -1,32 +1,144 @@
-use std::path::Path;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
 
-use falsy::{interpreter, parser::parse};
+use falsy::ast::{FalseInstruction, Spanned};
+use falsy::codegen;
+use falsy::interpreter::{self, Interpreter};
+use falsy::minifier::minify;
+use falsy::optimizer::{optimize, OptimizationLevel};
+use falsy::parser::parse;
+use falsy::partial_eval::{specialize, Specialized};
 
 test_each_file::test_each_path! { in "./tests/samples" => test_samples }
+test_each_file::test_each_path! { in "./tests/samples" as optimized => test_optimized_samples }
+test_each_file::test_each_path! { in "./tests/samples" as memoized => test_memoized_samples }
+test_each_file::test_each_path! { in "./tests/samples" as specialized => test_specialized_samples }
+test_each_file::test_each_path! { in "./tests/samples" as minified => test_minified_samples }
+test_each_file::test_each_path! { in "./tests/samples" as closures => test_closure_samples }
+test_each_file::test_each_path! { in "./tests/samples" as bytecode => test_bytecode_samples }
+test_each_file::test_each_path! { in "./tests/samples" as c => test_c_samples }
+test_each_file::test_each_path! { in "./tests/samples" as wasm => test_wasm_samples }
+test_each_file::test_each_path! { in "./tests/samples" as js => test_js_samples }
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+test_each_file::test_each_path! { in "./tests/samples" as x86_64 => test_x86_64_samples }
+#[cfg(feature = "jit")]
+test_each_file::test_each_path! { in "./tests/samples" as jit => test_jit_samples }
 
 fn test_samples(path: &Path) {
-    // Get all .false files in tests/samples
-    if path.extension().unwrap() != "false" {
+    let Some((ast, manifest)) = load_sample(path) else {
         return;
+    };
+
+    for run in manifest.runs {
+        let output = run_interpreter(ast.clone(), &run.input);
+
+        assert_eq!(
+            output, run.output,
+            "output mismatch for input {}",
+            run.input
+        );
     }
+}
 
-    let contents = std::fs::read_to_string(&path).unwrap();
-    let ast = parse(&contents).into_result().expect("Failed to parse");
+fn test_optimized_samples(path: &Path) {
+    let Some((ast, manifest)) = load_sample(path) else {
+        return;
+    };
 
-    let manifest = path.with_extension("toml");
-    let manifest = std::fs::read_to_string(manifest).unwrap();
-    let manifest: SampleManifest = toml::from_str(&manifest).unwrap();
+    for level in [OptimizationLevel::Basic, OptimizationLevel::Full] {
+        let optimized = optimize(ast.clone(), level);
+        for run in &manifest.runs {
+            let expected = run_interpreter(ast.clone(), &run.input);
+            let output = run_interpreter(optimized.clone(), &run.input);
+
+            assert_eq!(
+                output, expected,
+                "output mismatch at {level:?} for input {}",
+                run.input
+            );
+        }
+    }
+}
+
+fn test_memoized_samples(path: &Path) {
+    let Some((ast, manifest)) = load_sample(path) else {
+        return;
+    };
+
+    for run in manifest.runs {
+        let output = run_interpreter_with(ast.clone(), &run.input, |interpreter| {
+            interpreter.memoize(16)
+        });
+
+        assert_eq!(
+            output, run.output,
+            "output mismatch for input {}",
+            run.input
+        );
+    }
+}
+
+fn test_closure_samples(path: &Path) {
+    let Some((ast, manifest)) = load_sample(path) else {
+        return;
+    };
 
     for run in manifest.runs {
+        let output = run_interpreter_with(ast.clone(), &run.input, |interpreter| {
+            interpreter.backend(interpreter::Backend::Closures)
+        });
+
+        assert_eq!(
+            output, run.output,
+            "output mismatch for input {}",
+            run.input
+        );
+    }
+}
+
+/// Runs every sample under the tree walker and the JIT, which must agree on
+/// the output and on any runtime error.
+#[cfg(feature = "jit")]
+fn test_jit_samples(path: &Path) {
+    let Some((ast, manifest)) = load_sample(path) else {
+        return;
+    };
+
+    let run_with = |backend, input: &str| {
         let mut output = Vec::new();
-        let mut input = run.input.chars();
-        interpreter::Interpreter::new()
+        let mut input = input.chars();
+        let result = interpreter::Interpreter::new()
             .on_input(|| input.next().map(|c| c as u8))
             .on_output(|s| output.push(s.to_string()))
+            .backend(backend)
             .run_program(ast.clone())
-            .unwrap();
+            .map(|_| ())
+            .map_err(|e| (e.reason().to_string(), e.span()));
+        (output.join(""), result)
+    };
+    for run in manifest.runs {
+        let expected = run_with(interpreter::Backend::Tree, &run.input);
+        let jit = run_with(interpreter::Backend::Jit, &run.input);
 
-        let output = output.join("");
+        assert_eq!(jit, expected, "JIT mismatch for input {}", run.input);
+        assert_eq!(jit.0, run.output, "output mismatch for input {}", run.input);
+    }
+}
+
+/// Compiles each sample to bytecode, and runs what loading it gives.
+fn test_bytecode_samples(path: &Path) {
+    let Some((ast, manifest)) = load_sample(path) else {
+        return;
+    };
+
+    let source = std::fs::read_to_string(path).unwrap();
+    let compiled = falsy::bytecode::compile(&ast, &source, "sample.false");
+    let loaded = falsy::bytecode::load(&compiled).unwrap();
+    assert_eq!(loaded.program, ast);
+    assert!(loaded.matches_source(&source));
+    for run in manifest.runs {
+        let output = run_interpreter(loaded.program.clone(), &run.input);
 
         assert_eq!(
             output, run.output,
@@ -36,6 +148,327 @@ fn test_samples(path: &Path) {
     }
 }
 
+/// Compiles each sample to C and runs it, if there is a C compiler.
+fn test_c_samples(path: &Path) {
+    let Some((ast, manifest)) = load_sample(path) else {
+        return;
+    };
+    let Some(binary) = build_c(path, &ast) else {
+        eprintln!("No C compiler found, skipping {path:?}");
+        return;
+    };
+
+    for run in manifest.runs {
+        assert_eq!(
+            run_binary(&binary, &run.input),
+            run.output,
+            "output mismatch for input {}",
+            run.input
+        );
+    }
+}
+
+/// Compiles each sample to WebAssembly and runs it in an embedded runtime.
+fn test_wasm_samples(path: &Path) {
+    let Some((ast, manifest)) = load_sample(path) else {
+        return;
+    };
+    let source = std::fs::read_to_string(path).unwrap();
+    let stem = path.file_stem().unwrap().to_string_lossy();
+    let module = codegen::wasm::compile(&ast, &source, &stem);
+
+    for run in manifest.runs {
+        let (output, error) = run_wasm(&module, &run.input);
+        assert_eq!(error, None, "error for input {}", run.input);
+        assert_eq!(
+            output, run.output,
+            "output mismatch for input {}",
+            run.input
+        );
+    }
+}
+
+/// The I/O imports of a compiled module.
+struct WasmHost {
+    input: std::vec::IntoIter<u8>,
+    output: Vec<u8>,
+    error: Vec<u8>,
+}
+
+/// Runs a compiled module with `input`, and returns its output and error
+/// message, if it failed.
+fn run_wasm(module: &[u8], input: &str) -> (String, Option<String>) {
+    use wasmi::{Caller, Engine, Extern, Linker, Module, Store};
+
+    fn memory(caller: &Caller<'_, WasmHost>, ptr: i32, len: i32) -> Vec<u8> {
+        let memory = caller
+            .get_export("memory")
+            .and_then(Extern::into_memory)
+            .unwrap();
+        memory.data(caller)[ptr as usize..][..len as usize].to_vec()
+    }
+
+    let engine = Engine::default();
+    let module = Module::new(&engine, module).unwrap();
+    let host = WasmHost {
+        input: input.as_bytes().to_vec().into_iter(),
+        output: Vec::new(),
+        error: Vec::new(),
+    };
+    let mut store = Store::new(&engine, host);
+    let mut linker = Linker::new(&engine);
+    linker
+        .func_wrap("env", "read", |mut caller: Caller<'_, WasmHost>| {
+            caller.data_mut().input.next().map_or(-1, i32::from)
+        })
+        .unwrap();
+    linker
+        .func_wrap(
+            "env",
+            "write",
+            |mut caller: Caller<'_, WasmHost>, ptr: i32, len: i32| {
+                let bytes = memory(&caller, ptr, len);
+                caller.data_mut().output.extend(bytes);
+            },
+        )
+        .unwrap();
+    linker
+        .func_wrap(
+            "env",
+            "error",
+            |mut caller: Caller<'_, WasmHost>, ptr: i32, len: i32| {
+                let bytes = memory(&caller, ptr, len);
+                caller.data_mut().error.extend(bytes);
+            },
+        )
+        .unwrap();
+    let instance = linker
+        .instantiate(&mut store, &module)
+        .unwrap()
+        .start(&mut store)
+        .unwrap();
+    let run = instance.get_typed_func::<(), ()>(&store, "run").unwrap();
+    let result = run.call(&mut store, ());
+
+    let host = store.into_data();
+    let output = String::from_utf8(host.output).unwrap();
+    match result {
+        Ok(()) => (output, None),
+        Err(_) => (output, Some(String::from_utf8(host.error).unwrap())),
+    }
+}
+
+/// Compiles each sample to JavaScript and runs it with Node.js, if it is
+/// installed.
+fn test_js_samples(path: &Path) {
+    let Some((ast, manifest)) = load_sample(path) else {
+        return;
+    };
+    let source = std::fs::read_to_string(path).unwrap();
+    let stem = path.file_stem().unwrap().to_string_lossy();
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("js");
+    std::fs::create_dir_all(&dir).unwrap();
+    let module = dir.join(format!("{stem}.mjs"));
+    std::fs::write(&module, codegen::js::compile(&ast, &source, &stem)).unwrap();
+    let driver = format!(
+        "import {{ run }} from {:?};\nprocess.stdout.write(run(process.argv[1]));\n",
+        format!("./{stem}.mjs")
+    );
+
+    for run in manifest.runs {
+        let Ok(output) = Command::new("node")
+            .current_dir(&dir)
+            .args(["--input-type=module", "-e", &driver, &run.input])
+            .output()
+        else {
+            eprintln!("No Node.js found, skipping {path:?}");
+            return;
+        };
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap(),
+            run.output,
+            "output mismatch for input {}",
+            run.input
+        );
+    }
+}
+
+/// Compiles each sample to a native executable and checks it against the
+/// interpreter.
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+fn test_x86_64_samples(path: &Path) {
+    use std::os::unix::fs::PermissionsExt;
+
+    let Some((ast, manifest)) = load_sample(path) else {
+        return;
+    };
+    let source = std::fs::read_to_string(path).unwrap();
+    let stem = path.file_stem().unwrap().to_string_lossy();
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("x86_64");
+    std::fs::create_dir_all(&dir).unwrap();
+    let binary = dir.join(stem.as_ref());
+    std::fs::write(&binary, codegen::x86_64::compile(&ast, &source, &stem)).unwrap();
+    std::fs::set_permissions(&binary, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    for run in manifest.runs {
+        let expected = run_interpreter(ast.clone(), &run.input);
+        assert_eq!(
+            run_binary(&binary, &run.input),
+            expected,
+            "output mismatch for input {}",
+            run.input
+        );
+    }
+}
+
+fn build_c(path: &Path, ast: &[Spanned<FalseInstruction>]) -> Option<PathBuf> {
+    let source = std::fs::read_to_string(path).unwrap();
+    let stem = path.file_stem().unwrap().to_string_lossy();
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("c");
+    std::fs::create_dir_all(&dir).unwrap();
+    let c_file = dir.join(format!("{stem}.c"));
+    let binary = dir.join(stem.as_ref());
+    std::fs::write(&c_file, codegen::c::compile(ast, &source, &stem)).unwrap();
+
+    let status = Command::new(std::env::var("CC").unwrap_or_else(|_| "cc".to_string()))
+        .args([
+            "-std=c99",
+            "-Wall",
+            "-Wextra",
+            "-pedantic",
+            "-Werror",
+            "-O1",
+            "-o",
+        ])
+        .arg(&binary)
+        .arg(&c_file)
+        .status()
+        .ok()?;
+    assert!(status.success(), "Failed to compile {c_file:?}");
+    Some(binary)
+}
+
+/// Runs a compiled sample with `input` on stdin, and returns its stdout.
+fn run_binary(binary: &Path, input: &str) -> String {
+    let mut child = Command::new(binary)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success(), "{binary:?} failed");
+    String::from_utf8(output.stdout).unwrap()
+}
+
+fn test_specialized_samples(path: &Path) {
+    let Some((ast, manifest)) = load_sample(path) else {
+        return;
+    };
+
+    for run in manifest.runs {
+        let input: Vec<char> = run.input.chars().collect();
+        // Every prefix of the input, and then the whole input marked complete
+        let splits = (0..=input.len())
+            .map(|known| (known, false))
+            .chain([(input.len(), true)]);
+        for (known, input_complete) in splits {
+            let prefix: String = input[..known].iter().collect();
+            let rest: String = input[known..].iter().collect();
+            let specialized = specialize(&ast, &prefix, input_complete);
+            if input_complete {
+                assert!(
+                    matches!(specialized, Specialized::Finished { .. }),
+                    "expected {path:?} to finish with input {}",
+                    run.input
+                );
+            }
+
+            let source = specialized.to_source();
+            let residual = parse(&source)
+                .into_result()
+                .unwrap_or_else(|e| panic!("Residual program {source:?} failed to parse: {e:?}"));
+            assert_eq!(
+                run_interpreter(residual, &rest),
+                run.output,
+                "output mismatch for residual {source:?} with input {}",
+                run.input
+            );
+        }
+    }
+}
+
+/// Runs each sample minified, which must print the same.
+fn test_minified_samples(path: &Path) {
+    let Some((ast, manifest)) = load_sample(path) else {
+        return;
+    };
+
+    let source = minify(&ast);
+    let minified = parse(&source)
+        .into_result()
+        .unwrap_or_else(|e| panic!("Minified program {source:?} failed to parse: {e:?}"));
+    for run in manifest.runs {
+        assert_eq!(
+            run_interpreter(minified.clone(), &run.input),
+            run.output,
+            "output mismatch for minified {source:?} with input {}",
+            run.input
+        );
+    }
+}
+
+/// Parses a `.false` sample and its `.toml` manifest. Returns `None` for
+/// the manifests themselves, so every sample is only tested once.
+fn load_sample(path: &Path) -> Option<(Vec<Spanned<FalseInstruction>>, SampleManifest)> {
+    // Get all .false files in tests/samples
+    if path.extension().unwrap() != "false" {
+        return None;
+    }
+
+    let contents = std::fs::read_to_string(path).unwrap();
+    let ast = parse(&contents).into_result().expect("Failed to parse");
+
+    let manifest = path.with_extension("toml");
+    let manifest = std::fs::read_to_string(manifest).unwrap();
+    let manifest: SampleManifest = toml::from_str(&manifest).unwrap();
+
+    Some((ast, manifest))
+}
+
+fn run_interpreter(ast: Vec<Spanned<FalseInstruction>>, input: &str) -> String {
+    run_interpreter_with(ast, input, |interpreter| interpreter)
+}
+
+/// Runs a program in an interpreter set up by `configure`, giving its output.
+fn run_interpreter_with(
+    ast: Vec<Spanned<FalseInstruction>>,
+    input: &str,
+    configure: impl for<'i, 'o> FnOnce(Interpreter<'i, 'o>) -> Interpreter<'i, 'o>,
+) -> String {
+    let mut output = Vec::new();
+    let mut input = input.chars();
+    configure(
+        Interpreter::new()
+            .on_input(|| input.next().map(|c| c as u8))
+            .on_output(|s| output.push(s.to_string())),
+    )
+    .run_program(ast)
+    .unwrap();
+
+    output.join("")
+}
+
 #[derive(serde_derive::Deserialize)]
 struct SampleManifest {
     runs: Vec<SampleManifestRun>,